@@ -1,9 +1,6 @@
 mod server;
 mod plugins;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-
-use clap::Parser;
 use lyserver_shared_data::LYServerSharedData;
 
 use crate::{server::LYServer};
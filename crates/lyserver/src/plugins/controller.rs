@@ -0,0 +1,483 @@
+use std::{fs, io::Cursor, path::{Path, PathBuf}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_shared_data::{plugin_dir_name, LYServerInstalledPlugin, LYServerPluginController, LYServerPluginControllerError, LYServerPluginControllerErrorKind, LYServerSharedDataDirectories as _};
+use tokio::sync::Mutex;
+
+use crate::plugins::{read_plugin_manifest, LYServerPluginManager};
+
+pub struct LYServerPluginManagerController {
+    plugin_manager: Arc<Mutex<LYServerPluginManager>>,
+}
+
+impl LYServerPluginManagerController {
+    pub fn new(plugin_manager: Arc<Mutex<LYServerPluginManager>>) -> Arc<Self> {
+        Arc::new(Self {
+            plugin_manager,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerPluginController for LYServerPluginManagerController {
+    async fn list_plugins(&self) -> anyhow::Result<Vec<LYServerInstalledPlugin>> {
+        self.plugin_manager.lock().await.list_plugins().await
+    }
+
+    async fn install_plugin(&self, archive: Vec<u8>) -> anyhow::Result<LYServerPluginMetadata> {
+        self.plugin_manager.lock().await.install_plugin_archive(&archive).await
+    }
+
+    async fn uninstall_plugin(&self, plugin_id: &str) -> anyhow::Result<()> {
+        self.plugin_manager.lock().await.uninstall_plugin(plugin_id).await
+    }
+
+    async fn enable_plugin(&self, plugin_id: &str) -> anyhow::Result<()> {
+        self.plugin_manager.lock().await.enable_plugin(plugin_id).await
+    }
+
+    async fn disable_plugin(&self, plugin_id: &str) -> anyhow::Result<()> {
+        self.plugin_manager.lock().await.disable_plugin(plugin_id).await
+    }
+}
+
+impl LYServerPluginManager {
+    pub async fn list_plugins(&self) -> anyhow::Result<Vec<LYServerInstalledPlugin>> {
+        let loaded_plugins = self.shared_data.loaded_plugins.read().await
+            .iter()
+            .map(|(_, metadata, _)| metadata.clone())
+            .collect::<Vec<_>>();
+
+        let mut plugins = loaded_plugins
+            .iter()
+            .filter(|metadata| !self.plugin_paths.read().unwrap().contains_key(&metadata.id))
+            .map(|metadata| LYServerInstalledPlugin {
+                metadata: metadata.clone(),
+                path: None,
+                builtin: true,
+                enabled: true,
+                loaded: true,
+            })
+            .collect::<Vec<_>>();
+
+        for (plugin_metadata, plugin_path) in self.get_installed_plugins()? {
+            plugins.push(LYServerInstalledPlugin {
+                enabled: self.plugin_state.is_enabled(&plugin_metadata.id),
+                loaded: loaded_plugins.iter().any(|metadata| metadata.id == plugin_metadata.id),
+                metadata: plugin_metadata,
+                path: Some(plugin_path.display().to_string()),
+                builtin: false,
+            });
+        }
+
+        Ok(plugins)
+    }
+
+    pub async fn install_plugin_archive(&mut self, archive: &[u8]) -> anyhow::Result<LYServerPluginMetadata> {
+        let staging_path = self.shared_data
+            .resolve_data_path(Path::new("tmp"))
+            .join(format!("plugin-install-{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()));
+
+        fs::create_dir_all(&staging_path)
+            .map_err(|e| anyhow::anyhow!("Failed to create staging directory '{}': {}", staging_path.display(), e))?;
+
+        let result = self.install_plugin_from_staging(archive, &staging_path).await;
+
+        if let Err(e) = fs::remove_dir_all(&staging_path) {
+            log::warn!("PluginManager: Failed to clean up staging directory '{}': {}", staging_path.display(), e);
+        }
+
+        result
+    }
+
+    async fn install_plugin_from_staging(&mut self, archive: &[u8], staging_path: &Path) -> anyhow::Result<LYServerPluginMetadata> {
+        // Kept apart from where the old version is set aside, which would otherwise move along with archives holding the plugin at their root
+        let extraction_path = staging_path.join("archive");
+        extract_plugin_archive(archive, &extraction_path)?;
+
+        let extracted_plugin_path = find_extracted_plugin_root(&extraction_path)?;
+        let (plugin_metadata, _) = read_plugin_manifest(&extracted_plugin_path)
+            .map_err(|e| LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::InvalidArchive, format!("Invalid plugin archive: {}", e)))?;
+
+        if self.is_builtin_plugin(&plugin_metadata.id).await {
+            return Err(LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::Conflict, format!("Plugin '{}' conflicts with a built-in plugin", plugin_metadata.id)).into());
+        }
+
+        // The old version is moved aside rather than deleted, so it can be put back if the new one fails to install
+        let mut previous_plugin = None;
+
+        if let Some(existing_plugin_path) = self.find_installed_plugin_path(&plugin_metadata.id)? {
+            log::info!("PluginManager: Upgrading plugin '{}' installed at '{}'...", plugin_metadata.id, existing_plugin_path.display());
+
+            let was_loaded = self.is_plugin_loaded(&plugin_metadata.id).await;
+            if was_loaded {
+                self.unload_plugin(&plugin_metadata.id).await.map_err(anyhow::Error::msg)?;
+            }
+
+            let set_aside_path = staging_path.join("previous");
+            if let Err(e) = fs::rename(&existing_plugin_path, &set_aside_path) {
+                self.restore_previous_plugin(&plugin_metadata.id, None, Some((existing_plugin_path.clone(), existing_plugin_path.clone(), was_loaded))).await;
+                return Err(anyhow::anyhow!("Failed to move old plugin '{}' aside: {}", existing_plugin_path.display(), e));
+            }
+
+            previous_plugin = Some((set_aside_path, existing_plugin_path, was_loaded));
+        }
+
+        let plugin_path = self.shared_data
+            .resolve_data_path(Path::new("plugins"))
+            .join(plugin_dir_name(&plugin_metadata.id));
+
+        if let Err(e) = fs::rename(&extracted_plugin_path, &plugin_path) {
+            self.restore_previous_plugin(&plugin_metadata.id, None, previous_plugin).await;
+            return Err(anyhow::anyhow!("Failed to move plugin into '{}': {}", plugin_path.display(), e));
+        }
+
+        if self.plugin_state.is_enabled(&plugin_metadata.id)
+            && let Err(e) = self.load_plugin_from_path(&plugin_path).await
+        {
+            self.restore_previous_plugin(&plugin_metadata.id, Some(&plugin_path), previous_plugin).await;
+            return Err(anyhow::Error::msg(e));
+        }
+
+        if let Some((set_aside_path, _, _)) = previous_plugin
+            && let Err(e) = fs::remove_dir_all(&set_aside_path)
+        {
+            log::warn!("PluginManager: Failed to remove old plugin '{}': {}", set_aside_path.display(), e);
+        }
+
+        log::info!("PluginManager: Installed plugin '{}' (v{}) to '{}'", plugin_metadata.id, plugin_metadata.version, plugin_path.display());
+
+        Ok(plugin_metadata)
+    }
+
+    pub async fn uninstall_plugin(&mut self, plugin_id: &str) -> anyhow::Result<()> {
+        if self.is_builtin_plugin(plugin_id).await {
+            return Err(LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::Conflict, format!("Built-in plugin '{}' cannot be uninstalled", plugin_id)).into());
+        }
+
+        let plugin_path = self.find_installed_plugin_path(plugin_id)?
+            .ok_or_else(|| not_installed_error(plugin_id))?;
+
+        if self.is_plugin_loaded(plugin_id).await {
+            self.unload_plugin(plugin_id).await.map_err(anyhow::Error::msg)?;
+        }
+
+        fs::remove_dir_all(&plugin_path)
+            .map_err(|e| anyhow::anyhow!("Failed to remove plugin '{}': {}", plugin_path.display(), e))?;
+
+        self.plugin_state.set_enabled(plugin_id, true)?;
+
+        log::info!("PluginManager: Uninstalled plugin '{}'", plugin_id);
+
+        Ok(())
+    }
+
+    pub async fn enable_plugin(&mut self, plugin_id: &str) -> anyhow::Result<()> {
+        let plugin_path = self.find_installed_plugin_path(plugin_id)?
+            .ok_or_else(|| not_installed_error(plugin_id))?;
+
+        self.plugin_state.set_enabled(plugin_id, true)?;
+
+        if !self.is_plugin_loaded(plugin_id).await {
+            self.load_plugin_from_path(&plugin_path).await.map_err(anyhow::Error::msg)?;
+        }
+
+        log::info!("PluginManager: Enabled plugin '{}'", plugin_id);
+
+        Ok(())
+    }
+
+    pub async fn disable_plugin(&mut self, plugin_id: &str) -> anyhow::Result<()> {
+        if self.is_builtin_plugin(plugin_id).await {
+            return Err(LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::Conflict, format!("Built-in plugin '{}' cannot be disabled", plugin_id)).into());
+        }
+
+        if self.find_installed_plugin_path(plugin_id)?.is_none() {
+            return Err(not_installed_error(plugin_id).into());
+        }
+
+        self.plugin_state.set_enabled(plugin_id, false)?;
+
+        if self.is_plugin_loaded(plugin_id).await {
+            self.unload_plugin(plugin_id).await.map_err(anyhow::Error::msg)?;
+        }
+
+        log::info!("PluginManager: Disabled plugin '{}'", plugin_id);
+
+        Ok(())
+    }
+
+    async fn is_plugin_loaded(&self, plugin_id: &str) -> bool {
+        self.shared_data.loaded_plugins.read().await
+            .iter()
+            .any(|(_, metadata, _)| metadata.id == plugin_id)
+    }
+
+    async fn is_builtin_plugin(&self, plugin_id: &str) -> bool {
+        self.is_plugin_loaded(plugin_id).await && !self.plugin_paths.read().unwrap().contains_key(plugin_id)
+    }
+
+    fn get_installed_plugins(&self) -> anyhow::Result<Vec<(LYServerPluginMetadata, PathBuf)>> {
+        let plugins_path = self.shared_data.resolve_data_path(Path::new("plugins"));
+
+        if !plugins_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let installed_plugins = fs::read_dir(&plugins_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read plugins directory '{}': {}", plugins_path.display(), e))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .filter_map(|path| {
+                read_plugin_manifest(&path)
+                    .ok()
+                    .map(|(plugin_metadata, _)| (plugin_metadata, path))
+            })
+            .collect();
+
+        Ok(installed_plugins)
+    }
+
+    /// Rolls back a failed upgrade: removes the new plugin at `new_plugin_path`, if it was already moved into
+    /// place, and moves the previous version from where it was set aside back to its original path, loading it
+    /// again if it was loaded before.
+    async fn restore_previous_plugin(&mut self, plugin_id: &str, new_plugin_path: Option<&Path>, previous_plugin: Option<(PathBuf, PathBuf, bool)>) {
+        if let Some(new_plugin_path) = new_plugin_path {
+            if self.is_plugin_loaded(plugin_id).await
+                && let Err(e) = self.unload_plugin(plugin_id).await
+            {
+                log::warn!("PluginManager: Failed to unload plugin '{}' while rolling back: {}", plugin_id, e);
+            }
+
+            if let Err(e) = fs::remove_dir_all(new_plugin_path) {
+                log::warn!("PluginManager: Failed to remove plugin '{}' while rolling back: {}", new_plugin_path.display(), e);
+            }
+        }
+
+        let Some((set_aside_path, original_path, was_loaded)) = previous_plugin else {
+            return;
+        };
+
+        if set_aside_path != original_path && let Err(e) = fs::rename(&set_aside_path, &original_path) {
+            log::error!("PluginManager: Failed to restore old plugin '{}' from '{}': {}", original_path.display(), set_aside_path.display(), e);
+            return;
+        }
+
+        if was_loaded && let Err(e) = self.load_plugin_from_path(&original_path).await {
+            log::error!("PluginManager: Failed to reload old plugin '{}': {}", plugin_id, e);
+        }
+    }
+
+    fn find_installed_plugin_path(&self, plugin_id: &str) -> anyhow::Result<Option<PathBuf>> {
+        if let Some((plugin_path, _)) = self.plugin_paths.read().unwrap().get(plugin_id) {
+            return Ok(Some(plugin_path.clone()));
+        }
+
+        Ok(self.get_installed_plugins()?
+            .into_iter()
+            .find(|(plugin_metadata, _)| plugin_metadata.id == plugin_id)
+            .map(|(_, plugin_path)| plugin_path))
+    }
+}
+
+fn not_installed_error(plugin_id: &str) -> LYServerPluginControllerError {
+    LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::NotInstalled, format!("Plugin '{}' is not installed", plugin_id))
+}
+
+/// Archives which fail to extract are treated as broken uploads rather than server failures.
+fn extract_plugin_archive(archive: &[u8], destination: &Path) -> anyhow::Result<()> {
+    let invalid_archive = |message: String| LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::InvalidArchive, message);
+
+    if archive.starts_with(b"PK\x03\x04") {
+        zip::ZipArchive::new(Cursor::new(archive))
+            .and_then(|mut zip_archive| zip_archive.extract(destination))
+            .map_err(|e| invalid_archive(format!("Failed to extract zip archive: {}", e)))?;
+    } else if archive.starts_with(&[0x1f, 0x8b]) {
+        tar::Archive::new(flate2::read::GzDecoder::new(archive))
+            .unpack(destination)
+            .map_err(|e| invalid_archive(format!("Failed to extract tar.gz archive: {}", e)))?;
+    } else if archive.get(257..262) == Some(b"ustar") {
+        tar::Archive::new(archive)
+            .unpack(destination)
+            .map_err(|e| invalid_archive(format!("Failed to extract tar archive: {}", e)))?;
+    } else {
+        return Err(invalid_archive("Unsupported plugin archive, expected a zip, tar or tar.gz file".to_string()).into());
+    }
+
+    Ok(())
+}
+
+/// Archives either contain the plugin files at their root, or inside a single top-level directory.
+fn find_extracted_plugin_root(staging_path: &Path) -> anyhow::Result<PathBuf> {
+    if staging_path.join("manifest.toml").exists() {
+        return Ok(staging_path.to_path_buf());
+    }
+
+    let entries = fs::read_dir(staging_path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+
+    match entries.as_slice() {
+        [plugin_path] if plugin_path.is_dir() && plugin_path.join("manifest.toml").exists() => Ok(plugin_path.clone()),
+        _ => Err(LYServerPluginControllerError::new(LYServerPluginControllerErrorKind::InvalidArchive, "Invalid plugin archive: manifest.toml not found").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use lyserver_shared_data::LYServerSharedData;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lyserver-controller-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn manifest(version: &str, dependency: Option<&str>) -> String {
+        let mut manifest = format!(
+            "id = \"test@plugin\"\nname = \"test\"\ndescription = \"\"\nversion = \"{}\"\nauthor = \"\"\nwasm_entry_point = \"plugin.wasm\"\n",
+            version
+        );
+
+        if let Some(dependency) = dependency {
+            manifest.push_str(&format!("\n[[dependencies]]\nid = \"{}\"\nversion = \"*\"\n", dependency));
+        }
+
+        manifest
+    }
+
+    fn zip_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+
+        for (name, data) in entries {
+            writer.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn tar_gz_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn zip_archives_with_the_plugin_at_their_root_are_extracted() {
+        let staging_path = temp_path("zip-root");
+        let manifest = manifest("1.0.0", None);
+
+        extract_plugin_archive(&zip_archive(&[("manifest.toml", manifest.as_bytes()), ("plugin.wasm", b"\0asm")]), &staging_path).unwrap();
+
+        assert_eq!(find_extracted_plugin_root(&staging_path).unwrap(), staging_path);
+        assert_eq!(fs::read(staging_path.join("plugin.wasm")).unwrap(), b"\0asm");
+
+        fs::remove_dir_all(&staging_path).unwrap();
+    }
+
+    #[test]
+    fn tar_gz_archives_with_the_plugin_in_a_single_directory_are_extracted() {
+        let staging_path = temp_path("tar-gz-nested");
+        let manifest = manifest("1.0.0", None);
+
+        extract_plugin_archive(&tar_gz_archive(&[("test/manifest.toml", manifest.as_bytes()), ("test/plugin.wasm", b"\0asm")]), &staging_path).unwrap();
+
+        assert_eq!(find_extracted_plugin_root(&staging_path).unwrap(), staging_path.join("test"));
+
+        fs::remove_dir_all(&staging_path).unwrap();
+    }
+
+    #[test]
+    fn archives_without_a_manifest_are_invalid() {
+        let staging_path = temp_path("no-manifest");
+
+        extract_plugin_archive(&zip_archive(&[("a/manifest.toml", b""), ("b/manifest.toml", b"")]), &staging_path).unwrap();
+
+        let error = find_extracted_plugin_root(&staging_path).unwrap_err();
+        assert_eq!(error.downcast_ref::<LYServerPluginControllerError>().unwrap().kind, LYServerPluginControllerErrorKind::InvalidArchive);
+
+        fs::remove_dir_all(&staging_path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_archives_are_invalid() {
+        let staging_path = temp_path("unsupported");
+
+        let error = extract_plugin_archive(b"not an archive", &staging_path).unwrap_err();
+        assert_eq!(error.downcast_ref::<LYServerPluginControllerError>().unwrap().kind, LYServerPluginControllerErrorKind::InvalidArchive);
+    }
+
+    #[test]
+    fn archive_entries_cannot_escape_the_staging_directory() {
+        let root_path = temp_path("escape");
+        let staging_path = root_path.join("staging");
+        fs::create_dir_all(&staging_path).unwrap();
+
+        let _ = extract_plugin_archive(&zip_archive(&[("../escaped.txt", b"escaped")]), &staging_path);
+
+        assert!(!root_path.join("escaped.txt").exists());
+
+        fs::remove_dir_all(&root_path).unwrap();
+    }
+
+    #[test]
+    fn manifests_with_an_entry_point_outside_of_the_plugin_are_rejected() {
+        let plugin_path = temp_path("entry-point");
+        fs::create_dir_all(&plugin_path).unwrap();
+
+        for wasm_entry_point in ["../plugin.wasm", "/etc/passwd", "./plugin.wasm"] {
+            let manifest = manifest("1.0.0", None).replace("\"plugin.wasm\"", &format!("\"{}\"", wasm_entry_point));
+            fs::write(plugin_path.join("manifest.toml"), manifest).unwrap();
+
+            let error = read_plugin_manifest(&plugin_path).unwrap_err();
+            assert!(error.contains("relative path inside the plugin directory"), "{}", error);
+        }
+
+        fs::remove_dir_all(&plugin_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_upgrades_put_the_previous_version_back() {
+        let data_dir = temp_path("rollback");
+        let shared_data = Arc::new(LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap());
+
+        let installed_path = shared_data.resolve_data_path(Path::new("plugins")).join(plugin_dir_name("test@plugin"));
+        fs::create_dir_all(&installed_path).unwrap();
+        fs::write(installed_path.join("manifest.toml"), manifest("1.0.0", None)).unwrap();
+        fs::write(installed_path.join("plugin.wasm"), b"\0asm").unwrap();
+
+        let mut plugin_manager = LYServerPluginManager::new(shared_data);
+
+        // The missing dependency makes loading the new version fail after it was moved into place
+        let manifest = manifest("2.0.0", Some("missing@plugin"));
+        let archive = tar_gz_archive(&[("manifest.toml", manifest.as_bytes()), ("plugin.wasm", b"\0asm")]);
+
+        let error = plugin_manager.install_plugin_archive(&archive).await.unwrap_err();
+        assert!(error.to_string().contains("missing@plugin"), "{}", error);
+
+        let (plugin_metadata, _) = read_plugin_manifest(&installed_path).unwrap();
+        assert_eq!(plugin_metadata.version, "1.0.0");
+
+        let staging_entries = fs::read_dir(data_dir.join("tmp")).map(|entries| entries.count()).unwrap_or(0);
+        assert_eq!(staging_entries, 0);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
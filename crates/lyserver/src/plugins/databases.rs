@@ -0,0 +1,50 @@
+use std::{fs, path::{Component, Path}};
+
+use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_shared_data::{LYServerDatabaseService, LYServerSharedData, LYServerSharedDataServices as _};
+
+/// Opens the databases declared in a plugin's manifest, reading their migrations from the plugin directory.
+pub async fn open_plugin_databases(shared_data: &LYServerSharedData, metadata: &LYServerPluginMetadata, plugin_path: &Path) -> anyhow::Result<()> {
+    if metadata.databases.is_empty() {
+        return Ok(());
+    }
+
+    let database_service = shared_data.get_service::<dyn LYServerDatabaseService>()
+        .ok_or_else(|| anyhow::anyhow!("Plugin declares databases, but the database service is not available"))?;
+
+    for database in &metadata.databases {
+        let migrations = database.migrations.iter()
+            .map(|migration| read_migration(plugin_path, migration))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| anyhow::anyhow!("Failed to read migrations of database '{}': {}", database.name, e))?;
+
+        database_service.open_plugin_database(&metadata.id, &database.name, migrations)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open database '{}': {}", database.name, e))?;
+    }
+
+    Ok(())
+}
+
+/// Closes the databases of a plugin, if the database service is still around.
+pub async fn close_plugin_databases(shared_data: &LYServerSharedData, plugin_id: &str) {
+    let Some(database_service) = shared_data.get_service::<dyn LYServerDatabaseService>() else {
+        return;
+    };
+
+    if let Err(e) = database_service.close_plugin_databases(plugin_id).await {
+        log::warn!("PluginManager: Failed to close the databases of '{}': {}", plugin_id, e);
+    }
+}
+
+fn read_migration(plugin_path: &Path, migration: &str) -> anyhow::Result<String> {
+    let migration_path = Path::new(migration);
+
+    // Migrations come from an untrusted manifest, they may not point outside of the plugin directory
+    if !migration_path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(anyhow::anyhow!("Migration '{}' has to be a relative path inside the plugin directory", migration));
+    }
+
+    fs::read_to_string(plugin_path.join(migration_path))
+        .map_err(|e| anyhow::anyhow!("Failed to read '{}': {}", migration, e))
+}
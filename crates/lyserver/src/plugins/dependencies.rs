@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use lyserver_plugin_common::{LYServerPluginDependency, LYServerPluginMetadata};
+use semver::{Version, VersionReq};
+
+/// The order plugins should be loaded in, along with the plugins which cannot be loaded at all.
+#[derive(Debug, Default)]
+pub struct LYServerPluginLoadOrder {
+    pub order: Vec<String>,
+    pub errors: BTreeMap<String, String>,
+    /// Ids of candidates which were skipped because an earlier candidate or a loaded plugin has the same id
+    pub duplicates: Vec<String>,
+}
+
+/// Resolves the load order of `candidates`, so every plugin is loaded after the plugins it depends on.
+///
+/// Dependencies may also be satisfied by plugins which are already `loaded`. Candidates are kept in
+/// their given order wherever their dependencies allow it. When several candidates share an id only the
+/// first one is kept, so built-in plugins should come first.
+pub fn resolve_plugin_load_order(candidates: &[LYServerPluginMetadata], loaded: &[LYServerPluginMetadata]) -> LYServerPluginLoadOrder {
+    let mut errors = BTreeMap::new();
+    let mut duplicates = Vec::new();
+    let mut candidate_ids = HashSet::new();
+
+    let candidates = candidates
+        .iter()
+        .filter(|candidate| {
+            let is_unique = !loaded.iter().any(|metadata| metadata.id == candidate.id) && candidate_ids.insert(candidate.id.as_str());
+
+            if !is_unique {
+                duplicates.push(candidate.id.clone());
+            }
+
+            is_unique
+        })
+        .collect::<Vec<_>>();
+
+    let available = candidates
+        .iter()
+        .copied()
+        .chain(loaded.iter())
+        .map(|metadata| (metadata.id.as_str(), metadata))
+        .collect::<HashMap<_, _>>();
+
+    let dependency_errors = candidates
+        .iter()
+        .filter_map(|metadata| {
+            check_plugin_dependencies(metadata, |id| available.get(id).copied())
+                .err()
+                .map(|e| (metadata.id.clone(), e))
+        })
+        .collect::<Vec<_>>();
+
+    errors.extend(dependency_errors);
+
+    let pending = candidates
+        .iter()
+        .copied()
+        .filter(|metadata| !errors.contains_key(&metadata.id))
+        .collect::<Vec<_>>();
+
+    // Edges only matter between candidates, plugins which are already loaded are always satisfied
+    let mut edges = pending
+        .iter()
+        .map(|metadata| {
+            let dependency_ids = metadata.dependencies
+                .iter()
+                .map(|dependency| dependency.id.as_str())
+                .filter(|id| pending.iter().any(|other| other.id == *id))
+                .collect::<Vec<_>>();
+
+            (metadata.id.as_str(), dependency_ids)
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Plugins load without their optional dependencies, so those are left out where they would close a cycle
+    for metadata in &pending {
+        for dependency in &metadata.optional_dependencies {
+            let id = dependency.id.as_str();
+
+            if edges.contains_key(id) && !depends_on(id, &metadata.id, &edges) {
+                edges.entry(metadata.id.as_str()).or_default().push(id);
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut resolved = HashSet::new();
+
+    // Kahn's algorithm, always picking the earliest candidate which is ready so the given order is kept
+    while let Some(next) = pending.iter().find(|metadata| {
+        !resolved.contains(metadata.id.as_str()) && edges[metadata.id.as_str()].iter().all(|id| resolved.contains(id))
+    }) {
+        resolved.insert(next.id.as_str());
+        order.push(next.id.clone());
+    }
+
+    let unresolved = pending
+        .iter()
+        .map(|metadata| metadata.id.as_str())
+        .filter(|id| !resolved.contains(id))
+        .collect::<Vec<_>>();
+
+    for id in &unresolved {
+        let error = match find_dependency_cycle(id, &edges, &resolved) {
+            Some(cycle) => format!("Dependency cycle detected: {}", cycle.join(" -> ")),
+            None => format!(
+                "Depends on plugins in a dependency cycle: {}",
+                edges[id].iter().filter(|dependency_id| !resolved.contains(*dependency_id)).copied().collect::<Vec<_>>().join(", ")
+            ),
+        };
+
+        errors.insert(id.to_string(), error);
+    }
+
+    // Anything requiring a plugin which cannot be loaded cannot be loaded either, optional dependencies are simply left out
+    loop {
+        let failed = order
+            .iter()
+            .filter_map(|id| {
+                let metadata = available.get(id.as_str())?;
+                let failed_dependency = metadata.dependencies.iter().find(|dependency| errors.contains_key(&dependency.id))?;
+
+                Some((id.clone(), format!("Dependency '{}' cannot be loaded", failed_dependency.id)))
+            })
+            .collect::<Vec<_>>();
+
+        if failed.is_empty() {
+            break;
+        }
+
+        for (id, error) in failed {
+            order.retain(|ordered_id| *ordered_id != id);
+            errors.insert(id, error);
+        }
+    }
+
+    LYServerPluginLoadOrder { order, errors, duplicates }
+}
+
+/// Checks the dependencies of `metadata` against the plugins returned by `get_plugin`.
+///
+/// Required dependencies have to be present, optional dependencies only have to match their version when present.
+pub fn check_plugin_dependencies<'a>(
+    metadata: &LYServerPluginMetadata,
+    get_plugin: impl Fn(&str) -> Option<&'a LYServerPluginMetadata>,
+) -> Result<(), String> {
+    for dependency in &metadata.dependencies {
+        match get_plugin(&dependency.id) {
+            Some(dependency_metadata) => check_dependency_version(dependency, dependency_metadata)?,
+            None => return Err(format!("Missing required dependency '{}' ({})", dependency.id, dependency.version)),
+        }
+    }
+
+    for dependency in &metadata.optional_dependencies {
+        if let Some(dependency_metadata) = get_plugin(&dependency.id) {
+            check_dependency_version(dependency, dependency_metadata)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_dependency_version(dependency: &LYServerPluginDependency, dependency_metadata: &LYServerPluginMetadata) -> Result<(), String> {
+    let version_req = VersionReq::parse(&dependency.version)
+        .map_err(|e| format!("Invalid version requirement '{}' for dependency '{}': {}", dependency.version, dependency.id, e))?;
+
+    let version = Version::parse(&dependency_metadata.version)
+        .map_err(|e| format!("Dependency '{}' has an invalid version '{}': {}", dependency.id, dependency_metadata.version, e))?;
+
+    if !version_req.matches(&version) {
+        return Err(format!(
+            "Dependency '{}' requires version {}, but version {} is available",
+            dependency.id, dependency.version, dependency_metadata.version
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether `from` depends on `to`, directly or through other plugins.
+fn depends_on<'a>(from: &'a str, to: &str, edges: &HashMap<&'a str, Vec<&'a str>>) -> bool {
+    let mut stack = vec![from];
+    let mut visited = HashSet::new();
+
+    while let Some(id) = stack.pop() {
+        if id == to {
+            return true;
+        }
+
+        if visited.insert(id) {
+            stack.extend(edges[id].iter().copied());
+        }
+    }
+
+    false
+}
+
+fn find_dependency_cycle<'a>(start: &'a str, edges: &HashMap<&'a str, Vec<&'a str>>, resolved: &HashSet<&str>) -> Option<Vec<&'a str>> {
+    let mut path = vec![start];
+    let mut visited = HashSet::from([start]);
+
+    fn visit<'a>(
+        start: &'a str,
+        current: &'a str,
+        edges: &HashMap<&'a str, Vec<&'a str>>,
+        resolved: &HashSet<&str>,
+        path: &mut Vec<&'a str>,
+        visited: &mut HashSet<&'a str>,
+    ) -> bool {
+        for &next in edges[current].iter().filter(|id| !resolved.contains(*id)) {
+            if next == start {
+                path.push(next);
+                return true;
+            }
+
+            if visited.insert(next) {
+                path.push(next);
+
+                if visit(start, next, edges, resolved, path, visited) {
+                    return true;
+                }
+
+                path.pop();
+            }
+        }
+
+        false
+    }
+
+    visit(start, start, edges, resolved, &mut path, &mut visited).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(id: &str) -> lyserver_plugin_common::LYServerPluginMetadataBuilder {
+        LYServerPluginMetadata::builder().id(id).name(id).version("1.0.0")
+    }
+
+    #[test]
+    fn loads_dependencies_first() {
+        let candidates = [
+            plugin("a").dependency("b", "^1").build(),
+            plugin("b").build(),
+            plugin("c").build(),
+        ];
+
+        let load_order = resolve_plugin_load_order(&candidates, &[]);
+
+        assert_eq!(load_order.order, ["b", "a", "c"]);
+        assert!(load_order.errors.is_empty());
+    }
+
+    #[test]
+    fn rejects_dependency_cycles() {
+        let candidates = [
+            plugin("a").dependency("b", "^1").build(),
+            plugin("b").dependency("a", "^1").build(),
+            plugin("c").dependency("a", "^1").build(),
+            plugin("d").build(),
+        ];
+
+        let load_order = resolve_plugin_load_order(&candidates, &[]);
+
+        assert_eq!(load_order.order, ["d"]);
+        assert_eq!(load_order.errors["a"], "Dependency cycle detected: a -> b -> a");
+        assert_eq!(load_order.errors["b"], "Dependency cycle detected: b -> a -> b");
+        assert!(load_order.errors.contains_key("c"));
+    }
+
+    #[test]
+    fn skips_optional_dependencies_which_would_close_a_cycle() {
+        let candidates = [
+            plugin("a").optional_dependency("b", "^1").build(),
+            plugin("b").dependency("a", "^1").build(),
+            plugin("c").optional_dependency("d", "^1").build(),
+            plugin("d").optional_dependency("c", "^1").build(),
+            plugin("e").optional_dependency("e", "^1").build(),
+        ];
+
+        let load_order = resolve_plugin_load_order(&candidates, &[]);
+
+        assert_eq!(load_order.order, ["a", "b", "d", "c", "e"]);
+        assert!(load_order.errors.is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_dependencies() {
+        let candidates = [
+            plugin("a").dependency("missing", "^1").build(),
+            plugin("b").dependency("a", "^1").build(),
+            plugin("c").optional_dependency("missing", "^1").build(),
+        ];
+
+        let load_order = resolve_plugin_load_order(&candidates, &[]);
+
+        assert_eq!(load_order.order, ["c"]);
+        assert_eq!(load_order.errors["a"], "Missing required dependency 'missing' (^1)");
+        assert_eq!(load_order.errors["b"], "Dependency 'a' cannot be loaded");
+    }
+
+    #[test]
+    fn rejects_version_mismatches() {
+        let candidates = [
+            plugin("a").dependency("b", "^2").build(),
+            plugin("b").build(),
+            plugin("c").optional_dependency("b", ">=1.1").build(),
+        ];
+
+        let load_order = resolve_plugin_load_order(&candidates, &[]);
+
+        assert_eq!(load_order.order, ["b"]);
+        assert_eq!(load_order.errors["a"], "Dependency 'b' requires version ^2, but version 1.0.0 is available");
+        assert!(load_order.errors.contains_key("c"));
+    }
+
+    #[test]
+    fn dependencies_can_be_satisfied_by_loaded_plugins() {
+        let candidates = [plugin("a").dependency("b", "^1").build()];
+        let loaded = [plugin("b").build()];
+
+        let load_order = resolve_plugin_load_order(&candidates, &loaded);
+
+        assert_eq!(load_order.order, ["a"]);
+        assert!(load_order.errors.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_first_of_duplicate_ids() {
+        let candidates = [
+            plugin("builtin").build(),
+            plugin("a").dependency("builtin", "^1").build(),
+            plugin("builtin").version("2.0.0").wasm_entry_point("plugin.wasm").build(),
+            plugin("loaded").build(),
+        ];
+        let loaded = [plugin("loaded").build()];
+
+        let load_order = resolve_plugin_load_order(&candidates, &loaded);
+
+        assert_eq!(load_order.order, ["builtin", "a"]);
+        assert_eq!(load_order.duplicates, ["builtin", "loaded"]);
+        assert!(load_order.errors.is_empty());
+    }
+}
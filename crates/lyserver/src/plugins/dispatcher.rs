@@ -0,0 +1,260 @@
+use std::{panic::AssertUnwindSafe, sync::Arc};
+
+use futures::FutureExt;
+use lyserver_messaging_shared::LYServerMessageEvent;
+use lyserver_plugin_common::LYServerPluginCrash;
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::{LYServerPluginInstance, LYServerSharedData, LYServerSharedDataPlugins};
+use tokio::sync::{broadcast::{error::RecvError, Receiver}, mpsc, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::plugins::supervisor::panic_message;
+
+/// Feeds the events delivered to a plugin into its `handle_message_event` until `token` is cancelled.
+///
+/// Handlers are started in the order their events arrived and at most `event_concurrency` of them run at once, so
+/// events are only handled strictly one after another with an `event_concurrency` of 1. An error in a handler is
+/// logged and only affects its own event, panics and `LYServerPluginCrash` errors are reported to the plugin's
+/// supervisor on `crashes` instead. RPC events are served by the handlers registered on `plugin_shared_data`
+/// instead, unless the plugin handles them itself.
+pub async fn dispatch_plugin_events(
+    shared_data: Arc<LYServerSharedData>,
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+    plugin_id: String,
+    mut rx: Receiver<LYServerMessageEvent>,
+    event_concurrency: usize,
+    crashes: mpsc::UnboundedSender<(LYServerPluginInstance, anyhow::Error)>,
+    token: CancellationToken,
+) -> anyhow::Result<()> {
+    let semaphore = Arc::new(Semaphore::new(event_concurrency));
+
+    loop {
+        let event = tokio::select! {
+            res = rx.recv() => match res {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("PluginManager: Plugin '{}' missed {} event(s), its handlers are not keeping up", plugin_id, n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = token.cancelled() => break,
+        };
+
+        // The semaphore is fair, so waiting for a permit keeps the events in order
+        let permit = tokio::select! {
+            permit = Arc::clone(&semaphore).acquire_owned() => permit?,
+            _ = token.cancelled() => break,
+        };
+
+        // Looked up for every event since the supervisor may have replaced the instance
+        let Some(plugin) = shared_data.get_plugin_by_id(&plugin_id).await else {
+            continue;
+        };
+
+        let plugin_id = plugin_id.clone();
+        let plugin_shared_data = Arc::clone(&plugin_shared_data);
+        let crashes = crashes.clone();
+
+        tokio::spawn(async move {
+            let event_type = event.event_type.clone();
+            let event_id = event.event_id.clone();
+
+            let handler = async {
+
+                if event.is_rpc_event() && !plugin.handles_rpc_events() {
+                    plugin_shared_data.handle_rpc_event(event).await
+                } else {
+                    plugin.handle_message_event(event).await
+                }
+            };
+
+            let crash = match AssertUnwindSafe(handler).catch_unwind().await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) if e.is::<LYServerPluginCrash>() => Some(e),
+                Ok(Err(e)) => {
+                    log::error!("PluginManager: Plugin '{}' failed to handle event '{}' ({}): {}", plugin_id, event_type, event_id, e);
+                    None
+                }
+                Err(panic) => Some(anyhow::anyhow!("Handler panicked: {}", panic_message(&panic))),
+            };
+
+            if let Some(e) = crash {
+                log::error!("PluginManager: Plugin '{}' crashed handling event '{}' ({}): {}", plugin_id, event_type, event_id, e);
+                let _ = crashes.send((plugin, e));
+            }
+
+            drop(permit);
+        });
+    }
+
+    log::debug!("PluginManager: Stopped dispatching events to plugin '{}'", plugin_id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::Duration};
+
+    use lyserver_messaging_shared::LYServerMessageEventTarget;
+    use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    const TEST_PLUGIN_ID: &str = "events@plugin";
+
+    /// Native plugin which records the events it starts handling. `slow` events take a while, `error` and `crash`
+    /// events fail and `panic` events panic.
+    #[derive(Default)]
+    struct TestPlugin {
+        started: Mutex<Vec<String>>,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LYServerPlugin for TestPlugin {
+        fn metadata(&self) -> LYServerPluginMetadata {
+            LYServerPluginMetadata::builder()
+                .id(TEST_PLUGIN_ID)
+                .name("TestPlugin")
+                .version("1.0.0")
+                .build()
+        }
+
+        async fn init(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn destroy(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn handle_message_event(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
+            self.started.lock().unwrap().push(event.event_id.clone());
+
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+
+            let result = match event.event_type.as_str() {
+                "slow" => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok(())
+                }
+                "error" => Err(anyhow::anyhow!("handler failed")),
+                "crash" => Err(LYServerPluginCrash("guest trapped".to_string()).into()),
+                "panic" => panic!("handler panicked"),
+                _ => Ok(()),
+            };
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.finished.fetch_add(1, Ordering::SeqCst);
+
+            result
+        }
+    }
+
+    struct TestDispatcher {
+        plugin: Arc<TestPlugin>,
+        tx: broadcast::Sender<LYServerMessageEvent>,
+        crashes_rx: mpsc::UnboundedReceiver<(LYServerPluginInstance, anyhow::Error)>,
+        token: CancellationToken,
+    }
+
+    impl TestDispatcher {
+        async fn start(name: &str, event_concurrency: usize) -> Self {
+            let data_dir = std::env::temp_dir().join(format!("lyserver-dispatcher-{}-{}", std::process::id(), name));
+            let shared_data = Arc::new(LYServerSharedData::new_from_args([
+                "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+            ]).unwrap());
+
+            let plugin = Arc::new(TestPlugin::default());
+            let token = CancellationToken::new();
+            shared_data.loaded_plugins.write().await.push((plugin.clone(), plugin.metadata(), token.clone()));
+
+            let (tx, rx) = broadcast::channel(64);
+            let (crashes_tx, crashes_rx) = mpsc::unbounded_channel();
+
+            tokio::spawn(dispatch_plugin_events(
+                Arc::clone(&shared_data),
+                Arc::new(LYServerPluginSharedData::new(Arc::clone(&shared_data))),
+                TEST_PLUGIN_ID.to_string(),
+                rx,
+                event_concurrency,
+                crashes_tx,
+                token.child_token(),
+            ));
+
+            Self { plugin, tx, crashes_rx, token }
+        }
+
+        /// Sends events of the given types, returns their IDs.
+        fn send(&self, event_types: &[&str]) -> Vec<String> {
+            event_types.iter()
+                .map(|event_type| {
+                    let event = LYServerMessageEvent::new(*event_type, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()), LYServerMessageEventTarget::All, ());
+                    let event_id = event.event_id.clone();
+
+                    self.tx.send(event).unwrap();
+                    event_id
+                })
+                .collect()
+        }
+
+        async fn wait_until_finished(&self, events: usize) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.plugin.finished.load(Ordering::SeqCst) < events {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }).await.expect("the events were not handled");
+        }
+    }
+
+    impl Drop for TestDispatcher {
+        fn drop(&mut self) {
+            self.token.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_handled_one_after_another_in_order() {
+        let test = TestDispatcher::start("ordered", 1).await;
+
+        let event_ids = test.send(&["slow", "fast", "slow", "fast"]);
+        test.wait_until_finished(4).await;
+
+        assert_eq!(*test.plugin.started.lock().unwrap(), event_ids);
+        assert_eq!(test.plugin.max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn handlers_run_up_to_the_event_concurrency() {
+        let test = TestDispatcher::start("concurrent", 3).await;
+
+        test.send(&["slow"; 9]);
+        test.wait_until_finished(9).await;
+
+        assert_eq!(test.plugin.max_running.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn panics_and_crashes_are_reported_to_the_supervisor() {
+        let mut test = TestDispatcher::start("crashes", 1).await;
+
+        test.send(&["panic", "error", "crash", "fast"]);
+        test.wait_until_finished(3).await;
+
+        let (_, panic) = test.crashes_rx.recv().await.unwrap();
+        assert_eq!(panic.to_string(), "Handler panicked: handler panicked");
+
+        let (_, crash) = test.crashes_rx.recv().await.unwrap();
+        assert!(crash.is::<LYServerPluginCrash>());
+
+        // Errors only fail their own event and the dispatcher keeps going after a panic
+        assert!(test.crashes_rx.try_recv().is_err());
+        assert_eq!(test.plugin.started.lock().unwrap().len(), 4);
+    }
+}
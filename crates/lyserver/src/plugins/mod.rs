@@ -0,0 +1,554 @@
+mod controller;
+mod databases;
+mod dependencies;
+mod dispatcher;
+mod state;
+mod supervisor;
+mod watcher;
+
+pub use controller::LYServerPluginManagerController;
+
+use core::panic;
+use std::{collections::HashMap, fs, path::{Component, Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, SystemTime}};
+use lyserver_messaging_shared::LYServerMessageEventTarget;
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::{LYServerPluginInstance, LYServerSharedData, LYServerSharedDataDirectories, LYServerSharedDataMessaging, LYServerSharedDataResources, LYServerSharedDataServices};
+use tokio::{sync::{mpsc, Mutex}, task::JoinHandle};
+use futures::future::try_join_all;
+
+use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_plugin_wasm_loader::LYServerWASMLoader;
+use tokio_util::sync::CancellationToken;
+
+use crate::plugins::{databases::{close_plugin_databases, open_plugin_databases}, dependencies::{check_plugin_dependencies, resolve_plugin_load_order}, dispatcher::dispatch_plugin_events, state::LYServerPluginState, supervisor::supervise_plugin};
+
+pub type LYServerBuiltinPluginConstructor = Box<dyn Fn(Arc<LYServerPluginSharedData>) -> LYServerPluginInstance + Send + Sync>;
+
+/// Directories the plugins loaded from disk were loaded from and when, shared with the supervisors which forget the
+/// plugins that gave up.
+pub type LYServerPluginPaths = Arc<std::sync::RwLock<HashMap<String, (PathBuf, SystemTime)>>>;
+
+pub struct LYServerPluginManager {
+    wasm_loader: Arc<LYServerWASMLoader>,
+
+    builtin_plugins: Vec<(LYServerPluginMetadata, LYServerBuiltinPluginConstructor)>,
+
+    plugin_tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+    plugin_paths: LYServerPluginPaths,
+    plugin_state: LYServerPluginState,
+    plugins_watcher: Option<notify::RecommendedWatcher>,
+
+    shared_data: Arc<LYServerSharedData>,
+
+    messaging_loop_running: Arc<AtomicBool>,
+}
+
+impl LYServerPluginManager {
+    pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
+        let plugin_state_path = shared_data.resolve_data_path(Path::new("plugin_state.toml"));
+        let plugin_state = LYServerPluginState::load(&plugin_state_path)
+            .unwrap_or_else(|e| {
+                log::error!("PluginManager: {}, all plugins will be enabled", e);
+                LYServerPluginState::new(&plugin_state_path)
+            });
+
+        Self {
+            wasm_loader: LYServerWASMLoader::new(shared_data.clone()).into(),
+
+            builtin_plugins: Vec::new(),
+
+            plugin_tasks: Vec::new(),
+            plugin_paths: LYServerPluginPaths::default(),
+            plugin_state,
+            plugins_watcher: None,
+
+            shared_data,
+
+            messaging_loop_running: AtomicBool::new(false).into(),
+        }
+    }
+
+    pub async fn init_messaging_loop(&mut self) -> anyhow::Result<()> {
+        log::info!("PluginManager: Initializing global messaging loop...");
+
+        let shared_data_clone = Arc::clone(&self.shared_data);
+        let messaging_loop_running = Arc::clone(&self.messaging_loop_running);
+
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = async {
+                    while !shared_data_clone.shutdown_flag.load(Ordering::Relaxed) {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                } => {}
+                
+                _ = async {
+                    messaging_loop_running.store(true, Ordering::Relaxed);
+
+                    log::info!("Starting plugin global messaging loop...");
+
+                    loop {
+                        if let Some(event) = shared_data_clone.receive_event().await {
+                            log::debug!("!!!!!!!!!!!!!!!!!!!! Received event: {}", event.event_type);
+    
+                            let shared_data_clone = Arc::clone(&shared_data_clone);
+    
+                            tokio::spawn(async move {
+                                log::debug!("got event: {}", event.event_type);
+                                let plugin_receivers = shared_data_clone.messaging_plugin_tx.read().await
+                                    .iter()
+                                    .filter_map(|(plugin_id, tx)| {
+                                        if event.event_target.is_all() || event.event_target.plugin_id() == Some(plugin_id.to_string()) {
+                                            Some(tx.clone())
+                                        } else {
+                                            None
+                                        }
+                                    })
+                                    .collect::<Vec<_>>();
+                                log::debug!("got plugin receivers: {}", plugin_receivers.len());
+        
+                                let plugin_receiver_count = plugin_receivers.len();
+        
+                                log::debug!("[Plugin Messaging] Broadcasting event to {} plugins '{}' ({}): {} -> {}", plugin_receiver_count, event.event_type, event.event_id, event.event_sender, event.event_target);
+        
+                                let event_clone = event.clone();
+                                for tx in plugin_receivers.iter() {
+                                    log::debug!("iter plugin tx");
+        
+                                    let tx_clone = Arc::clone(tx);
+                                    let event_clone = event_clone.clone();
+        
+                                    tokio::spawn(async move {
+                                        log::debug!("pub plugin event");
+        
+                                        let _ = tx_clone.send(event_clone.clone());
+                                    });
+                                }
+                            });
+                        } else {
+                            log::debug!("[Plugin Messaging] Channel closed, stopping global messaging loop");
+                            break;
+                        }
+                    }
+
+                    Ok::<(), anyhow::Error>(())
+                } => {}
+            }
+
+            messaging_loop_running.store(false, Ordering::Relaxed);
+            
+            log::error!("Plugin global messaging loop has exited unexpectedly.");
+
+            Ok(())
+        });
+
+        self.plugin_tasks.push(handle);
+
+        Ok(())
+    }
+
+    /// Registers a plugin which is compiled into the server, it is loaded alongside the directory plugins by `init`.
+    pub fn register_builtin_plugin<F>(&mut self, metadata: LYServerPluginMetadata, constructor: F)
+    where
+        F: Fn(Arc<LYServerPluginSharedData>) -> LYServerPluginInstance + Send + Sync + 'static,
+    {
+        self.builtin_plugins.push((metadata, Box::new(constructor)));
+    }
+
+    pub async fn init(&mut self) -> anyhow::Result<()> {
+        log::info!("PluginManager: Initializing server plugins...");
+
+        let plugins_path = self.shared_data.resolve_data_path(Path::new("plugins"));
+
+        let mut candidates = self.builtin_plugins
+            .iter()
+            .map(|(metadata, _)| metadata.clone())
+            .collect::<Vec<_>>();
+        let mut directory_plugins = HashMap::new();
+
+        if plugins_path.exists() {
+            let mut available_plugins = fs::read_dir(&plugins_path)
+                .expect("Failed to read plugins directory")
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>();
+
+            available_plugins.sort();
+
+            log::debug!("Found {} plugin(s) in '{}'", available_plugins.len(), plugins_path.display());
+
+            for plugin in available_plugins {
+                match read_plugin_manifest(&plugin) {
+                    Ok((plugin_metadata, _)) if !self.plugin_state.is_enabled(&plugin_metadata.id) => {
+                        log::info!("Skipping disabled plugin '{}' at '{}'", plugin_metadata.id, plugin.display());
+                    }
+                    Ok((plugin_metadata, _)) => {
+                        directory_plugins.entry(plugin_metadata.id.clone()).or_insert(plugin);
+                        candidates.push(plugin_metadata);
+                    }
+                    Err(e) => log::error!("Failed to load plugin '{}': {}", plugin.display(), e),
+                }
+            }
+        } else {
+            log::debug!("No plugins directory found at '{}'.", plugins_path.display());
+
+            fs::create_dir(plugins_path.clone())
+                .expect("Failed to create plugins directory");
+        }
+
+        let load_order = resolve_plugin_load_order(&candidates, &self.get_loaded_plugin_metadata().await);
+
+        let mut builtin_plugins = self.builtin_plugins
+            .drain(..)
+            .map(|(metadata, constructor)| (metadata.id, constructor))
+            .collect::<HashMap<_, _>>();
+
+        for plugin_id in &load_order.duplicates {
+            log::error!("Skipping plugin '{}', another plugin with the same id is already present", plugin_id);
+        }
+
+        for (plugin_id, error) in &load_order.errors {
+            if builtin_plugins.contains_key(plugin_id) {
+                return Err(anyhow::anyhow!("Failed to load built-in plugin '{}': {}", plugin_id, error));
+            }
+
+            log::error!("Failed to load plugin '{}': {}", plugin_id, error);
+        }
+
+        log::debug!("Resolved plugin load order: {}", load_order.order.join(", "));
+
+        for plugin_id in load_order.order {
+            if let Some(constructor) = builtin_plugins.remove(&plugin_id) {
+                self.load_plugin(plugin_id.clone(), move |plugin_shared_data| std::future::ready(Ok(constructor(plugin_shared_data))))
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to load built-in plugin '{}': {}", plugin_id, e))?;
+            } else if let Some(plugin_path) = directory_plugins.get(&plugin_id) {
+                log::info!("Loading plugin from path: '{}'", plugin_path.display());
+
+                if let Err(e) = self.load_plugin_from_path(plugin_path).await {
+                    log::error!("Failed to load plugin '{}': {}", plugin_path.display(), e);
+                }
+            }
+        }
+
+        log::info!("PluginManager: Loaded {} plugin(s)!", self.shared_data.loaded_plugins.read().await.len());
+
+        for (_, metadata, _) in self.shared_data.loaded_plugins.read().await.iter() {
+            log::info!("    {} (v{}) - {}", 
+                metadata.name,  
+                metadata.version,
+                metadata.id);
+        }
+
+        Ok(())
+    }
+
+    pub async fn load_plugin_from_path(&mut self, plugin_path: &Path) -> anyhow::Result<LYServerPluginInstance, String> {
+        let plugin_path_display = plugin_path.display();
+
+        if !plugin_path.exists() {
+            return Err(format!("Plugin at '{}' does not exist", plugin_path_display));
+        }
+
+        if !plugin_path.is_dir() {
+            return Err(format!("Plugin path '{}' is not a directory", plugin_path_display));
+        }
+
+        let (plugin_metadata, wasm_full_path) = read_plugin_manifest(plugin_path)?;
+
+        let loaded_plugins = self.get_loaded_plugin_metadata().await;
+        check_plugin_dependencies(&plugin_metadata, |id| loaded_plugins.iter().find(|metadata| metadata.id == id))
+            .map_err(|e| format!("Plugin '{}' cannot be loaded: {}", plugin_metadata.id, e))?;
+
+        // Migrated before the plugin is instantiated, so its init already sees the current schema
+        open_plugin_databases(&self.shared_data, &plugin_metadata, plugin_path)
+            .await
+            .map_err(|e| format!("Plugin '{}' cannot be loaded: {}", plugin_metadata.id, e))?;
+
+        let wasm_loader_consumer = Arc::clone(&self.wasm_loader);
+        let plugin_id = plugin_metadata.id.clone();
+
+        // Registered before the plugin starts, its supervisor removes it again if the plugin gives up
+        self.plugin_paths.write().unwrap().insert(plugin_id.clone(), (plugin_path.to_path_buf(), SystemTime::now()));
+
+        let plugin = self
+            .load_plugin(plugin_metadata.id.clone(), move |plugin_shared_data| {
+                // The supervisor calls this again whenever the plugin is restarted
+                let wasm_loader_consumer = Arc::clone(&wasm_loader_consumer);
+                let plugin_metadata = plugin_metadata.clone();
+                let wasm_full_path = wasm_full_path.clone();
+
+                async move {
+                    wasm_loader_consumer
+                        .create_wasm_plugin_instance(&plugin_metadata, &wasm_full_path, plugin_shared_data)
+                        .await
+                        .map_err(|e| {
+                            format!(
+                                "Failed to create WASM plugin '{}': {}",
+                                plugin_metadata.id, e
+                            )
+                        })
+                }
+            })
+            .await;
+
+        let plugin = match plugin {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                self.plugin_paths.write().unwrap().remove(&plugin_id);
+                close_plugin_databases(&self.shared_data, &plugin_id).await;
+                return Err(e);
+            }
+        };
+
+        Ok(plugin)
+    }
+
+    pub async fn reload_plugin_from_path(&mut self, plugin_path: &Path) -> anyhow::Result<LYServerPluginInstance, String> {
+        if let Some(plugin_id) = self.get_plugin_id_by_path(plugin_path) {
+            log::info!("PluginManager: Reloading plugin '{}' from '{}'...", plugin_id, plugin_path.display());
+
+            self.unload_plugin(&plugin_id).await?;
+        }
+
+        self.load_plugin_from_path(plugin_path).await
+    }
+
+    pub async fn unload_plugin(&mut self, plugin_id: &str) -> anyhow::Result<(), String> {
+        let plugin_token = self.shared_data.loaded_plugins.read().await
+            .iter()
+            .find(|(_, metadata, _)| metadata.id == plugin_id)
+            .map(|(_, _, token)| token.clone())
+            .ok_or_else(|| format!("Plugin '{}' is not loaded", plugin_id))?;
+
+        log::info!("PluginManager: Unloading plugin '{}'...", plugin_id);
+
+        let dependent_plugin_ids = self.get_loaded_plugin_metadata().await
+            .into_iter()
+            .filter(|metadata| metadata.dependencies.iter().any(|dependency| dependency.id == plugin_id))
+            .map(|metadata| metadata.id)
+            .collect::<Vec<_>>();
+
+        if !dependent_plugin_ids.is_empty() {
+            log::warn!("PluginManager: Plugin(s) {} depend on '{}', which is being unloaded", dependent_plugin_ids.join(", "), plugin_id);
+        }
+
+        plugin_token.cancel();
+
+        // The plugin task removes itself from the loaded plugins once destroy has run
+        let timeout = Duration::from_secs(10);
+        let plugin_unloaded = tokio::time::timeout(timeout, async {
+            while self.shared_data.loaded_plugins.read().await.iter().any(|(_, metadata, _)| metadata.id == plugin_id) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await;
+
+        if plugin_unloaded.is_err() {
+            log::warn!("PluginManager: Plugin '{}' did not unload within {}s, removing it anyway", plugin_id, timeout.as_secs());
+
+            self.shared_data.loaded_plugins.write().await
+                .retain(|(_, metadata, _)| metadata.id != plugin_id);
+
+            self.shared_data.unregister_plugin_messaging(plugin_id)
+                .await
+                .map_err(|e| format!("Failed to unregister plugin messaging for '{}': {}", plugin_id, e))?;
+
+            self.shared_data.unregister_plugin_resource_usage(plugin_id);
+            self.shared_data.unregister_plugin_services(plugin_id);
+        }
+
+        close_plugin_databases(&self.shared_data, plugin_id).await;
+
+        self.plugin_paths.write().unwrap().remove(plugin_id);
+
+        Ok(())
+    }
+
+    /// Whether the manifest or wasm module of a plugin loaded from `plugin_path` changed after it was loaded.
+    pub fn is_plugin_modified_since_load(&self, plugin_path: &Path) -> bool {
+        let Some(loaded_at) = self.get_plugin_id_by_path(plugin_path)
+            .and_then(|plugin_id| self.plugin_paths.read().unwrap().get(&plugin_id).map(|(_, loaded_at)| *loaded_at)) else {
+            return true;
+        };
+
+        match read_plugin_manifest(plugin_path) {
+            Ok((_, wasm_full_path)) => [plugin_path.join("manifest.toml"), wasm_full_path]
+                .iter()
+                .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                .any(|modified_at| modified_at > loaded_at),
+            Err(_) => true,
+        }
+    }
+
+    pub async fn get_loaded_plugin_metadata(&self) -> Vec<LYServerPluginMetadata> {
+        self.shared_data.loaded_plugins.read().await
+            .iter()
+            .map(|(_, metadata, _)| metadata.clone())
+            .collect()
+    }
+
+    pub fn get_plugin_id_by_path(&self, plugin_path: &Path) -> Option<String> {
+        self.plugin_paths.read().unwrap()
+            .iter()
+            .find(|(_, (path, _))| path.as_path() == plugin_path)
+            .map(|(plugin_id, _)| plugin_id.clone())
+    }
+
+    pub async fn load_plugin<F, Fut, T: Into<String>>(
+        &mut self,
+        plugin_id: T,
+        constructor: F,
+    ) -> anyhow::Result<LYServerPluginInstance, String>
+    where
+        F: Fn(Arc<LYServerPluginSharedData>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<LYServerPluginInstance, String>> + Send + 'static,
+    {
+        let shared_data = self.shared_data.clone();
+        let mut plugin_shared_data = LYServerPluginSharedData::new(shared_data.clone());
+
+        let plugin_id = plugin_id.into();
+
+        plugin_shared_data.register_plugin_messaging(plugin_id.clone())
+            .await
+            .map_err(|e| format!("Failed to register plugin messaging for '{}': {}", plugin_id, e))?;
+
+        // Subscribe before the plugin starts so no event sent during init is missed
+        let plugin_events_rx = plugin_shared_data.subscribe_events();
+
+        let plugin_shared_data = Arc::new(plugin_shared_data);
+        let plugin = match constructor(plugin_shared_data.clone()).await {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                if let Err(e) = self.shared_data.unregister_plugin_messaging(&plugin_id).await {
+                    log::warn!("PluginManager: {}", e);
+                }
+
+                return Err(e);
+            }
+        };
+        let plugin_metadata = plugin.metadata().clone();
+
+        let plugin_token = CancellationToken::new();
+
+        self.shared_data.loaded_plugins.write().await.push((plugin.clone(), plugin_metadata, plugin_token.clone()));
+
+        let (crashes_tx, crashes_rx) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(supervise_plugin(
+            Arc::clone(&self.shared_data),
+            Arc::clone(&plugin_shared_data),
+            Arc::clone(&plugin),
+            constructor,
+            crashes_rx,
+            Arc::clone(&self.plugin_paths),
+            plugin_token.child_token(),
+        ));
+
+        self.plugin_tasks.push(handle);
+
+        if plugin.receives_message_events() {
+            let event_concurrency = plugin.message_event_concurrency()
+                .map_or(self.shared_data.plugin_event_concurrency, |concurrency| concurrency.min(self.shared_data.plugin_event_concurrency));
+
+            let handle = tokio::spawn(dispatch_plugin_events(
+                Arc::clone(&self.shared_data),
+                Arc::clone(&plugin_shared_data),
+                plugin_id.clone(),
+                plugin_events_rx,
+                event_concurrency,
+                crashes_tx,
+                plugin_token.child_token(),
+            ));
+
+            self.plugin_tasks.push(handle);
+        }
+
+        let timeout = Duration::from_secs(10);
+        log::info!("Waiting for plugin '{}' to initialize... (timeout in {}s)", plugin.metadata().id, timeout.as_secs());
+
+        if !self.messaging_loop_running.load(Ordering::Relaxed) {
+            panic!("PluginManager: Plugin global messaging loop is not running, cannot wait for plugin initialization.");   
+        }
+
+        let plugin_sender = LYServerMessageEventTarget::Plugin(plugin_id.clone());
+
+        if plugin_shared_data.wait_until_event(move |e| e.event_type == "plugin_init" && e.event_sender == plugin_sender, Duration::from_secs(10)).await.is_none() {
+            log::error!("PluginManager: Plugin '{}' did not initialize within {}s, unloading plugin...", plugin.metadata().id, timeout.as_secs());
+
+            plugin_token.cancel();
+
+            return Err(format!("Plugin '{}' did not initialize within {}s", plugin.metadata().id, timeout.as_secs()));
+        }
+
+        Ok(plugin)
+    }
+
+    pub async fn wait_for_all_plugins(plugin_manager: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        // Plugins can be (re)loaded while we wait, so keep draining until no tasks are left
+        loop {
+            let plugin_tasks = plugin_manager.lock().await.plugin_tasks.drain(..).collect::<Vec<_>>();
+
+            if plugin_tasks.is_empty() {
+                return Ok(());
+            }
+
+            if let Err(e) = try_join_all(plugin_tasks).await {
+                return Err(anyhow::anyhow!("Plugin thread panicked! {:#?}", e));
+            }
+        }
+    }
+
+    pub async fn destroy(&mut self) -> anyhow::Result<()> {
+        log::info!("PluginManager: Destroying all plugins...");
+
+        let loaded_plugins = &self.shared_data.loaded_plugins.write().await;
+        for (_, metadata, token) in loaded_plugins.iter() {
+            log::info!("Unloading plugin '{}'...", metadata.id);
+            token.cancel();
+        }
+
+        self.plugins_watcher.take();
+
+        Ok(())
+    }
+}
+
+pub fn read_plugin_manifest(plugin_path: &Path) -> anyhow::Result<(LYServerPluginMetadata, PathBuf), String> {
+    let plugin_path_display = plugin_path.display();
+
+    let plugin_manifest_file_path = fs::read_dir(plugin_path)
+        .map_err(|e| format!("Failed to read plugin dir '{}': {}", plugin_path_display, e))?
+        .find_map(|entry| {
+            entry.ok().and_then(|e| {
+                if e.file_name().eq_ignore_ascii_case("manifest.toml") {
+                    Some(e.path())
+                } else {
+                    None
+                }
+            })
+        })
+        .ok_or_else(|| format!("manifest.toml not found in '{}'", plugin_path_display))?;
+
+    let plugin_manifest_content = fs::read_to_string(&plugin_manifest_file_path)
+        .map_err(|e| format!("Failed to read manifest '{}': {}", plugin_manifest_file_path.display(), e))?;
+
+    let plugin_metadata: LYServerPluginMetadata = toml::from_str(&plugin_manifest_content)
+        .map_err(|e| format!("Failed to parse manifest '{}': {}", plugin_manifest_file_path.display(), e))?;
+
+    let wasm_path = plugin_metadata.wasm_entry_point
+        .as_deref()
+        .ok_or_else(|| format!("Plugin '{}' missing wasm_entry_point in manifest", plugin_metadata.id))?;
+
+    // The entry point comes from an untrusted manifest, it may not point outside of the plugin directory
+    if !Path::new(wasm_path).components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("Plugin '{}' wasm entry '{}' has to be a relative path inside the plugin directory", plugin_metadata.id, wasm_path));
+    }
+
+    let wasm_full_path = plugin_path.join(wasm_path);
+
+    if !wasm_full_path.exists() {
+        return Err(format!("Plugin '{}' wasm entry '{}' not found", plugin_metadata.id, wasm_full_path.display()));
+    }
+
+    Ok((plugin_metadata, wasm_full_path))
+}
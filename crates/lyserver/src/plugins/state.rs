@@ -0,0 +1,62 @@
+use std::{collections::BTreeSet, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+/// Plugin state which has to survive restarts, stored as `plugin_state.toml` in the data directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LYServerPluginState {
+    #[serde(skip)]
+    path: PathBuf,
+
+    #[serde(default)]
+    pub disabled: BTreeSet<String>,
+}
+
+impl LYServerPluginState {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut state: LYServerPluginState = if path.exists() {
+            let state_content = fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read plugin state '{}': {}", path.display(), e))?;
+
+            toml::from_str(&state_content)
+                .map_err(|e| anyhow::anyhow!("Failed to parse plugin state '{}': {}", path.display(), e))?
+        } else {
+            LYServerPluginState::new(path)
+        };
+
+        state.path = path.to_path_buf();
+
+        Ok(state)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let state_content = toml::to_string_pretty(self)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize plugin state: {}", e))?;
+
+        fs::write(&self.path, state_content)
+            .map_err(|e| anyhow::anyhow!("Failed to write plugin state '{}': {}", self.path.display(), e))?;
+
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, plugin_id: &str) -> bool {
+        !self.disabled.contains(plugin_id)
+    }
+
+    pub fn set_enabled(&mut self, plugin_id: &str, enabled: bool) -> anyhow::Result<()> {
+        if enabled {
+            self.disabled.remove(plugin_id);
+        } else {
+            self.disabled.insert(plugin_id.to_string());
+        }
+
+        self.save()
+    }
+}
@@ -0,0 +1,393 @@
+use std::{any::Any, panic::AssertUnwindSafe, sync::Arc, time::{Duration, Instant}};
+
+use futures::FutureExt;
+use lyserver_plugin_common::LYServerPluginRestartPolicy;
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::{LYServerPluginInstance, LYServerPluginSupervisorState, LYServerPluginSupervisorStatus, LYServerSharedData, LYServerSharedDataMessaging, LYServerSharedDataPlugins, LYServerSharedDataResources, LYServerSharedDataServices};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::plugins::{databases::close_plugin_databases, LYServerPluginPaths};
+
+/// Crashes of a plugin instance while handling an event, reported by its dispatcher.
+pub type LYServerPluginCrashReports = mpsc::UnboundedReceiver<(LYServerPluginInstance, anyhow::Error)>;
+
+/// Runs a loaded plugin until `token` is cancelled, re-creating it with `constructor` according to its supervisor policy
+/// whenever its init fails, traps or panics, or one of the `crashes` reported while it handles events.
+///
+/// The restart count starts over once a plugin has been running for twice its maximum backoff.
+pub async fn supervise_plugin<F, Fut>(
+    shared_data: Arc<LYServerSharedData>,
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+    mut plugin: LYServerPluginInstance,
+    constructor: F,
+    mut crashes: LYServerPluginCrashReports,
+    plugin_paths: LYServerPluginPaths,
+    token: CancellationToken,
+) -> anyhow::Result<()>
+where
+    F: Fn(Arc<LYServerPluginSharedData>) -> Fut,
+    Fut: Future<Output = Result<LYServerPluginInstance, String>>,
+{
+    let plugin_id = plugin.metadata().id.clone();
+    let supervisor = plugin.metadata().supervisor.clone();
+    let stable_after = Duration::from_millis(supervisor.backoff_max_ms).saturating_mul(2);
+    let mut status = LYServerPluginSupervisorStatus::default();
+    let mut running_since = Instant::now();
+
+    shared_data.set_plugin_supervisor_status(&plugin_id, status.clone());
+
+    loop {
+        let result = tokio::select! {
+            res = AssertUnwindSafe(plugin.init()).catch_unwind() => {
+                res.unwrap_or_else(|panic| Err(anyhow::anyhow!("Plugin panicked: {}", panic_message(&panic))))
+            }
+            Some(e) = next_crash(&mut crashes, &plugin) => Err(e),
+            _ = token.cancelled() => break,
+        };
+
+        let result = match result {
+            Ok(_) if supervisor.restart != LYServerPluginRestartPolicy::Always => {
+                // Plugins whose init returns stay loaded until they are cancelled or crash handling an event
+                tokio::select! {
+                    Some(e) = next_crash(&mut crashes, &plugin) => Err(e),
+                    _ = token.cancelled() => break,
+                }
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) => {
+                log::warn!("PluginManager: Plugin '{}' has completed, restarting it...", plugin_id);
+            }
+            Err(e) => {
+                log::error!("PluginManager: Plugin '{}' failed: {}", plugin_id, e);
+                status.last_error = Some(e.to_string());
+            }
+        }
+
+        if running_since.elapsed() >= stable_after {
+            status.restart_count = 0;
+        }
+
+        if let Err(e) = plugin.destroy().await {
+            log::warn!("PluginManager: Failed to destroy plugin '{}' after it stopped: {}", plugin_id, e);
+        }
+
+        if supervisor.restart == LYServerPluginRestartPolicy::Never {
+            status.state = LYServerPluginSupervisorState::Failed;
+            shared_data.set_plugin_supervisor_status(&plugin_id, status);
+            remove_failed_plugin(&shared_data, &plugin_paths, &plugin_id, &plugin).await;
+
+            return Ok(());
+        }
+
+        let restarted_plugin = loop {
+            if status.restart_count >= supervisor.max_restarts {
+                log::error!("PluginManager: Plugin '{}' failed after {} restart(s), giving up", plugin_id, status.restart_count);
+
+                status.state = LYServerPluginSupervisorState::Failed;
+                shared_data.set_plugin_supervisor_status(&plugin_id, status);
+                remove_failed_plugin(&shared_data, &plugin_paths, &plugin_id, &plugin).await;
+
+                return Ok(());
+            }
+
+            let backoff = supervisor.backoff(status.restart_count);
+
+            status.state = LYServerPluginSupervisorState::Restarting;
+            shared_data.set_plugin_supervisor_status(&plugin_id, status.clone());
+
+            log::info!("PluginManager: Restarting plugin '{}' in {}ms ({}/{})...", plugin_id, backoff.as_millis(), status.restart_count + 1, supervisor.max_restarts);
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = token.cancelled() => {
+                    shared_data.remove_plugin_supervisor_status(&plugin_id);
+                    remove_loaded_plugin(&shared_data, &plugin_id, &plugin).await;
+
+                    return Ok(());
+                }
+            }
+
+            status.restart_count += 1;
+
+            match constructor(plugin_shared_data.clone()).await {
+                Ok(restarted_plugin) => break restarted_plugin,
+                Err(e) => {
+                    log::error!("PluginManager: Failed to restart plugin '{}': {}", plugin_id, e);
+                    status.last_error = Some(e);
+                }
+            }
+        };
+
+        replace_loaded_plugin(&shared_data, &plugin, &restarted_plugin).await;
+        plugin = restarted_plugin;
+        running_since = Instant::now();
+
+        status.state = LYServerPluginSupervisorState::Running;
+        shared_data.set_plugin_supervisor_status(&plugin_id, status.clone());
+    }
+
+    log::info!("Plugin got shutdown signal, destroying plugin '{}'...", plugin_id);
+
+    if let Err(e) = plugin.destroy().await {
+        log::error!("PluginManager: Failed to destroy plugin '{}': {}", plugin_id, e);
+    } else {
+        log::warn!("PluginManager: Plugin '{}' runtime has completed, closing...", plugin_id);
+    }
+
+    shared_data.remove_plugin_supervisor_status(&plugin_id);
+    remove_loaded_plugin(&shared_data, &plugin_id, &plugin).await;
+
+    Ok(())
+}
+
+/// Waits for a crash of `plugin`, reports about instances which have already been replaced are dropped.
+async fn next_crash(crashes: &mut LYServerPluginCrashReports, plugin: &LYServerPluginInstance) -> Option<anyhow::Error> {
+    loop {
+        let (crashed_plugin, e) = crashes.recv().await?;
+
+        if Arc::ptr_eq(&crashed_plugin, plugin) {
+            return Some(e);
+        }
+    }
+}
+
+/// Removes `plugin` from the loaded plugins, unless it has already been replaced or force-removed.
+/// Returns whether it was removed.
+async fn remove_loaded_plugin(shared_data: &LYServerSharedData, plugin_id: &str, plugin: &LYServerPluginInstance) -> bool {
+    let mut loaded_plugins = shared_data.loaded_plugins.write().await;
+
+    let Some(pos) = loaded_plugins.iter().position(|(p, _, _)| Arc::ptr_eq(p, plugin)) else {
+        return false;
+    };
+
+    loaded_plugins.remove(pos);
+
+    if let Err(e) = shared_data.unregister_plugin_messaging(plugin_id).await {
+        log::warn!("PluginManager: {}", e);
+    }
+
+    shared_data.unregister_plugin_resource_usage(plugin_id);
+    shared_data.unregister_plugin_services(plugin_id);
+
+    true
+}
+
+/// Unregisters a plugin which gave up like unloading it would, so it can be loaded again from its path.
+async fn remove_failed_plugin(shared_data: &LYServerSharedData, plugin_paths: &LYServerPluginPaths, plugin_id: &str, plugin: &LYServerPluginInstance) {
+    if !remove_loaded_plugin(shared_data, plugin_id, plugin).await {
+        return;
+    }
+
+    close_plugin_databases(shared_data, plugin_id).await;
+    plugin_paths.write().unwrap().remove(plugin_id);
+}
+
+async fn replace_loaded_plugin(shared_data: &LYServerSharedData, plugin: &LYServerPluginInstance, restarted_plugin: &LYServerPluginInstance) {
+    let mut loaded_plugins = shared_data.loaded_plugins.write().await;
+
+    if let Some((p, metadata, _)) = loaded_plugins.iter_mut().find(|(p, _, _)| Arc::ptr_eq(p, plugin)) {
+        *p = Arc::clone(restarted_plugin);
+        *metadata = restarted_plugin.metadata().clone();
+    }
+}
+
+pub fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::atomic::{AtomicU32, Ordering}, time::SystemTime};
+
+    use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata, LYServerPluginSupervisor};
+    use lyserver_shared_data::LYServerPluginSupervisorStatus;
+
+    use super::*;
+
+    const TEST_PLUGIN_ID: &str = "failing@plugin";
+
+    /// Native plugin whose init fails after `init_duration`, or keeps running when `init_duration` is `None`.
+    struct TestPlugin {
+        supervisor: LYServerPluginSupervisor,
+        init_duration: Option<Duration>,
+    }
+
+    #[async_trait::async_trait]
+    impl LYServerPlugin for TestPlugin {
+        fn metadata(&self) -> LYServerPluginMetadata {
+            let mut metadata = LYServerPluginMetadata::builder()
+                .id(TEST_PLUGIN_ID)
+                .name("TestPlugin")
+                .version("1.0.0")
+                .build();
+
+            metadata.supervisor = self.supervisor.clone();
+            metadata
+        }
+
+        async fn init(&self) -> anyhow::Result<()> {
+            match self.init_duration {
+                Some(init_duration) => {
+                    tokio::time::sleep(init_duration).await;
+                    Err(anyhow::anyhow!("init failed"))
+                }
+                None => Ok(()),
+            }
+        }
+
+        async fn destroy(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct TestSupervisor {
+        shared_data: Arc<LYServerSharedData>,
+        plugin_paths: LYServerPluginPaths,
+        constructed: Arc<AtomicU32>,
+        crashes_tx: mpsc::UnboundedSender<(LYServerPluginInstance, anyhow::Error)>,
+        plugin: LYServerPluginInstance,
+        token: CancellationToken,
+        handle: tokio::task::JoinHandle<anyhow::Result<()>>,
+    }
+
+    impl TestSupervisor {
+        async fn start(name: &str, supervisor: LYServerPluginSupervisor, init_duration: Option<Duration>) -> Self {
+            let data_dir = std::env::temp_dir().join(format!("lyserver-supervisor-{}-{}", std::process::id(), name));
+            let shared_data = Arc::new(LYServerSharedData::new_from_args([
+                "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+            ]).unwrap());
+
+            let plugin: LYServerPluginInstance = Arc::new(TestPlugin { supervisor: supervisor.clone(), init_duration });
+            let token = CancellationToken::new();
+            shared_data.loaded_plugins.write().await.push((Arc::clone(&plugin), plugin.metadata(), token.clone()));
+
+            let plugin_paths = LYServerPluginPaths::default();
+            plugin_paths.write().unwrap().insert(TEST_PLUGIN_ID.to_string(), (PathBuf::from("/plugins/failing"), SystemTime::now()));
+
+            let constructed = Arc::new(AtomicU32::new(0));
+            let constructed_clone = Arc::clone(&constructed);
+            let constructor = move |_| {
+                constructed_clone.fetch_add(1, Ordering::SeqCst);
+                let plugin: LYServerPluginInstance = Arc::new(TestPlugin { supervisor: supervisor.clone(), init_duration });
+
+                async move { Ok(plugin) }
+            };
+
+            let (crashes_tx, crashes_rx) = mpsc::unbounded_channel();
+            let handle = tokio::spawn(supervise_plugin(
+                Arc::clone(&shared_data),
+                Arc::new(LYServerPluginSharedData::new(Arc::clone(&shared_data))),
+                Arc::clone(&plugin),
+                constructor,
+                crashes_rx,
+                Arc::clone(&plugin_paths),
+                token.child_token(),
+            ));
+
+            Self { shared_data, plugin_paths, constructed, crashes_tx, plugin, token, handle }
+        }
+
+        async fn wait_until_stopped(&mut self) {
+            tokio::time::timeout(Duration::from_secs(5), &mut self.handle).await.expect("the supervisor did not stop").unwrap().unwrap();
+        }
+
+        fn status(&self) -> Option<LYServerPluginSupervisorStatus> {
+            self.shared_data.get_plugin_supervisor_status().remove(TEST_PLUGIN_ID)
+        }
+
+        async fn wait_for_restarts(&self, restarts: u32) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.constructed.load(Ordering::SeqCst) < restarts {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }).await.expect("the plugin was not restarted");
+        }
+    }
+
+    fn supervisor(max_restarts: u32, backoff_initial_ms: u64, backoff_max_ms: u64) -> LYServerPluginSupervisor {
+        LYServerPluginSupervisor {
+            restart: LYServerPluginRestartPolicy::OnFailure,
+            max_restarts,
+            backoff_initial_ms,
+            backoff_max_ms,
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_plugins_are_restarted_with_backoff_until_the_supervisor_gives_up() {
+        let mut test = TestSupervisor::start("backoff", supervisor(3, 20, 1_000), Some(Duration::ZERO)).await;
+        let started_at = Instant::now();
+
+        test.wait_until_stopped().await;
+
+        // 20ms + 40ms + 80ms of backoff before the three restarts
+        assert!(started_at.elapsed() >= Duration::from_millis(140), "{:?}", started_at.elapsed());
+        assert_eq!(test.constructed.load(Ordering::SeqCst), 3);
+
+        let status = test.status().unwrap();
+        assert_eq!(status.state, LYServerPluginSupervisorState::Failed);
+        assert_eq!(status.restart_count, 3);
+        assert_eq!(status.last_error.as_deref(), Some("init failed"));
+    }
+
+    #[tokio::test]
+    async fn plugins_the_supervisor_gave_up_on_are_unregistered() {
+        let mut test = TestSupervisor::start("give-up", supervisor(1, 1, 1), Some(Duration::ZERO)).await;
+
+        test.wait_until_stopped().await;
+
+        assert!(test.shared_data.loaded_plugins.read().await.is_empty());
+        assert!(test.plugin_paths.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restart_count_starts_over_after_a_stable_period() {
+        // Each instance runs for longer than twice the maximum backoff before failing
+        let mut test = TestSupervisor::start("stable", supervisor(1, 5, 5), Some(Duration::from_millis(30))).await;
+
+        test.wait_for_restarts(3).await;
+
+        let status = test.status().unwrap();
+        assert_ne!(status.state, LYServerPluginSupervisorState::Failed);
+        assert!(status.restart_count <= 1, "{}", status.restart_count);
+
+        test.token.cancel();
+        test.wait_until_stopped().await;
+
+        assert!(test.status().is_none());
+        assert_eq!(test.plugin_paths.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn plugins_crashing_on_an_event_are_restarted() {
+        let mut test = TestSupervisor::start("event-crash", supervisor(3, 1, 1), None).await;
+
+        test.crashes_tx.send((Arc::clone(&test.plugin), anyhow::anyhow!("event handler trapped"))).unwrap();
+        test.wait_for_restarts(1).await;
+
+        // The restarted instance replaces the crashed one
+        let loaded_plugins = test.shared_data.loaded_plugins.read().await;
+        assert_eq!(loaded_plugins.len(), 1);
+        assert!(!Arc::ptr_eq(&loaded_plugins[0].0, &test.plugin));
+        drop(loaded_plugins);
+
+        // Reports about the replaced instance are ignored
+        test.crashes_tx.send((Arc::clone(&test.plugin), anyhow::anyhow!("stale report"))).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(test.constructed.load(Ordering::SeqCst), 1);
+
+        let status = test.status().unwrap();
+        assert_eq!(status.state, LYServerPluginSupervisorState::Running);
+        assert_eq!(status.last_error.as_deref(), Some("event handler trapped"));
+
+        test.token.cancel();
+        test.wait_until_stopped().await;
+    }
+}
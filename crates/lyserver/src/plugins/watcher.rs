@@ -0,0 +1,140 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::{atomic::Ordering, Arc}, time::Duration};
+
+use lyserver_shared_data::LYServerSharedDataDirectories as _;
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::plugins::{read_plugin_manifest, LYServerPluginManager};
+
+const PLUGINS_WATCHER_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Changes are applied after this long even if files keep changing, e.g. while a large plugin is copied.
+const PLUGINS_WATCHER_MAX_BATCH_DELAY: Duration = Duration::from_secs(30);
+
+impl LYServerPluginManager {
+    pub async fn init_plugins_watcher(plugin_manager: Arc<Mutex<Self>>) -> anyhow::Result<()> {
+        let (shared_data, plugins_path) = {
+            let locked_plugin_manager = plugin_manager.lock().await;
+            let plugins_path = locked_plugin_manager.shared_data.resolve_data_path(Path::new("plugins"));
+
+            (Arc::clone(&locked_plugin_manager.shared_data), plugins_path)
+        };
+
+        log::info!("PluginManager: Watching '{}' for plugin changes...", plugins_path.display());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            match res {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                        for path in event.paths {
+                            let _ = tx.send(path);
+                        }
+                    }
+                }
+                Err(e) => log::error!("PluginManager: Plugin watcher error: {}", e),
+            }
+        })?;
+
+        watcher.watch(&plugins_path, RecursiveMode::Recursive)?;
+
+        let plugin_manager_clone = Arc::clone(&plugin_manager);
+
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = async {
+                    while !shared_data.shutdown_flag.load(Ordering::Relaxed) {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                } => {}
+
+                _ = async {
+                    while let Some(path) = rx.recv().await {
+                        let mut changed_paths = HashSet::from([path]);
+                        let batch_deadline = Instant::now() + PLUGINS_WATCHER_MAX_BATCH_DELAY;
+
+                        // Builds write the manifest and the wasm module separately, wait for them to settle
+                        loop {
+                            let debounce_deadline = (Instant::now() + PLUGINS_WATCHER_DEBOUNCE).min(batch_deadline);
+
+                            match tokio::time::timeout_at(debounce_deadline, rx.recv()).await {
+                                Ok(Some(path)) => changed_paths.insert(path),
+                                _ => break,
+                            };
+                        }
+
+                        let mut locked_plugin_manager = plugin_manager_clone.lock().await;
+
+                        for plugin_path in get_changed_plugin_paths(&plugins_path, &changed_paths) {
+                            locked_plugin_manager.handle_plugin_path_changed(&plugin_path).await;
+                        }
+                    }
+                } => {}
+            }
+
+            Ok(())
+        });
+
+        let mut locked_plugin_manager = plugin_manager.lock().await;
+        locked_plugin_manager.plugins_watcher = Some(watcher);
+        locked_plugin_manager.plugin_tasks.push(handle);
+
+        Ok(())
+    }
+
+    async fn handle_plugin_path_changed(&mut self, plugin_path: &Path) {
+        if !plugin_path.exists() {
+            if let Some(plugin_id) = self.get_plugin_id_by_path(plugin_path) {
+                log::info!("PluginManager: Plugin directory '{}' was removed, unloading '{}'...", plugin_path.display(), plugin_id);
+
+                if let Err(e) = self.unload_plugin(&plugin_id).await {
+                    log::error!("PluginManager: Failed to unload plugin '{}': {}", plugin_id, e);
+                }
+            }
+
+            return;
+        }
+
+        if let Ok((plugin_metadata, _)) = read_plugin_manifest(plugin_path)
+            && !self.plugin_state.is_enabled(&plugin_metadata.id) {
+            log::debug!("PluginManager: Ignoring changes to disabled plugin '{}'", plugin_metadata.id);
+            return;
+        }
+
+        if !self.is_plugin_modified_since_load(plugin_path) {
+            return;
+        }
+
+        match self.reload_plugin_from_path(plugin_path).await {
+            Ok(plugin) => log::info!("PluginManager: Reloaded plugin '{}' (v{})", plugin.metadata().id, plugin.metadata().version),
+            Err(e) => log::error!("PluginManager: Failed to reload plugin '{}': {}", plugin_path.display(), e),
+        }
+    }
+}
+
+fn get_changed_plugin_paths(plugins_path: &Path, changed_paths: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+    changed_paths
+        .iter()
+        .filter_map(|changed_path| {
+            let relative_path = changed_path.strip_prefix(plugins_path).ok()?;
+            let plugin_dir_name = relative_path.components().next()?;
+            let plugin_path = plugins_path.join(plugin_dir_name);
+
+            let changed_file_name = changed_path.file_name()?.to_ascii_lowercase();
+
+            if changed_path == &plugin_path {
+                // The plugin directory itself was added or removed
+                return Some(plugin_path);
+            }
+
+            if changed_file_name == "manifest.toml" {
+                return Some(plugin_path);
+            }
+
+            match read_plugin_manifest(&plugin_path) {
+                Ok((_, wasm_full_path)) if &wasm_full_path == changed_path => Some(plugin_path),
+                _ => None,
+            }
+        })
+        .collect()
+}
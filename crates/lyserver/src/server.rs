@@ -1,15 +1,15 @@
-use std::{net::SocketAddr, sync::{atomic::Ordering, Arc}};
+use std::sync::{atomic::Ordering, Arc};
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
 
 use lyserver_database::LYServerDatabasePlugin;
 use lyserver_http::LYServerHTTPServerPlugin;
+use lyserver_library::LYServerLibraryPlugin;
 use lyserver_plugin_common::LYServerPlugin;
 use lyserver_preferences::LYServerPreferencesPlugin;
-use lyserver_shared_data::LYServerSharedData;
+use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataPlugins as _};
 use tokio::sync::Mutex;
 
-use crate::plugins::LYServerPluginManager;
+use crate::plugins::{LYServerPluginManager, LYServerPluginManagerController};
 
 pub struct LYServer {
     shared_data: Arc<LYServerSharedData>,
@@ -38,8 +38,16 @@ impl LYServer {
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        if self.shared_data.migrations_dry_run {
+            return LYServerDatabasePlugin::dry_run_migrations(Arc::clone(&self.shared_data)).await;
+        }
+
         self.plugin_manager.lock().await.init_messaging_loop().await?;
 
+        self.shared_data
+            .set_plugin_controller(LYServerPluginManagerController::new(Arc::clone(&self.plugin_manager)))
+            .await;
+
         let shared_data_clone = Arc::clone(&self.shared_data);
         let plugin_manager_clone = Arc::clone(&self.plugin_manager);
 
@@ -58,28 +66,32 @@ impl LYServer {
             res = async {
                 let mut locked_plugin_manager = plugin_manager_clone.lock().await;
 
-                if let Err(e) = locked_plugin_manager.load_plugin("database@lyserver.local", |plugin_shared_data| async move {
-                    Box::new(LYServerDatabasePlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>)
-                }).await {
-                    log::error!("Failed to load database plugin: {}", e);
-                    return Err(anyhow::anyhow!("Failed to load database plugin"));
-                }
-    
-                if let Err(e) = locked_plugin_manager.load_plugin("preferences@lyserver.local", |plugin_shared_data| async move {
-                    Box::new(LYServerPreferencesPlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>)
-                }).await {
-                    log::error!("Failed to load preferences plugin: {}", e);
-                    return Err(anyhow::anyhow!("Failed to load preferences plugin"));
-                }
-    
-                if let Err(e) = locked_plugin_manager.load_plugin("http@lyserver.local", |plugin_shared_data| async move {
-                    Box::new(LYServerHTTPServerPlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>)
-                }).await {
-                    log::error!("Failed to load HTTP server plugin: {}", e);
-                    return Err(anyhow::anyhow!("Failed to load HTTP server plugin"));
+                locked_plugin_manager.register_builtin_plugin(LYServerDatabasePlugin::plugin_metadata(), |plugin_shared_data| {
+                    LYServerDatabasePlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>
+                });
+
+                locked_plugin_manager.register_builtin_plugin(LYServerPreferencesPlugin::plugin_metadata(), |plugin_shared_data| {
+                    LYServerPreferencesPlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>
+                });
+
+                locked_plugin_manager.register_builtin_plugin(LYServerLibraryPlugin::plugin_metadata(), |plugin_shared_data| {
+                    LYServerLibraryPlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>
+                });
+
+                locked_plugin_manager.register_builtin_plugin(LYServerHTTPServerPlugin::plugin_metadata(), |plugin_shared_data| {
+                    LYServerHTTPServerPlugin::new(plugin_shared_data) as Arc<dyn LYServerPlugin + Send + Sync>
+                });
+
+                if let Err(e) = locked_plugin_manager.init().await {
+                    log::error!("Failed to load server plugins: {}", e);
+                    return Err(e);
                 }
 
-                locked_plugin_manager.init().await;
+                drop(locked_plugin_manager);
+
+                if let Err(e) = LYServerPluginManager::init_plugins_watcher(Arc::clone(&plugin_manager_clone)).await {
+                    log::error!("Failed to watch plugins directory, hot reloading is disabled: {}", e);
+                }
 
                 log::info!("----------------------------------");
                 log::info!("");
@@ -88,7 +100,7 @@ impl LYServer {
                 log::info!("");
                 log::info!("----------------------------------");
 
-                if let Err(e) = locked_plugin_manager.wait_for_all_plugins().await {
+                if let Err(e) = LYServerPluginManager::wait_for_all_plugins(Arc::clone(&plugin_manager_clone)).await {
                     log::error!("Unrecoverable error occurred in plugins: {}", e);
                 }
 
@@ -98,7 +110,7 @@ impl LYServer {
             },
         }
 
-        let _ = plugin_manager_clone.lock().await.wait_for_all_plugins().await;
+        let _ = LYServerPluginManager::wait_for_all_plugins(plugin_manager_clone).await;
 
         log::info!("Goodbye");
 
@@ -1,10 +1,11 @@
 use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Ok;
-use futures::future::BoxFuture;
-use sqlx::{sqlite::{SqliteArgumentValue, SqliteArguments, SqlitePoolOptions, SqliteRow}, Encode, Pool, Row as _, Sqlite, Type, Arguments as _};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
 use tokio::sync::Mutex;
 
+use crate::migrations::{LYServerMigrationReport, LYServerMigrator};
+
 pub trait LYServerDatabaseConnection {
     async fn get_pool(&self) -> anyhow::Result<Arc<Pool<Sqlite>>>;
 }
@@ -28,7 +29,7 @@ impl LYServerDatabase {
                 .expect("Failed to create database file");
         }
 
-        let db_connection_uri = format!("sqlite://{}", db_path.display().to_string());
+        let db_connection_uri = format!("sqlite://{}", db_path.display());
 
         Self {
             db_connection_uri,
@@ -36,43 +37,24 @@ impl LYServerDatabase {
         }
     }
 
-    pub async fn get_schema_version(&self) -> anyhow::Result<u32> {
+    /// Applies the pending migrations of `migrator`, refusing databases with a newer schema than it knows.
+    pub async fn migrate(&self, migrator: &LYServerMigrator) -> anyhow::Result<LYServerMigrationReport> {
         if let Some(pool) = &*self.pool.lock().await {
-            let row: SqliteRow = sqlx::query("PRAGMA user_version")
-                .fetch_one(pool)
-                .await?;
-
-            let version: u32 = row.get(0);
-            Ok(version)
+            migrator.run(pool).await
         } else {
             Err(anyhow::anyhow!("Database connection pool is not initialized."))
         }
     }
 
-    pub async fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
-        if let Some(pool) = &*self.pool.lock().await {
-            let query = format!("PRAGMA user_version = {}", version);
+    /// Connects only to report the pending migrations of `migrator`, without applying them.
+    pub async fn dry_run_migrations(mut self, migrator: LYServerMigrator) -> anyhow::Result<LYServerMigrationReport> {
+        self.connect().await?;
 
-            sqlx::query(query.as_str())
-                .execute(pool)
-                .await?;
+        let report = self.migrate(&migrator.dry_run(true)).await;
 
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Database connection pool is not initialized."))
-        }
-    }
+        self.disconnect().await?;
 
-    pub async fn with_db_connection<T>(
-        &self,
-        f: impl for<'a> FnOnce(&'a Pool<Sqlite>) -> BoxFuture<'a, anyhow::Result<T>>,
-    ) -> anyhow::Result<T> {
-        let pool = self.pool.lock().await;
-        if let Some(pool) = &*pool {
-            f(pool).await
-        } else {
-            Err(anyhow::anyhow!("Database connection pool is not initialized."))
-        }
+        report
     }
 }
 
@@ -120,7 +102,7 @@ impl LYServerDatabaseLifecycle for LYServerDatabase {
             
             Ok(())
         } else {
-            return Err(anyhow::anyhow!("Database connection pool is not initialized."));
+            Err(anyhow::anyhow!("Database connection pool is not initialized."))
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::{path::PathBuf, sync::Arc};
+
+use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataDirectories as _};
+use sqlx::{Pool, Sqlite};
+
+use crate::{database::{LYServerDatabase, LYServerDatabaseConnection, LYServerDatabaseLifecycle}, migrations::{LYServerMigration, LYServerMigrationReport, LYServerMigrator}};
+
+/// Backs the key/value storage of plugins, every plugin id is its own namespace.
+pub struct LYServerKVDatabase {
+    db: LYServerDatabase,
+}
+
+impl LYServerDatabaseConnection for LYServerKVDatabase {
+    async fn get_pool(&self) -> anyhow::Result<Arc<Pool<Sqlite>>> {
+        self.db.get_pool().await
+    }
+}
+
+impl LYServerDatabaseLifecycle for LYServerKVDatabase {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        self.db.connect().await?;
+
+        self.maybe_migrate_schema().await?;
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.db.disconnect().await
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.db.health_check().await
+    }
+}
+
+impl LYServerKVDatabase {
+    pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
+        Self::from_path(shared_data.resolve_data_path_str("plugin_kv.db"))
+    }
+
+    pub(crate) fn from_path(db_path: PathBuf) -> Self {
+        Self {
+            db: LYServerDatabase::new(db_path),
+        }
+    }
+
+    fn migrator() -> LYServerMigrator {
+        LYServerMigrator::new("plugin_kv", vec![
+            LYServerMigration::sql(1, "create_plugin_kv", "
+                CREATE TABLE IF NOT EXISTS plugin_kv (
+                    namespace TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    updated_at DATETIME DEFAULT (CURRENT_TIMESTAMP),
+                    PRIMARY KEY (namespace, key)
+                )
+            "),
+        ])
+    }
+
+    pub async fn maybe_migrate_schema(&self) -> anyhow::Result<()> {
+        self.db.migrate(&Self::migrator()).await?;
+
+        Ok(())
+    }
+
+    /// Reports the pending migrations without applying them.
+    pub async fn dry_run_migrations(self) -> anyhow::Result<LYServerMigrationReport> {
+        self.db.dry_run_migrations(Self::migrator()).await
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataDirectories as _};
+use sqlx::{Pool, Sqlite};
+
+use crate::{database::{LYServerDatabase, LYServerDatabaseConnection, LYServerDatabaseLifecycle}, migrations::{LYServerMigration, LYServerMigrationReport, LYServerMigrator}};
+
+/// The music library indexed by `library@lyserver.local`.
+pub struct LYServerLibraryDatabase {
+    db: LYServerDatabase,
+}
+
+impl LYServerDatabaseConnection for LYServerLibraryDatabase {
+    async fn get_pool(&self) -> anyhow::Result<Arc<Pool<Sqlite>>> {
+        self.db.get_pool().await
+    }
+}
+
+impl LYServerDatabaseLifecycle for LYServerLibraryDatabase {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        self.db.connect().await?;
+
+        self.maybe_migrate_schema().await?;
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.db.disconnect().await
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.db.health_check().await
+    }
+}
+
+impl LYServerLibraryDatabase {
+    pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
+        let db_path = shared_data.resolve_data_path_str("library.db");
+
+        Self {
+            db: LYServerDatabase::new(db_path),
+        }
+    }
+
+    fn migrator() -> LYServerMigrator {
+        LYServerMigrator::new("library", vec![
+            LYServerMigration::sql(1, "create_library", LIBRARY_DB_MIGRATION_1),
+        ])
+    }
+
+    pub async fn maybe_migrate_schema(&self) -> anyhow::Result<()> {
+        self.db.migrate(&Self::migrator()).await?;
+
+        Ok(())
+    }
+
+    /// Reports the pending migrations without applying them.
+    pub async fn dry_run_migrations(self) -> anyhow::Result<LYServerMigrationReport> {
+        self.db.dry_run_migrations(Self::migrator()).await
+    }
+}
+
+const LIBRARY_DB_MIGRATION_1: &str = "
+    CREATE TABLE IF NOT EXISTS artists (
+        id INTEGER PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL UNIQUE
+    );
+
+    CREATE TABLE IF NOT EXISTS albums (
+        id INTEGER PRIMARY KEY NOT NULL,
+        title TEXT NOT NULL,
+        artist_id INTEGER,
+        year INTEGER,
+        FOREIGN KEY (artist_id) REFERENCES artists (id) ON DELETE SET NULL
+    );
+
+    CREATE UNIQUE INDEX IF NOT EXISTS albums_title_artist ON albums (title, IFNULL(artist_id, 0));
+
+    CREATE TABLE IF NOT EXISTS tracks (
+        id INTEGER PRIMARY KEY NOT NULL,
+        path TEXT NOT NULL UNIQUE,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        format TEXT NOT NULL,
+        title TEXT NOT NULL,
+        artist_id INTEGER,
+        album_id INTEGER,
+        track_number INTEGER,
+        disc_number INTEGER,
+        duration_ms INTEGER,
+        genre TEXT,
+        year INTEGER,
+        scanned_at DATETIME DEFAULT (CURRENT_TIMESTAMP),
+        FOREIGN KEY (artist_id) REFERENCES artists (id) ON DELETE SET NULL,
+        FOREIGN KEY (album_id) REFERENCES albums (id) ON DELETE SET NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS tracks_album ON tracks (album_id, disc_number, track_number);
+    CREATE INDEX IF NOT EXISTS tracks_artist ON tracks (artist_id);
+";
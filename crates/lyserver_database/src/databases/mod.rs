@@ -1 +1,4 @@
-pub mod preferences;
\ No newline at end of file
+pub mod preferences;
+pub mod kv;
+pub mod library;
+pub mod plugin;
\ No newline at end of file
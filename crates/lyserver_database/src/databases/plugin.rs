@@ -0,0 +1,74 @@
+use std::{path::Path, sync::Arc};
+
+use lyserver_shared_data::{plugin_dir_name, LYServerSharedData, LYServerSharedDataDirectories as _};
+use sqlx::{Pool, Sqlite};
+
+use crate::{database::{LYServerDatabase, LYServerDatabaseConnection, LYServerDatabaseLifecycle}, migrations::{LYServerMigration, LYServerMigrator}};
+
+/// A database declared in the `[[databases]]` of a plugin manifest, its schema version is the number of migrations applied.
+pub struct LYServerPluginOwnedDatabase {
+    plugin_id: String,
+    name: String,
+    migrations: Vec<String>,
+
+    db: LYServerDatabase,
+}
+
+impl LYServerDatabaseConnection for LYServerPluginOwnedDatabase {
+    async fn get_pool(&self) -> anyhow::Result<Arc<Pool<Sqlite>>> {
+        self.db.get_pool().await
+    }
+}
+
+impl LYServerDatabaseLifecycle for LYServerPluginOwnedDatabase {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        self.db.connect().await?;
+
+        self.maybe_migrate_schema().await?;
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.db.disconnect().await
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.db.health_check().await
+    }
+}
+
+impl LYServerPluginOwnedDatabase {
+    pub fn new(shared_data: Arc<LYServerSharedData>, plugin_id: &str, name: &str, migrations: Vec<String>) -> anyhow::Result<Self> {
+        // The name ends up in a file path, so keep it to a plain file stem
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+            return Err(anyhow::anyhow!("Invalid database name '{}', only ASCII letters, digits, '-' and '_' are allowed", name));
+        }
+
+        let db_dir = shared_data.resolve_data_path(&Path::new("databases").join(plugin_dir_name(plugin_id)));
+
+        std::fs::create_dir_all(&db_dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create database directory '{}': {}", db_dir.display(), e))?;
+
+        Ok(Self {
+            plugin_id: plugin_id.to_string(),
+            name: name.to_string(),
+            migrations,
+
+            db: LYServerDatabase::new(db_dir.join(format!("{}.db", name))),
+        })
+    }
+
+    pub async fn maybe_migrate_schema(&self) -> anyhow::Result<()> {
+        let migrations = self.migrations.iter()
+            .enumerate()
+            .map(|(i, sql)| LYServerMigration::sql(i as u32 + 1, format!("migration {}", i + 1), sql.clone()))
+            .collect();
+
+        let migrator = LYServerMigrator::new(format!("{}/{}", self.plugin_id, self.name), migrations);
+
+        self.db.migrate(&migrator).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+
+use lyserver_shared_data::LYServerKVService;
+use sqlx::{Row as _, Sqlite, Transaction};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{database::LYServerDatabaseConnection as _, databases::kv::LYServerKVDatabase};
+
+/// The `kv` service published by the database plugin.
+pub struct LYServerKVStoreService {
+    kv: Arc<RwLock<LYServerKVDatabase>>,
+    /// Serializes writes, so the quota check and the write it guards see the same namespace
+    write_lock: Mutex<()>,
+}
+
+impl LYServerKVStoreService {
+    pub fn new(kv: Arc<RwLock<LYServerKVDatabase>>) -> Self {
+        Self {
+            kv,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    async fn read_value(tx: &mut Transaction<'_, Sqlite>, namespace: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let row = sqlx::query("SELECT value FROM plugin_kv WHERE namespace = ? AND key = ?")
+            .bind(namespace)
+            .bind(key)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        Ok(row.map(|row| row.get::<Vec<u8>, _>(0)))
+    }
+
+    async fn write_value(tx: &mut Transaction<'_, Sqlite>, namespace: &str, key: &str, value: Vec<u8>, max_bytes: u64) -> anyhow::Result<()> {
+        if max_bytes > 0 {
+            let row = sqlx::query("SELECT COALESCE(SUM(LENGTH(CAST(key AS BLOB)) + LENGTH(value)), 0) FROM plugin_kv WHERE namespace = ? AND key != ?")
+                .bind(namespace)
+                .bind(key)
+                .fetch_one(&mut **tx)
+                .await?;
+
+            let usage = row.get::<i64, _>(0) as u64 + (key.len() + value.len()) as u64;
+
+            if usage > max_bytes {
+                return Err(anyhow::anyhow!("KV quota of '{}' exceeded, storing '{}' would use {} of {} bytes", namespace, key, usage, max_bytes));
+            }
+        }
+
+        sqlx::query("INSERT INTO plugin_kv (namespace, key, value) VALUES (?, ?, ?)
+            ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value, updated_at = CURRENT_TIMESTAMP")
+            .bind(namespace)
+            .bind(key)
+            .bind(value)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_value(tx: &mut Transaction<'_, Sqlite>, namespace: &str, key: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM plugin_kv WHERE namespace = ? AND key = ?")
+            .bind(namespace)
+            .bind(key)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerKVService for LYServerKVStoreService {
+    async fn get(&self, namespace: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let pool = self.kv.read().await.get_pool().await?;
+
+        let row = sqlx::query("SELECT value FROM plugin_kv WHERE namespace = ? AND key = ?")
+            .bind(namespace)
+            .bind(key)
+            .fetch_optional(&*pool)
+            .await?;
+
+        Ok(row.map(|row| row.get::<Vec<u8>, _>(0)))
+    }
+
+    async fn set(&self, namespace: &str, key: &str, value: Vec<u8>, max_bytes: u64) -> anyhow::Result<()> {
+        let _write_guard = self.write_lock.lock().await;
+        let pool = self.kv.read().await.get_pool().await?;
+
+        let mut tx = pool.begin().await?;
+        Self::write_value(&mut tx, namespace, key, value, max_bytes).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, namespace: &str, key: &str) -> anyhow::Result<bool> {
+        let _write_guard = self.write_lock.lock().await;
+        let pool = self.kv.read().await.get_pool().await?;
+
+        let mut tx = pool.begin().await?;
+        let deleted = Self::delete_value(&mut tx, namespace, key).await?;
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    async fn list_prefix(&self, namespace: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let pool = self.kv.read().await.get_pool().await?;
+
+        // Compared with SUBSTR rather than LIKE, so `%` and `_` in the prefix need no escaping
+        let rows = sqlx::query("SELECT key FROM plugin_kv WHERE namespace = ? AND SUBSTR(key, 1, LENGTH(?)) = ? ORDER BY key")
+            .bind(namespace)
+            .bind(prefix)
+            .bind(prefix)
+            .fetch_all(&*pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get::<String, _>(0)).collect())
+    }
+
+    async fn compare_and_swap(&self, namespace: &str, key: &str, expected: Option<Vec<u8>>, value: Option<Vec<u8>>, max_bytes: u64) -> anyhow::Result<bool> {
+        let _write_guard = self.write_lock.lock().await;
+        let pool = self.kv.read().await.get_pool().await?;
+
+        let mut tx = pool.begin().await?;
+
+        if Self::read_value(&mut tx, namespace, key).await? != expected {
+            return Ok(false);
+        }
+
+        match value {
+            Some(value) => Self::write_value(&mut tx, namespace, key, value, max_bytes).await?,
+            None => {
+                Self::delete_value(&mut tx, namespace, key).await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn usage(&self, namespace: &str) -> anyhow::Result<u64> {
+        let pool = self.kv.read().await.get_pool().await?;
+
+        let row = sqlx::query("SELECT COALESCE(SUM(LENGTH(CAST(key AS BLOB)) + LENGTH(value)), 0) FROM plugin_kv WHERE namespace = ?")
+            .bind(namespace)
+            .fetch_one(&*pool)
+            .await?;
+
+        Ok(row.get::<i64, _>(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::LYServerDatabaseLifecycle as _;
+
+    use super::*;
+
+    async fn open_kv(name: &str) -> LYServerKVStoreService {
+        let db_path = std::env::temp_dir().join(format!("lyserver-kv-test-{}-{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&db_path);
+
+        let mut kv = LYServerKVDatabase::from_path(db_path);
+        kv.connect().await.unwrap();
+
+        LYServerKVStoreService::new(Arc::new(RwLock::new(kv)))
+    }
+
+    #[tokio::test]
+    async fn enforces_the_namespace_quota() {
+        let kv = open_kv("quota").await;
+
+        kv.set("a", "key", vec![0; 7], 10).await.unwrap();
+        assert_eq!(kv.usage("a").await.unwrap(), 10);
+
+        // Overwriting a key only counts its new value
+        kv.set("a", "key", vec![1; 7], 10).await.unwrap();
+        assert!(kv.set("a", "key", vec![1; 8], 10).await.is_err());
+        assert!(kv.set("a", "other", vec![], 10).await.is_err());
+        assert_eq!(kv.get("a", "key").await.unwrap(), Some(vec![1; 7]));
+
+        // Quotas are per namespace, and 0 disables them
+        kv.set("b", "key", vec![0; 7], 10).await.unwrap();
+        kv.set("a", "other", vec![0; 100], 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_only_replaces_the_expected_value() {
+        let kv = open_kv("cas").await;
+
+        assert!(kv.compare_and_swap("a", "key", None, Some(b"1".to_vec()), 0).await.unwrap());
+        assert!(!kv.compare_and_swap("a", "key", None, Some(b"2".to_vec()), 0).await.unwrap());
+        assert!(!kv.compare_and_swap("a", "key", Some(b"2".to_vec()), Some(b"3".to_vec()), 0).await.unwrap());
+        assert_eq!(kv.get("a", "key").await.unwrap(), Some(b"1".to_vec()));
+
+        assert!(kv.compare_and_swap("a", "key", Some(b"1".to_vec()), Some(b"2".to_vec()), 0).await.unwrap());
+        assert_eq!(kv.get("a", "key").await.unwrap(), Some(b"2".to_vec()));
+
+        assert!(kv.compare_and_swap("a", "key", Some(b"2".to_vec()), None, 0).await.unwrap());
+        assert_eq!(kv.get("a", "key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_respects_the_quota() {
+        let kv = open_kv("cas-quota").await;
+
+        assert!(kv.compare_and_swap("a", "key", None, Some(vec![0; 100]), 10).await.is_err());
+        assert_eq!(kv.get("a", "key").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use sqlx::{Executor as _, Pool, Row as _, Sqlite, SqliteConnection};
+
+/// Table the runner records applied migrations in, next to `PRAGMA user_version`.
+const MIGRATIONS_TABLE: &str = "_lyserver_migrations";
+
+pub type LYServerMigrationFn = Box<dyn for<'c> Fn(&'c mut SqliteConnection) -> BoxFuture<'c, anyhow::Result<()>> + Send + Sync>;
+
+pub enum LYServerMigrationStep {
+    Sql(String),
+    Rust(LYServerMigrationFn),
+}
+
+/// A schema change which brings a database to `version`.
+pub struct LYServerMigration {
+    pub version: u32,
+    pub name: String,
+    pub step: LYServerMigrationStep,
+}
+
+impl LYServerMigration {
+    /// A migration running one or more SQL statements.
+    pub fn sql(version: u32, name: impl Into<String>, sql: impl Into<String>) -> Self {
+        Self {
+            version,
+            name: name.into(),
+            step: LYServerMigrationStep::Sql(sql.into()),
+        }
+    }
+
+    /// A migration running Rust code, for changes SQL cannot express, e.g. rewriting values.
+    pub fn rust<F>(version: u32, name: impl Into<String>, f: F) -> Self
+    where
+        F: for<'c> Fn(&'c mut SqliteConnection) -> BoxFuture<'c, anyhow::Result<()>> + Send + Sync + 'static,
+    {
+        Self {
+            version,
+            name: name.into(),
+            step: LYServerMigrationStep::Rust(Box::new(f)),
+        }
+    }
+
+    /// Detects a migration being edited after it was applied, Rust migrations can only be told apart by name.
+    pub fn checksum(&self) -> String {
+        let content = match &self.step {
+            LYServerMigrationStep::Sql(sql) => sql.as_str(),
+            LYServerMigrationStep::Rust(_) => self.name.as_str(),
+        };
+
+        // FNV-1a, stable across builds unlike the std hashers
+        let checksum = content.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+
+        format!("{:016x}", checksum)
+    }
+}
+
+/// What a run of the migrator did, or would have done in a dry run.
+#[derive(Debug, Clone, Default)]
+pub struct LYServerMigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    /// Versions and names of the migrations applied, in order
+    pub applied: Vec<(u32, String)>,
+    pub dry_run: bool,
+}
+
+/// Brings a database up to the newest of an ordered list of migrations, each applied in its own transaction.
+pub struct LYServerMigrator {
+    database_name: String,
+    migrations: Vec<LYServerMigration>,
+    dry_run: bool,
+}
+
+impl LYServerMigrator {
+    pub fn new(database_name: impl Into<String>, migrations: Vec<LYServerMigration>) -> Self {
+        Self {
+            database_name: database_name.into(),
+            migrations,
+            dry_run: false,
+        }
+    }
+
+    /// Only reports the pending migrations, without changing the database.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The version the database is at once every migration is applied.
+    pub fn latest_version(&self) -> u32 {
+        self.migrations.last().map_or(0, |migration| migration.version)
+    }
+
+    pub async fn run(&self, pool: &Pool<Sqlite>) -> anyhow::Result<LYServerMigrationReport> {
+        self.validate()?;
+
+        let current_version = sqlx::query("PRAGMA user_version")
+            .fetch_one(pool)
+            .await?
+            .get::<u32, _>(0);
+
+        let latest_version = self.latest_version();
+
+        if current_version > latest_version {
+            return Err(anyhow::anyhow!(
+                "The '{}' database is at schema version {}, which is newer than the version {} this server knows, refusing to start",
+                self.database_name, current_version, latest_version,
+            ));
+        }
+
+        if !self.dry_run {
+            sqlx::query(&format!("CREATE TABLE IF NOT EXISTS {} (
+                version INTEGER PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL,
+                checksum TEXT NOT NULL,
+                applied_at DATETIME DEFAULT (CURRENT_TIMESTAMP)
+            )", MIGRATIONS_TABLE))
+                .execute(pool)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to create {} table: {}", MIGRATIONS_TABLE, e))?;
+        }
+
+        self.verify_checksums(pool, current_version).await?;
+
+        let mut report = LYServerMigrationReport {
+            from_version: current_version,
+            to_version: current_version,
+            applied: Vec::new(),
+            dry_run: self.dry_run,
+        };
+
+        for migration in self.migrations.iter().filter(|migration| migration.version > current_version) {
+            if self.dry_run {
+                log::info!("Would migrate the '{}' database to version {} ({})", self.database_name, migration.version, migration.name);
+            } else {
+                log::info!("Migrating the '{}' database to version {} ({})", self.database_name, migration.version, migration.name);
+
+                self.apply(pool, migration)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Migration {} ({}) of the '{}' database failed: {}", migration.version, migration.name, self.database_name, e))?;
+            }
+
+            report.to_version = migration.version;
+            report.applied.push((migration.version, migration.name.clone()));
+        }
+
+        if self.dry_run && report.applied.is_empty() {
+            log::info!("The '{}' database is up to date at version {}", self.database_name, current_version);
+        }
+
+        Ok(report)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut previous_version = 0;
+
+        for migration in &self.migrations {
+            if migration.version <= previous_version {
+                return Err(anyhow::anyhow!(
+                    "Migrations of the '{}' database have to be ordered by strictly increasing versions starting at 1, found {} after {}",
+                    self.database_name, migration.version, previous_version,
+                ));
+            }
+
+            previous_version = migration.version;
+        }
+
+        Ok(())
+    }
+
+    async fn verify_checksums(&self, pool: &Pool<Sqlite>, current_version: u32) -> anyhow::Result<()> {
+        let table_exists = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(MIGRATIONS_TABLE)
+            .fetch_optional(pool)
+            .await?
+            .is_some();
+
+        if !table_exists {
+            return Ok(());
+        }
+
+        let recorded = sqlx::query(&format!("SELECT version, checksum FROM {}", MIGRATIONS_TABLE))
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| (row.get::<u32, _>(0), row.get::<String, _>(1)))
+            .collect::<HashMap<_, _>>();
+
+        // Databases migrated before checksums were recorded have no rows, those migrations cannot be verified
+        for migration in self.migrations.iter().filter(|migration| migration.version <= current_version) {
+            if let Some(checksum) = recorded.get(&migration.version)
+                && *checksum != migration.checksum() {
+                    return Err(anyhow::anyhow!(
+                        "Migration {} ({}) of the '{}' database was changed after it was applied",
+                        migration.version, migration.name, self.database_name,
+                    ));
+                }
+        }
+
+        Ok(())
+    }
+
+    async fn apply(&self, pool: &Pool<Sqlite>, migration: &LYServerMigration) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await?;
+
+        match &migration.step {
+            LYServerMigrationStep::Sql(sql) => {
+                // SQLite runs every statement of the string, so a migration may hold several
+                tx.execute(sql.as_str()).await?;
+            }
+            LYServerMigrationStep::Rust(f) => f(&mut tx).await?,
+        }
+
+        let record_query = format!("INSERT OR REPLACE INTO {} (version, name, checksum) VALUES (?, ?, ?)", MIGRATIONS_TABLE);
+        let version_query = format!("PRAGMA user_version = {}", migration.version);
+
+        sqlx::query(&record_query)
+            .bind(migration.version)
+            .bind(&migration.name)
+            .bind(migration.checksum())
+            .execute(&mut *tx)
+            .await?;
+
+        // The version lives in the database header, so it is rolled back along with the migration
+        sqlx::query(&version_query)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    // Every connection to `sqlite::memory:` is its own database, so the pool keeps a single one
+    async fn memory_pool() -> Pool<Sqlite> {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    async fn user_version(pool: &Pool<Sqlite>) -> u32 {
+        sqlx::query("PRAGMA user_version").fetch_one(pool).await.unwrap().get::<u32, _>(0)
+    }
+
+    fn migrations() -> Vec<LYServerMigration> {
+        vec![
+            LYServerMigration::sql(1, "create_a", "CREATE TABLE a (id INTEGER PRIMARY KEY)"),
+            LYServerMigration::sql(2, "create_b", "CREATE TABLE b (id INTEGER PRIMARY KEY)"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn applies_pending_migrations_in_order() {
+        let pool = memory_pool().await;
+
+        let report = LYServerMigrator::new("test", migrations()).run(&pool).await.unwrap();
+
+        assert_eq!((report.from_version, report.to_version), (0, 2));
+        assert_eq!(report.applied, [(1, "create_a".to_string()), (2, "create_b".to_string())]);
+        assert_eq!(user_version(&pool).await, 2);
+
+        let report = LYServerMigrator::new("test", migrations()).run(&pool).await.unwrap();
+
+        assert_eq!((report.from_version, report.to_version), (2, 2));
+        assert!(report.applied.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_database_untouched() {
+        let pool = memory_pool().await;
+
+        let report = LYServerMigrator::new("test", migrations()).dry_run(true).run(&pool).await.unwrap();
+
+        assert!(report.dry_run);
+        assert_eq!(report.to_version, 2);
+        assert_eq!(report.applied.len(), 2);
+        assert_eq!(user_version(&pool).await, 0);
+
+        let tables = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'").fetch_all(&pool).await.unwrap();
+        assert!(tables.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_unordered_versions() {
+        let pool = memory_pool().await;
+
+        let mut migrations = migrations();
+        migrations.reverse();
+
+        assert!(LYServerMigrator::new("test", migrations).run(&pool).await.is_err());
+        assert!(LYServerMigrator::new("test", vec![LYServerMigration::sql(0, "zero", "SELECT 1")]).run(&pool).await.is_err());
+        assert_eq!(user_version(&pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_migrations_changed_after_they_were_applied() {
+        let pool = memory_pool().await;
+
+        LYServerMigrator::new("test", migrations()).run(&pool).await.unwrap();
+
+        let mut changed_migrations = migrations();
+        changed_migrations[0] = LYServerMigration::sql(1, "create_a", "CREATE TABLE a (id INTEGER PRIMARY KEY, name TEXT)");
+
+        let error = LYServerMigrator::new("test", changed_migrations).run(&pool).await.unwrap_err();
+        assert!(error.to_string().contains("was changed after it was applied"));
+    }
+
+    #[tokio::test]
+    async fn refuses_databases_with_a_newer_schema() {
+        let pool = memory_pool().await;
+
+        LYServerMigrator::new("test", migrations()).run(&pool).await.unwrap();
+
+        let mut older_migrations = migrations();
+        older_migrations.truncate(1);
+
+        let error = LYServerMigrator::new("test", older_migrations).run(&pool).await.unwrap_err();
+
+        assert!(error.to_string().contains("newer than the version 1"));
+    }
+
+    #[tokio::test]
+    async fn rolls_back_failed_migrations() {
+        let pool = memory_pool().await;
+
+        let failing_migrations = vec![
+            LYServerMigration::sql(1, "create_a", "CREATE TABLE a (id INTEGER PRIMARY KEY)"),
+            LYServerMigration::sql(2, "broken", "CREATE TABLE b (id INTEGER PRIMARY KEY); INSERT INTO missing VALUES (1)"),
+        ];
+
+        assert!(LYServerMigrator::new("test", failing_migrations).run(&pool).await.is_err());
+        assert_eq!(user_version(&pool).await, 1);
+
+        let b_exists = sqlx::query("SELECT 1 FROM sqlite_master WHERE name = 'b'").fetch_optional(&pool).await.unwrap();
+        assert!(b_exists.is_none());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, sync::Arc};
+
+use lyserver_shared_data::{LYServerDatabaseRow, LYServerDatabaseService, LYServerDatabaseValue, LYServerSharedData};
+use sqlx::{sqlite::SqliteRow, Column as _, Pool, Row as _, Sqlite, TypeInfo as _, ValueRef as _};
+use tokio::sync::RwLock;
+
+use crate::{database::{LYServerDatabaseConnection as _, LYServerDatabaseLifecycle as _}, databases::{library::LYServerLibraryDatabase, plugin::LYServerPluginOwnedDatabase, preferences::LYServerPreferencesDatabase}};
+
+/// The `database` service published by the database plugin.
+pub struct LYServerDatabaseQueryService {
+    shared_data: Arc<LYServerSharedData>,
+    preferences: Arc<RwLock<LYServerPreferencesDatabase>>,
+    library: Arc<RwLock<LYServerLibraryDatabase>>,
+    /// Databases declared by plugins, keyed by plugin id and database name
+    plugin_databases: RwLock<HashMap<(String, String), LYServerPluginOwnedDatabase>>,
+}
+
+impl LYServerDatabaseQueryService {
+    pub fn new(shared_data: Arc<LYServerSharedData>, preferences: Arc<RwLock<LYServerPreferencesDatabase>>, library: Arc<RwLock<LYServerLibraryDatabase>>) -> Self {
+        Self {
+            shared_data,
+            preferences,
+            library,
+            plugin_databases: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Closes the databases of every plugin, called when the database plugin is destroyed.
+    pub async fn close_all_plugin_databases(&self) {
+        for ((plugin_id, name), mut database) in self.plugin_databases.write().await.drain() {
+            if let Err(e) = database.disconnect().await {
+                log::warn!("Failed to close database '{}' of plugin '{}': {}", name, plugin_id, e);
+            }
+        }
+    }
+
+    async fn execute(pool: &Pool<Sqlite>, database: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>> {
+        let mut query_obj = sqlx::query(query);
+
+        for (i, arg) in args.into_iter().enumerate() {
+            let result = match arg {
+                LYServerDatabaseValue::Null => query_obj.try_bind(None::<String>),
+                LYServerDatabaseValue::Integer(value) => query_obj.try_bind(value),
+                LYServerDatabaseValue::Real(value) => query_obj.try_bind(value),
+                LYServerDatabaseValue::Text(value) => query_obj.try_bind(value),
+                LYServerDatabaseValue::Blob(value) => query_obj.try_bind(value),
+            };
+
+            if let Err(e) = result {
+                return Err(anyhow::anyhow!("Failed to bind argument {}: {}", i, e));
+            }
+        }
+
+        log::info!("Executing query on {} database: {:#?}", database, query);
+
+        let rows = query_obj.fetch_all(pool).await?;
+
+        Ok(rows.iter().map(Self::read_row).collect())
+    }
+
+    fn read_row(row: &SqliteRow) -> LYServerDatabaseRow {
+        let mut row_map = HashMap::new();
+
+        for (i, column) in row.columns().iter().enumerate() {
+            let column_name = column.name().to_string();
+            let value_ref = row.try_get_raw(i).ok();
+
+            let value = match value_ref {
+                Some(val) if !val.is_null() => {
+                    match val.type_info().name() {
+                        "INTEGER" | "INT" | "BOOLEAN" => row.try_get::<i64, _>(i).ok().map(LYServerDatabaseValue::Integer),
+                        "REAL" => row.try_get::<f64, _>(i).ok().map(LYServerDatabaseValue::Real),
+                        "BLOB" => row.try_get::<Vec<u8>, _>(i).ok().map(LYServerDatabaseValue::Blob),
+                        _ => row.try_get::<String, _>(i).ok().map(LYServerDatabaseValue::Text),
+                    }
+                },
+                _ => None,
+            };
+
+            row_map.insert(column_name, value.unwrap_or(LYServerDatabaseValue::Null));
+        }
+
+        row_map
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerDatabaseService for LYServerDatabaseQueryService {
+    async fn query(&self, database: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>> {
+        let pool = match database {
+            "preferences" => self.preferences.read().await.get_pool().await?,
+            "library" => self.library.read().await.get_pool().await?,
+            _ => return Err(anyhow::anyhow!("Unknown database: {}", database)),
+        };
+
+        Self::execute(&pool, database, query, args).await
+    }
+
+    async fn open_plugin_database(&self, plugin_id: &str, name: &str, migrations: Vec<String>) -> anyhow::Result<()> {
+        let mut database = LYServerPluginOwnedDatabase::new(Arc::clone(&self.shared_data), plugin_id, name, migrations)?;
+        database.connect().await?;
+
+        let previous = self.plugin_databases.write().await
+            .insert((plugin_id.to_string(), name.to_string()), database);
+
+        // A reloaded plugin opens its databases again
+        if let Some(mut previous) = previous {
+            previous.disconnect().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn close_plugin_databases(&self, plugin_id: &str) -> anyhow::Result<()> {
+        let closed = {
+            let mut plugin_databases = self.plugin_databases.write().await;
+            let keys = plugin_databases.keys()
+                .filter(|(owner, _)| owner == plugin_id)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            keys.into_iter()
+                .filter_map(|key| plugin_databases.remove(&key))
+                .collect::<Vec<_>>()
+        };
+
+        for mut database in closed {
+            database.disconnect().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn query_plugin_database(&self, plugin_id: &str, name: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>> {
+        let pool = self.plugin_databases.read().await
+            .get(&(plugin_id.to_string(), name.to_string()))
+            .ok_or_else(|| anyhow::anyhow!("Plugin '{}' has no database '{}'", plugin_id, name))?
+            .get_pool()
+            .await?;
+
+        Self::execute(&pool, &format!("{}/{}", plugin_id, name), query, args).await
+    }
+}
@@ -1,40 +1,48 @@
 pub use lyserver_plugin_wasm_runtime::alloc;
 
-use lyserver_plugin_wasm_runtime::{http::{router::LYServerHTTPRouter, LYServerHTTPRequest}, ipc::{self, LYServerMessageEvent}, log};
+use lyserver_plugin_wasm_runtime::{config, http::LYServerHTTPRequest, ipc, log};
 
 #[unsafe(no_mangle)]
 extern "C" fn lyserver_plugin_init() {
     log::info!("✨ Hello from LYServer Hello Plugin!");
 
     log::info!("This is a simple plugin that demonstrates how to use the LYServer Plugin API.");
-    let init_event = LYServerMessageEvent::new("plugin_init", "all", "hello@lyserver", 0);
-    ipc::tx(&init_event)
-        .expect("Failed to send plugin init event");
-
-    while let Some(message) = ipc::recv() {
-        if message.event_type == "http_request" {
-            let request = message.data_as::<LYServerHTTPRequest>()
-                .expect("Failed to deserialize LYServerHTTPRequest");
-
-            if request.match_request("GET", "/hello").is_some() {
-                let response = request.build_response()
-                    .body("hello from WASM!")
-                    .build();
-
-                let reply_message = message.reply("http_response", "hello@lyserver".into(), response)
-                    .expect("Failed to create reply message");
-
-                ipc::tx(&reply_message)
-                    .expect("Failed to send HTTP response");
-            }
-        }
-    }
+
+    // Returning from init announces the plugin, its events are pushed into lyserver_plugin_handle_message_event
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn lyserver_plugin_handle_message_event(message_ptr: *mut u8, message_len: *mut u8) {
     let message = ipc::deserialize_event(message_ptr, message_len)
         .expect("Failed to deserialize LYServerMessageEvent");
+
+    if message.event_type == "http_request" {
+        let request = message.data_as::<LYServerHTTPRequest>()
+            .expect("Failed to deserialize LYServerHTTPRequest");
+
+        if request.match_request("GET", "/hello").is_some() {
+            let handled_message = message.reply("http_request_handle_intent", "hello@lyserver".into(), ())
+                .expect("Failed to create reply message");
+
+            ipc::tx(&handled_message)
+                .expect("Failed to send HTTP request handled message");
+
+            let greeting = config::get::<String>("greeting")
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| "hello".to_string());
+
+            let response = request.build_response()
+                .body(format!("{} from WASM!", greeting))
+                .build();
+
+            let reply_message = message.reply("http_response", "hello@lyserver".into(), response)
+                .expect("Failed to create reply message");
+
+            ipc::tx(&reply_message)
+                .expect("Failed to send HTTP response");
+        }
+    }
 }
 
 #[unsafe(no_mangle)]
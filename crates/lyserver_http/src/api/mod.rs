@@ -1,12 +1,46 @@
 mod plugin;
+mod stream;
 
-use actix_web::{dev::HttpServiceFactory, web::{self, Data}, HttpResponse, Responder, Scope};
-use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataPlugins as _, LYServerSharedDataStatus};
+use actix_web::{dev::HttpServiceFactory, web};
 
 use crate::api::plugin::LYServerRouterPluginMiddlewareFactory;
 
 pub fn router() -> impl HttpServiceFactory {
     web::scope("")
         .wrap(LYServerRouterPluginMiddlewareFactory)
-}
\ No newline at end of file
+}
+/// Plugins connected through the server bus, for testing their exchanges with the HTTP server.
+#[cfg(test)]
+pub(crate) async fn connect_test_plugins(name: &str, plugin_ids: &[&str]) -> Vec<std::sync::Arc<lyserver_plugin_shared_data::LYServerPluginSharedData>> {
+    use std::sync::Arc;
+
+    use lyserver_plugin_shared_data::LYServerPluginSharedData;
+    use lyserver_shared_data::LYServerSharedData;
+
+    let data_dir = std::env::temp_dir().join(format!("lyserver-http-{}-{}", std::process::id(), name));
+    let shared_data = Arc::new(LYServerSharedData::new_from_args([
+        "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+    ]).unwrap());
+
+    // Delivers bus events to the plugins they target, like the plugin manager's messaging loop
+    let mut bus = shared_data.messaging_global_tx.subscribe();
+    let shared_data_clone = Arc::clone(&shared_data);
+    tokio::spawn(async move {
+        while let Ok(event) = bus.recv().await {
+            for (plugin_id, tx) in shared_data_clone.messaging_plugin_tx.read().await.iter() {
+                if event.event_target.is_all() || event.event_target.plugin_id().as_deref() == Some(plugin_id) {
+                    let _ = tx.send(event.clone());
+                }
+            }
+        }
+    });
+
+    let mut plugins = Vec::new();
+    for plugin_id in plugin_ids {
+        let mut plugin_shared_data = LYServerPluginSharedData::new(Arc::clone(&shared_data));
+        plugin_shared_data.register_plugin_messaging(plugin_id.to_string()).await.unwrap();
+        plugins.push(Arc::new(plugin_shared_data));
+    }
+
+    plugins
+}
@@ -1,13 +1,17 @@
-use std::{collections::HashMap, sync::Arc, task::{Context, Poll}, time::Duration};
+use std::{sync::Arc, task::{Context, Poll}, time::Duration};
 
-use actix_web::{body::BoxBody, dev::{Service, ServiceRequest, ServiceResponse, Transform}, http::StatusCode, web::{self, BytesMut}, Error, HttpMessage};
+use actix_web::{body::BoxBody, dev::{Service, ServiceRequest, ServiceResponse, Transform}, http::StatusCode, web::{self, BytesMut}, Error};
 use futures_util::{future::{ok, LocalBoxFuture, Ready}, StreamExt};
-use lyserver_http_shared::{LYServerHTTPRequest, LYServerHTTPResponse};
+use lyserver_http_shared::{stream::LYServerHTTPResponseStream, LYServerHTTPHandleIntent, LYServerHTTPRequest, LYServerHTTPResponse};
 use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataMessaging, LYServerSharedDataPlugins};
-use serde::Serialize;
-use serde_json::json;
+use lyserver_shared_data::LYServerSharedDataPlugins;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::api::stream::{create_chunked_body, create_file_body};
+
+/// How long the plugin which claimed a request has to respond.
+const HTTP_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct LYServerRouterPluginMiddlewareFactory;
 
@@ -30,6 +34,71 @@ pub struct LYServerRouterPluginMiddleware<S> {
     service: Arc<S>,
 }
 
+impl<S> LYServerRouterPluginMiddleware<S> {
+    /// Sends the `http_request` event to every plugin and returns the id and response of the plugin which claimed
+    /// it, along with its `http_response` event when it did not serve the request over RPC.
+    async fn dispatch_request(
+        shared_plugin_data: &LYServerPluginSharedData,
+        event: LYServerMessageEvent,
+        request: LYServerHTTPRequest,
+    ) -> anyhow::Result<(String, LYServerHTTPResponse, Option<LYServerMessageEvent>)> {
+        // Subscribe before dispatching, plugins may claim and answer the request right away
+        let mut rx = shared_plugin_data.subscribe_events();
+        let event_id = event.event_id.clone();
+
+        shared_plugin_data.dispatch_event(event)
+            .map_err(|e| anyhow::anyhow!("Failed to dispatch HTTP event: {}", e))?;
+
+        log::debug!("WAITING FOR HANDLE INTENT....");
+
+        let intent = next_event(&mut rx, |event| event.event_type == "http_request_handle_intent" && event.event_id == event_id, Duration::from_secs(5))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No plugin responded with intent"))?;
+
+        log::warn!("(!!!) Plugin '{}' handling HTTP request event: {}", intent.event_sender, intent.event_id);
+
+        let plugin_id = intent.event_sender.to_string();
+
+        // Plugins from before RPC claim requests without any data
+        if let Some(method) = intent.data_as::<LYServerHTTPHandleIntent>().unwrap_or_default().rpc_method {
+            let response = shared_plugin_data
+                .call::<_, LYServerHTTPResponse>(intent.event_sender, &method, &request, HTTP_RESPONSE_TIMEOUT)
+                .await?;
+
+            return Ok((plugin_id, response, None));
+        }
+
+        let reply = next_event(&mut rx, |event| event.event_type == "http_response" && event.event_id == event_id && event.event_sender == intent.event_sender, HTTP_RESPONSE_TIMEOUT)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Plugin response timed out after {}s", HTTP_RESPONSE_TIMEOUT.as_secs()))?;
+
+        let response = reply.data_as::<LYServerHTTPResponse>()
+            .map_err(|e| anyhow::anyhow!("Plugin response deserialisation failed: {}", e))?;
+
+        Ok((plugin_id, response, Some(reply)))
+    }
+}
+
+/// Waits up to `timeout` for the next event matching `predicate`.
+async fn next_event(
+    rx: &mut Receiver<LYServerMessageEvent>,
+    predicate: impl Fn(&LYServerMessageEvent) -> bool,
+    timeout: Duration,
+) -> Option<LYServerMessageEvent> {
+    let fut = async {
+        loop {
+            match rx.recv().await {
+                Ok(event) if predicate(&event) => return Some(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => log::warn!("Missed {} events while waiting for a plugin response", n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, fut).await.ok().flatten()
+}
+
 impl<S> Service<ServiceRequest> for LYServerRouterPluginMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static
@@ -75,69 +144,27 @@ where
                     .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
                     .collect();
     
-                let http_req = LYServerHTTPRequest::new(
+                let mut http_req = LYServerHTTPRequest::new(
                     req_ref.method().to_string(),
                     req_ref.uri().to_string(),
                     version,
                     headers,
                     Some(body_bytes.to_vec()),
                 );
+                http_req.remote_address = req_ref.peer_addr().map(|addr| addr.ip());
     
                 let msg = shared_plugin_data
-                    .create_event("http_request", LYServerMessageEventTarget::All, http_req)
+                    .create_event("http_request", LYServerMessageEventTarget::All, http_req.clone())
                     .await
                     .map_err(|e| {
                         log::error!("Failed to create HTTP event: {}", e);
                         actix_web::error::ErrorInternalServerError("event err")
                     })?;
     
-                let msg_id = msg.event_id.clone();
-    
-                shared_plugin_data.dispatch_event(msg).map_err(|e| {
-                    log::error!("Failed to dispatch HTTP event: {}", e);
-                    actix_web::error::ErrorInternalServerError("dispatch err")
-                })?;
-    
-                log::debug!("WAITING FOR HANDLE INTENT....");
-
-                let shared_plugin_data_clone = Arc::clone(&shared_plugin_data);
-                let resp: anyhow::Result<LYServerMessageEvent> = {
-                    let msg_id_clone = msg_id.clone();
-
-                    if let Some(reply) = shared_plugin_data_clone
-                        .wait_until_event(
-                            move |event| event.event_type == "http_request_handle_intent" && event.event_id == msg_id_clone.clone(),
-                            Duration::from_secs(5),
-                        )
-                        .await
-                    {
-                        log::warn!("(!!!) Plugin '{}' handling HTTP request event: {}", reply.event_sender.to_string(), reply.event_id);
-
-                        let msg_id_clone = msg_id.clone();
-
-                        if let Some(reply) = shared_plugin_data_clone
-                            .wait_until_event(
-                                move |event| event.event_type == "http_response" && event.event_id == msg_id_clone.clone() && event.event_sender == reply.event_sender,
-                                Duration::from_secs(60),
-                            )
-                            .await
-                        {
-                            Ok(reply)
-                        } else {
-                            Err(anyhow::anyhow!("Plugin response timed out after 60s").into())
-                        }
-                    } else {
-                        Err(anyhow::anyhow!("No plugin responded with intent").into())
-                    }
-                };
+                let resp = Self::dispatch_request(&shared_plugin_data, msg, http_req).await;
 
                 match resp {
-                    Ok(reply) => {
-                        let http_resp: LYServerHTTPResponse = reply.data_as().map_err(|e| {
-                            log::error!("Plugin response deserialisation failed: {}", e);
-                            actix_web::error::ErrorInternalServerError("deser fail")
-                        })?;
-        
+                    Ok((plugin_id, http_resp, reply)) => {
                         let mut builder = actix_web::HttpResponse::build(
                             StatusCode::from_u16(http_resp.status_code)
                                 .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
@@ -147,7 +174,6 @@ where
                             builder.insert_header((k, v));
                         }
         
-                        let plugin_id = reply.event_sender.to_string();
                         if let Some(plugin_meta) = shared_plugin_data
                             .app_shared_data
                             .get_plugin_metadata_by_id(&plugin_id)
@@ -158,7 +184,28 @@ where
                             builder.insert_header(("x-lyserver-plugin-name", plugin_meta.name));
                         }
         
-                        let final_resp = builder.body(http_resp.body).map_into_boxed_body();
+                        let final_resp = match http_resp.stream {
+                            Some(LYServerHTTPResponseStream::File { path, offset, length }) => {
+                                let body = create_file_body(&shared_plugin_data, &plugin_id, &path, offset, length)
+                                    .await
+                                    .map_err(|e| {
+                                        log::error!("Failed to serve a file for plugin '{}': {}", plugin_id, e);
+                                        actix_web::error::ErrorInternalServerError("file err")
+                                    })?;
+
+                                builder.body(body).map_into_boxed_body()
+                            }
+                            Some(LYServerHTTPResponseStream::Chunked) => {
+                                // Chunks reply to the `http_response` event, a response returned over RPC has none
+                                let reply = reply.ok_or_else(|| {
+                                    log::error!("Plugin '{}' returned a chunked response over RPC", plugin_id);
+                                    actix_web::error::ErrorInternalServerError("stream err")
+                                })?;
+
+                                builder.streaming(create_chunked_body(shared_plugin_data.clone().into_inner(), reply)).map_into_boxed_body()
+                            }
+                            None => builder.body(http_resp.body).map_into_boxed_body(),
+                        };
                         Ok(ServiceResponse::new(
                             req_for_response.request().clone(),
                             final_resp,
@@ -177,7 +224,7 @@ where
                     }
                 }
             } else {
-                Err(anyhow::anyhow!("Missing plugin data").into())
+                Err(anyhow::anyhow!("Missing plugin data"))
             };
     
             let response = match plugin_response {
@@ -191,4 +238,73 @@ where
             Ok(response)
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::{api::connect_test_plugins, HTTP_PLUGIN_ID};
+
+    use super::*;
+
+    const FAKE_PLUGIN_ID: &str = "fake@plugin";
+
+    /// Claims every request, serving it over RPC or replying with an `http_response` event.
+    fn serve_requests(plugin: Arc<LYServerPluginSharedData>, over_rpc: bool) {
+        plugin.register_rpc_handler("http_request", |request: LYServerHTTPRequest| async move {
+            Ok(request.build_response().body("over rpc").build())
+        });
+
+        let mut rx = plugin.subscribe_events();
+        tokio::spawn(async move {
+            while let Ok(event) = rx.recv().await {
+                if event.is_rpc_event() {
+                    let plugin = Arc::clone(&plugin);
+                    tokio::spawn(async move { plugin.handle_rpc_event(event).await });
+                    continue;
+                }
+
+                if event.event_type != "http_request" {
+                    continue;
+                }
+
+                let request = event.data_as::<LYServerHTTPRequest>().unwrap();
+
+                if over_rpc {
+                    let intent = LYServerHTTPHandleIntent { rpc_method: Some("http_request".to_string()) };
+                    plugin.reply_event("http_request_handle_intent", event, intent).await.unwrap();
+                } else {
+                    plugin.reply_event("http_request_handle_intent", event.clone(), ()).await.unwrap();
+                    plugin.reply_event("http_response", event, request.build_response().body("as event").build()).await.unwrap();
+                }
+            }
+        });
+    }
+
+    async fn request(name: &str, over_rpc: bool) -> (String, LYServerHTTPResponse, Option<LYServerMessageEvent>) {
+        let plugins = connect_test_plugins(name, &[HTTP_PLUGIN_ID, FAKE_PLUGIN_ID]).await;
+        let (http, fake) = (Arc::clone(&plugins[0]), Arc::clone(&plugins[1]));
+        serve_requests(fake, over_rpc);
+
+        let request = LYServerHTTPRequest::new("GET".to_string(), "/fake".to_string(), "HTTP/1.1".to_string(), Default::default(), None);
+        let event = http.create_event("http_request", LYServerMessageEventTarget::All, request.clone()).await.unwrap();
+
+        LYServerRouterPluginMiddleware::<()>::dispatch_request(&http, event, request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatch_request_calls_plugins_serving_requests_over_rpc() {
+        let (plugin_id, response, reply) = request("rpc", true).await;
+
+        assert_eq!(plugin_id, FAKE_PLUGIN_ID);
+        assert_eq!(response.body, b"over rpc");
+        assert!(reply.is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_request_takes_the_http_response_event_of_other_plugins() {
+        let (plugin_id, response, reply) = request("event", false).await;
+
+        assert_eq!(plugin_id, FAKE_PLUGIN_ID);
+        assert_eq!(response.body, b"as event");
+        assert!(reply.is_some());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{io, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use actix_web::{body::SizedStream, web::Bytes};
+use futures_util::Stream;
+use lyserver_http_shared::stream::{LYServerHTTPResponseChunk, LYServerHTTPResponseDemand, LYSERVER_HTTP_RESPONSE_CANCEL_EVENT, LYSERVER_HTTP_RESPONSE_CHUNK_EVENT, LYSERVER_HTTP_RESPONSE_CHUNK_WINDOW, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT};
+use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::{LYServerSharedDataDirectories as _, LYServerSharedDataPlugins as _};
+use tokio::{io::{AsyncReadExt as _, AsyncSeekExt as _}, sync::broadcast::{error::RecvError, Receiver}};
+use tokio_util::io::ReaderStream;
+
+/// How long the server waits for a demanded chunk before aborting the response.
+const LYSERVER_HTTP_RESPONSE_CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Opens the file a plugin asked to be served, actix only reads from it as fast as the client takes it.
+pub async fn create_file_body(
+    plugin_shared_data: &LYServerPluginSharedData,
+    plugin_id: &str,
+    path: &str,
+    offset: u64,
+    length: u64,
+) -> anyhow::Result<SizedStream<impl Stream<Item = Result<Bytes, io::Error>> + use<>>> {
+    let metadata = plugin_shared_data.app_shared_data
+        .get_plugin_metadata_by_id(plugin_id)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Plugin '{}' is not loaded", plugin_id))?;
+
+    // Built-in plugins are trusted with the host filesystem, WASM plugins only with what they can see themselves
+    let host_path = match metadata.wasm_entry_point {
+        Some(_) => plugin_shared_data.app_shared_data.resolve_plugin_guest_path(&metadata, Path::new(path))?,
+        None => PathBuf::from(path),
+    };
+
+    let mut file = tokio::fs::File::open(&host_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open '{}': {}", host_path.display(), e))?;
+
+    let file_length = file.metadata().await?.len();
+    if offset.saturating_add(length) > file_length {
+        return Err(anyhow::anyhow!("Range {}+{} is outside of '{}' ({} bytes)", offset, length, host_path.display(), file_length));
+    }
+
+    file.seek(io::SeekFrom::Start(offset)).await?;
+
+    Ok(SizedStream::new(length, ReaderStream::new(file.take(length))))
+}
+
+/// Pulls the chunks of a [`Chunked`](lyserver_http_shared::stream::LYServerHTTPResponseStream::Chunked) body
+/// from the plugin which sent `response_event`, asking for more only as the client consumes them.
+pub fn create_chunked_body(
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+    response_event: LYServerMessageEvent,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let body = LYServerChunkedBody {
+        rx: plugin_shared_data.subscribe_events(),
+        plugin_shared_data,
+        response_event,
+        sequence: 0,
+        finished: false,
+    };
+
+    futures_util::stream::unfold(body, |mut body| async move {
+        match body.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), body)),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), body)),
+        }
+    })
+}
+
+struct LYServerChunkedBody {
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+    /// The `http_response` event announcing the body, demands and cancellations reply to it
+    response_event: LYServerMessageEvent,
+    rx: Receiver<LYServerMessageEvent>,
+    sequence: u64,
+    finished: bool,
+}
+
+impl LYServerChunkedBody {
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, io::Error> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        // Asking for the next chunks only once the previous one was taken is what slows the plugin down to the client
+        self.send(LYSERVER_HTTP_RESPONSE_DEMAND_EVENT, LYServerHTTPResponseDemand {
+            until: self.sequence + LYSERVER_HTTP_RESPONSE_CHUNK_WINDOW,
+        })?;
+
+        let chunk = match tokio::time::timeout(LYSERVER_HTTP_RESPONSE_CHUNK_TIMEOUT, self.receive_chunk()).await {
+            Ok(chunk) => chunk,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Chunk {} of response {} timed out", self.sequence, self.response_event.event_id))),
+        };
+
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                self.cancel();
+                return Err(e);
+            }
+        };
+
+        if let Some(error) = chunk.error {
+            self.finished = true;
+            return Err(io::Error::other(error));
+        }
+
+        self.sequence += 1;
+        self.finished = chunk.last;
+
+        if chunk.last && chunk.data.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Bytes::from(chunk.data)))
+    }
+
+    async fn receive_chunk(&mut self) -> Result<LYServerHTTPResponseChunk, io::Error> {
+        loop {
+            let event = match self.rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => return Err(io::Error::other(format!("Missed {} events while streaming response {}", n, self.response_event.event_id))),
+                Err(RecvError::Closed) => return Err(io::Error::other("Plugin event channel closed")),
+            };
+
+            if event.event_type != LYSERVER_HTTP_RESPONSE_CHUNK_EVENT
+                || event.event_id != self.response_event.event_id
+                || event.event_sender != self.response_event.event_sender {
+                continue;
+            }
+
+            let chunk = event.data_as::<LYServerHTTPResponseChunk>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            if chunk.sequence != self.sequence {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected chunk {}, received {}", self.sequence, chunk.sequence)));
+            }
+
+            return Ok(chunk);
+        }
+    }
+
+    fn send(&self, event_type: &str, data: impl serde::Serialize) -> Result<(), io::Error> {
+        let plugin_id = self.plugin_shared_data.plugin_id
+            .clone()
+            .ok_or_else(|| io::Error::other("Plugin ID is not set"))?;
+
+        let event = self.response_event
+            .reply(event_type, LYServerMessageEventTarget::Plugin(plugin_id), data)
+            .map_err(io::Error::other)?;
+
+        self.plugin_shared_data.dispatch_event(event).map_err(io::Error::other)
+    }
+
+    /// Stops reading the body, telling the plugin to stop producing it.
+    fn cancel(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        self.finished = true;
+
+        if let Err(e) = self.send(LYSERVER_HTTP_RESPONSE_CANCEL_EVENT, ()) {
+            log::warn!("Failed to cancel response {}: {}", self.response_event.event_id, e);
+        }
+    }
+}
+
+impl Drop for LYServerChunkedBody {
+    fn drop(&mut self) {
+        // The client went away before the last chunk
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt as _;
+    use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{api::connect_test_plugins, HTTP_PLUGIN_ID};
+
+    use super::*;
+
+    const PRODUCER_PLUGIN_ID: &str = "producer@plugin";
+
+    /// Connects the HTTP server with a producer plugin, returns both and the `http_response` announcing the body.
+    async fn connect(name: &str) -> (Arc<LYServerPluginSharedData>, Arc<LYServerPluginSharedData>, LYServerMessageEvent) {
+        let mut plugins = connect_test_plugins(name, &[HTTP_PLUGIN_ID, PRODUCER_PLUGIN_ID]).await;
+        let producer = plugins.pop().unwrap();
+        let server = plugins.pop().unwrap();
+
+        let response_event = LYServerMessageEvent::new(
+            "http_response",
+            LYServerMessageEventTarget::Plugin(HTTP_PLUGIN_ID.to_string()),
+            LYServerMessageEventTarget::Plugin(PRODUCER_PLUGIN_ID.to_string()),
+            (),
+        );
+
+        (server, producer, response_event)
+    }
+
+    fn send_chunk(producer: &LYServerPluginSharedData, response_event: &LYServerMessageEvent, chunk: LYServerHTTPResponseChunk) {
+        // Chunks go where the response went, under its id
+        let event = LYServerMessageEvent::new(LYSERVER_HTTP_RESPONSE_CHUNK_EVENT, response_event.event_target.clone(), response_event.event_sender.clone(), chunk);
+
+        producer.dispatch_event(LYServerMessageEvent { event_id: response_event.event_id.clone(), ..event }).unwrap();
+    }
+
+    fn chunk(sequence: u64, data: &str, last: bool) -> LYServerHTTPResponseChunk {
+        LYServerHTTPResponseChunk { sequence, data: data.as_bytes().to_vec(), last, error: None }
+    }
+
+    /// Waits for the next event of `event_type` about the response.
+    async fn next_event(rx: &mut Receiver<LYServerMessageEvent>, response_event: &LYServerMessageEvent, event_type: &str) -> LYServerMessageEvent {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = rx.recv().await.unwrap();
+
+                if event.event_type == event_type && event.event_id == response_event.event_id {
+                    return event;
+                }
+            }
+        }).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunks_are_sent_as_the_body_demands_them() {
+        let (server, producer, response_event) = connect("stream-demand").await;
+        let mut rx = producer.subscribe_events();
+
+        let body = create_chunked_body(server, response_event.clone());
+
+        let chunks = ["Hello", ", ", "world"];
+        let producer_task = tokio::spawn({
+            let response_event = response_event.clone();
+
+            async move {
+                let mut sent = 0;
+                let mut demands = Vec::new();
+
+                while sent < chunks.len() as u64 {
+                    let demand = next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT).await
+                        .data_as::<LYServerHTTPResponseDemand>()
+                        .unwrap();
+                    demands.push(demand.until);
+
+                    while sent < demand.until.min(chunks.len() as u64) {
+                        send_chunk(&producer, &response_event, chunk(sent, chunks[sent as usize], sent + 1 == chunks.len() as u64));
+                        sent += 1;
+                    }
+                }
+
+                demands
+            }
+        });
+
+        let body = body.map(|chunk| chunk.unwrap()).collect::<Vec<_>>().await;
+
+        assert_eq!(body.concat(), b"Hello, world");
+        assert_eq!(producer_task.await.unwrap()[0], LYSERVER_HTTP_RESPONSE_CHUNK_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn chunks_out_of_sequence_fail_and_cancel_the_body() {
+        let (server, producer, response_event) = connect("stream-sequence").await;
+        let mut rx = producer.subscribe_events();
+
+        let mut body = Box::pin(create_chunked_body(server, response_event.clone()));
+        let next = tokio::spawn(async move { (body.next().await, body) });
+
+        next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT).await;
+        send_chunk(&producer, &response_event, chunk(1, "skipped", false));
+
+        let (error, _body) = next.await.unwrap();
+        assert_eq!(error.unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_CANCEL_EVENT).await;
+    }
+
+    #[tokio::test]
+    async fn dropping_the_body_cancels_it() {
+        let (server, producer, response_event) = connect("stream-cancel").await;
+        let mut rx = producer.subscribe_events();
+
+        let mut body = Box::pin(create_chunked_body(server, response_event.clone()));
+        let next = tokio::spawn(async move { (body.next().await, body) });
+
+        next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT).await;
+        send_chunk(&producer, &response_event, chunk(0, "first", false));
+
+        let (first, body) = next.await.unwrap();
+        assert_eq!(first.unwrap().unwrap(), "first");
+
+        // The client went away before the last chunk
+        drop(body);
+
+        next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_CANCEL_EVENT).await;
+    }
+
+    #[tokio::test]
+    async fn chunk_errors_end_the_body() {
+        let (server, producer, response_event) = connect("stream-error").await;
+        let mut rx = producer.subscribe_events();
+
+        let mut body = Box::pin(create_chunked_body(server, response_event.clone()));
+        let next = tokio::spawn(async move { (body.next().await, body) });
+
+        next_event(&mut rx, &response_event, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT).await;
+        send_chunk(&producer, &response_event, LYServerHTTPResponseChunk { error: Some("disk on fire".to_string()), ..chunk(0, "", false) });
+
+        let (error, mut body) = next.await.unwrap();
+        assert_eq!(error.unwrap().unwrap_err().to_string(), "disk on fire");
+        assert!(body.next().await.is_none());
+    }
+
+    /// Built-in plugin which only exists to be looked up by its metadata.
+    struct TestPlugin(LYServerPluginMetadata);
+
+    #[async_trait::async_trait]
+    impl LYServerPlugin for TestPlugin {
+        fn metadata(&self) -> LYServerPluginMetadata {
+            self.0.clone()
+        }
+
+        async fn init(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn destroy(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn load_test_plugin(plugin_shared_data: &LYServerPluginSharedData, metadata: LYServerPluginMetadata) {
+        plugin_shared_data.app_shared_data.loaded_plugins
+            .write()
+            .await
+            .push((Arc::new(TestPlugin(metadata.clone())), metadata, CancellationToken::new()));
+    }
+
+    #[tokio::test]
+    async fn file_bodies_are_limited_to_the_file() {
+        let (server, _producer, _) = connect("stream-file").await;
+        load_test_plugin(&server, LYServerPluginMetadata::builder().id(PRODUCER_PLUGIN_ID).version("1.0.0").build()).await;
+
+        let path = server.app_shared_data.data_dir.join("body.txt");
+        std::fs::write(&path, "0123456789").unwrap();
+        let path = path.to_str().unwrap();
+
+        let body = create_file_body(&server, PRODUCER_PLUGIN_ID, path, 2, 5).await.unwrap();
+        assert_eq!(actix_web::body::to_bytes(body).await.unwrap(), "23456");
+
+        let body = create_file_body(&server, PRODUCER_PLUGIN_ID, path, 0, 10).await.unwrap();
+        assert_eq!(actix_web::body::to_bytes(body).await.unwrap(), "0123456789");
+
+        for (offset, length) in [(8, 5), (11, 0), (u64::MAX, 1)] {
+            let error = create_file_body(&server, PRODUCER_PLUGIN_ID, path, offset, length).await.err().unwrap();
+            assert!(error.to_string().contains("is outside of"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn wasm_plugins_only_serve_files_they_can_see() {
+        let (server, _producer, _) = connect("stream-wasm-file").await;
+        load_test_plugin(&server, LYServerPluginMetadata::builder().id(PRODUCER_PLUGIN_ID).version("1.0.0").wasm_entry_point("plugin.wasm").build()).await;
+
+        let path = server.app_shared_data.data_dir.join("body.txt");
+        std::fs::write(&path, "0123456789").unwrap();
+
+        assert!(create_file_body(&server, PRODUCER_PLUGIN_ID, path.to_str().unwrap(), 0, 10).await.is_err());
+    }
+}
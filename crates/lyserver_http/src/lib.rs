@@ -1,16 +1,20 @@
 mod api;
 
-use std::{net::SocketAddr, sync::{Arc}};
+use std::{future::Future, sync::Arc};
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{web, App, HttpServer};
 
-use lyserver_http_shared::{router::LYServerHTTPRouter, LYServerHTTPRequest};
-use lyserver_messaging_shared::LYServerMessageEvent;
+use lyserver_http_shared::{error::LYServerHTTPError, router::{LYServerHTTPRoute, LYServerHTTPRouter}, LYServerHTTPRequest, LYServerHTTPResponse};
+use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
 use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataStatus as _};
-use serde_json::Value;
-use tokio::sync::{Mutex, RwLock};
+use lyserver_shared_data::{LYServerPluginController, LYServerPluginControllerError, LYServerPluginControllerErrorKind, LYServerSharedDataPlugins as _, LYServerSharedDataStatus as _};
+use serde_json::json;
+
+pub const HTTP_PLUGIN_ID: &str = "http@lyserver.local";
+
+/// Headers reverse proxies add with the address of the client they forward a request for.
+const FORWARDING_HEADERS: [&str; 3] = ["forwarded", "x-forwarded-for", "x-real-ip"];
 
 pub struct LYServerHTTPServerPlugin {
     plugin_shared_data: Arc<LYServerPluginSharedData>,
@@ -23,9 +27,21 @@ impl LYServerHTTPServerPlugin {
         })
     }
 
+    pub fn plugin_metadata() -> LYServerPluginMetadata {
+        LYServerPluginMetadata::builder()
+            .id(HTTP_PLUGIN_ID)
+            .name("LYServerHTTPServerPlugin")
+            .description("HTTP server plugin for LYServer")
+            .version(env!("CARGO_PKG_VERSION").to_string())
+            .author("LYServer")
+            .build()
+    }
+
     pub async fn handle_http_request(plugin_shared_data: Arc<LYServerPluginSharedData>, event: LYServerMessageEvent) -> anyhow::Result<()> {
         let request = event.data_as::<LYServerHTTPRequest>().expect("Failed to deserialize LYServerHTTPRequest");
 
+        let admin_error = Self::check_admin_access(&plugin_shared_data, &event, &request);
+
         let mut router = LYServerHTTPRouter::new();
 
         router.add_matcher("GET", "/", |route| {
@@ -53,6 +69,91 @@ impl LYServerHTTPServerPlugin {
             }
         });
 
+        let plugin_shared_data_clone = Arc::clone(&plugin_shared_data);
+        router.add_matcher("GET", "/plugins", move |route| {
+            let plugin_shared_data_clone = Arc::clone(&plugin_shared_data_clone);
+
+            async move {
+                let plugin_controller = Self::get_plugin_controller(&plugin_shared_data_clone).await?;
+                let plugins = plugin_controller.list_plugins().await.map_err(Self::plugin_controller_error)?;
+
+                let response = route.request.build_response()
+                    .json(json!({
+                        "ok": true,
+                        "data": plugins
+                    }))
+                    .build();
+
+                Ok(response)
+            }
+        });
+
+        Self::add_admin_matcher(&mut router, &plugin_shared_data, &admin_error, "POST", "/plugins", |plugin_controller, route| {
+            async move {
+                let archive = route.request.body
+                    .clone()
+                    .filter(|body| !body.is_empty())
+                    .ok_or_else(|| anyhow::anyhow!("Request body must contain a plugin archive"))?;
+
+                let plugin_metadata = plugin_controller.install_plugin(archive).await.map_err(Self::plugin_controller_error)?;
+
+                let response = route.request.build_response()
+                    .status_code(201)
+                    .json(json!({
+                        "ok": true,
+                        "data": plugin_metadata
+                    }))
+                    .build();
+
+                Ok(response)
+            }
+        });
+
+        Self::add_admin_matcher(&mut router, &plugin_shared_data, &admin_error, "DELETE", "/plugins/:id", |plugin_controller, route| {
+            async move {
+                let plugin_id = route.params.get("id")
+                    .ok_or_else(|| anyhow::anyhow!("Missing 'id' parameter in request"))?;
+
+                plugin_controller.uninstall_plugin(plugin_id).await.map_err(Self::plugin_controller_error)?;
+
+                let response = route.request.build_response()
+                    .status_code(204)
+                    .build();
+
+                Ok(response)
+            }
+        });
+
+        Self::add_admin_matcher(&mut router, &plugin_shared_data, &admin_error, "POST", "/plugins/:id/enable", |plugin_controller, route| {
+            async move {
+                let plugin_id = route.params.get("id")
+                    .ok_or_else(|| anyhow::anyhow!("Missing 'id' parameter in request"))?;
+
+                plugin_controller.enable_plugin(plugin_id).await.map_err(Self::plugin_controller_error)?;
+
+                let response = route.request.build_response()
+                    .json(json!({ "ok": true }))
+                    .build();
+
+                Ok(response)
+            }
+        });
+
+        Self::add_admin_matcher(&mut router, &plugin_shared_data, &admin_error, "POST", "/plugins/:id/disable", |plugin_controller, route| {
+            async move {
+                let plugin_id = route.params.get("id")
+                    .ok_or_else(|| anyhow::anyhow!("Missing 'id' parameter in request"))?;
+
+                plugin_controller.disable_plugin(plugin_id).await.map_err(Self::plugin_controller_error)?;
+
+                let response = route.request.build_response()
+                    .json(json!({ "ok": true }))
+                    .build();
+
+                Ok(response)
+            }
+        });
+
         router.add_matcher("GET", "/favicon.ico", |route| {
             async move {
                 let response = route.request.not_found_response();
@@ -60,24 +161,111 @@ impl LYServerHTTPServerPlugin {
             }
         });
 
+        if !router.has_match(&request) {
+            return Ok(());
+        }
+
+        plugin_shared_data.reply_event("http_request_handle_intent", event.clone(), ()).await?;
+
         if let Some(response) = router.respond(request).await {
             plugin_shared_data.reply_event("http_response", event, response).await?;
         }
 
         Ok(())
     }
+
+    async fn get_plugin_controller(plugin_shared_data: &LYServerPluginSharedData) -> anyhow::Result<Arc<dyn LYServerPluginController>> {
+        plugin_shared_data.app_shared_data
+            .get_plugin_controller()
+            .await
+            .ok_or_else(|| LYServerHTTPError::new(503, "Plugin management is not available").into())
+    }
+
+    /// Adds a plugin management route, answered with `admin_error` instead of running `handler` when the
+    /// request may not manage plugins.
+    fn add_admin_matcher<F, Fut>(
+        router: &mut LYServerHTTPRouter,
+        plugin_shared_data: &Arc<LYServerPluginSharedData>,
+        admin_error: &Option<LYServerHTTPError>,
+        method: &str,
+        path: &str,
+        handler: F,
+    ) where
+        F: Fn(Arc<dyn LYServerPluginController>, LYServerHTTPRoute) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<LYServerHTTPResponse>> + Send + 'static,
+    {
+        let plugin_shared_data = Arc::clone(plugin_shared_data);
+        let admin_error = admin_error.clone();
+        let handler = Arc::new(handler);
+
+        router.add_matcher(method, path, move |route| {
+            let plugin_shared_data = Arc::clone(&plugin_shared_data);
+            let admin_error = admin_error.clone();
+            let handler = Arc::clone(&handler);
+
+            async move {
+                if let Some(admin_error) = admin_error {
+                    return Err(admin_error.into());
+                }
+
+                let plugin_controller = Self::get_plugin_controller(&plugin_shared_data).await?;
+
+                handler(plugin_controller, route).await
+            }
+        });
+    }
+
+    /// Installing, removing, enabling or disabling plugins runs code on the server. It requires the
+    /// `--admin-token` as a bearer token, or a client connecting from localhost when no token is configured.
+    /// Behind a reverse proxy every client connects from localhost, so forwarded requests always need the token.
+    /// Returns the error to answer with when the request may not manage plugins.
+    fn check_admin_access(plugin_shared_data: &LYServerPluginSharedData, event: &LYServerMessageEvent, request: &LYServerHTTPRequest) -> Option<LYServerHTTPError> {
+        // Other plugins could forge the client address or replay the token, only our own listener is trusted
+        if event.event_sender != LYServerMessageEventTarget::Plugin(HTTP_PLUGIN_ID.to_string()) {
+            return Some(LYServerHTTPError::new(403, "Plugin management is only available over HTTP"));
+        }
+
+        match &plugin_shared_data.app_shared_data.admin_token {
+            Some(admin_token) => {
+                let token = request.header("authorization")
+                    .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                    .map(str::trim);
+
+                match token {
+                    Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => None,
+                    _ => Some(LYServerHTTPError::new(401, "A valid admin token is required to manage plugins")),
+                }
+            }
+            None if FORWARDING_HEADERS.iter().any(|header| request.header(header).is_some()) => {
+                Some(LYServerHTTPError::new(403, "Forwarded requests can only manage plugins when an admin token is configured"))
+            }
+            None if request.remote_address.is_some_and(|address| address.is_loopback()) => None,
+            None => Some(LYServerHTTPError::new(403, "Plugins can only be managed from localhost unless an admin token is configured")),
+        }
+    }
+
+    /// Refused operations are the client's fault, anything else failed on the server's side.
+    fn plugin_controller_error(error: anyhow::Error) -> anyhow::Error {
+        let status_code = match error.downcast_ref::<LYServerPluginControllerError>().map(|error| error.kind) {
+            Some(LYServerPluginControllerErrorKind::NotInstalled) => 404,
+            Some(LYServerPluginControllerErrorKind::Conflict) => 409,
+            Some(LYServerPluginControllerErrorKind::InvalidArchive) => 400,
+            None => 500,
+        };
+
+        LYServerHTTPError::new(status_code, error).into()
+    }
+}
+
+/// Compares tokens without leaking the length of their common prefix through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
 }
 
 #[async_trait::async_trait]
 impl LYServerPlugin for LYServerHTTPServerPlugin {
     fn metadata(&self) -> LYServerPluginMetadata {
-        LYServerPluginMetadata::builder()
-            .id("http@lyserver.local")
-            .name("LYServerHTTPServerPlugin")
-            .description("HTTP server plugin for LYServer")
-            .version(env!("CARGO_PKG_VERSION").to_string())
-            .author("LYServer")
-            .build()
+        Self::plugin_metadata()
     }
 
     async fn init(&self) -> anyhow::Result<()> {
@@ -108,7 +296,7 @@ impl LYServerPlugin for LYServerHTTPServerPlugin {
                 log::error!("Error handling HTTP event: {}", e);
             }
         } else {
-            log::debug!("Received event: {} from {}", event.event_type, event.event_sender.to_string());
+            log::debug!("Received event: {} from {}", event.event_type, event.event_sender);
         }
 
         Ok(())
@@ -119,16 +307,46 @@ impl LYServerPlugin for LYServerHTTPServerPlugin {
 
         Ok(())
     }
+}
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr};
 
-    async fn invoke(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
-        // Handle incoming requests to the HTTP server plugin
-        // This is a placeholder implementation
-        Ok(format!("Invoked method: {}, args: {:?}", method, args).into())
+    use super::*;
+
+    async fn admin_error(name: &str, sender: &str, headers: &[(&str, &str)], remote_address: &str) -> Option<u16> {
+        let plugins = crate::api::connect_test_plugins(name, &[sender]).await;
+
+        let headers = headers.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<HashMap<_, _>>();
+        let mut request = LYServerHTTPRequest::new("POST".to_string(), "/plugins".to_string(), "HTTP/1.1".to_string(), headers, None);
+        request.remote_address = Some(remote_address.parse::<IpAddr>().unwrap());
+
+        let event = plugins[0].create_event("http_request", LYServerMessageEventTarget::All, request.clone()).await.unwrap();
+
+        LYServerHTTPServerPlugin::check_admin_access(&plugins[0], &event, &request).map(|error| error.status_code)
     }
 
-    async fn receive(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
-        // Handle incoming requests to the HTTP server plugin
-        // This is a placeholder implementation
-        Ok(format!("Received method: {}, args: {:?}", method, args).into())
+    #[tokio::test]
+    async fn localhost_may_manage_plugins_without_a_token() {
+        assert_eq!(admin_error("admin-local", HTTP_PLUGIN_ID, &[], "127.0.0.1").await, None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn remote_clients_may_not_manage_plugins_without_a_token() {
+        assert_eq!(admin_error("admin-remote", HTTP_PLUGIN_ID, &[], "192.0.2.1").await, Some(403));
+    }
+
+    #[tokio::test]
+    async fn forwarded_requests_may_not_manage_plugins_without_a_token() {
+        for header in FORWARDING_HEADERS {
+            assert_eq!(admin_error(&format!("admin-{}", header), HTTP_PLUGIN_ID, &[(header, "192.0.2.1")], "127.0.0.1").await, Some(403));
+        }
+
+        assert_eq!(admin_error("admin-case", HTTP_PLUGIN_ID, &[("X-Forwarded-For", "192.0.2.1")], "127.0.0.1").await, Some(403));
+    }
+
+    #[tokio::test]
+    async fn other_plugins_may_not_manage_plugins() {
+        assert_eq!(admin_error("admin-plugin", "other@plugin", &[], "127.0.0.1").await, Some(403));
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt::Display;
+
+/// A route handler error answered with `status_code`, other errors are answered with 400, or 500
+/// when they were caused by an I/O failure.
+#[derive(Debug, Clone)]
+pub struct LYServerHTTPError {
+    pub status_code: u16,
+    pub message: String,
+}
+
+impl LYServerHTTPError {
+    pub fn new(status_code: u16, message: impl Display) -> Self {
+        Self {
+            status_code,
+            message: message.to_string(),
+        }
+    }
+
+    /// The status an error returned by a route handler is answered with.
+    pub fn status_code_of(error: &anyhow::Error) -> u16 {
+        if let Some(error) = error.downcast_ref::<Self>() {
+            return error.status_code;
+        }
+
+        if error.chain().any(|cause| cause.is::<std::io::Error>()) {
+            return 500;
+        }
+
+        400
+    }
+}
+
+impl Display for LYServerHTTPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LYServerHTTPError {}
@@ -0,0 +1,219 @@
+use std::{path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{LYServerHTTPRequest, LYServerHTTPResponse};
+
+/// A satisfiable `Range` request, both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LYServerHTTPByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// How a `Range` header is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LYServerHTTPRange {
+    /// The header is absent, malformed or asks for several ranges, the full resource is sent
+    Full,
+    Partial(LYServerHTTPByteRange),
+    /// None of the range lies within the resource
+    NotSatisfiable,
+}
+
+impl LYServerHTTPRange {
+    /// Parses a `bytes=` range against a resource of `size` bytes.
+    pub fn parse(header: &str, size: u64) -> Self {
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return Self::Full;
+        };
+
+        let Some((start, end)) = spec.trim().split_once('-').filter(|_| !spec.contains(',')) else {
+            return Self::Full;
+        };
+
+        match (start.trim(), end.trim()) {
+            ("", "") => Self::Full,
+            // The last `suffix` bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => Self::NotSatisfiable,
+                Ok(_) if size == 0 => Self::NotSatisfiable,
+                Ok(suffix) => Self::Partial(LYServerHTTPByteRange { start: size.saturating_sub(suffix), end: size - 1 }),
+                Err(_) => Self::Full,
+            },
+            (start, end) => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return Self::Full;
+                };
+
+                let end = match end {
+                    "" => u64::MAX,
+                    end => match end.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Self::Full,
+                    },
+                };
+
+                if start >= size {
+                    return Self::NotSatisfiable;
+                }
+
+                Self::Partial(LYServerHTTPByteRange { start, end: end.min(size - 1) })
+            }
+        }
+    }
+}
+
+/// Validators of a file, used to answer conditional requests.
+#[derive(Debug, Clone)]
+pub struct LYServerHTTPFileInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl LYServerHTTPFileInfo {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        })
+    }
+
+    /// A strong ETag made of the size and modification time, it changes whenever the file is rewritten.
+    pub fn etag(&self) -> String {
+        let modified = self.modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos());
+
+        format!("\"{:x}-{:x}\"", self.size, modified)
+    }
+
+    pub fn last_modified(&self) -> String {
+        httpdate::fmt_http_date(self.modified)
+    }
+
+    /// Whether an `If-Modified-Since` or `If-Range` date still describes this file, HTTP dates only
+    /// carry whole seconds.
+    fn not_modified_since(&self, date: &str) -> bool {
+        let Ok(date) = httpdate::parse_http_date(date) else {
+            return false;
+        };
+
+        let modified = self.modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+        let date = date.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+
+        modified <= date
+    }
+}
+
+impl LYServerHTTPRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Answers the request with the file at `path`, honouring `Range`, `If-Range`, `If-None-Match` and
+    /// `If-Modified-Since`. The body is a file stream, the server reads the requested bytes itself.
+    pub fn build_file_response(&self, path: &Path, content_type: &str) -> anyhow::Result<LYServerHTTPResponse> {
+        let info = LYServerHTTPFileInfo::from_path(path)?;
+        let path_str = path.to_str()
+            .ok_or_else(|| anyhow::anyhow!("'{}' is not valid UTF-8", path.display()))?;
+        let etag = info.etag();
+
+        let builder = self.build_response()
+            .header("accept-ranges".to_string(), "bytes".to_string())
+            .header("etag".to_string(), etag.clone())
+            .header("last-modified".to_string(), info.last_modified());
+
+        let not_modified = match self.header("if-none-match") {
+            Some(if_none_match) => if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            }),
+            None => self.header("if-modified-since").is_some_and(|date| info.not_modified_since(date)),
+        };
+
+        if not_modified {
+            return Ok(builder.status_code(304).build());
+        }
+
+        let builder = builder.header("content-type".to_string(), content_type.to_string());
+
+        // A stale If-Range asks for the whole, changed file instead of a piece of it
+        let range_applies = match self.header("if-range") {
+            Some(if_range) if if_range.trim().starts_with('"') => if_range.trim() == etag,
+            Some(if_range) => info.not_modified_since(if_range),
+            None => true,
+        };
+
+        let range = match self.header("range") {
+            Some(range) if range_applies => LYServerHTTPRange::parse(range, info.size),
+            _ => LYServerHTTPRange::Full,
+        };
+
+        match range {
+            LYServerHTTPRange::Partial(range) => Ok(builder
+                .status_code(206)
+                .header("content-range".to_string(), format!("bytes {}-{}/{}", range.start, range.end, info.size))
+                .file_stream(path_str, range.start, range.end - range.start + 1)
+                .build()),
+            LYServerHTTPRange::Full => Ok(builder.file_stream(path_str, 0, info.size).build()),
+            LYServerHTTPRange::NotSatisfiable => Ok(builder
+                .status_code(416)
+                .header("content-range".to_string(), format!("bytes */{}", info.size))
+                .build()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> LYServerHTTPRange {
+        LYServerHTTPRange::Partial(LYServerHTTPByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_closed_ranges() {
+        assert_eq!(LYServerHTTPRange::parse("bytes=0-99", 1000), partial(0, 99));
+        assert_eq!(LYServerHTTPRange::parse(" bytes=10 - 20 ", 1000), partial(10, 20));
+        assert_eq!(LYServerHTTPRange::parse("bytes=900-2000", 1000), partial(900, 999));
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(LYServerHTTPRange::parse("bytes=100-", 1000), partial(100, 999));
+        assert_eq!(LYServerHTTPRange::parse("bytes=999-", 1000), partial(999, 999));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(LYServerHTTPRange::parse("bytes=-100", 1000), partial(900, 999));
+        assert_eq!(LYServerHTTPRange::parse("bytes=-5000", 1000), partial(0, 999));
+        assert_eq!(LYServerHTTPRange::parse("bytes=-0", 1000), LYServerHTTPRange::NotSatisfiable);
+        assert_eq!(LYServerHTTPRange::parse("bytes=-100", 0), LYServerHTTPRange::NotSatisfiable);
+    }
+
+    #[test]
+    fn ranges_starting_past_the_end_are_not_satisfiable() {
+        assert_eq!(LYServerHTTPRange::parse("bytes=1000-", 1000), LYServerHTTPRange::NotSatisfiable);
+        assert_eq!(LYServerHTTPRange::parse("bytes=1500-2000", 1000), LYServerHTTPRange::NotSatisfiable);
+        assert_eq!(LYServerHTTPRange::parse("bytes=0-", 0), LYServerHTTPRange::NotSatisfiable);
+    }
+
+    #[test]
+    fn falls_back_to_the_full_resource() {
+        // Several ranges would need a multipart response
+        assert_eq!(LYServerHTTPRange::parse("bytes=0-10,20-30", 1000), LYServerHTTPRange::Full);
+        assert_eq!(LYServerHTTPRange::parse("bytes=0-10, -5", 1000), LYServerHTTPRange::Full);
+
+        assert_eq!(LYServerHTTPRange::parse("items=0-10", 1000), LYServerHTTPRange::Full);
+        assert_eq!(LYServerHTTPRange::parse("bytes=20-10", 1000), LYServerHTTPRange::Full);
+        assert_eq!(LYServerHTTPRange::parse("bytes=-", 1000), LYServerHTTPRange::Full);
+        assert_eq!(LYServerHTTPRange::parse("bytes=a-b", 1000), LYServerHTTPRange::Full);
+        assert_eq!(LYServerHTTPRange::parse("bytes=10", 1000), LYServerHTTPRange::Full);
+    }
+}
@@ -1,12 +1,15 @@
-use std::{collections::HashMap};
+use std::{collections::HashMap, net::IpAddr};
 use bytes::Bytes;
 use path_tree::PathTree;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::json;
 
-use crate::router::LYServerHTTPRoute;
+use crate::{router::LYServerHTTPRoute, stream::LYServerHTTPResponseStream};
 
+pub mod error;
+pub mod file;
 pub mod router;
+pub mod stream;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LYServerHTTPResponse {
@@ -14,6 +17,9 @@ pub struct LYServerHTTPResponse {
     pub status_code: u16,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    /// Set when the body is streamed instead of being sent in `body`
+    #[serde(default)]
+    pub stream: Option<LYServerHTTPResponseStream>,
 }
 
 pub struct LYServerHTTPResponseBuilder {
@@ -28,6 +34,7 @@ impl LYServerHTTPResponseBuilder {
                 status_code: 200,
                 headers: HashMap::new(),
                 body: Vec::new(),
+                stream: None,
             },
         }
     }
@@ -47,6 +54,20 @@ impl LYServerHTTPResponseBuilder {
         self
     }
 
+    /// Lets the server send `length` bytes of a file starting at `offset`, without the file passing the bus.
+    pub fn file_stream(mut self, path: impl Into<String>, offset: u64, length: u64) -> Self {
+        self.response.body = Vec::new();
+        self.response.stream = Some(LYServerHTTPResponseStream::File { path: path.into(), offset, length });
+        self
+    }
+
+    /// Announces a body which follows as `http_response_chunk` events.
+    pub fn chunked_stream(mut self) -> Self {
+        self.response.body = Vec::new();
+        self.response.stream = Some(LYServerHTTPResponseStream::Chunked);
+        self
+    }
+
     pub fn json(mut self, data: impl Serialize) -> Self {
         match serde_json::to_string(&data) {
             Ok(json_body) => {
@@ -62,12 +83,32 @@ impl LYServerHTTPResponseBuilder {
     }
 
     pub fn build(mut self) -> LYServerHTTPResponse {
-        self.response.headers.insert("content-length".to_string(), self.response.body.len().to_string());
+        match &self.response.stream {
+            Some(LYServerHTTPResponseStream::File { length, .. }) => {
+                self.response.headers.insert("content-length".to_string(), length.to_string());
+            }
+            // The length of a chunked body is not known up front
+            Some(LYServerHTTPResponseStream::Chunked) => {
+                self.response.headers.remove("content-length");
+            }
+            None => {
+                self.response.headers.insert("content-length".to_string(), self.response.body.len().to_string());
+            }
+        }
 
         self.response
     }
 }
 
+/// Data of an `http_request_handle_intent` event, claiming an `http_request` for the plugin sending it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LYServerHTTPHandleIntent {
+    /// Method the server calls over RPC with the [`LYServerHTTPRequest`] for its [`LYServerHTTPResponse`].
+    /// Without one, the plugin replies with an `http_response` event instead.
+    #[serde(default)]
+    pub rpc_method: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LYServerHTTPRequest {
     pub method: String,
@@ -76,6 +117,9 @@ pub struct LYServerHTTPRequest {
     pub version: String,
     pub headers: HashMap<String, String>,
     pub body: Option<Vec<u8>>,
+    /// Address of the client, unknown for requests which did not come through the HTTP server
+    #[serde(default)]
+    pub remote_address: Option<IpAddr>,
 }
 
 impl LYServerHTTPRequest {
@@ -87,6 +131,7 @@ impl LYServerHTTPRequest {
             version,
             headers,
             body,
+            remote_address: None,
         }
     }
 
@@ -101,7 +146,7 @@ impl LYServerHTTPRequest {
 
         let mut tree = PathTree::new();
         let _ = tree.insert(uri, 0);
-        if let Some((_, url)) = tree.find(&request.uri) {
+        if let Some((_, url)) = tree.find(request.path()) {
             let params = url.params()
                 .iter()
                 .map(|(k, v)| (k.to_string(), v.to_string()))
@@ -119,6 +164,26 @@ impl LYServerHTTPRequest {
         }
     }
 
+    /// The requested path, without the query string.
+    pub fn path(&self) -> &str {
+        self.uri.split_once('?').map_or(self.uri.as_str(), |(path, _)| path)
+    }
+
+    /// Parameters of the query string, a repeated one keeps its last value.
+    pub fn query(&self) -> HashMap<String, String> {
+        let Some((_, query)) = self.uri.split_once('?') else {
+            return HashMap::new();
+        };
+
+        query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_query_component(key), decode_query_component(value))
+            })
+            .collect()
+    }
+
     pub fn build_response(&self) -> LYServerHTTPResponseBuilder {
         LYServerHTTPResponseBuilder::new(self.clone())
     }
@@ -153,4 +218,32 @@ impl LYServerHTTPRequest {
             None => Err(anyhow::Error::msg("No body available")),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Undoes the `+` and `%XX` escaping of a query string component.
+fn decode_query_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
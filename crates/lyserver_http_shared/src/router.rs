@@ -1,8 +1,6 @@
 use std::{collections::HashMap, pin::Pin};
 
-use path_tree::PathTree;
-
-use crate::{LYServerHTTPRequest, LYServerHTTPResponse, LYServerHTTPResponseBuilder};
+use crate::{error::LYServerHTTPError, LYServerHTTPRequest, LYServerHTTPResponse};
 
 pub struct LYServerHTTPRoute {
     pub method: String,
@@ -22,6 +20,12 @@ type BoxedRouteHandler = Box<
         + Sync,
 >;
 
+impl Default for LYServerHTTPRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LYServerHTTPRouter {
     pub fn new() -> Self {
         Self {
@@ -45,6 +49,13 @@ impl LYServerHTTPRouter {
         LYServerHTTPRequest::static_match_request(request, method, uri)
     }
 
+    /// Whether any route matches the request, used to announce the intent to handle it before responding.
+    pub fn has_match(&self, request: &LYServerHTTPRequest) -> bool {
+        self.matchers
+            .iter()
+            .any(|(method, uri, _)| self.match_request(request.clone(), method, uri).is_some())
+    }
+
     pub async fn respond(&self, request: LYServerHTTPRequest) -> Option<LYServerHTTPResponse> {
         for (method, uri, handler) in &self.matchers {
             if let Some(route) = self.match_request(request.clone(), method, uri) {
@@ -53,7 +64,7 @@ impl LYServerHTTPRouter {
                     Err(e) => {
                         return Some(
                             request
-                                .build_error_response(400, format!("{}", e))
+                                .build_error_response(LYServerHTTPError::status_code_of(&e), format!("{}", e))
                                 .build(),
                         );
                     }
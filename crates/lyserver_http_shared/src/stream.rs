@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+/// A piece of a [`LYServerHTTPResponseStream::Chunked`] body, replying to the `http_request` event.
+pub const LYSERVER_HTTP_RESPONSE_CHUNK_EVENT: &str = "http_response_chunk";
+/// Sent by the server to ask for more chunks, carrying a [`LYServerHTTPResponseDemand`].
+pub const LYSERVER_HTTP_RESPONSE_DEMAND_EVENT: &str = "http_response_demand";
+/// Sent by the server when the client went away, no more chunks are read.
+pub const LYSERVER_HTTP_RESPONSE_CANCEL_EVENT: &str = "http_response_cancel";
+
+/// How many chunks the server asks for ahead of the ones the client has consumed.
+pub const LYSERVER_HTTP_RESPONSE_CHUNK_WINDOW: u64 = 4;
+/// Size plugins should keep chunks at, so a few of them in flight stay cheap to broadcast.
+pub const LYSERVER_HTTP_RESPONSE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A body which is not part of the `http_response` event itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LYServerHTTPResponseStream {
+    /// Read from disk by the server, `length` bytes starting at `offset`. WASM plugins can only name
+    /// files inside their data directory or their `fs.read` grants, as they see them.
+    File {
+        path: String,
+        offset: u64,
+        length: u64,
+    },
+    /// Sent by the plugin as `http_response_chunk` events, each one only after the server demanded it.
+    Chunked,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LYServerHTTPResponseChunk {
+    /// Counts up from 0
+    pub sequence: u64,
+    pub data: Vec<u8>,
+    /// Whether this is the final chunk, it may carry data as well
+    pub last: bool,
+    /// Ends the body early, the server aborts the response
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LYServerHTTPResponseDemand {
+    /// Chunks with a lower sequence may be sent
+    pub until: u64,
+}
@@ -0,0 +1,240 @@
+use std::sync::Arc;
+
+use lyserver_http_shared::{router::LYServerHTTPRouter, LYServerHTTPRequest};
+use lyserver_messaging_shared::LYServerMessageEvent;
+use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::LYSERVER_PREFERENCE_CHANGED_EVENT;
+use tokio::sync::Mutex;
+
+use crate::{scanner::{LYServerLibraryScanner, LIBRARY_ROOTS_PREFERENCE}, tags::content_type_for_format, transcoder::{LYServerTranscodeProfile, LYServerTranscoder}, watcher::LYServerLibraryWatcher};
+
+pub mod scanner;
+pub mod tags;
+pub mod transcoder;
+pub mod watcher;
+
+pub const LIBRARY_PLUGIN_ID: &str = "library@lyserver.local";
+const PREFERENCES_PLUGIN_ID: &str = "preferences@lyserver.local";
+
+pub struct LYServerLibraryPlugin {
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+
+    scanner: Arc<LYServerLibraryScanner>,
+    transcoder: Arc<LYServerTranscoder>,
+    watcher: Mutex<Option<LYServerLibraryWatcher>>,
+}
+
+impl LYServerLibraryPlugin {
+    pub fn new(plugin_shared_data: Arc<LYServerPluginSharedData>) -> Arc<Self> {
+        let scanner = Arc::new(LYServerLibraryScanner::new(Arc::clone(&plugin_shared_data.app_shared_data)));
+        let transcoder = Arc::new(LYServerTranscoder::new(Arc::clone(&plugin_shared_data.app_shared_data)));
+
+        Arc::new(Self {
+            plugin_shared_data,
+            scanner,
+            transcoder,
+            watcher: Mutex::new(None),
+        })
+    }
+
+    pub fn plugin_metadata() -> LYServerPluginMetadata {
+        LYServerPluginMetadata::builder()
+            .id(LIBRARY_PLUGIN_ID)
+            .name("LYServerLibraryPlugin")
+            .description("Music library plugin for LYServer")
+            .version(env!("CARGO_PKG_VERSION").to_string())
+            .author("LYServer")
+            .dependency("database@lyserver.local", env!("CARGO_PKG_VERSION"))
+            .dependency(PREFERENCES_PLUGIN_ID, env!("CARGO_PKG_VERSION"))
+            .build()
+    }
+
+    async fn restart_watcher(&self) {
+        let mut watcher = self.watcher.lock().await;
+        watcher.take();
+
+        match LYServerLibraryWatcher::start(Arc::clone(&self.scanner)).await {
+            Ok(new_watcher) => *watcher = Some(new_watcher),
+            Err(e) => log::error!("Failed to watch the library roots: {}", e),
+        }
+    }
+
+    /// Scans the library in the background, scanning a large library takes a while.
+    fn spawn_scan(&self) {
+        let scanner = Arc::clone(&self.scanner);
+
+        tokio::spawn(async move {
+            if let Err(e) = scanner.scan().await {
+                log::error!("Library scan failed: {}", e);
+            }
+        });
+    }
+
+    /// Answers `GET /tracks/:id/stream?format=..&bitrate=..` from the transcode cache, or streams a new
+    /// transcode as ffmpeg produces it.
+    async fn handle_transcode_request(
+        plugin_shared_data: Arc<LYServerPluginSharedData>,
+        scanner: Arc<LYServerLibraryScanner>,
+        transcoder: Arc<LYServerTranscoder>,
+        event: LYServerMessageEvent,
+        request: LYServerHTTPRequest,
+        id: &str,
+    ) -> anyhow::Result<()> {
+        let query = request.query();
+        let format = query.get("format").map(String::as_str).unwrap_or_default();
+
+        let profile = match LYServerTranscodeProfile::parse(format, query.get("bitrate").map(String::as_str)) {
+            Ok(profile) => profile,
+            Err(e) => {
+                let response = request.build_error_response(400, e.to_string()).build();
+                return plugin_shared_data.reply_event("http_response", event, response).await;
+            }
+        };
+
+        let track = match id.parse::<i64>() {
+            Ok(id) => scanner.get_track_file(id).await?,
+            Err(_) => None,
+        };
+
+        let Some((path, _)) = track else {
+            return plugin_shared_data.reply_event("http_response", event, request.not_found_response()).await;
+        };
+
+        let cache_path = transcoder.get_cache_path(&path, &profile)?;
+
+        if let Some(cached_path) = transcoder.get_cached(&cache_path) {
+            let response = tokio::task::spawn_blocking(move || {
+                request.build_file_response(&cached_path, profile.format.content_type())
+            }).await??;
+
+            return plugin_shared_data.reply_event("http_response", event, response).await;
+        }
+
+        let transcode = match transcoder.start(&path, profile, cache_path).await {
+            Ok(transcode) => transcode,
+            Err(e) => {
+                log::error!("Failed to transcode track {}: {}", id, e);
+
+                let response = request.build_error_response(503, e.to_string()).build();
+                return plugin_shared_data.reply_event("http_response", event, response).await;
+            }
+        };
+
+        // The length is unknown until ffmpeg is done, so neither ranges nor validators are offered
+        let response = request.build_response()
+            .header("content-type".to_string(), profile.format.content_type().to_string())
+            .header("accept-ranges".to_string(), "none".to_string())
+            .build();
+
+        plugin_shared_data.reply_http_stream(event, response, transcode.chunks).await
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerPlugin for LYServerLibraryPlugin {
+    fn metadata(&self) -> LYServerPluginMetadata {
+        Self::plugin_metadata()
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        self.plugin_shared_data.dispatch_init_event().await?;
+
+        self.restart_watcher().await;
+        self.spawn_scan();
+
+        Ok(())
+    }
+
+    async fn handle_message_event(
+        &self,
+        event: LYServerMessageEvent,
+    ) -> anyhow::Result<()> {
+        if event.event_type == LYSERVER_PREFERENCE_CHANGED_EVENT
+            && event.event_sender.plugin_id().as_deref() == Some(PREFERENCES_PLUGIN_ID)
+            && event.data_as::<String>().is_ok_and(|key| key == LIBRARY_ROOTS_PREFERENCE) {
+            // New roots have to be watched and indexed, the tracks of dropped ones go away with the scan
+            self.restart_watcher().await;
+            self.spawn_scan();
+
+            return Ok(());
+        }
+
+        if event.event_type == "http_request" {
+            let request = event.data_as::<LYServerHTTPRequest>().expect("Failed to deserialize LYServerHTTPRequest");
+
+            if let Some(route) = request.match_request("GET", "/tracks/:id/stream")
+                && request.query().contains_key("format") {
+                self.plugin_shared_data.reply_event("http_request_handle_intent", event.clone(), ()).await?;
+
+                // Waiting for a worker and streaming the output must not hold up other requests
+                let plugin_shared_data = Arc::clone(&self.plugin_shared_data);
+                let scanner = Arc::clone(&self.scanner);
+                let transcoder = Arc::clone(&self.transcoder);
+                tokio::spawn(async move {
+                    let id = route.params.get("id").cloned().unwrap_or_default();
+
+                    if let Err(e) = Self::handle_transcode_request(plugin_shared_data, scanner, transcoder, event, request, &id).await {
+                        log::error!("Failed to stream transcode of track {}: {}", id, e);
+                    }
+                });
+
+                return Ok(());
+            }
+
+            let mut router = LYServerHTTPRouter::new();
+
+            let scanner_clone = Arc::clone(&self.scanner);
+            router.add_matcher("GET", "/tracks/:id/stream", move |route| {
+                let scanner_clone = Arc::clone(&scanner_clone);
+
+                async move {
+                    let id = route.params.get("id")
+                        .ok_or_else(|| anyhow::anyhow!("Missing 'id' parameter in request"))?
+                        .parse::<i64>()?;
+
+                    let Some((path, format)) = scanner_clone.get_track_file(id).await? else {
+                        return Ok(route.request.not_found_response());
+                    };
+
+                    let request = route.request;
+                    let response = tokio::task::spawn_blocking(move || {
+                        request.build_file_response(&path, content_type_for_format(&format))
+                    }).await??;
+
+                    Ok(response)
+                }
+            });
+
+            if !router.has_match(&request) {
+                return Ok(());
+            }
+
+            self.plugin_shared_data.reply_event("http_request_handle_intent", event.clone(), ()).await?;
+
+            if let Some(response) = router.respond(request).await {
+                self.plugin_shared_data.reply_event("http_response", event, response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn destroy(&self) -> anyhow::Result<()> {
+        self.watcher.lock().await.take();
+
+        Ok(())
+    }
+
+    async fn invoke(&self, method: &str, _args: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match method {
+            "scan" => {
+                // The roots may have changed since the last scan
+                self.restart_watcher().await;
+
+                Ok(serde_cbor::to_vec(&self.scanner.scan().await?)?)
+            }
+            _ => Err(anyhow::anyhow!("Unknown method: {}", method)),
+        }
+    }
+}
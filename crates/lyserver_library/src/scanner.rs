@@ -0,0 +1,815 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::Arc, time::UNIX_EPOCH};
+
+use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
+use lyserver_shared_data::{LYServerDatabaseRow, LYServerDatabaseValue, LYServerPreferencesService, LYServerSharedData, LYServerSharedDataDatabase as _, LYServerSharedDataMessaging as _, LYServerSharedDataServices as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use walkdir::WalkDir;
+
+use crate::{tags::{is_supported_audio_file, read_track_tags, LYServerTrackTags}, LIBRARY_PLUGIN_ID};
+
+/// Preference holding the directories the library is scanned from, a JSON array of paths.
+///
+/// Changing it re-watches the roots and rescans the library.
+pub const LIBRARY_ROOTS_PREFERENCE: &str = "library.roots";
+
+const LIBRARY_DATABASE: &str = "library";
+
+/// What a scan changed in the library database.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LYServerLibraryScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub moved: usize,
+    /// Files which could not be read, with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+/// Data of the `library_track_added`, `library_track_updated`, `library_track_moved` and
+/// `library_track_removed` events.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LYServerLibraryTrackEvent {
+    pub id: i64,
+    pub path: String,
+    /// Where a moved track was before
+    pub previous_path: Option<String>,
+}
+
+/// An audio file found under a library root.
+#[derive(Debug, Clone)]
+pub struct LYServerLibraryFile {
+    pub path: PathBuf,
+    pub size: i64,
+    pub mtime: i64,
+}
+
+impl LYServerLibraryFile {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as i64);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: metadata.len() as i64,
+            mtime,
+        })
+    }
+}
+
+pub struct LYServerLibraryScanner {
+    shared_data: Arc<LYServerSharedData>,
+
+    /// Scans share the database, only one runs at a time
+    scan_lock: Mutex<()>,
+}
+
+impl LYServerLibraryScanner {
+    pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
+        Self {
+            shared_data,
+            scan_lock: Mutex::new(()),
+        }
+    }
+
+    /// The configured library roots, an unset preference means an empty library.
+    pub async fn get_roots(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let preferences = self.shared_data.get_service::<dyn LYServerPreferencesService>()
+            .ok_or_else(|| anyhow::anyhow!("Preferences service is not available"))?;
+
+        let Ok(preference) = preferences.get(LIBRARY_ROOTS_PREFERENCE).await else {
+            return Ok(Vec::new());
+        };
+
+        match preference.value() {
+            Value::Null => Ok(Vec::new()),
+            Value::String(root) => Ok(vec![PathBuf::from(root)]),
+            Value::Array(roots) => roots.into_iter()
+                .map(|root| match root {
+                    Value::String(root) => Ok(PathBuf::from(root)),
+                    other => Err(anyhow::anyhow!("'{}' has to hold paths, found {}", LIBRARY_ROOTS_PREFERENCE, other)),
+                })
+                .collect(),
+            other => Err(anyhow::anyhow!("'{}' has to be an array of paths, found {}", LIBRARY_ROOTS_PREFERENCE, other)),
+        }
+    }
+
+    /// Walks every library root, indexing new and changed files and dropping the ones which are gone.
+    ///
+    /// Files are compared by size and mtime, unchanged ones are not read again. A new file only takes over the
+    /// track of a file which is gone when their tags match as well.
+    pub async fn scan(&self) -> anyhow::Result<LYServerLibraryScanReport> {
+        let _guard = self.scan_lock.lock().await;
+
+        let roots = self.get_roots().await?;
+        let indexed = self.get_indexed_files().await?;
+
+        // A root which is missing, e.g. an unmounted drive, keeps its tracks until it comes back
+        let (available_roots, missing_roots): (Vec<_>, Vec<_>) = roots.into_iter().partition(|root| root.is_dir());
+        for root in &missing_roots {
+            log::warn!("Library root {} is not available, keeping its tracks", root.display());
+        }
+
+        let files = tokio::task::spawn_blocking(move || walk_roots(&available_roots)).await?;
+
+        let seen = files.iter()
+            .map(|file| file.path.to_string_lossy().to_string())
+            .collect::<HashSet<_>>();
+
+        let gone = indexed.iter()
+            .filter(|(path, _)| !seen.contains(*path) && !missing_roots.iter().any(|root| Path::new(path).starts_with(root)))
+            .map(|(path, (id, size, mtime))| (path.clone(), (*id, *size, *mtime)))
+            .collect();
+
+        let report = self.sync(&indexed, files, gone).await?;
+
+        log::info!(
+            "Library scan finished: {} added, {} updated, {} moved, {} unchanged, {} removed, {} failed",
+            report.added, report.updated, report.moved, report.unchanged, report.removed, report.failed.len(),
+        );
+
+        Ok(report)
+    }
+
+    /// Applies changes of single files or directories, e.g. reported by the watcher, without walking every root.
+    ///
+    /// A path which no longer exists drops the tracks at or below it.
+    pub async fn apply_changes(&self, changed_paths: HashSet<PathBuf>) -> anyhow::Result<LYServerLibraryScanReport> {
+        let _guard = self.scan_lock.lock().await;
+
+        let roots = self.get_roots().await?;
+        let indexed = self.get_indexed_files().await?;
+
+        let changed_paths = changed_paths.into_iter()
+            .filter(|path| roots.iter().any(|root| path.starts_with(root)))
+            .collect::<Vec<_>>();
+
+        let (files, gone, indexed) = tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+
+            for path in &changed_paths {
+                if path.is_dir() {
+                    files.extend(walk_roots(std::slice::from_ref(path)));
+                } else if path.is_file() && is_supported_audio_file(path) {
+                    match LYServerLibraryFile::from_path(path) {
+                        Ok(file) => files.push(file),
+                        Err(e) => log::warn!("Failed to read metadata of {}: {}", path.display(), e),
+                    }
+                }
+            }
+
+            // A file may be reported on its own and through its directory
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            files.dedup_by(|a, b| a.path == b.path);
+
+            let gone = indexed.iter()
+                .filter(|(path, _)| {
+                    let path = Path::new(path);
+                    changed_paths.iter().any(|changed_path| path.starts_with(changed_path)) && !path.exists()
+                })
+                .map(|(path, (id, size, mtime))| (path.clone(), (*id, *size, *mtime)))
+                .collect::<HashMap<_, _>>();
+
+            (files, gone, indexed)
+        }).await?;
+
+        self.sync(&indexed, files, gone).await
+    }
+
+    /// Brings the database in line with the `files` found on disk and the indexed tracks which are `gone`,
+    /// announcing every change on the bus.
+    async fn sync(
+        &self,
+        indexed: &HashMap<String, (i64, i64, i64)>,
+        files: Vec<LYServerLibraryFile>,
+        mut gone: HashMap<String, (i64, i64, i64)>,
+    ) -> anyhow::Result<LYServerLibraryScanReport> {
+        let mut report = LYServerLibraryScanReport::default();
+
+        for file in files {
+            let path = file.path.to_string_lossy().to_string();
+            let existing = indexed.get(&path);
+
+            if existing.is_some_and(|(_, size, mtime)| *size == file.size && *mtime == file.mtime) {
+                report.unchanged += 1;
+                continue;
+            }
+
+            let tags = read_file_tags(&file).await;
+
+            // A renamed file keeps its size, mtime and tags, moving the track keeps its id
+            if existing.is_none()
+                && let Ok(tags) = &tags {
+                let candidates = gone.iter()
+                    .filter(|(_, (_, size, mtime))| *size == file.size && *mtime == file.mtime)
+                    .map(|(path, (id, _, _))| (path.clone(), *id))
+                    .collect::<Vec<_>>();
+
+                let mut moved_from = None;
+                for (previous_path, id) in candidates {
+                    if self.track_matches(id, Path::new(&previous_path), tags).await? {
+                        moved_from = Some((previous_path, id));
+                        break;
+                    }
+                }
+
+                if let Some((previous_path, id)) = moved_from {
+                    gone.remove(&previous_path);
+
+                    self.move_track(id, &path).await?;
+                    self.dispatch_track_event("library_track_moved", id, &path, Some(previous_path));
+                    report.moved += 1;
+                    continue;
+                }
+            }
+
+            let indexed_id = match tags {
+                Ok(tags) => self.upsert_track(&file, tags).await,
+                Err(e) => Err(e),
+            };
+
+            match indexed_id {
+                Ok(id) if existing.is_some() => {
+                    self.dispatch_track_event("library_track_updated", id, &path, None);
+                    report.updated += 1;
+                }
+                Ok(id) => {
+                    self.dispatch_track_event("library_track_added", id, &path, None);
+                    report.added += 1;
+                }
+                Err(e) => {
+                    log::warn!("Failed to index {}: {}", path, e);
+                    report.failed.push((path, e.to_string()));
+                }
+            }
+        }
+
+        for (path, (id, _, _)) in gone {
+            self.remove_track(id).await?;
+            self.dispatch_track_event("library_track_removed", id, &path, None);
+            report.removed += 1;
+        }
+
+        if report.removed > 0 || report.updated > 0 {
+            self.remove_orphans().await?;
+        }
+
+        Ok(report)
+    }
+
+    fn dispatch_track_event(&self, event_type: &str, id: i64, path: &str, previous_path: Option<String>) {
+        let event = LYServerMessageEvent::new(
+            event_type,
+            LYServerMessageEventTarget::All,
+            LYServerMessageEventTarget::Plugin(LIBRARY_PLUGIN_ID.to_string()),
+            LYServerLibraryTrackEvent {
+                id,
+                path: path.to_string(),
+                previous_path,
+            },
+        );
+
+        if let Err(e) = self.shared_data.dispatch_event(event) {
+            log::warn!("Failed to dispatch '{}' for track {}: {}", event_type, id, e);
+        }
+    }
+
+    /// Reads the tags of a file and inserts or updates its track, returning the track id.
+    pub async fn index_file(&self, file: &LYServerLibraryFile) -> anyhow::Result<i64> {
+        let tags = read_file_tags(file).await?;
+
+        self.upsert_track(file, tags).await
+    }
+
+    /// Whether the indexed track `id`, last seen at `path`, holds the same tags as a file read since.
+    ///
+    /// Files of the same size and mtime are not necessarily the same file, e.g. tracks of an album ripped at once.
+    async fn track_matches(&self, id: i64, path: &Path, tags: &LYServerTrackTags) -> anyhow::Result<bool> {
+        let rows = self.shared_data.query(LIBRARY_DATABASE, "
+            SELECT tracks.title, artists.name AS artist, albums.title AS album, tracks.track_number, tracks.disc_number,
+                tracks.duration_ms, tracks.genre, tracks.year
+            FROM tracks
+            LEFT JOIN artists ON artists.id = tracks.artist_id
+            LEFT JOIN albums ON albums.id = tracks.album_id
+            WHERE tracks.id = ?
+        ", vec![id.into()]).await?;
+
+        let Some(row) = rows.first() else {
+            return Ok(false);
+        };
+
+        let text = |column: &str| row.get(column).and_then(LYServerDatabaseValue::as_str).map(str::to_string);
+        let number = |column: &str| row.get(column).and_then(LYServerDatabaseValue::as_i64);
+
+        Ok(text("title") == Some(track_title(path, tags))
+            && text("artist") == tags.artist
+            && text("album") == tags.album
+            && number("track_number") == tags.track_number.map(i64::from)
+            && number("disc_number") == tags.disc_number.map(i64::from)
+            && number("duration_ms") == tags.duration_ms.map(|duration_ms| duration_ms as i64)
+            && text("genre") == tags.genre
+            && number("year") == tags.year.map(i64::from))
+    }
+
+    /// Path and format of an indexed track.
+    pub async fn get_track_file(&self, id: i64) -> anyhow::Result<Option<(PathBuf, String)>> {
+        let rows = self.shared_data.query(LIBRARY_DATABASE, "SELECT path, format FROM tracks WHERE id = ?", vec![id.into()]).await?;
+
+        Ok(rows.first().and_then(|row| {
+            let path = row.get("path")?.as_str()?;
+            let format = row.get("format")?.as_str()?;
+
+            Some((PathBuf::from(path), format.to_string()))
+        }))
+    }
+
+    async fn get_indexed_files(&self) -> anyhow::Result<HashMap<String, (i64, i64, i64)>> {
+        let rows = self.shared_data.query(LIBRARY_DATABASE, "SELECT id, path, size, mtime FROM tracks", vec![]).await?;
+
+        Ok(rows.iter()
+            .filter_map(|row| {
+                let id = row.get("id")?.as_i64()?;
+                let path = row.get("path")?.as_str()?.to_string();
+                let size = row.get("size")?.as_i64()?;
+                let mtime = row.get("mtime")?.as_i64()?;
+
+                Some((path, (id, size, mtime)))
+            })
+            .collect())
+    }
+
+    async fn upsert_track(&self, file: &LYServerLibraryFile, tags: LYServerTrackTags) -> anyhow::Result<i64> {
+        let artist_id = match &tags.artist {
+            Some(artist) => Some(self.get_or_create_artist(artist).await?),
+            None => None,
+        };
+
+        let album_artist_id = match &tags.album_artist {
+            Some(album_artist) => Some(self.get_or_create_artist(album_artist).await?),
+            None => artist_id,
+        };
+
+        let album_id = match &tags.album {
+            Some(album) => Some(self.get_or_create_album(album, album_artist_id, tags.year).await?),
+            None => None,
+        };
+
+        let title = track_title(&file.path, &tags);
+
+        let format = file.path.extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        let rows = self.shared_data.query(LIBRARY_DATABASE, "
+            INSERT INTO tracks (path, size, mtime, format, title, artist_id, album_id, track_number, disc_number, duration_ms, genre, year)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (path) DO UPDATE SET
+                size = excluded.size,
+                mtime = excluded.mtime,
+                format = excluded.format,
+                title = excluded.title,
+                artist_id = excluded.artist_id,
+                album_id = excluded.album_id,
+                track_number = excluded.track_number,
+                disc_number = excluded.disc_number,
+                duration_ms = excluded.duration_ms,
+                genre = excluded.genre,
+                year = excluded.year,
+                scanned_at = CURRENT_TIMESTAMP
+            RETURNING id
+        ", vec![
+            file.path.to_string_lossy().to_string().into(),
+            file.size.into(),
+            file.mtime.into(),
+            format.into(),
+            title.into(),
+            artist_id.into(),
+            album_id.into(),
+            tags.track_number.into(),
+            tags.disc_number.into(),
+            tags.duration_ms.map(|duration_ms| duration_ms as i64).into(),
+            tags.genre.into(),
+            tags.year.into(),
+        ]).await?;
+
+        first_id(&rows)
+    }
+
+    async fn get_or_create_artist(&self, name: &str) -> anyhow::Result<i64> {
+        let rows = self.shared_data.query(LIBRARY_DATABASE, "
+            INSERT INTO artists (name) VALUES (?)
+            ON CONFLICT (name) DO UPDATE SET name = excluded.name
+            RETURNING id
+        ", vec![name.into()]).await?;
+
+        first_id(&rows)
+    }
+
+    async fn get_or_create_album(&self, title: &str, artist_id: Option<i64>, year: Option<u32>) -> anyhow::Result<i64> {
+        let rows = self.shared_data.query(
+            LIBRARY_DATABASE,
+            "SELECT id FROM albums WHERE title = ? AND IFNULL(artist_id, 0) = IFNULL(?, 0)",
+            vec![title.into(), artist_id.into()],
+        ).await?;
+
+        if let Ok(id) = first_id(&rows) {
+            if year.is_some() {
+                self.shared_data.query(
+                    LIBRARY_DATABASE,
+                    "UPDATE albums SET year = IFNULL(year, ?) WHERE id = ?",
+                    vec![year.into(), id.into()],
+                ).await?;
+            }
+
+            return Ok(id);
+        }
+
+        let rows = self.shared_data.query(
+            LIBRARY_DATABASE,
+            "INSERT INTO albums (title, artist_id, year) VALUES (?, ?, ?) RETURNING id",
+            vec![title.into(), artist_id.into(), year.into()],
+        ).await?;
+
+        first_id(&rows)
+    }
+
+    async fn move_track(&self, id: i64, path: &str) -> anyhow::Result<()> {
+        self.shared_data.query(
+            LIBRARY_DATABASE,
+            "UPDATE tracks SET path = ?, scanned_at = CURRENT_TIMESTAMP WHERE id = ?",
+            vec![path.into(), id.into()],
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn remove_track(&self, id: i64) -> anyhow::Result<()> {
+        self.shared_data.query(LIBRARY_DATABASE, "DELETE FROM tracks WHERE id = ?", vec![id.into()]).await?;
+
+        Ok(())
+    }
+
+    /// Drops albums and artists no track refers to anymore.
+    async fn remove_orphans(&self) -> anyhow::Result<()> {
+        self.shared_data.query(LIBRARY_DATABASE, "
+            DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM tracks WHERE album_id IS NOT NULL)
+        ", vec![]).await?;
+
+        self.shared_data.query(LIBRARY_DATABASE, "
+            DELETE FROM artists
+            WHERE id NOT IN (SELECT artist_id FROM tracks WHERE artist_id IS NOT NULL)
+                AND id NOT IN (SELECT artist_id FROM albums WHERE artist_id IS NOT NULL)
+        ", vec![]).await?;
+
+        Ok(())
+    }
+}
+
+fn walk_roots(roots: &[PathBuf]) -> Vec<LYServerLibraryFile> {
+    let mut files = Vec::new();
+
+    for root in roots {
+        for entry in WalkDir::new(root).follow_links(true) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("Failed to read an entry of library root {}: {}", root.display(), e);
+                    continue;
+                }
+            };
+
+            if !entry.file_type().is_file() || !is_supported_audio_file(entry.path()) {
+                continue;
+            }
+
+            match LYServerLibraryFile::from_path(entry.path()) {
+                Ok(file) => files.push(file),
+                Err(e) => log::warn!("Failed to read metadata of {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+
+    files
+}
+
+async fn read_file_tags(file: &LYServerLibraryFile) -> anyhow::Result<LYServerTrackTags> {
+    let path = file.path.clone();
+
+    tokio::task::spawn_blocking(move || read_track_tags(&path)).await?
+}
+
+/// The title a track is indexed with, untagged files are named after the file.
+fn track_title(path: &Path, tags: &LYServerTrackTags) -> String {
+    tags.title.clone().unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    })
+}
+
+fn first_id(rows: &[LYServerDatabaseRow]) -> anyhow::Result<i64> {
+    rows.first()
+        .and_then(|row| row.get("id"))
+        .and_then(LYServerDatabaseValue::as_i64)
+        .ok_or_else(|| anyhow::anyhow!("Query returned no id"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use lyserver_database::LYServerDatabasePlugin;
+    use lyserver_plugin_common::LYServerPlugin as _;
+    use lyserver_plugin_shared_data::LYServerPluginSharedData;
+    use lyserver_preferences::LYServerPreferencesPlugin;
+    use lyserver_shared_data::LYServerDatabaseService;
+    use tokio::sync::broadcast::Receiver;
+
+    use super::*;
+
+    /// A scanner over `<dir>/music`, backed by the real database and preferences plugins.
+    struct TestLibrary {
+        scanner: LYServerLibraryScanner,
+        root: PathBuf,
+        /// The bus drops events nobody listens to, and fails to send them
+        _events: Receiver<LYServerMessageEvent>,
+    }
+
+    impl TestLibrary {
+        async fn start(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("lyserver-library-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+
+            let root = dir.join("music");
+            std::fs::create_dir_all(&root).unwrap();
+
+            let data_dir = dir.join("data");
+            let shared_data = Arc::new(LYServerSharedData::new_from_args([
+                "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+            ]).unwrap());
+            let events = shared_data.messaging_global_tx.subscribe();
+
+            // The database plugin keeps health checking after init, so it never returns
+            let database = LYServerDatabasePlugin::new(Self::plugin_shared_data(&shared_data, "database@lyserver.local").await);
+            tokio::spawn(async move { database.init().await });
+
+            for _ in 0..500 {
+                if shared_data.get_service::<dyn LYServerDatabaseService>().is_some() {
+                    break;
+                }
+
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let preferences = LYServerPreferencesPlugin::new(Self::plugin_shared_data(&shared_data, "preferences@lyserver.local").await);
+            preferences.init().await.unwrap();
+
+            let library = Self {
+                scanner: LYServerLibraryScanner::new(shared_data),
+                root,
+                _events: events,
+            };
+            library.set_roots(serde_json::json!([library.root])).await;
+
+            library
+        }
+
+        async fn plugin_shared_data(shared_data: &Arc<LYServerSharedData>, plugin_id: &str) -> Arc<LYServerPluginSharedData> {
+            let mut plugin_shared_data = LYServerPluginSharedData::new(Arc::clone(shared_data));
+            plugin_shared_data.register_plugin_messaging(plugin_id.to_string()).await.unwrap();
+
+            Arc::new(plugin_shared_data)
+        }
+
+        async fn set_roots(&self, roots: Value) {
+            let preferences = self.scanner.shared_data.get_service::<dyn LYServerPreferencesService>().unwrap();
+
+            preferences.set(LIBRARY_ROOTS_PREFERENCE, roots).await.unwrap();
+        }
+
+        /// Writes an MP3 titled `title` at `path` below the root, with a fixed mtime so its size and mtime can be
+        /// matched by another file.
+        fn write_track(&self, path: &str, title: &str) -> PathBuf {
+            let path = self.root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+            // ID3v2.3 tag with a single TIT2 frame
+            let mut text = vec![0u8];
+            text.extend_from_slice(title.as_bytes());
+
+            let mut frame = b"TIT2".to_vec();
+            frame.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(&text);
+
+            let size = frame.len() as u32;
+            let mut data = b"ID3\x03\x00\x00".to_vec();
+            data.extend_from_slice(&[(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+            data.extend_from_slice(&frame);
+
+            // Silent MPEG-1 Layer III frames, 128 kbit/s at 44.1 kHz
+            for _ in 0..16 {
+                data.extend_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+                data.extend_from_slice(&[0; 413]);
+            }
+
+            std::fs::write(&path, data).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+                .unwrap();
+
+            path
+        }
+
+        async fn indexed(&self) -> HashMap<String, i64> {
+            self.scanner.get_indexed_files().await.unwrap()
+                .into_iter()
+                .map(|(path, (id, _, _))| (path, id))
+                .collect()
+        }
+
+        async fn title(&self, id: i64) -> String {
+            let rows = self.scanner.shared_data.query(LIBRARY_DATABASE, "SELECT title FROM tracks WHERE id = ?", vec![id.into()]).await.unwrap();
+
+            rows[0].get("title").and_then(LYServerDatabaseValue::as_str).unwrap().to_string()
+        }
+    }
+
+    fn key(path: &Path) -> String {
+        path.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn scan_indexes_added_files_and_skips_unchanged_ones() {
+        let library = TestLibrary::start("scan-add").await;
+        let path = library.write_track("album/a.mp3", "Alpha");
+
+        let report = library.scanner.scan().await.unwrap();
+        assert_eq!((report.added, report.unchanged), (1, 0));
+
+        let report = library.scanner.scan().await.unwrap();
+        assert_eq!((report.added, report.unchanged), (0, 1));
+
+        let indexed = library.indexed().await;
+        assert_eq!(library.title(indexed[&key(&path)]).await, "Alpha");
+    }
+
+    #[tokio::test]
+    async fn scan_updates_a_changed_file_in_place() {
+        let library = TestLibrary::start("scan-update").await;
+        let path = library.write_track("a.mp3", "Alpha");
+        library.scanner.scan().await.unwrap();
+        let id = library.indexed().await[&key(&path)];
+
+        library.write_track("a.mp3", "Alpha, remastered");
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!((report.updated, report.added), (1, 0));
+        assert_eq!(library.indexed().await[&key(&path)], id);
+        assert_eq!(library.title(id).await, "Alpha, remastered");
+    }
+
+    #[tokio::test]
+    async fn scan_keeps_the_id_of_a_moved_file() {
+        let library = TestLibrary::start("scan-move").await;
+        let from = library.write_track("a.mp3", "Alpha");
+        library.scanner.scan().await.unwrap();
+        let id = library.indexed().await[&key(&from)];
+
+        let to = library.root.join("album/a.mp3");
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        std::fs::rename(&from, &to).unwrap();
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!((report.moved, report.added, report.removed), (1, 0, 0));
+        assert_eq!(library.indexed().await, HashMap::from([(key(&to), id)]));
+    }
+
+    #[tokio::test]
+    async fn scan_does_not_merge_different_files_of_the_same_size_and_mtime() {
+        let library = TestLibrary::start("scan-no-merge").await;
+        let removed = library.write_track("a.mp3", "Alpha");
+        library.scanner.scan().await.unwrap();
+        let id = library.indexed().await[&key(&removed)];
+
+        std::fs::remove_file(&removed).unwrap();
+        let added = library.write_track("b.mp3", "Bravo");
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!((report.moved, report.added, report.removed), (0, 1, 1));
+
+        let indexed = library.indexed().await;
+        assert_ne!(indexed[&key(&added)], id);
+        assert_eq!(library.title(indexed[&key(&added)]).await, "Bravo");
+    }
+
+    #[tokio::test]
+    async fn scan_drops_removed_files() {
+        let library = TestLibrary::start("scan-remove").await;
+        let path = library.write_track("a.mp3", "Alpha");
+        let kept = library.write_track("b.mp3", "Bravo");
+        library.scanner.scan().await.unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!((report.removed, report.unchanged), (1, 1));
+        assert_eq!(library.indexed().await.into_keys().collect::<Vec<_>>(), vec![key(&kept)]);
+    }
+
+    #[tokio::test]
+    async fn scan_keeps_the_tracks_of_a_missing_root() {
+        let library = TestLibrary::start("scan-missing-root").await;
+        let path = library.write_track("a.mp3", "Alpha");
+        library.scanner.scan().await.unwrap();
+
+        let unmounted = library.root.with_file_name("unmounted");
+        std::fs::rename(&library.root, &unmounted).unwrap();
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!(report.removed, 0);
+        assert!(library.indexed().await.contains_key(&key(&path)));
+
+        std::fs::rename(&unmounted, &library.root).unwrap();
+        let report = library.scanner.scan().await.unwrap();
+
+        assert_eq!((report.unchanged, report.added), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn apply_changes_indexes_an_added_file() {
+        let library = TestLibrary::start("apply-add").await;
+        library.scanner.scan().await.unwrap();
+
+        let path = library.write_track("a.mp3", "Alpha");
+        let report = library.scanner.apply_changes(HashSet::from([path.clone()])).await.unwrap();
+
+        assert_eq!(report.added, 1);
+        assert!(report.failed.is_empty());
+
+        let indexed = library.indexed().await;
+        assert_eq!(library.title(indexed[&key(&path)]).await, "Alpha");
+    }
+
+    #[tokio::test]
+    async fn apply_changes_keeps_the_id_of_a_moved_file() {
+        let library = TestLibrary::start("apply-move").await;
+        let from = library.write_track("a.mp3", "Alpha");
+        library.scanner.scan().await.unwrap();
+        let id = library.indexed().await[&key(&from)];
+
+        let to = library.root.join("renamed.mp3");
+        std::fs::rename(&from, &to).unwrap();
+        let report = library.scanner.apply_changes(HashSet::from([from.clone(), to.clone()])).await.unwrap();
+
+        assert_eq!((report.moved, report.added, report.removed), (1, 0, 0));
+        assert_eq!(library.indexed().await, HashMap::from([(key(&to), id)]));
+    }
+
+    #[tokio::test]
+    async fn apply_changes_drops_a_deleted_file() {
+        let library = TestLibrary::start("apply-delete").await;
+        let path = library.write_track("a.mp3", "Alpha");
+        let kept = library.write_track("b.mp3", "Bravo");
+        library.scanner.scan().await.unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        let report = library.scanner.apply_changes(HashSet::from([path])).await.unwrap();
+
+        assert_eq!(report.removed, 1);
+        assert_eq!(library.indexed().await.into_keys().collect::<Vec<_>>(), vec![key(&kept)]);
+    }
+
+    #[tokio::test]
+    async fn apply_changes_drops_the_tracks_of_a_removed_directory() {
+        let library = TestLibrary::start("apply-remove-dir").await;
+        library.write_track("album/1.mp3", "Alpha");
+        library.write_track("album/2.mp3", "Bravo");
+        let kept = library.write_track("single.mp3", "Charlie");
+        library.scanner.scan().await.unwrap();
+
+        let album = library.root.join("album");
+        std::fs::remove_dir_all(&album).unwrap();
+        let report = library.scanner.apply_changes(HashSet::from([album])).await.unwrap();
+
+        assert_eq!(report.removed, 2);
+        assert_eq!(library.indexed().await.into_keys().collect::<Vec<_>>(), vec![key(&kept)]);
+    }
+
+    #[tokio::test]
+    async fn apply_changes_ignores_paths_outside_the_roots() {
+        let library = TestLibrary::start("apply-outside").await;
+        library.scanner.scan().await.unwrap();
+
+        let outside = library.root.parent().unwrap().join("outside.mp3");
+        std::fs::copy(library.write_track("a.mp3", "Alpha"), &outside).unwrap();
+        let report = library.scanner.apply_changes(HashSet::from([outside])).await.unwrap();
+
+        assert_eq!(report.added, 0);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{fs::File, path::Path};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::{formats::FormatOptions, io::MediaSourceStream, meta::{MetadataOptions, MetadataRevision, StandardTagKey}, probe::Hint};
+
+/// File extensions the scanner indexes, lowercase.
+pub const LIBRARY_AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "m4a", "mp4"];
+
+/// Tags read from an audio file, anything the file does not carry is left empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LYServerTrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration_ms: Option<u64>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+}
+
+/// MIME type served for a track of the given format, its lowercase file extension.
+pub fn content_type_for_format(format: &str) -> &'static str {
+    match format {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "m4a" | "mp4" => "audio/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn is_supported_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| LIBRARY_AUDIO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Probes the container of `path` and reads its tags, blocking on file IO.
+pub fn read_track_tags(path: &Path) -> anyhow::Result<LYServerTrackTags> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| anyhow::anyhow!("Failed to probe {}: {}", path.display(), e))?;
+
+    let mut tags = LYServerTrackTags::default();
+
+    // Tags in front of the container (ID3v2) come first, the container's own tags win over them
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|metadata| metadata.current()) {
+        apply_revision(&mut tags, revision);
+    }
+
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_revision(&mut tags, revision);
+    }
+
+    tags.duration_ms = probed.format.default_track().and_then(|track| {
+        let time_base = track.codec_params.time_base?;
+        let n_frames = track.codec_params.n_frames?;
+        let time = time_base.calc_time(n_frames);
+
+        Some(time.seconds * 1000 + (time.frac * 1000.0) as u64)
+    });
+
+    Ok(tags)
+}
+
+fn apply_revision(tags: &mut LYServerTrackTags, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        let Some(key) = tag.std_key else {
+            continue;
+        };
+
+        let value = tag.value.to_string().trim().to_string();
+        if value.is_empty() {
+            continue;
+        }
+
+        match key {
+            StandardTagKey::TrackTitle => tags.title = Some(value),
+            StandardTagKey::Artist => tags.artist = Some(value),
+            StandardTagKey::AlbumArtist => tags.album_artist = Some(value),
+            StandardTagKey::Album => tags.album = Some(value),
+            StandardTagKey::TrackNumber => tags.track_number = parse_leading_number(&value).or(tags.track_number),
+            StandardTagKey::DiscNumber => tags.disc_number = parse_leading_number(&value).or(tags.disc_number),
+            StandardTagKey::Genre => tags.genre = Some(value),
+            StandardTagKey::Date => tags.year = parse_leading_number(&value).or(tags.year),
+            // Only used when the file has no plain date
+            StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate if tags.year.is_none() => {
+                tags.year = parse_leading_number(&value);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads `3` out of `3`, `3/12` or `2004-05-01`.
+fn parse_leading_number(value: &str) -> Option<u32> {
+    let digits = value.chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>();
+
+    digits.parse().ok()
+}
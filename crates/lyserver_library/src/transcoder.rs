@@ -0,0 +1,407 @@
+use std::{fs::FileTimes, path::{Path, PathBuf}, process::Stdio, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use lyserver_http_shared::stream::LYSERVER_HTTP_RESPONSE_CHUNK_SIZE;
+use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataDirectories as _};
+use tokio::{io::{AsyncReadExt as _, AsyncWriteExt as _}, process::{Child, Command}, sync::{mpsc, OwnedSemaphorePermit, Semaphore}};
+
+/// How long a request waits for a free worker before it is turned away.
+const TRANSCODE_WORKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const TRANSCODE_MIN_BITRATE_KBPS: u32 = 32;
+const TRANSCODE_MAX_BITRATE_KBPS: u32 = 320;
+const TRANSCODE_DEFAULT_BITRATE_KBPS: u32 = 128;
+
+static TRANSCODE_PART_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LYServerTranscodeFormat {
+    Opus,
+    Mp3,
+    Aac,
+    Vorbis,
+}
+
+impl LYServerTranscodeFormat {
+    pub fn parse(format: &str) -> anyhow::Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "opus" => Ok(Self::Opus),
+            "mp3" => Ok(Self::Mp3),
+            "aac" => Ok(Self::Aac),
+            "vorbis" | "ogg" => Ok(Self::Vorbis),
+            _ => Err(anyhow::anyhow!("Unsupported transcode format '{}', expected opus, mp3, aac or vorbis", format)),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Opus | Self::Vorbis => "audio/ogg",
+            Self::Mp3 => "audio/mpeg",
+            Self::Aac => "audio/aac",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Opus => "opus",
+            Self::Mp3 => "mp3",
+            Self::Aac => "aac",
+            Self::Vorbis => "ogg",
+        }
+    }
+
+    /// ffmpeg encoder and muxer, muxers which can be written to a pipe only.
+    fn ffmpeg_codec_and_muxer(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Opus => ("libopus", "ogg"),
+            Self::Mp3 => ("libmp3lame", "mp3"),
+            Self::Aac => ("aac", "adts"),
+            Self::Vorbis => ("libvorbis", "ogg"),
+        }
+    }
+}
+
+/// A variant of a track to transcode to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LYServerTranscodeProfile {
+    pub format: LYServerTranscodeFormat,
+    pub bitrate_kbps: u32,
+}
+
+impl LYServerTranscodeProfile {
+    /// Reads `format` and `bitrate` (in kbit/s) of a query string, the bitrate is clamped to what encoders handle well.
+    pub fn parse(format: &str, bitrate: Option<&str>) -> anyhow::Result<Self> {
+        let bitrate_kbps = match bitrate {
+            Some(bitrate) => bitrate.parse::<u32>()
+                .map_err(|_| anyhow::anyhow!("Invalid bitrate '{}'", bitrate))?,
+            None => TRANSCODE_DEFAULT_BITRATE_KBPS,
+        };
+
+        Ok(Self {
+            format: LYServerTranscodeFormat::parse(format)?,
+            bitrate_kbps: bitrate_kbps.clamp(TRANSCODE_MIN_BITRATE_KBPS, TRANSCODE_MAX_BITRATE_KBPS),
+        })
+    }
+}
+
+/// A transcode which is running, its output arrives on `chunks`.
+pub struct LYServerTranscode {
+    pub chunks: mpsc::Receiver<anyhow::Result<Vec<u8>>>,
+}
+
+/// Transcodes tracks with ffmpeg on a bounded pool of workers, keeping the results in an on-disk cache.
+pub struct LYServerTranscoder {
+    shared_data: Arc<LYServerSharedData>,
+    workers: Arc<Semaphore>,
+    cache_path: PathBuf,
+}
+
+impl LYServerTranscoder {
+    pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
+        let workers = Arc::new(Semaphore::new(shared_data.transcode_workers));
+        let cache_path = shared_data.resolve_data_path_str("transcodes");
+
+        Self {
+            shared_data,
+            workers,
+            cache_path,
+        }
+    }
+
+    /// Where the transcoded variant of a source file is cached. The key covers the size and mtime of the
+    /// source, so a changed file is transcoded again.
+    pub fn get_cache_path(&self, source: &Path, profile: &LYServerTranscodeProfile) -> anyhow::Result<PathBuf> {
+        let metadata = std::fs::metadata(source)?;
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos());
+
+        let key = format!("{}\0{}\0{}\0{}\0{}", source.display(), metadata.len(), mtime, profile.format.extension(), profile.bitrate_kbps);
+
+        // FNV-1a, stable across builds unlike the std hashers
+        let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+
+        Ok(self.cache_path.join(format!("{:016x}-{}.{}", hash, profile.bitrate_kbps, profile.format.extension())))
+    }
+
+    /// Returns the cached variant if there is one, marking it as recently used. Only its access time is
+    /// touched, the mtime is part of the ETag it is served with.
+    pub fn get_cached(&self, cache_path: &Path) -> Option<PathBuf> {
+        let file = std::fs::File::options().append(true).open(cache_path).ok()?;
+
+        if let Err(e) = file.set_times(FileTimes::new().set_accessed(SystemTime::now())) {
+            log::debug!("Failed to touch cached transcode {}: {}", cache_path.display(), e);
+        }
+
+        Some(cache_path.to_path_buf())
+    }
+
+    /// Starts transcoding `source` once a worker is free. The output is streamed as it is produced and
+    /// written to `cache_path` when ffmpeg finishes, dropping the chunk receiver aborts the transcode.
+    pub async fn start(&self, source: &Path, profile: LYServerTranscodeProfile, cache_path: PathBuf) -> anyhow::Result<LYServerTranscode> {
+        let permit = tokio::time::timeout(TRANSCODE_WORKER_TIMEOUT, Arc::clone(&self.workers).acquire_owned())
+            .await
+            .map_err(|_| anyhow::anyhow!("All {} transcode workers are busy", self.shared_data.transcode_workers))??;
+
+        let (codec, muxer) = profile.format.ffmpeg_codec_and_muxer();
+
+        let mut child = Command::new(&self.shared_data.ffmpeg_path)
+            .args(["-hide_banner", "-loglevel", "error", "-nostdin", "-i"])
+            .arg(source)
+            .args(["-map", "0:a:0", "-vn", "-map_metadata", "-1", "-c:a", codec])
+            .args(["-b:a", &format!("{}k", profile.bitrate_kbps)])
+            .args(["-f", muxer, "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to start {}: {}", self.shared_data.ffmpeg_path.display(), e))?;
+
+        log::info!("Transcoding {} to {:?} at {} kbit/s", source.display(), profile.format, profile.bitrate_kbps);
+
+        // Keeping a few chunks buffered lets ffmpeg run slightly ahead of the client
+        let (tx, rx) = mpsc::channel(4);
+
+        let cache_dir = self.cache_path.clone();
+        let cache_max_bytes = self.shared_data.transcode_cache_max_bytes;
+
+        tokio::spawn(async move {
+            let result = pump_transcode(&mut child, &tx, &cache_path, permit).await;
+
+            match result {
+                Ok(true) => {
+                    if let Err(e) = evict_cache(&cache_dir, cache_max_bytes).await {
+                        log::warn!("Failed to evict the transcode cache: {}", e);
+                    }
+                }
+                Ok(false) => log::debug!("Transcode to {} was cancelled", cache_path.display()),
+                Err(e) => {
+                    log::error!("Transcode to {} failed: {}", cache_path.display(), e);
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+        });
+
+        Ok(LYServerTranscode { chunks: rx })
+    }
+}
+
+/// Forwards the output of ffmpeg to `tx` and a partial cache file, which replaces `cache_path` once ffmpeg
+/// succeeded. Returns whether the transcode ran to completion, `false` when the client went away.
+async fn pump_transcode(
+    child: &mut Child,
+    tx: &mpsc::Sender<anyhow::Result<Vec<u8>>>,
+    cache_path: &Path,
+    _permit: OwnedSemaphorePermit,
+) -> anyhow::Result<bool> {
+    let mut stdout = child.stdout.take()
+        .ok_or_else(|| anyhow::anyhow!("ffmpeg has no stdout"))?;
+
+    // Drained alongside stdout so a chatty ffmpeg cannot block on a full pipe
+    let stderr = child.stderr.take().map(|mut stderr| tokio::spawn(async move {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output).await;
+        output
+    }));
+
+    tokio::fs::create_dir_all(cache_path.parent().unwrap_or(Path::new("."))).await?;
+
+    // Concurrent transcodes of the same variant each write their own part, the last one to finish wins
+    let part_path = cache_path.with_extension(format!("{}.part", TRANSCODE_PART_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let mut part = tokio::fs::File::create(&part_path).await?;
+
+    let completed = async {
+        let mut buffer = vec![0u8; LYSERVER_HTTP_RESPONSE_CHUNK_SIZE];
+
+        loop {
+            let read = stdout.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            part.write_all(&buffer[..read]).await?;
+
+            if tx.send(Ok(buffer[..read].to_vec())).await.is_err() {
+                return Ok(false);
+            }
+        }
+
+        let status = child.wait().await?;
+        if !status.success() {
+            let stderr = match stderr {
+                Some(stderr) => stderr.await.unwrap_or_default(),
+                None => String::new(),
+            };
+
+            return Err(anyhow::anyhow!("ffmpeg exited with {}: {}", status, stderr.trim()));
+        }
+
+        part.flush().await?;
+
+        Ok(true)
+    }.await;
+
+    match completed {
+        Ok(true) => {
+            tokio::fs::rename(&part_path, cache_path).await?;
+            Ok(true)
+        }
+        other => {
+            let _ = child.start_kill();
+            let _ = tokio::fs::remove_file(&part_path).await;
+            other
+        }
+    }
+}
+
+/// Deletes the least recently used transcodes until the cache fits into `max_bytes`.
+async fn evict_cache(cache_dir: &Path, max_bytes: u64) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    let mut total_bytes = 0;
+
+    let mut dir = tokio::fs::read_dir(cache_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+
+        // Parts of running transcodes are not evicted from under them
+        if !metadata.is_file() || entry.path().extension().is_some_and(|extension| extension == "part") {
+            continue;
+        }
+
+        total_bytes += metadata.len();
+        let last_used = metadata.accessed().or_else(|_| metadata.modified()).unwrap_or(UNIX_EPOCH);
+        entries.push((last_used, metadata.len(), entry.path()));
+    }
+
+    entries.sort_by_key(|(last_used, _, _)| *last_used);
+
+    for (_, size, path) in entries {
+        if total_bytes <= max_bytes {
+            break;
+        }
+
+        // Files which cannot be removed still count, evicting the next ones has to make up for them
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            log::warn!("Failed to evict cached transcode {}: {}", path.display(), e);
+            continue;
+        }
+
+        total_bytes -= size;
+
+        log::debug!("Evicted cached transcode {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lyserver-transcoder-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn transcoder(data_dir: &Path) -> LYServerTranscoder {
+        let shared_data = LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap();
+
+        LYServerTranscoder::new(Arc::new(shared_data))
+    }
+
+    fn write_cached(cache_dir: &Path, name: &str, size: usize, last_used_secs: u64) -> PathBuf {
+        let path = cache_dir.join(name);
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+
+        let last_used = UNIX_EPOCH + Duration::from_secs(last_used_secs);
+        File::options().append(true).open(&path).unwrap()
+            .set_times(FileTimes::new().set_accessed(last_used).set_modified(last_used))
+            .unwrap();
+
+        path
+    }
+
+    #[test]
+    fn profiles_accept_format_aliases_in_any_case() {
+        assert_eq!(LYServerTranscodeProfile::parse("OPUS", None).unwrap().format, LYServerTranscodeFormat::Opus);
+        assert_eq!(LYServerTranscodeProfile::parse("ogg", None).unwrap().format, LYServerTranscodeFormat::Vorbis);
+        assert_eq!(LYServerTranscodeProfile::parse("Vorbis", None).unwrap().format, LYServerTranscodeFormat::Vorbis);
+        assert!(LYServerTranscodeProfile::parse("flac", None).is_err());
+    }
+
+    #[test]
+    fn profile_bitrates_are_clamped() {
+        let bitrate = |bitrate| LYServerTranscodeProfile::parse("mp3", bitrate).unwrap().bitrate_kbps;
+
+        assert_eq!(bitrate(None), TRANSCODE_DEFAULT_BITRATE_KBPS);
+        assert_eq!(bitrate(Some("192")), 192);
+        assert_eq!(bitrate(Some("0")), TRANSCODE_MIN_BITRATE_KBPS);
+        assert_eq!(bitrate(Some("100000")), TRANSCODE_MAX_BITRATE_KBPS);
+    }
+
+    #[test]
+    fn profiles_with_invalid_bitrates_are_rejected() {
+        for bitrate in ["", "fast", "-128", "128k", "99999999999"] {
+            assert!(LYServerTranscodeProfile::parse("mp3", Some(bitrate)).is_err(), "{}", bitrate);
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_paths_only_change_with_the_source_or_profile() {
+        let data_dir = temp_path("cache-path");
+        let transcoder = transcoder(&data_dir);
+
+        let source = data_dir.join("track.flac");
+        std::fs::write(&source, b"flac").unwrap();
+        File::options().append(true).open(&source).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let opus = LYServerTranscodeProfile::parse("opus", Some("96")).unwrap();
+        let cache_path = transcoder.get_cache_path(&source, &opus).unwrap();
+
+        assert_eq!(transcoder.get_cache_path(&source, &opus).unwrap(), cache_path);
+        assert_eq!(cache_path.parent(), Some(data_dir.join("transcodes").as_path()));
+        assert!(cache_path.file_name().unwrap().to_str().unwrap().ends_with("-96.opus"));
+
+        let mp3 = LYServerTranscodeProfile::parse("mp3", Some("96")).unwrap();
+        assert_ne!(transcoder.get_cache_path(&source, &mp3).unwrap(), cache_path);
+
+        let opus_128 = LYServerTranscodeProfile::parse("opus", Some("128")).unwrap();
+        assert_ne!(transcoder.get_cache_path(&source, &opus_128).unwrap(), cache_path);
+
+        File::options().append(true).open(&source).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_001))
+            .unwrap();
+        assert_ne!(transcoder.get_cache_path(&source, &opus).unwrap(), cache_path);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn eviction_removes_the_least_recently_used_transcodes_first() {
+        let cache_dir = temp_path("evict");
+
+        let oldest = write_cached(&cache_dir, "oldest.opus", 100, 1_000);
+        let newest = write_cached(&cache_dir, "newest.opus", 100, 3_000);
+        let older = write_cached(&cache_dir, "older.opus", 100, 2_000);
+        let part = write_cached(&cache_dir, "running.opus.0.part", 100, 0);
+
+        evict_cache(&cache_dir, 150).await.unwrap();
+
+        assert!(!oldest.exists());
+        assert!(!older.exists());
+        assert!(newest.exists());
+        assert!(part.exists());
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
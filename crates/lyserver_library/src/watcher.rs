@@ -0,0 +1,80 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::scanner::LYServerLibraryScanner;
+
+/// How long the library has to be quiet before a burst of changes, e.g. a copied album, is applied.
+const LIBRARY_WATCHER_DEBOUNCE: Duration = Duration::from_secs(2);
+/// Longest a batch collects changes, so a library which is never quiet still gets updated.
+const LIBRARY_WATCHER_MAX_BATCH_DELAY: Duration = Duration::from_secs(30);
+
+/// Keeps the library database in sync with changes under the library roots.
+pub struct LYServerLibraryWatcher {
+    _watcher: RecommendedWatcher,
+    handle: JoinHandle<()>,
+}
+
+impl LYServerLibraryWatcher {
+    pub async fn start(scanner: Arc<LYServerLibraryScanner>) -> anyhow::Result<Self> {
+        let roots = scanner.get_roots().await?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            match res {
+                Ok(event) => {
+                    // Renames are reported as modifications of both paths
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                        for path in event.paths {
+                            let _ = tx.send(path);
+                        }
+                    }
+                }
+                Err(e) => log::error!("Library watcher error: {}", e),
+            }
+        })?;
+
+        for root in &roots {
+            if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+                log::warn!("Failed to watch library root {}: {}", root.display(), e);
+                continue;
+            }
+
+            log::info!("Watching library root {} for changes...", root.display());
+        }
+
+        let handle = tokio::spawn(async move {
+            while let Some(path) = rx.recv().await {
+                let mut changed_paths = HashSet::from([path]);
+                let batch_deadline = Instant::now() + LIBRARY_WATCHER_MAX_BATCH_DELAY;
+
+                loop {
+                    let deadline = batch_deadline.min(Instant::now() + LIBRARY_WATCHER_DEBOUNCE);
+
+                    match tokio::time::timeout_at(deadline, rx.recv()).await {
+                        Ok(Some(path)) => changed_paths.insert(path),
+                        _ => break,
+                    };
+                }
+
+                match scanner.apply_changes(changed_paths).await {
+                    Ok(report) => log::debug!("Applied library changes: {:?}", report),
+                    Err(e) => log::error!("Failed to apply library changes: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            _watcher: watcher,
+            handle,
+        })
+    }
+}
+
+impl Drop for LYServerLibraryWatcher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
@@ -1,14 +1,10 @@
 pub use lyserver_plugin_wasm_runtime::alloc;
 
-use lyserver_plugin_wasm_runtime::{http::{router::LYServerHTTPRouter, LYServerHTTPRequest}, ipc::{self, LYServerMessageEvent}, log};
+use lyserver_plugin_wasm_runtime::{http::LYServerHTTPRequest, ipc, log};
 
 #[unsafe(no_mangle)]
 extern "C" fn lyserver_plugin_init() {
     log::info!("Media plugin initialized!");
-
-    let init_event = LYServerMessageEvent::new("plugin_init", "all", "media@lyserver", 0);
-    ipc::tx(&init_event)
-        .expect("Failed to send plugin init event");
 }
 
 #[unsafe(no_mangle)]
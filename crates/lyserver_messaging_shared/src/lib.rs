@@ -1,5 +1,7 @@
+pub mod rpc;
+
 use anyhow::bail;
-use serde::{de::Error, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use serde_cbor::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,11 +85,11 @@ pub enum LYServerMessageEventTarget {
     Plugin(String),
 }
 
-impl ToString for LYServerMessageEventTarget {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for LYServerMessageEventTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            LYServerMessageEventTarget::All => "all".to_string(),
-            LYServerMessageEventTarget::Plugin(id) => id.clone(),
+            LYServerMessageEventTarget::All => write!(f, "all"),
+            LYServerMessageEventTarget::Plugin(id) => write!(f, "{}", id),
         }
     }
 }
@@ -142,14 +144,12 @@ impl LYServerMessageEvent {
     ) -> Self {
         let event_id = lyserver_random_id::generate();
 
-        let event = Self {
+        Self {
             event_id,
             event_type: event_type.into(),
             event_target: target.into(),
             event_sender: sender.into(),
             data: serde_cbor::to_vec(&data).expect("Failed to serialize event data"),
-        };
-
-        event
+        }
     }
 }
\ No newline at end of file
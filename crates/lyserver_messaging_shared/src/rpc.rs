@@ -0,0 +1,183 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::LYServerMessageEvent;
+
+pub const LYSERVER_RPC_REQUEST_EVENT: &str = "rpc_request";
+pub const LYSERVER_RPC_RESPONSE_EVENT: &str = "rpc_response";
+pub const LYSERVER_RPC_CANCEL_EVENT: &str = "rpc_cancel";
+
+/// A call to `method` on another plugin, sent as the data of an `rpc_request` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LYServerRPCRequest {
+    /// Matches the response to its request, independent of the event ids used to carry them
+    pub correlation_id: String,
+    pub method: String,
+    pub data: Vec<u8>,
+}
+
+impl LYServerRPCRequest {
+    pub fn new<T: Serialize>(method: impl Into<String>, data: &T) -> Result<Self, LYServerRPCError> {
+        let data = serde_cbor::to_vec(data)
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::InvalidRequest, format!("Failed to serialize request: {}", e)))?;
+
+        Ok(Self {
+            correlation_id: lyserver_random_id::generate(),
+            method: method.into(),
+            data,
+        })
+    }
+
+    pub fn data_as<T: for<'de> Deserialize<'de>>(&self) -> Result<T, LYServerRPCError> {
+        serde_cbor::from_slice(&self.data)
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::InvalidRequest, format!("Failed to deserialize request for '{}': {}", self.method, e)))
+    }
+}
+
+/// The outcome of a call, sent back to the caller as the data of an `rpc_response` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LYServerRPCResponse {
+    pub correlation_id: String,
+    pub result: Result<Vec<u8>, LYServerRPCError>,
+}
+
+impl LYServerRPCResponse {
+    pub fn ok<T: Serialize>(correlation_id: impl Into<String>, data: &T) -> Self {
+        let result = serde_cbor::to_vec(data)
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::InvalidResponse, format!("Failed to serialize response: {}", e)));
+
+        Self {
+            correlation_id: correlation_id.into(),
+            result,
+        }
+    }
+
+    pub fn err(correlation_id: impl Into<String>, error: LYServerRPCError) -> Self {
+        Self {
+            correlation_id: correlation_id.into(),
+            result: Err(error),
+        }
+    }
+
+    pub fn into_result<T: for<'de> Deserialize<'de>>(self) -> Result<T, LYServerRPCError> {
+        let data = self.result?;
+
+        serde_cbor::from_slice(&data)
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::InvalidResponse, format!("Failed to deserialize response: {}", e)))
+    }
+}
+
+/// Tells the callee that the caller stopped waiting for a call, sent as the data of an `rpc_cancel` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LYServerRPCCancel {
+    pub correlation_id: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LYServerRPCErrorKind {
+    /// The target plugin has no handler registered for the method
+    MethodNotFound,
+    InvalidRequest,
+    InvalidResponse,
+    /// The handler itself returned an error or panicked
+    Handler,
+    Timeout,
+    Cancelled,
+    /// The request could not be delivered, e.g. because the caller lacks the capability to send it
+    Transport,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LYServerRPCError {
+    pub kind: LYServerRPCErrorKind,
+    pub message: String,
+}
+
+impl LYServerRPCError {
+    pub fn new(kind: LYServerRPCErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for LYServerRPCError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for LYServerRPCError {}
+
+impl LYServerMessageEvent {
+    pub fn is_rpc_event(&self) -> bool {
+        matches!(self.event_type.as_str(), LYSERVER_RPC_REQUEST_EVENT | LYSERVER_RPC_RESPONSE_EVENT | LYSERVER_RPC_CANCEL_EVENT)
+    }
+
+    /// Parses the response to the call with `correlation_id`, if this event is one.
+    pub fn rpc_response_for(&self, correlation_id: &str) -> Option<LYServerRPCResponse> {
+        if self.event_type != LYSERVER_RPC_RESPONSE_EVENT {
+            return None;
+        }
+
+        self.data_as::<LYServerRPCResponse>()
+            .ok()
+            .filter(|response| response.correlation_id == correlation_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LYServerMessageEventTarget;
+
+    use super::*;
+
+    fn response_event(response: &LYServerRPCResponse) -> LYServerMessageEvent {
+        LYServerMessageEvent::new(LYSERVER_RPC_RESPONSE_EVENT, "caller@plugin", "callee@plugin", response)
+    }
+
+    #[test]
+    fn requests_carry_their_own_correlation_id() {
+        let first = LYServerRPCRequest::new("add", &(1, 2)).unwrap();
+        let second = LYServerRPCRequest::new("add", &(1, 2)).unwrap();
+
+        assert_ne!(first.correlation_id, second.correlation_id);
+        assert_eq!(first.data_as::<(i32, i32)>().unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn request_data_of_the_wrong_type_is_an_invalid_request() {
+        let request = LYServerRPCRequest::new("add", &"one").unwrap();
+
+        assert_eq!(request.data_as::<(i32, i32)>().unwrap_err().kind, LYServerRPCErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn responses_round_trip_their_result() {
+        assert_eq!(LYServerRPCResponse::ok("id", &3).into_result::<i32>().unwrap(), 3);
+
+        let error = LYServerRPCError::new(LYServerRPCErrorKind::Handler, "boom");
+        assert_eq!(LYServerRPCResponse::err("id", error.clone()).into_result::<i32>().unwrap_err(), error);
+
+        assert_eq!(LYServerRPCResponse::ok("id", &"three").into_result::<i32>().unwrap_err().kind, LYServerRPCErrorKind::InvalidResponse);
+    }
+
+    #[test]
+    fn rpc_response_for_matches_the_correlation_id() {
+        let event = response_event(&LYServerRPCResponse::ok("id", &3));
+
+        assert!(event.is_rpc_event());
+        assert!(event.rpc_response_for("id").is_some());
+        assert!(event.rpc_response_for("other").is_none());
+    }
+
+    #[test]
+    fn rpc_response_for_ignores_other_events() {
+        let event = LYServerMessageEvent::new("http_response", LYServerMessageEventTarget::All, "callee@plugin".into(), LYServerRPCResponse::ok("id", &3));
+
+        assert!(!event.is_rpc_event());
+        assert!(event.rpc_response_for("id").is_none());
+    }
+}
@@ -0,0 +1,137 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A permission requested by a plugin in the `capabilities` list of its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum LYServerPluginCapability {
+    /// `http.routes`, allows replying to HTTP requests
+    HttpRoutes,
+    /// `database.query`
+    DatabaseQuery,
+    /// `preferences.read`
+    PreferencesRead,
+    /// `preferences.write`
+    PreferencesWrite,
+    /// `fs.read:<path>`, read-only access to a directory on the host
+    FsRead(String),
+    /// `rpc.call`, allows calling methods on other plugins
+    RpcCall,
+    /// `plugins.invoke:<plugin-id>`, allows invoking methods of another WASM plugin, where `*` allows any of
+    /// them. Built-in plugins cannot be invoked this way
+    PluginsInvoke(String),
+    /// `services.call:<name>`, allows calling the serialized fallback of a registered service
+    ServiceCall(String),
+    /// `messaging.send:<event_type>`, where `*` allows sending any event type
+    MessagingSend(String),
+}
+
+impl LYServerPluginCapability {
+    /// Whether this capability allows sending events of `event_type`.
+    pub fn allows_event_type(&self, event_type: &str) -> bool {
+        match self {
+            LYServerPluginCapability::HttpRoutes => matches!(event_type, "http_request_handle_intent" | "http_response" | "http_response_chunk"),
+            LYServerPluginCapability::RpcCall => matches!(event_type, "rpc_request" | "rpc_cancel"),
+            LYServerPluginCapability::MessagingSend(allowed_event_type) => allowed_event_type == "*" || allowed_event_type == event_type,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for LYServerPluginCapability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match s.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (s, None),
+        };
+
+        let argument = argument.filter(|argument| !argument.is_empty());
+
+        match (name, argument) {
+            ("http.routes", None) => Ok(LYServerPluginCapability::HttpRoutes),
+            ("database.query", None) => Ok(LYServerPluginCapability::DatabaseQuery),
+            ("preferences.read", None) => Ok(LYServerPluginCapability::PreferencesRead),
+            ("preferences.write", None) => Ok(LYServerPluginCapability::PreferencesWrite),
+            ("rpc.call", None) => Ok(LYServerPluginCapability::RpcCall),
+            ("fs.read", Some(path)) => Ok(LYServerPluginCapability::FsRead(path.to_string())),
+            ("plugins.invoke", Some(plugin_id)) => Ok(LYServerPluginCapability::PluginsInvoke(plugin_id.to_string())),
+            ("services.call", Some(service)) => Ok(LYServerPluginCapability::ServiceCall(service.to_string())),
+            ("messaging.send", Some(event_type)) => Ok(LYServerPluginCapability::MessagingSend(event_type.to_string())),
+            ("fs.read" | "plugins.invoke" | "services.call" | "messaging.send", None) => Err(format!("Capability '{}' requires an argument, e.g. '{}:<value>'", name, name)),
+            _ => Err(format!("Unknown capability '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for LYServerPluginCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LYServerPluginCapability::HttpRoutes => write!(f, "http.routes"),
+            LYServerPluginCapability::DatabaseQuery => write!(f, "database.query"),
+            LYServerPluginCapability::PreferencesRead => write!(f, "preferences.read"),
+            LYServerPluginCapability::PreferencesWrite => write!(f, "preferences.write"),
+            LYServerPluginCapability::FsRead(path) => write!(f, "fs.read:{}", path),
+            LYServerPluginCapability::RpcCall => write!(f, "rpc.call"),
+            LYServerPluginCapability::PluginsInvoke(plugin_id) => write!(f, "plugins.invoke:{}", plugin_id),
+            LYServerPluginCapability::ServiceCall(service) => write!(f, "services.call:{}", service),
+            LYServerPluginCapability::MessagingSend(event_type) => write!(f, "messaging.send:{}", event_type),
+        }
+    }
+}
+
+impl TryFrom<String> for LYServerPluginCapability {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<LYServerPluginCapability> for String {
+    fn from(value: LYServerPluginCapability) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trips_through_from_str() {
+        let capabilities = [
+            LYServerPluginCapability::HttpRoutes,
+            LYServerPluginCapability::DatabaseQuery,
+            LYServerPluginCapability::PreferencesRead,
+            LYServerPluginCapability::PreferencesWrite,
+            LYServerPluginCapability::FsRead("/music".to_string()),
+            LYServerPluginCapability::RpcCall,
+            LYServerPluginCapability::PluginsInvoke("*".to_string()),
+            LYServerPluginCapability::ServiceCall("library".to_string()),
+            LYServerPluginCapability::MessagingSend("custom_event".to_string()),
+        ];
+
+        for capability in capabilities {
+            assert_eq!(capability.to_string().parse::<LYServerPluginCapability>(), Ok(capability));
+        }
+    }
+
+    #[test]
+    fn argument_keeps_colons() {
+        assert_eq!(
+            "fs.read:C:/music".parse::<LYServerPluginCapability>(),
+            Ok(LYServerPluginCapability::FsRead("C:/music".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_unexpected_arguments() {
+        assert!("fs.read".parse::<LYServerPluginCapability>().is_err());
+        assert!("messaging.send:".parse::<LYServerPluginCapability>().is_err());
+        assert!("http.routes:anything".parse::<LYServerPluginCapability>().is_err());
+        assert!("unknown.capability".parse::<LYServerPluginCapability>().is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Schema of the `[config]` table of a plugin manifest, keyed by option name.
+pub type LYServerPluginConfigSchema = BTreeMap<String, LYServerPluginConfigField>;
+
+/// An option a plugin can be configured with, e.g.
+///
+/// ```toml
+/// [config.greeting]
+/// type = "string"
+/// default = "Hello"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LYServerPluginConfigField {
+    #[serde(rename = "type")]
+    pub field_type: LYServerPluginConfigType,
+    pub default: Option<Value>,
+    /// Whether loading the plugin fails when neither a default nor a preference provides a value
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LYServerPluginConfigType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Array,
+    Table,
+}
+
+impl LYServerPluginConfigType {
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            LYServerPluginConfigType::String => value.is_string(),
+            LYServerPluginConfigType::Integer => value.is_i64() || value.is_u64(),
+            LYServerPluginConfigType::Float => value.is_number(),
+            LYServerPluginConfigType::Boolean => value.is_boolean(),
+            LYServerPluginConfigType::Array => value.is_array(),
+            LYServerPluginConfigType::Table => value.is_object(),
+        }
+    }
+}
+
+impl fmt::Display for LYServerPluginConfigType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LYServerPluginConfigType::String => "string",
+            LYServerPluginConfigType::Integer => "integer",
+            LYServerPluginConfigType::Float => "float",
+            LYServerPluginConfigType::Boolean => "boolean",
+            LYServerPluginConfigType::Array => "array",
+            LYServerPluginConfigType::Table => "table",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Preference key prefix overriding the config of a plugin, `plugins.<id>.`.
+pub fn plugin_config_preference_prefix(plugin_id: &str) -> String {
+    format!("plugins.{}.", plugin_id)
+}
+
+/// Applies `overrides` on top of the schema defaults, naming every missing or mistyped option.
+///
+/// Overrides for options the schema does not declare are ignored.
+pub fn resolve_plugin_config(schema: &LYServerPluginConfigSchema, mut overrides: BTreeMap<String, Value>) -> anyhow::Result<Map<String, Value>> {
+    let mut config = Map::new();
+    let mut problems = Vec::new();
+
+    for (key, field) in schema {
+        let value = match overrides.remove(key).or_else(|| field.default.clone()) {
+            Some(value) => value,
+            None if field.required => {
+                problems.push(format!("'{}' is required", key));
+                continue;
+            }
+            None => continue,
+        };
+
+        if !field.field_type.matches(&value) {
+            problems.push(format!("'{}' has to be a {}, found {}", key, field.field_type, value));
+            continue;
+        }
+
+        config.insert(key.clone(), value);
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!("Invalid config: {}", problems.join(", ")));
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn field(field_type: LYServerPluginConfigType, default: Option<Value>, required: bool) -> LYServerPluginConfigField {
+        LYServerPluginConfigField {
+            field_type,
+            default,
+            required,
+            description: String::new(),
+        }
+    }
+
+    fn test_schema() -> LYServerPluginConfigSchema {
+        BTreeMap::from([
+            ("greeting".to_string(), field(LYServerPluginConfigType::String, Some(json!("Hello")), false)),
+            ("retries".to_string(), field(LYServerPluginConfigType::Integer, Some(json!(3)), false)),
+            ("ratio".to_string(), field(LYServerPluginConfigType::Float, None, false)),
+            ("token".to_string(), field(LYServerPluginConfigType::String, None, true)),
+        ])
+    }
+
+    #[test]
+    fn types_match_their_json_values() {
+        assert!(LYServerPluginConfigType::String.matches(&json!("text")));
+        assert!(LYServerPluginConfigType::Integer.matches(&json!(-1)));
+        assert!(LYServerPluginConfigType::Integer.matches(&json!(u64::MAX)));
+        assert!(!LYServerPluginConfigType::Integer.matches(&json!(1.5)));
+        assert!(LYServerPluginConfigType::Float.matches(&json!(1.5)));
+        assert!(LYServerPluginConfigType::Float.matches(&json!(1)));
+        assert!(LYServerPluginConfigType::Boolean.matches(&json!(true)));
+        assert!(!LYServerPluginConfigType::Boolean.matches(&json!("true")));
+        assert!(LYServerPluginConfigType::Array.matches(&json!([1, 2])));
+        assert!(LYServerPluginConfigType::Table.matches(&json!({ "key": "value" })));
+        assert!(!LYServerPluginConfigType::Table.matches(&json!(null)));
+    }
+
+    #[test]
+    fn overrides_replace_defaults() {
+        let overrides = BTreeMap::from([
+            ("retries".to_string(), json!(5)),
+            ("token".to_string(), json!("secret")),
+            ("undeclared".to_string(), json!(true)),
+        ]);
+
+        let config = resolve_plugin_config(&test_schema(), overrides).unwrap();
+
+        assert_eq!(Value::Object(config), json!({ "greeting": "Hello", "retries": 5, "token": "secret" }));
+    }
+
+    #[test]
+    fn every_missing_or_mistyped_option_is_named() {
+        let overrides = BTreeMap::from([
+            ("greeting".to_string(), json!(42)),
+            ("ratio".to_string(), json!("fast")),
+        ]);
+
+        let error = resolve_plugin_config(&test_schema(), overrides).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Invalid config: 'greeting' has to be a string, found 42, 'ratio' has to be a float, found \"fast\", 'token' is required",
+        );
+    }
+
+    #[test]
+    fn mistyped_defaults_are_rejected() {
+        let schema = BTreeMap::from([
+            ("enabled".to_string(), field(LYServerPluginConfigType::Boolean, Some(json!("yes")), false)),
+        ]);
+
+        assert!(resolve_plugin_config(&schema, BTreeMap::new()).is_err());
+    }
+}
@@ -1,7 +1,11 @@
+mod capabilities;
+mod config;
+
+pub use capabilities::LYServerPluginCapability;
+pub use config::{plugin_config_preference_prefix, resolve_plugin_config, LYServerPluginConfigField, LYServerPluginConfigSchema, LYServerPluginConfigType};
+
 use lyserver_messaging_shared::LYServerMessageEvent;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct LYServerPluginMetadata {
@@ -11,25 +15,129 @@ pub struct LYServerPluginMetadata {
     pub version: String,
     pub author: String,
     pub wasm_entry_point: Option<String>,
+    #[serde(default)]
+    pub dependencies: Vec<LYServerPluginDependency>,
+    #[serde(default)]
+    pub optional_dependencies: Vec<LYServerPluginDependency>,
+    #[serde(default)]
+    pub capabilities: Vec<LYServerPluginCapability>,
+    #[serde(default)]
+    pub limits: LYServerPluginLimits,
+    #[serde(default)]
+    pub supervisor: LYServerPluginSupervisor,
+    #[serde(default)]
+    pub config: LYServerPluginConfigSchema,
+    #[serde(default)]
+    pub databases: Vec<LYServerPluginDatabase>,
+}
+
+/// An SQLite database owned by a plugin, from the `[[databases]]` tables of a plugin manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LYServerPluginDatabase {
+    pub name: String,
+    /// SQL files relative to the plugin directory, applied in order, the database version is the number applied
+    #[serde(default)]
+    pub migrations: Vec<String>,
+}
+
+/// How a plugin is restarted when it crashes, from the `[supervisor]` table of a plugin manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LYServerPluginSupervisor {
+    pub restart: LYServerPluginRestartPolicy,
+    pub max_restarts: u32,
+    pub backoff_initial_ms: u64,
+    pub backoff_max_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LYServerPluginRestartPolicy {
+    Never,
+    /// Restart when init fails, traps or panics
+    #[default]
+    OnFailure,
+    /// Also restart when init returns successfully
+    Always,
+}
+
+impl Default for LYServerPluginSupervisor {
+    fn default() -> Self {
+        Self {
+            restart: LYServerPluginRestartPolicy::default(),
+            max_restarts: 5,
+            backoff_initial_ms: 500,
+            backoff_max_ms: 30_000,
+        }
+    }
+}
+
+impl LYServerPluginSupervisor {
+    /// Delay before the restart following `restart_count` previous restarts, doubling each time.
+    pub fn backoff(&self, restart_count: u32) -> std::time::Duration {
+        let backoff_ms = self.backoff_initial_ms
+            .saturating_mul(2u64.saturating_pow(restart_count))
+            .min(self.backoff_max_ms);
+
+        std::time::Duration::from_millis(backoff_ms)
+    }
+}
+
+/// Resource limits from the `[limits]` table of a plugin manifest, unset values fall back to the server defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LYServerPluginLimits {
+    /// CPU budget of a single call into the plugin in wasmtime fuel units, `0` disables the limit
+    pub fuel_per_call: Option<u64>,
+    /// Whether the plugin is restarted after a call ran out of fuel, instead of failing the call. Running out of
+    /// fuel in init always fails the plugin, which is then restarted according to its supervisor policy
+    #[serde(default)]
+    pub restart_on_exhaustion: bool,
+    /// Cap on the linear memory of the plugin, in bytes
+    pub max_memory_bytes: Option<usize>,
+    /// Cap on the elements of all tables of the plugin
+    pub max_table_elements: Option<usize>,
+    /// Cap on the instances the plugin may create
+    pub max_instances: Option<usize>,
+    /// Cap on the keys and values the plugin may keep in its key/value storage, in bytes
+    pub kv_max_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LYServerPluginDependency {
+    pub id: String,
+    /// Semver requirement the dependency's version has to match, e.g. `^0.1`
+    #[serde(default = "LYServerPluginDependency::default_version")]
+    pub version: String,
+}
+
+impl LYServerPluginDependency {
+    pub fn new(id: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            version: version.into(),
+        }
+    }
+
+    fn default_version() -> String {
+        "*".to_string()
+    }
 }
 
 impl LYServerPluginMetadata {
     pub fn builder() -> LYServerPluginMetadataBuilder {
         LYServerPluginMetadataBuilder::default()
     }   
+
+    pub fn has_capability(&self, capability: &LYServerPluginCapability) -> bool {
+        self.capabilities.contains(capability)
+    }
 }
 
+#[derive(Default)]
 pub struct LYServerPluginMetadataBuilder {
     metadata: LYServerPluginMetadata,
 }
 
-impl Default for LYServerPluginMetadataBuilder {
-    fn default() -> Self {
-        Self {
-            metadata: LYServerPluginMetadata::default(),
-        }
-    }
-}
 
 impl LYServerPluginMetadataBuilder {
     pub fn id(mut self, id: impl Into<String>) -> Self {
@@ -62,20 +170,84 @@ impl LYServerPluginMetadataBuilder {
         self
     }
 
+    pub fn dependency(mut self, id: impl Into<String>, version: impl Into<String>) -> Self {
+        self.metadata.dependencies.push(LYServerPluginDependency::new(id, version));
+        self
+    }
+
+    pub fn optional_dependency(mut self, id: impl Into<String>, version: impl Into<String>) -> Self {
+        self.metadata.optional_dependencies.push(LYServerPluginDependency::new(id, version));
+        self
+    }
+
+    pub fn capability(mut self, capability: LYServerPluginCapability) -> Self {
+        self.metadata.capabilities.push(capability);
+        self
+    }
+
+    pub fn limits(mut self, limits: LYServerPluginLimits) -> Self {
+        self.metadata.limits = limits;
+        self
+    }
+
+    pub fn supervisor(mut self, supervisor: LYServerPluginSupervisor) -> Self {
+        self.metadata.supervisor = supervisor;
+        self
+    }
+
+    pub fn config_field(mut self, key: impl Into<String>, field: LYServerPluginConfigField) -> Self {
+        self.metadata.config.insert(key.into(), field);
+        self
+    }
+
+    pub fn database(mut self, database: LYServerPluginDatabase) -> Self {
+        self.metadata.databases.push(database);
+        self
+    }
+
     pub fn build(self) -> LYServerPluginMetadata {
         self.metadata
     }
 }
 
+/// Error of a plugin which cannot keep running, like a trapped WASM instance. Returned from
+/// `handle_message_event` it makes the supervisor restart the plugin according to its policy.
+#[derive(Debug, Clone)]
+pub struct LYServerPluginCrash(pub String);
+
+impl std::fmt::Display for LYServerPluginCrash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LYServerPluginCrash {}
+
 #[async_trait::async_trait]
 pub trait LYServerPlugin: Send + Sync {
     fn metadata(&self) -> LYServerPluginMetadata;
 
     async fn init(&self) -> anyhow::Result<()>;
     async fn destroy(&self) -> anyhow::Result<()>;
-    async fn invoke(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value>;
-    async fn receive(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value>;
+    /// Calls `method` on the plugin with CBOR encoded arguments, returning its CBOR encoded result.
+    async fn invoke(&self, method: &str, _args: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow::anyhow!("Plugin '{}' does not support invoking '{}'", self.metadata().id, method))
+    }
     async fn handle_message_event(&self, _: LYServerMessageEvent) -> anyhow::Result<()> {
         Ok(())
     }
+    /// Whether the plugin manager should feed the events targeted at this plugin into `handle_message_event`.
+    fn receives_message_events(&self) -> bool {
+        true
+    }
+    /// How many events may be handled at once, capped by the server wide `--plugin-event-concurrency`, which is
+    /// also used for `None`. Handlers are started in the order their events arrived, but only `Some(1)` guarantees
+    /// that one event has been handled before the next one is.
+    fn message_event_concurrency(&self) -> Option<usize> {
+        None
+    }
+    /// Whether RPC events go to `handle_message_event` instead of the handlers registered on the plugin's shared data.
+    fn handles_rpc_events(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use lyserver_http_shared::{stream::{LYServerHTTPResponseChunk, LYServerHTTPResponseDemand, LYServerHTTPResponseStream, LYSERVER_HTTP_RESPONSE_CANCEL_EVENT, LYSERVER_HTTP_RESPONSE_CHUNK_EVENT, LYSERVER_HTTP_RESPONSE_DEMAND_EVENT}, LYServerHTTPResponse};
+use lyserver_messaging_shared::LYServerMessageEvent;
+use tokio::sync::{broadcast::{error::RecvError, Receiver}, mpsc};
+
+use crate::LYServerPluginSharedData;
+
+/// How long a chunked response waits for the server to ask for more before giving up on the client.
+const LYSERVER_HTTP_RESPONSE_DEMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl LYServerPluginSharedData {
+    /// Replies to an `http_request` event with a body streamed from `chunks`.
+    ///
+    /// Chunks are only pulled from `chunks` once the server asks for them, so a slow client slows down the
+    /// producer instead of filling the bus. Ends when the producer closes `chunks` or the client goes away.
+    pub async fn reply_http_stream(
+        &self,
+        event: LYServerMessageEvent,
+        mut response: LYServerHTTPResponse,
+        mut chunks: mpsc::Receiver<anyhow::Result<Vec<u8>>>,
+    ) -> anyhow::Result<()> {
+        response.body = Vec::new();
+        response.stream = Some(LYServerHTTPResponseStream::Chunked);
+        response.headers.remove("content-length");
+
+        // Subscribe before replying so the first demand cannot be missed
+        let mut rx = self.subscribe_events();
+
+        self.reply_event("http_response", event.clone(), response).await?;
+
+        let mut demanded = 0;
+        let mut sequence = 0;
+
+        loop {
+            while sequence >= demanded {
+                match tokio::time::timeout(LYSERVER_HTTP_RESPONSE_DEMAND_TIMEOUT, wait_for_demand(&mut rx, &event.event_id)).await {
+                    Ok(Some(until)) => demanded = demanded.max(until),
+                    Ok(None) => return Ok(()),
+                    Err(_) => return Err(anyhow::anyhow!("The server stopped asking for chunks of response {}", event.event_id)),
+                }
+            }
+
+            let chunk = match chunks.recv().await {
+                Some(Ok(data)) => LYServerHTTPResponseChunk { sequence, data, last: false, error: None },
+                Some(Err(e)) => LYServerHTTPResponseChunk { sequence, data: Vec::new(), last: true, error: Some(e.to_string()) },
+                None => LYServerHTTPResponseChunk { sequence, data: Vec::new(), last: true, error: None },
+            };
+
+            let last = chunk.last;
+            self.reply_event(LYSERVER_HTTP_RESPONSE_CHUNK_EVENT, event.clone(), chunk).await?;
+
+            if last {
+                return Ok(());
+            }
+
+            sequence += 1;
+        }
+    }
+}
+
+/// The next sequence the server asks for up to, or `None` once it cancelled the response.
+async fn wait_for_demand(rx: &mut Receiver<LYServerMessageEvent>, event_id: &str) -> Option<u64> {
+    loop {
+        match rx.recv().await {
+            Ok(event) if event.event_id == event_id && event.event_type == LYSERVER_HTTP_RESPONSE_DEMAND_EVENT => {
+                match event.data_as::<LYServerHTTPResponseDemand>() {
+                    Ok(demand) => return Some(demand.until),
+                    Err(e) => log::warn!("[Plugin Messaging] Invalid demand for response {}: {}", event_id, e),
+                }
+            }
+            Ok(event) if event.event_id == event_id && event.event_type == LYSERVER_HTTP_RESPONSE_CANCEL_EVENT => return None,
+            Ok(_) => {}
+            Err(RecvError::Lagged(n)) => log::warn!("[Plugin Messaging] Missed {} events while streaming response {}", n, event_id),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
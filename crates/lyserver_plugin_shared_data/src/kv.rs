@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use lyserver_shared_data::{LYServerKVService, LYServerSharedDataKV as _, LYServerSharedDataServices as _};
+
+use crate::LYServerPluginSharedData;
+
+impl LYServerPluginSharedData {
+    /// Reads a key from the plugin's key/value storage.
+    pub async fn kv_get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let (service, namespace) = self.kv_service()?;
+
+        service.get(namespace, key).await
+    }
+
+    /// Stores a value, failing when it would push the plugin over its KV quota.
+    pub async fn kv_set(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        let (service, namespace) = self.kv_service()?;
+        let max_bytes = self.app_shared_data.get_plugin_kv_max_bytes(namespace).await;
+
+        service.set(namespace, key, value, max_bytes).await
+    }
+
+    /// Removes a key, returning whether it existed.
+    pub async fn kv_delete(&self, key: &str) -> anyhow::Result<bool> {
+        let (service, namespace) = self.kv_service()?;
+
+        service.delete(namespace, key).await
+    }
+
+    /// The keys starting with `prefix`, in ascending order.
+    pub async fn kv_list_prefix(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let (service, namespace) = self.kv_service()?;
+
+        service.list_prefix(namespace, prefix).await
+    }
+
+    /// Replaces the value of a key if it still holds `expected`, `None` standing for a missing key on both sides.
+    pub async fn kv_compare_and_swap(&self, key: &str, expected: Option<Vec<u8>>, value: Option<Vec<u8>>) -> anyhow::Result<bool> {
+        let (service, namespace) = self.kv_service()?;
+        let max_bytes = self.app_shared_data.get_plugin_kv_max_bytes(namespace).await;
+
+        service.compare_and_swap(namespace, key, expected, value, max_bytes).await
+    }
+
+    fn kv_service(&self) -> anyhow::Result<(Arc<dyn LYServerKVService>, &str)> {
+        let namespace = self.plugin_id.as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Plugin messaging is not registered, the plugin has no KV namespace"))?;
+
+        let service = self.app_shared_data
+            .get_service::<dyn LYServerKVService>()
+            .ok_or_else(|| anyhow::anyhow!("KV service is not available"))?;
+
+        Ok((service, namespace))
+    }
+}
@@ -1,6 +1,13 @@
-use std::{clone, collections::HashSet, sync::Arc, time::Duration};
+mod http;
+mod kv;
+mod rpc;
 
-use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
+pub use rpc::{LYServerRPCHandler, LYServerRPCHandlerFuture};
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use lyserver_messaging_shared::{rpc::LYSERVER_RPC_RESPONSE_EVENT, LYServerMessageEvent, LYServerMessageEventTarget};
+use lyserver_plugin_common::LYServerPluginCapability;
 use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataMessaging as _};
 use tokio::sync::RwLock;
 
@@ -9,30 +16,36 @@ pub struct LYServerPluginSharedData {
 
     pub plugin_id: Option<String>,
 
+    /// Capabilities granted to the plugin, `None` for built-in plugins which are trusted with everything
+    capabilities: std::sync::RwLock<Option<Vec<LYServerPluginCapability>>>,
+
     tx: tokio::sync::broadcast::Sender<LYServerMessageEvent>,
     rx: Arc<RwLock<Option<tokio::sync::broadcast::Receiver<LYServerMessageEvent>>>>,
     rx_sync: Arc<std::sync::Mutex<Option<tokio::sync::broadcast::Receiver<LYServerMessageEvent>>>>,
 
-    tx_2: Arc<tokio::sync::mpsc::UnboundedSender<LYServerMessageEvent>>,
-    rx_2: Arc<RwLock<tokio::sync::mpsc::UnboundedReceiver<LYServerMessageEvent>>>,
+    /// Handlers serving calls to this plugin, keyed by method
+    rpc_handlers: std::sync::RwLock<HashMap<String, LYServerRPCHandler>>,
+    /// Calls currently being served, so callers can cancel them
+    rpc_running: std::sync::Mutex<HashMap<String, tokio::task::AbortHandle>>,
 }
 
 impl LYServerPluginSharedData {
     pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
         let (tx, _) = tokio::sync::broadcast::channel::<LYServerMessageEvent>(1024);
-        let (tx_2, rx_2) = tokio::sync::mpsc::unbounded_channel::<LYServerMessageEvent>();
 
         Self {
             app_shared_data: shared_data,
 
             plugin_id: None,
 
+            capabilities: std::sync::RwLock::new(None),
+
             tx,
             rx: Arc::new(RwLock::new(None)),
             rx_sync: Arc::new(std::sync::Mutex::new(None)),
 
-            tx_2: Arc::new(tx_2),
-            rx_2: Arc::new(RwLock::new(rx_2)),
+            rpc_handlers: std::sync::RwLock::new(HashMap::new()),
+            rpc_running: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
@@ -55,22 +68,25 @@ impl LYServerPluginSharedData {
         let plugin_id_clone = plugin_id.clone();
         let mut rx_clone = self.tx.subscribe();
 
-        let tx_2_clone = Arc::clone(&self.tx_2);
-
         tokio::spawn(async move {
             log::error!("SPAWNED MESSAGING TOKIO TASK FOR PLUGIN: {}", plugin_id_clone);
 
             loop {
-                if let Ok(event) = rx_clone.recv().await {
-                    log::debug!("[Plugin Messaging SHARED DATA RUNIME: {}] Received event '{}' ({}): {} -> {}",
-                        plugin_id_clone,
-                        event.event_type, event.event_id,
-                        event.event_sender.to_string(), event.event_target.to_string()
-                    );
-
-                    let _ = tx_2_clone.send(event.clone());
-                } else {
-                    log::warn!("[Plugin Messaging SHARED DATA RUNIME: {}] Receiver closed, stopping event loop", plugin_id_clone);
+                match rx_clone.recv().await {
+                    Ok(event) => {
+                        log::debug!("[Plugin Messaging SHARED DATA RUNIME: {}] Received event '{}' ({}): {} -> {}",
+                            plugin_id_clone,
+                            event.event_type, event.event_id,
+                            event.event_sender, event.event_target
+                        );
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("[Plugin Messaging SHARED DATA RUNIME: {}] Missed {} messages", plugin_id_clone, n);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        log::warn!("[Plugin Messaging SHARED DATA RUNIME: {}] Receiver closed, stopping event loop", plugin_id_clone);
+                        break;
+                    }
                 }
             }
         });
@@ -78,10 +94,42 @@ impl LYServerPluginSharedData {
         Ok(())
     }
 
+    pub fn set_capabilities(&self, capabilities: Vec<LYServerPluginCapability>) {
+        *self.capabilities.write().unwrap() = Some(capabilities);
+    }
+
+    pub fn has_capability(&self, capability: &LYServerPluginCapability) -> bool {
+        match self.capabilities.read().unwrap().as_ref() {
+            Some(capabilities) => capabilities.contains(capability),
+            None => true,
+        }
+    }
+
+    pub fn can_send_event_type(&self, event_type: &str) -> bool {
+        // Every plugin has to announce that it finished initializing, and may answer the calls it was sent
+        if matches!(event_type, "plugin_init" | LYSERVER_RPC_RESPONSE_EVENT) {
+            return true;
+        }
+
+        match self.capabilities.read().unwrap().as_ref() {
+            Some(capabilities) => capabilities.iter().any(|capability| capability.allows_event_type(event_type)),
+            None => true,
+        }
+    }
+
     pub fn dispatch_event(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
+        if !self.can_send_event_type(&event.event_type) {
+            log::warn!("[Plugin Messaging] Rejected event '{}' ({}) from '{}': plugin was not granted a capability to send it",
+                event.event_type, event.event_id,
+                self.plugin_id.as_deref().unwrap_or("unknown")
+            );
+
+            return Err(anyhow::anyhow!("Plugin is not allowed to send '{}' events", event.event_type));
+        }
+
         log::debug!("[Plugin Messaging] Dispatching event '{}' ({}): {} -> {}",
             event.event_type, event.event_id,
-            event.event_sender.to_string(), event.event_target.to_string()
+            event.event_sender, event.event_target
         );
 
         self.app_shared_data.dispatch_event(event)
@@ -94,6 +142,29 @@ impl LYServerPluginSharedData {
         let event_obj = serde_cbor::from_slice::<LYServerMessageEvent>(&event)
             .map_err(|e| anyhow::anyhow!("Failed to parse event CBOR: {}", e))?;
 
+        self.dispatch_guest_event(event_obj)
+    }
+
+    /// Dispatches an event built by a WASM guest, rejecting it unless it is sent as this plugin.
+    pub fn dispatch_guest_event(
+        &self,
+        event_obj: LYServerMessageEvent,
+    ) -> anyhow::Result<()> {
+        let plugin_id = self.plugin_id
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Plugin ID is not set"))?;
+
+        // Guests serialize events themselves, so never trust the sender they claim to be
+        if event_obj.event_sender.plugin_id().as_deref() != Some(plugin_id) {
+            log::warn!("[Plugin Messaging] Rejected event '{}' ({}) from '{}': spoofed sender '{}'",
+                event_obj.event_type, event_obj.event_id,
+                plugin_id,
+                event_obj.event_sender
+            );
+
+            return Err(anyhow::anyhow!("Event sender '{}' does not match plugin '{}'", event_obj.event_sender.to_string(), plugin_id));
+        }
+
         self.dispatch_event(event_obj)
     }
 
@@ -111,6 +182,11 @@ impl LYServerPluginSharedData {
         Ok(LYServerMessageEvent::new(event_type, target, LYServerMessageEventTarget::Plugin(plugin_id), data))
     }
 
+    /// Subscribes to every event delivered to this plugin from now on.
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<LYServerMessageEvent> {
+        self.tx.subscribe()
+    }
+
     pub fn receive_event_sync(&self) -> Option<LYServerMessageEvent> {
         let mut rx = self.rx_sync.lock().unwrap();
         
@@ -158,12 +234,7 @@ impl LYServerPluginSharedData {
         predicate: impl Fn(&LYServerMessageEvent) -> bool + Send + 'static,
         timeout: Duration,
     ) -> Option<LYServerMessageEvent> {
-        // let tx_read = self.rx_2.clone();
-
-        let mut rx = {
-            let rx = self.tx.subscribe();
-            rx
-        };
+        let mut rx = self.tx.subscribe();
     
         let fut = async {
             loop {
@@ -199,4 +270,51 @@ impl LYServerPluginSharedData {
 
         self.dispatch_event(init_event)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLUGIN_ID: &str = "guest@plugin";
+
+    async fn guest_plugin(name: &str) -> (LYServerPluginSharedData, tokio::sync::broadcast::Receiver<LYServerMessageEvent>) {
+        let data_dir = std::env::temp_dir().join(format!("lyserver-guest-{}-{}", std::process::id(), name));
+        let shared_data = Arc::new(LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap());
+        let bus = shared_data.messaging_global_tx.subscribe();
+
+        let mut plugin_shared_data = LYServerPluginSharedData::new(shared_data);
+        plugin_shared_data.register_plugin_messaging(PLUGIN_ID.to_string()).await.unwrap();
+
+        (plugin_shared_data, bus)
+    }
+
+    fn guest_event(sender: LYServerMessageEventTarget) -> LYServerMessageEvent {
+        LYServerMessageEvent::new("guest_event", LYServerMessageEventTarget::All, sender, ())
+    }
+
+    #[tokio::test]
+    async fn guest_events_sent_as_the_plugin_are_dispatched() {
+        let (plugin_shared_data, mut bus) = guest_plugin("own-sender").await;
+
+        plugin_shared_data.dispatch_guest_event(guest_event(LYServerMessageEventTarget::Plugin(PLUGIN_ID.to_string()))).unwrap();
+
+        assert_eq!(bus.try_recv().unwrap().event_type, "guest_event");
+    }
+
+    #[tokio::test]
+    async fn guest_events_with_a_spoofed_sender_are_rejected() {
+        let (plugin_shared_data, mut bus) = guest_plugin("spoofed-sender").await;
+
+        for sender in [
+            LYServerMessageEventTarget::Plugin("http@lyserver.local".to_string()),
+            LYServerMessageEventTarget::Plugin(format!("{} ", PLUGIN_ID)),
+            LYServerMessageEventTarget::All,
+        ] {
+            assert!(plugin_shared_data.dispatch_guest_event(guest_event(sender)).is_err());
+        }
+
+        assert!(bus.try_recv().is_err());
+    }
+}
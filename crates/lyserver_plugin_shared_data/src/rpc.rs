@@ -0,0 +1,356 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use lyserver_messaging_shared::{rpc::{LYServerRPCCancel, LYServerRPCError, LYServerRPCErrorKind, LYServerRPCRequest, LYServerRPCResponse, LYSERVER_RPC_CANCEL_EVENT, LYSERVER_RPC_REQUEST_EVENT, LYSERVER_RPC_RESPONSE_EVENT}, LYServerMessageEvent, LYServerMessageEventTarget};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::LYServerPluginSharedData;
+
+pub type LYServerRPCHandlerFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, LYServerRPCError>> + Send>>;
+pub type LYServerRPCHandler = Arc<dyn Fn(LYServerRPCRequest) -> LYServerRPCHandlerFuture + Send + Sync>;
+
+/// Tells the callee to stop working on a call once the caller no longer waits for it.
+struct LYServerRPCCancelGuard<'a> {
+    plugin_shared_data: &'a LYServerPluginSharedData,
+    target: LYServerMessageEventTarget,
+    correlation_id: String,
+    armed: bool,
+}
+
+impl Drop for LYServerRPCCancelGuard<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let cancel = LYServerRPCCancel { correlation_id: self.correlation_id.clone() };
+
+        let result = self.plugin_shared_data.plugin_target()
+            .and_then(|sender| self.plugin_shared_data.dispatch_event(LYServerMessageEvent::new(LYSERVER_RPC_CANCEL_EVENT, self.target.clone(), sender, cancel)));
+
+        if let Err(e) = result {
+            log::warn!("[Plugin RPC] Failed to cancel call {}: {}", self.correlation_id, e);
+        }
+    }
+}
+
+impl LYServerPluginSharedData {
+    /// Calls `method` on the `target` plugin and waits up to `timeout` for its response.
+    ///
+    /// Dropping the returned future cancels the call, the callee is told to abort its handler.
+    pub async fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        target: impl Into<LYServerMessageEventTarget>,
+        method: &str,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, LYServerRPCError> {
+        let target = target.into();
+
+        if !target.is_plugin() {
+            return Err(LYServerRPCError::new(LYServerRPCErrorKind::InvalidRequest, "Calls must target a single plugin"));
+        }
+
+        let request = LYServerRPCRequest::new(method, request)?;
+        let correlation_id = request.correlation_id.clone();
+
+        let sender = self.plugin_target()
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::Transport, e.to_string()))?;
+
+        // Subscribe before sending so a fast response cannot be missed
+        let mut rx = self.subscribe_events();
+
+        self.dispatch_event(LYServerMessageEvent::new(LYSERVER_RPC_REQUEST_EVENT, target.clone(), sender, request))
+            .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::Transport, e.to_string()))?;
+
+        let mut guard = LYServerRPCCancelGuard {
+            plugin_shared_data: self,
+            target: target.clone(),
+            correlation_id: correlation_id.clone(),
+            armed: true,
+        };
+
+        let fut = async {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if event.event_sender == target => {
+                        if let Some(response) = event.rpc_response_for(&correlation_id) {
+                            return Ok(response);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("[Plugin RPC] Missed {} messages while waiting for call {}", n, correlation_id);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        return Err(LYServerRPCError::new(LYServerRPCErrorKind::Transport, "Plugin event stream closed"));
+                    }
+                }
+            }
+        };
+
+        let response = tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| LYServerRPCError::new(LYServerRPCErrorKind::Timeout, format!("'{}' on '{}' did not respond within {}ms", method, target, timeout.as_millis())))??;
+
+        guard.armed = false;
+
+        response.into_result()
+    }
+
+    /// Registers the handler serving calls to `method` on this plugin, replacing any previous one.
+    pub fn register_rpc_handler<Req, Resp, F, Fut>(&self, method: impl Into<String>, handler: F)
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize + Send + 'static,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        let handler: LYServerRPCHandler = Arc::new(move |request: LYServerRPCRequest| {
+            let handler = Arc::clone(&handler);
+
+            Box::pin(async move {
+                let data = request.data_as::<Req>()?;
+
+                let response = handler(data)
+                    .await
+                    .map_err(|e| match e.downcast::<LYServerRPCError>() {
+                        Ok(e) => e,
+                        Err(e) => LYServerRPCError::new(LYServerRPCErrorKind::Handler, e.to_string()),
+                    })?;
+
+                serde_cbor::to_vec(&response)
+                    .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::InvalidResponse, format!("Failed to serialize response: {}", e)))
+            })
+        });
+
+        self.rpc_handlers.write().unwrap().insert(method.into(), handler);
+    }
+
+    pub fn unregister_rpc_handler(&self, method: &str) {
+        self.rpc_handlers.write().unwrap().remove(method);
+    }
+
+    /// Serves an `rpc_request` or `rpc_cancel` event addressed to this plugin, responses are left to their callers.
+    pub async fn handle_rpc_event(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
+        match event.event_type.as_str() {
+            LYSERVER_RPC_REQUEST_EVENT => self.handle_rpc_request(event).await,
+            LYSERVER_RPC_CANCEL_EVENT => {
+                let cancel = event.data_as::<LYServerRPCCancel>()?;
+
+                if let Some(handle) = self.rpc_running.lock().unwrap().remove(&rpc_running_key(&event, &cancel.correlation_id)) {
+                    log::debug!("[Plugin RPC] Call {} was cancelled by '{}'", cancel.correlation_id, event.event_sender);
+
+                    handle.abort();
+                }
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_rpc_request(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
+        let request = event.data_as::<LYServerRPCRequest>()?;
+        let correlation_id = request.correlation_id.clone();
+
+        let handler = self.rpc_handlers.read().unwrap().get(&request.method).cloned();
+
+        let Some(handler) = handler else {
+            let error = LYServerRPCError::new(LYServerRPCErrorKind::MethodNotFound, format!("Plugin has no handler for '{}'", request.method));
+
+            return self.reply_event(LYSERVER_RPC_RESPONSE_EVENT, event, LYServerRPCResponse::err(correlation_id, error)).await;
+        };
+
+        // Handlers run in their own task so a cancellation from the caller can abort them
+        let key = rpc_running_key(&event, &correlation_id);
+        let task = tokio::spawn(handler(request));
+
+        self.rpc_running.lock().unwrap().insert(key.clone(), task.abort_handle());
+
+        let result = task.await;
+
+        self.rpc_running.lock().unwrap().remove(&key);
+
+        let response = match result {
+            Ok(Ok(data)) => LYServerRPCResponse { correlation_id, result: Ok(data) },
+            Ok(Err(error)) => LYServerRPCResponse::err(correlation_id, error),
+            // Nobody is waiting for a cancelled call anymore
+            Err(e) if e.is_cancelled() => return Ok(()),
+            Err(e) => LYServerRPCResponse::err(correlation_id, LYServerRPCError::new(LYServerRPCErrorKind::Handler, format!("Handler panicked: {}", e))),
+        };
+
+        self.reply_event(LYSERVER_RPC_RESPONSE_EVENT, event, response).await
+    }
+
+    fn plugin_target(&self) -> anyhow::Result<LYServerMessageEventTarget> {
+        self.plugin_id
+            .clone()
+            .map(LYServerMessageEventTarget::Plugin)
+            .ok_or_else(|| anyhow::anyhow!("Plugin ID is not set"))
+    }
+}
+
+/// Correlation ids are only unique per caller, so running calls are keyed by both.
+fn rpc_running_key(event: &LYServerMessageEvent, correlation_id: &str) -> String {
+    format!("{}/{}", event.event_sender, correlation_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use lyserver_shared_data::LYServerSharedData;
+    use tokio::sync::{mpsc, Notify};
+
+    use super::*;
+
+    const CALLER: &str = "caller@plugin";
+    const CALLEE: &str = "callee@plugin";
+
+    /// A caller and a callee connected through the server bus, the callee serving calls like the plugin dispatcher.
+    async fn connect(name: &str) -> (Arc<LYServerPluginSharedData>, Arc<LYServerPluginSharedData>) {
+        let data_dir = std::env::temp_dir().join(format!("lyserver-rpc-{}-{}", std::process::id(), name));
+        let shared_data = Arc::new(LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap());
+
+        // Delivers bus events to the plugins they target, like the plugin manager's messaging loop
+        let mut bus = shared_data.messaging_global_tx.subscribe();
+        let shared_data_clone = Arc::clone(&shared_data);
+        tokio::spawn(async move {
+            while let Ok(event) = bus.recv().await {
+                for (plugin_id, tx) in shared_data_clone.messaging_plugin_tx.read().await.iter() {
+                    if event.event_target.is_all() || event.event_target.plugin_id().as_deref() == Some(plugin_id) {
+                        let _ = tx.send(event.clone());
+                    }
+                }
+            }
+        });
+
+        let mut plugins = Vec::new();
+        for plugin_id in [CALLER, CALLEE] {
+            let mut plugin_shared_data = LYServerPluginSharedData::new(Arc::clone(&shared_data));
+            plugin_shared_data.register_plugin_messaging(plugin_id.to_string()).await.unwrap();
+            plugins.push(Arc::new(plugin_shared_data));
+        }
+
+        let callee = plugins.pop().unwrap();
+        let caller = plugins.pop().unwrap();
+
+        let mut rx = callee.subscribe_events();
+        let callee_clone = Arc::clone(&callee);
+        tokio::spawn(async move {
+            while let Ok(event) = rx.recv().await {
+                if event.is_rpc_event() {
+                    let callee_clone = Arc::clone(&callee_clone);
+                    tokio::spawn(async move { callee_clone.handle_rpc_event(event).await });
+                }
+            }
+        });
+
+        (caller, callee)
+    }
+
+    #[tokio::test]
+    async fn call_returns_the_handler_response() {
+        let (caller, callee) = connect("round-trip").await;
+        callee.register_rpc_handler("add", |(a, b): (i32, i32)| async move { Ok(a + b) });
+
+        let sum = caller.call::<_, i32>(CALLEE, "add", &(1, 2), Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(sum, 3);
+    }
+
+    #[tokio::test]
+    async fn call_returns_handler_errors() {
+        let (caller, callee) = connect("error").await;
+        callee.register_rpc_handler("fail", |_: ()| async move { Err::<(), _>(anyhow::anyhow!("boom")) });
+
+        let error = caller.call::<_, ()>(CALLEE, "fail", &(), Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error, LYServerRPCError::new(LYServerRPCErrorKind::Handler, "boom"));
+
+        let error = caller.call::<_, ()>(CALLEE, "missing", &(), Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.kind, LYServerRPCErrorKind::MethodNotFound);
+    }
+
+    #[tokio::test]
+    async fn call_only_targets_single_plugins() {
+        let (caller, _callee) = connect("broadcast").await;
+
+        let error = caller.call::<_, ()>(LYServerMessageEventTarget::All, "add", &(), Duration::from_secs(5)).await.unwrap_err();
+
+        assert_eq!(error.kind, LYServerRPCErrorKind::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn call_times_out_and_cancels_the_handler() {
+        let (caller, callee) = connect("timeout").await;
+
+        let (cancelled_tx, mut cancelled_rx) = mpsc::unbounded_channel::<()>();
+        callee.register_rpc_handler("hang", move |_: ()| {
+            let guard = CancelledGuard(cancelled_tx.clone());
+
+            async move {
+                let _guard = guard;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+
+                Ok(())
+            }
+        });
+
+        let error = caller.call::<_, ()>(CALLEE, "hang", &(), Duration::from_millis(100)).await.unwrap_err();
+        assert_eq!(error.kind, LYServerRPCErrorKind::Timeout);
+
+        tokio::time::timeout(Duration::from_secs(5), cancelled_rx.recv()).await
+            .expect("The handler was not cancelled");
+    }
+
+    #[tokio::test]
+    async fn dropping_a_call_cancels_the_handler() {
+        let (caller, callee) = connect("cancel").await;
+
+        let started = Arc::new(Notify::new());
+        let finished = Arc::new(AtomicUsize::new(0));
+        let (cancelled_tx, mut cancelled_rx) = mpsc::unbounded_channel::<()>();
+
+        let started_clone = Arc::clone(&started);
+        let finished_clone = Arc::clone(&finished);
+        callee.register_rpc_handler("hang", move |_: ()| {
+            let guard = CancelledGuard(cancelled_tx.clone());
+            let started = Arc::clone(&started_clone);
+            let finished = Arc::clone(&finished_clone);
+
+            async move {
+                let _guard = guard;
+                started.notify_one();
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+
+                Ok(())
+            }
+        });
+
+        let call = tokio::spawn(async move {
+            caller.call::<_, ()>(CALLEE, "hang", &(), Duration::from_secs(60)).await
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), started.notified()).await
+            .expect("The handler did not start");
+        call.abort();
+
+        tokio::time::timeout(Duration::from_secs(5), cancelled_rx.recv()).await
+            .expect("The handler was not cancelled");
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+    }
+
+    /// Reports when a handler is dropped, which is how an aborted handler ends.
+    struct CancelledGuard(mpsc::UnboundedSender<()>);
+
+    impl Drop for CancelledGuard {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+}
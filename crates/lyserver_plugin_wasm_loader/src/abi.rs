@@ -0,0 +1,320 @@
+use std::sync::Arc;
+
+use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use wasmtime::{ExternType, Module, ValType};
+
+use crate::{
+    LYServerWASMLinkerState, LYSERVER_PLUGIN_ABI_ALLOC_METHOD, LYSERVER_PLUGIN_ABI_DATABASE_QUERY_METHOD, LYSERVER_PLUGIN_ABI_DESTROY_METHOD,
+    LYSERVER_PLUGIN_ABI_FREE_METHOD, LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD, LYSERVER_PLUGIN_ABI_INIT_METHOD,
+    LYSERVER_PLUGIN_ABI_INVOKE_PLUGIN_METHOD, LYSERVER_PLUGIN_ABI_KV_COMPARE_AND_SWAP_METHOD, LYSERVER_PLUGIN_ABI_KV_DELETE_METHOD,
+    LYSERVER_PLUGIN_ABI_KV_GET_METHOD, LYSERVER_PLUGIN_ABI_KV_LIST_METHOD, LYSERVER_PLUGIN_ABI_KV_SET_METHOD, LYSERVER_PLUGIN_ABI_LOG_DEBUG_METHOD, LYSERVER_PLUGIN_ABI_LOG_ERROR_METHOD,
+    LYSERVER_PLUGIN_ABI_LOG_INFO_METHOD, LYSERVER_PLUGIN_ABI_LOG_WARN_METHOD, LYSERVER_PLUGIN_ABI_PREFERENCES_GET_METHOD,
+    LYSERVER_PLUGIN_ABI_PREFERENCES_SET_METHOD, LYSERVER_PLUGIN_ABI_RECEIVE_MESSAGE_METHOD, LYSERVER_PLUGIN_ABI_SEND_MESSAGE_METHOD,
+    LYSERVER_PLUGIN_ABI_SERVICE_CALL_METHOD, LYSERVER_PLUGIN_ABI_STDOUT_WRITE_METHOD, LYSERVER_PLUGIN_ABI_TRY_RECEIVE_MESSAGE_METHOD,
+};
+
+/// Newest version of the `extern "C"` plugin ABI, implemented by this server.
+pub const LYSERVER_PLUGIN_ABI_VERSION: u32 = 3;
+
+/// Custom section modules declare the ABI version they were built for in, as a little endian `u32`.
+///
+/// Modules without it predate versioning and are treated as version 1.
+pub const LYSERVER_PLUGIN_ABI_VERSION_SECTION: &str = "lyserver_plugin_abi_version";
+
+/// Replaces host functions for modules built against an older ABI version, after the current ones were defined.
+pub type LYServerPluginABIShim = fn(&mut wasmtime::Linker<LYServerWASMLinkerState>, &LYServerPluginMetadata, Arc<LYServerPluginSharedData>) -> anyhow::Result<()>;
+
+pub struct LYServerPluginABI {
+    pub version: u32,
+    /// Exports every module built for this version has to provide
+    pub required_exports: &'static [&'static str],
+    pub shims: &'static [LYServerPluginABIShim],
+}
+
+/// The ABI versions this server can load, oldest first.
+pub const LYSERVER_PLUGIN_ABI_COMPATIBILITY: &[LYServerPluginABI] = &[
+    // The original ABI, events were only received by polling from init
+    LYServerPluginABI {
+        version: 1,
+        required_exports: &[
+            LYSERVER_PLUGIN_ABI_INIT_METHOD,
+            LYSERVER_PLUGIN_ABI_DESTROY_METHOD,
+            LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD,
+            LYSERVER_PLUGIN_ABI_ALLOC_METHOD,
+        ],
+        // Later versions only added functions, so the current ones behave the same for version 1 modules
+        shims: &[],
+    },
+    // Events are pushed once init returns and buffers handed to the guest are freed through `lyserver_plugin_free`
+    LYServerPluginABI {
+        version: 2,
+        required_exports: &[
+            LYSERVER_PLUGIN_ABI_INIT_METHOD,
+            LYSERVER_PLUGIN_ABI_DESTROY_METHOD,
+            LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD,
+            LYSERVER_PLUGIN_ABI_ALLOC_METHOD,
+            LYSERVER_PLUGIN_ABI_FREE_METHOD,
+        ],
+        shims: &[],
+    },
+    // Adds the per-plugin key/value storage
+    LYServerPluginABI {
+        version: 3,
+        required_exports: &[
+            LYSERVER_PLUGIN_ABI_INIT_METHOD,
+            LYSERVER_PLUGIN_ABI_DESTROY_METHOD,
+            LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD,
+            LYSERVER_PLUGIN_ABI_ALLOC_METHOD,
+            LYSERVER_PLUGIN_ABI_FREE_METHOD,
+        ],
+        shims: &[],
+    },
+];
+
+struct LYServerPluginABIImport {
+    name: &'static str,
+    /// ABI version the function was added in
+    since: u32,
+    /// Number of `i32` parameters and results
+    params: usize,
+    results: usize,
+    /// Name of the capability the function is only linked with
+    capability: Option<&'static str>,
+}
+
+const fn abi_import(name: &'static str, since: u32, params: usize, results: usize, capability: Option<&'static str>) -> LYServerPluginABIImport {
+    LYServerPluginABIImport { name, since, params, results, capability }
+}
+
+/// Every host function `mutate_linker` can provide in the `env` module.
+const LYSERVER_PLUGIN_ABI_IMPORTS: &[LYServerPluginABIImport] = &[
+    abi_import(LYSERVER_PLUGIN_ABI_STDOUT_WRITE_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_LOG_INFO_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_LOG_WARN_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_LOG_ERROR_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_LOG_DEBUG_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_RECEIVE_MESSAGE_METHOD, 1, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_SEND_MESSAGE_METHOD, 1, 3, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_DATABASE_QUERY_METHOD, 1, 4, 1, Some("database.query")),
+    abi_import(LYSERVER_PLUGIN_ABI_PREFERENCES_GET_METHOD, 1, 4, 1, Some("preferences.read")),
+    abi_import(LYSERVER_PLUGIN_ABI_PREFERENCES_SET_METHOD, 1, 4, 1, Some("preferences.write")),
+    abi_import(LYSERVER_PLUGIN_ABI_TRY_RECEIVE_MESSAGE_METHOD, 2, 2, 0, None),
+    abi_import(LYSERVER_PLUGIN_ABI_SERVICE_CALL_METHOD, 2, 4, 1, Some("services.call")),
+    abi_import(LYSERVER_PLUGIN_ABI_INVOKE_PLUGIN_METHOD, 2, 4, 1, Some("plugins.invoke")),
+    abi_import(LYSERVER_PLUGIN_ABI_KV_GET_METHOD, 3, 4, 1, None),
+    abi_import(LYSERVER_PLUGIN_ABI_KV_SET_METHOD, 3, 4, 1, None),
+    abi_import(LYSERVER_PLUGIN_ABI_KV_DELETE_METHOD, 3, 4, 1, None),
+    abi_import(LYSERVER_PLUGIN_ABI_KV_LIST_METHOD, 3, 4, 1, None),
+    abi_import(LYSERVER_PLUGIN_ABI_KV_COMPARE_AND_SWAP_METHOD, 3, 4, 1, None),
+];
+
+/// Reads the ABI version a module declares in its `lyserver_plugin_abi_version` custom section.
+pub fn read_abi_version(bytes: &[u8]) -> anyhow::Result<Option<u32>> {
+    // Skip the magic and version of the module header
+    let mut offset = 8;
+
+    while offset < bytes.len() {
+        let id = bytes[offset];
+        offset += 1;
+
+        let size = read_leb128_u32(bytes, &mut offset)? as usize;
+        let section = bytes.get(offset..offset + size)
+            .ok_or_else(|| anyhow::anyhow!("Section at offset {} is truncated", offset))?;
+
+        offset += size;
+
+        // Only custom sections carry a name
+        if id != 0 {
+            continue;
+        }
+
+        let mut name_offset = 0;
+        let name_len = read_leb128_u32(section, &mut name_offset)? as usize;
+        let name = section.get(name_offset..name_offset + name_len)
+            .ok_or_else(|| anyhow::anyhow!("Custom section name at offset {} is truncated", offset))?;
+
+        if name != LYSERVER_PLUGIN_ABI_VERSION_SECTION.as_bytes() {
+            continue;
+        }
+
+        let payload: [u8; 4] = section[name_offset + name_len..]
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Section '{}' has to hold exactly 4 bytes", LYSERVER_PLUGIN_ABI_VERSION_SECTION))?;
+
+        return Ok(Some(u32::from_le_bytes(payload)));
+    }
+
+    Ok(None)
+}
+
+fn read_leb128_u32(bytes: &[u8], offset: &mut usize) -> anyhow::Result<u32> {
+    let mut result = 0u32;
+
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*offset)
+            .ok_or_else(|| anyhow::anyhow!("Unexpected end of module at offset {}", offset))?;
+
+        *offset += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+
+    Err(anyhow::anyhow!("Invalid LEB128 integer at offset {}", offset))
+}
+
+/// Checks a module against the ABI version it declares, naming every missing export and incompatible import.
+pub fn check_module_abi(metadata: &LYServerPluginMetadata, module: &Module, bytes: &[u8]) -> anyhow::Result<&'static LYServerPluginABI> {
+    let version = read_abi_version(bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to read the ABI version of plugin '{}': {}", metadata.id, e))?
+        .unwrap_or(1);
+
+    if version > LYSERVER_PLUGIN_ABI_VERSION {
+        return Err(anyhow::anyhow!(
+            "Plugin '{}' was built for ABI v{}, but this server only supports up to v{}",
+            metadata.id, version, LYSERVER_PLUGIN_ABI_VERSION,
+        ));
+    }
+
+    let abi = LYSERVER_PLUGIN_ABI_COMPATIBILITY.iter()
+        .find(|abi| abi.version == version)
+        .ok_or_else(|| anyhow::anyhow!(
+            "Plugin '{}' was built for ABI v{}, which is no longer supported, the oldest supported version is v{}",
+            metadata.id, version, LYSERVER_PLUGIN_ABI_COMPATIBILITY[0].version,
+        ))?;
+
+    let mut problems = Vec::new();
+
+    for export in abi.required_exports {
+        if module.get_export(export).is_none() {
+            problems.push(format!("missing export '{}'", export));
+        }
+    }
+
+    for import in module.imports().filter(|import| import.module() == "env") {
+        let Some(abi_import) = LYSERVER_PLUGIN_ABI_IMPORTS.iter().find(|abi_import| abi_import.name == import.name()) else {
+            problems.push(format!("import '{}' is not provided by this server", import.name()));
+            continue;
+        };
+
+        let ExternType::Func(func_type) = import.ty() else {
+            problems.push(format!("import '{}' has to be a function", import.name()));
+            continue;
+        };
+
+        let is_i32 = |ty: &ValType| matches!(ty, ValType::I32);
+
+        if func_type.params().len() != abi_import.params
+            || func_type.results().len() != abi_import.results
+            || !func_type.params().all(|ty| is_i32(&ty))
+            || !func_type.results().all(|ty| is_i32(&ty))
+        {
+            problems.push(format!(
+                "import '{}' has changed, expected {} i32 parameter(s) and {} i32 result(s), found {:?}",
+                import.name(), abi_import.params, abi_import.results, func_type,
+            ));
+        }
+
+        if let Some(capability) = abi_import.capability {
+            let is_granted = metadata.capabilities.iter()
+                .any(|granted| granted.to_string().split(':').next() == Some(capability))
+                // Plugins may always query the databases they declare themselves
+                || (abi_import.name == LYSERVER_PLUGIN_ABI_DATABASE_QUERY_METHOD && !metadata.databases.is_empty());
+
+            if !is_granted {
+                problems.push(format!("import '{}' requires the '{}' capability", import.name(), capability));
+            }
+        }
+
+        if abi_import.since > version {
+            log::warn!("Plugin '{}' declares ABI v{} but imports '{}', which was added in v{}", metadata.id, version, import.name(), abi_import.since);
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Plugin '{}' (ABI v{}) is not compatible with this server (ABI v{}): {}",
+            metadata.id, version, LYSERVER_PLUGIN_ABI_VERSION, problems.join(", "),
+        ));
+    }
+
+    Ok(abi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+    fn custom_section(name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut content = vec![name.len() as u8];
+        content.extend_from_slice(name.as_bytes());
+        content.extend_from_slice(payload);
+
+        let mut section = vec![0, content.len() as u8];
+        section.extend(content);
+        section
+    }
+
+    fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = MODULE_HEADER.to_vec();
+        sections.iter().for_each(|section| bytes.extend_from_slice(section));
+        bytes
+    }
+
+    #[test]
+    fn reads_the_declared_version() {
+        let bytes = module(&[
+            vec![1, 1, 0x60],
+            custom_section("name", &[1, 2, 3]),
+            custom_section(LYSERVER_PLUGIN_ABI_VERSION_SECTION, &3u32.to_le_bytes()),
+        ]);
+
+        assert_eq!(read_abi_version(&bytes).unwrap(), Some(3));
+        assert_eq!(read_abi_version(&module(&[vec![1, 1, 0x60]])).unwrap(), None);
+        assert_eq!(read_abi_version(&MODULE_HEADER).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_truncated_modules() {
+        let bytes = module(&[custom_section(LYSERVER_PLUGIN_ABI_VERSION_SECTION, &3u32.to_le_bytes())]);
+
+        for len in MODULE_HEADER.len() + 1..bytes.len() {
+            assert!(read_abi_version(&bytes[..len]).is_err(), "accepted a module truncated to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_version_sections() {
+        let too_short = module(&[custom_section(LYSERVER_PLUGIN_ABI_VERSION_SECTION, &[3, 0])]);
+        assert!(read_abi_version(&too_short).is_err());
+
+        // The name length points past the end of the section
+        let bad_name = module(&[vec![0, 2, 10, b'a']]);
+        assert!(read_abi_version(&bad_name).is_err());
+    }
+
+    #[test]
+    fn reads_leb128_integers() {
+        let mut offset = 0;
+        assert_eq!(read_leb128_u32(&[0xe5, 0x8e, 0x26], &mut offset).unwrap(), 624485);
+        assert_eq!(offset, 3);
+
+        let mut offset = 1;
+        assert_eq!(read_leb128_u32(&[0xff, 0x7f], &mut offset).unwrap(), 127);
+        assert_eq!(offset, 2);
+    }
+
+    #[test]
+    fn rejects_truncated_and_overlong_leb128_integers() {
+        assert!(read_leb128_u32(&[], &mut 0).is_err());
+        assert!(read_leb128_u32(&[0x80], &mut 0).is_err());
+        assert!(read_leb128_u32(&[0xe5, 0x8e], &mut 0).is_err());
+        assert!(read_leb128_u32(&[0x01], &mut 1).is_err());
+        assert!(read_leb128_u32(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], &mut 0).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+use std::sync::Arc;
+
+use lyserver_messaging_shared::{LYServerMessageEvent, LYServerMessageEventTarget};
+use lyserver_plugin_common::{LYServerPlugin, LYServerPluginCrash, LYServerPluginMetadata};
+use lyserver_plugin_shared_data::LYServerPluginSharedData;
+use lyserver_shared_data::LYServerPluginResourceUsage;
+use tokio::sync::Mutex;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime_wasi::p2::{IoView, WasiCtx, WasiView};
+
+use crate::{create_wasi_ctx_builder, LYServerWASMSandbox, limits::LYServerWASMResourceLimiter, plugin_impl::PLUGIN_FUEL_ASYNC_YIELD_INTERVAL};
+
+wasmtime::component::bindgen!({
+    path: "wit",
+    world: "plugin",
+    async: true,
+});
+
+use lyserver::plugin::{logging, messaging, types};
+
+pub struct LYServerWASMComponentState {
+    plugin_id: String,
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+
+    wasi_ctx: WasiCtx,
+    resource_table: ResourceTable,
+    limiter: LYServerWASMResourceLimiter,
+}
+
+impl IoView for LYServerWASMComponentState {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.resource_table
+    }
+}
+
+impl WasiView for LYServerWASMComponentState {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.wasi_ctx
+    }
+}
+
+impl types::Host for LYServerWASMComponentState {}
+
+impl messaging::Host for LYServerWASMComponentState {
+    async fn send(&mut self, event: types::MessageEvent) -> Result<(), String> {
+        self.plugin_shared_data
+            .dispatch_guest_event(event.into())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn try_receive(&mut self) -> Option<types::MessageEvent> {
+        self.plugin_shared_data
+            .receive_event_sync()
+            .map(Into::into)
+    }
+}
+
+impl logging::Host for LYServerWASMComponentState {
+    async fn log(&mut self, level: logging::Level, message: String) {
+        match level {
+            logging::Level::Debug => log::debug!("[Plugin {}]: {}", self.plugin_id, message),
+            logging::Level::Info => log::info!("[Plugin {}]: {}", self.plugin_id, message),
+            logging::Level::Warn => log::warn!("[Plugin {}]: {}", self.plugin_id, message),
+            logging::Level::Error => log::error!("[Plugin {}]: {}", self.plugin_id, message),
+        }
+    }
+}
+
+/// A plugin built as a WASI preview 2 component against the `plugin` world in `wit/plugin.wit`.
+pub struct LYServerWASMComponentPlugin {
+    metadata: LYServerPluginMetadata,
+    fuel_per_call: u64,
+
+    runtime: Arc<Mutex<LYServerWASMComponentRuntime>>,
+
+    plugin_shared_data: Arc<LYServerPluginSharedData>,
+}
+
+struct LYServerWASMComponentRuntime {
+    bindings: Plugin,
+    store: wasmtime::Store<LYServerWASMComponentState>,
+}
+
+impl LYServerWASMComponentPlugin {
+    pub async fn new(
+        metadata: LYServerPluginMetadata,
+        engine: &wasmtime::Engine,
+        component: &Component,
+        plugin_shared_data: Arc<LYServerPluginSharedData>,
+        fuel_per_call: u64,
+        resource_usage: Arc<LYServerPluginResourceUsage>,
+        sandbox: LYServerWASMSandbox,
+    ) -> anyhow::Result<Self> {
+        let mut linker: Linker<LYServerWASMComponentState> = Linker::new(engine);
+        wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+        Plugin::add_to_linker(&mut linker, |state: &mut LYServerWASMComponentState| state)?;
+
+        let mut store = wasmtime::Store::new(engine, LYServerWASMComponentState {
+            plugin_id: metadata.id.clone(),
+            plugin_shared_data: Arc::clone(&plugin_shared_data),
+
+            wasi_ctx: create_wasi_ctx_builder(&metadata, &sandbox)?.build(),
+            resource_table: ResourceTable::new(),
+            limiter: LYServerWASMResourceLimiter::new(&metadata.id, resource_usage),
+        });
+
+        store.limiter(|state| &mut state.limiter);
+
+        // Start instances with an empty tank, every call refuels with the plugin's budget
+        store.set_fuel(0)?;
+        store.fuel_async_yield_interval(Some(PLUGIN_FUEL_ASYNC_YIELD_INTERVAL))?;
+
+        let bindings = Plugin::instantiate_async(&mut store, component, &linker)
+            .await
+            .map_err(|e| anyhow::Error::msg(format!("Failed to instantiate plugin '{}': {}", metadata.id, e)))?;
+
+        Ok(Self {
+            metadata,
+            fuel_per_call,
+
+            runtime: Arc::new(Mutex::new(LYServerWASMComponentRuntime { bindings, store })),
+
+            plugin_shared_data,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerPlugin for LYServerWASMComponentPlugin {
+    fn metadata(&self) -> LYServerPluginMetadata {
+        self.metadata.clone()
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        let mut runtime = self.runtime.lock().await;
+        let runtime = &mut *runtime;
+
+        runtime.store.set_fuel(self.fuel_per_call)?;
+
+        runtime.bindings.call_init(&mut runtime.store)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to call init for '{}': {}", self.metadata.id, e))?
+            .map_err(|e| anyhow::anyhow!("Plugin '{}' failed to initialize: {}", self.metadata.id, e))?;
+
+        self.plugin_shared_data.dispatch_init_event().await
+    }
+
+    async fn destroy(&self) -> anyhow::Result<()> {
+        let mut runtime = self.runtime.lock().await;
+        let runtime = &mut *runtime;
+
+        runtime.store.set_fuel(self.fuel_per_call)?;
+
+        runtime.bindings.call_destroy(&mut runtime.store)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to call destroy for '{}': {}", self.metadata.id, e))
+    }
+
+    async fn handle_message_event(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
+        let event_type = event.event_type.clone();
+        let event = types::MessageEvent::from(event);
+
+        let mut runtime = self.runtime.lock().await;
+        let runtime = &mut *runtime;
+
+        runtime.store.set_fuel(self.fuel_per_call)?;
+
+        runtime.bindings.call_handle_message_event(&mut runtime.store, &event)
+            .await
+            .map_err(|e| LYServerPluginCrash(format!("Failed to call handle_message_event for '{}': {}", self.metadata.id, e)))?
+            .map_err(|e| anyhow::anyhow!("Plugin '{}' failed to handle '{}': {}", self.metadata.id, event_type, e))
+    }
+
+    fn message_event_concurrency(&self) -> Option<usize> {
+        // Calls into a component are serialized on its store, pushing one event at a time keeps them in order
+        Some(1)
+    }
+
+    fn handles_rpc_events(&self) -> bool {
+        true
+    }
+}
+
+impl From<LYServerMessageEventTarget> for types::EventTarget {
+    fn from(target: LYServerMessageEventTarget) -> Self {
+        match target {
+            LYServerMessageEventTarget::All => types::EventTarget::All,
+            LYServerMessageEventTarget::Plugin(id) => types::EventTarget::Plugin(id),
+        }
+    }
+}
+
+impl From<types::EventTarget> for LYServerMessageEventTarget {
+    fn from(target: types::EventTarget) -> Self {
+        match target {
+            types::EventTarget::All => LYServerMessageEventTarget::All,
+            types::EventTarget::Plugin(id) => LYServerMessageEventTarget::Plugin(id),
+        }
+    }
+}
+
+impl From<LYServerMessageEvent> for types::MessageEvent {
+    fn from(event: LYServerMessageEvent) -> Self {
+        Self {
+            event_id: event.event_id,
+            event_type: event.event_type,
+            event_target: event.event_target.into(),
+            event_sender: event.event_sender.into(),
+            data: event.data,
+        }
+    }
+}
+
+impl From<types::MessageEvent> for LYServerMessageEvent {
+    fn from(event: types::MessageEvent) -> Self {
+        Self {
+            event_id: event.event_id,
+            event_type: event.event_type,
+            event_target: event.event_target.into(),
+            event_sender: event.event_sender.into(),
+            data: event.data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lyserver_messaging_shared::LYServerMessageEventTarget;
+    use lyserver_shared_data::{LYServerPluginInstance, LYServerSharedData};
+
+    use crate::LYServerWASMLoader;
+
+    use super::*;
+
+    const TEST_PLUGIN_ID: &str = "component@plugin";
+
+    /// Implements the `plugin` world: init succeeds, handling an event fails with its type as the error and
+    /// traps for events whose type is 4 bytes long.
+    const TEST_COMPONENT: &str = r#"
+        (component
+            (core module $m
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 1024))
+                (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $heap))
+                    (global.set $heap (i32.add (global.get $heap) (local.get 3)))
+                    (local.get $ptr))
+                (func (export "init") (result i32)
+                    (i32.store8 (i32.const 0) (i32.const 0))
+                    (i32.const 0))
+                (func (export "destroy"))
+                (func (export "handle-message-event") (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+                    (if (i32.eq (local.get 3) (i32.const 4)) (then unreachable))
+                    (i32.store8 (i32.const 16) (i32.const 1))
+                    (i32.store (i32.const 20) (local.get 2))
+                    (i32.store (i32.const 24) (local.get 3))
+                    (i32.const 16)))
+            (core instance $i (instantiate $m))
+
+            (type $event-target' (variant (case "all") (case "plugin" string)))
+            (export $event-target "event-target" (type $event-target'))
+            (type $message-event' (record
+                (field "event-id" string)
+                (field "event-type" string)
+                (field "event-target" $event-target)
+                (field "event-sender" $event-target)
+                (field "data" (list u8))))
+            (export $message-event "message-event" (type $message-event'))
+
+            (func (export "init") (result (result (error string)))
+                (canon lift (core func $i "init") (memory $i "memory") (realloc (func $i "realloc"))))
+            (func (export "destroy")
+                (canon lift (core func $i "destroy")))
+            (func (export "handle-message-event") (param "event" $message-event) (result (result (error string)))
+                (canon lift (core func $i "handle-message-event") (memory $i "memory") (realloc (func $i "realloc")))))
+    "#;
+
+    async fn load_test_component(name: &str) -> (LYServerPluginInstance, tokio::sync::broadcast::Receiver<LYServerMessageEvent>) {
+        let data_dir = std::env::temp_dir().join(format!("lyserver-component-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        let shared_data = Arc::new(LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap());
+        let bus = shared_data.messaging_global_tx.subscribe();
+
+        let mut plugin_shared_data = LYServerPluginSharedData::new(Arc::clone(&shared_data));
+        plugin_shared_data.register_plugin_messaging(TEST_PLUGIN_ID.to_string()).await.unwrap();
+
+        let wasm_path = data_dir.join("plugin.wasm");
+        std::fs::write(&wasm_path, wat::parse_str(TEST_COMPONENT).unwrap()).unwrap();
+
+        let metadata = LYServerPluginMetadata::builder()
+            .id(TEST_PLUGIN_ID)
+            .name("component")
+            .version("1.0.0")
+            .build();
+
+        let plugin = LYServerWASMLoader::new(shared_data)
+            .create_wasm_plugin_instance(&metadata, &wasm_path, Arc::new(plugin_shared_data))
+            .await
+            .unwrap();
+
+        (plugin, bus)
+    }
+
+    fn test_event(event_type: &str) -> LYServerMessageEvent {
+        LYServerMessageEvent::new(event_type, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()), LYServerMessageEventTarget::All, ())
+    }
+
+    #[tokio::test]
+    async fn components_are_initialized_and_announced() {
+        let (plugin, mut bus) = load_test_component("init").await;
+
+        plugin.init().await.unwrap();
+
+        let init_event = bus.try_recv().unwrap();
+        assert_eq!(init_event.event_type, "plugin_init");
+        assert_eq!(init_event.event_sender, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()));
+
+        plugin.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn component_errors_are_reported_without_crashing_the_plugin() {
+        let (plugin, _bus) = load_test_component("error").await;
+
+        let error = plugin.handle_message_event(test_event("library_changed")).await.unwrap_err();
+
+        assert!(error.downcast_ref::<LYServerPluginCrash>().is_none());
+        assert!(error.to_string().ends_with(": library_changed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn component_traps_crash_the_plugin() {
+        let (plugin, _bus) = load_test_component("trap").await;
+
+        let error = plugin.handle_message_event(test_event("trap")).await.unwrap_err();
+
+        assert!(error.downcast_ref::<LYServerPluginCrash>().is_some(), "{}", error);
+    }
+}
@@ -1,14 +1,18 @@
+pub mod abi;
 pub mod plugin_impl;
+pub mod component_impl;
+pub mod limits;
 
-use std::{path::Path, sync::{Arc, Mutex}};
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Arc};
 
-use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_messaging_shared::LYServerMessageEvent;
+use lyserver_plugin_common::{plugin_config_preference_prefix, resolve_plugin_config, LYServerPluginCapability, LYServerPluginMetadata};
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::LYServerSharedData;
-use wasmtime::{Caller, Module};
-use wasmtime_wasi::preview1::{WasiP1Ctx};
+use lyserver_shared_data::{LYServerDatabaseValue, LYServerPluginInstance, LYServerPluginResourceUsage, LYServerPreferencesService, LYServerSharedData, LYServerSharedDataDatabase as _, LYServerSharedDataDirectories as _, LYServerSharedDataPlugins as _, LYServerSharedDataResources as _, LYServerSharedDataServices as _};
+use wasmtime::{component::Component, Caller, Module};
+use wasmtime_wasi::{p2::WasiCtxBuilder, preview1::WasiP1Ctx, DirPerms, FilePerms};
 
-use crate::plugin_impl::LYServerWASMPlugin;
+use crate::{abi::check_module_abi, limits::{create_plugin_resource_usage, LYServerWASMResourceLimiter}, component_impl::LYServerWASMComponentPlugin, plugin_impl::{LYServerWASMGuestState, LYServerWASMPlugin}};
 
 pub const LYSERVER_PLUGIN_ABI_INIT_METHOD: &str = "lyserver_plugin_init";
 pub const LYSERVER_PLUGIN_ABI_DESTROY_METHOD: &str = "lyserver_plugin_destroy";
@@ -19,8 +23,27 @@ pub const LYSERVER_PLUGIN_ABI_LOG_WARN_METHOD: &str = "lyserver_plugin_log_warn"
 pub const LYSERVER_PLUGIN_ABI_LOG_ERROR_METHOD: &str = "lyserver_plugin_log_error";
 pub const LYSERVER_PLUGIN_ABI_LOG_DEBUG_METHOD: &str = "lyserver_plugin_log_debug";
 pub const LYSERVER_PLUGIN_ABI_RECEIVE_MESSAGE_METHOD: &str = "lyserver_plugin_receive_message";
+pub const LYSERVER_PLUGIN_ABI_TRY_RECEIVE_MESSAGE_METHOD: &str = "lyserver_plugin_try_receive_message";
 pub const LYSERVER_PLUGIN_ABI_SEND_MESSAGE_METHOD: &str = "lyserver_plugin_send_message";
 pub const LYSERVER_PLUGIN_ABI_ALLOC_METHOD: &str = "lyserver_plugin_alloc";
+pub const LYSERVER_PLUGIN_ABI_FREE_METHOD: &str = "lyserver_plugin_free";
+pub const LYSERVER_PLUGIN_ABI_INVOKE_METHOD: &str = "lyserver_plugin_invoke";
+pub const LYSERVER_PLUGIN_ABI_INVOKE_PLUGIN_METHOD: &str = "lyserver_plugin_invoke_plugin";
+pub const LYSERVER_PLUGIN_ABI_DATABASE_QUERY_METHOD: &str = "lyserver_plugin_database_query";
+pub const LYSERVER_PLUGIN_ABI_PREFERENCES_GET_METHOD: &str = "lyserver_plugin_preferences_get";
+pub const LYSERVER_PLUGIN_ABI_PREFERENCES_SET_METHOD: &str = "lyserver_plugin_preferences_set";
+pub const LYSERVER_PLUGIN_ABI_SERVICE_CALL_METHOD: &str = "lyserver_plugin_service_call";
+pub const LYSERVER_PLUGIN_ABI_KV_GET_METHOD: &str = "lyserver_plugin_kv_get";
+pub const LYSERVER_PLUGIN_ABI_KV_SET_METHOD: &str = "lyserver_plugin_kv_set";
+pub const LYSERVER_PLUGIN_ABI_KV_DELETE_METHOD: &str = "lyserver_plugin_kv_delete";
+pub const LYSERVER_PLUGIN_ABI_KV_LIST_METHOD: &str = "lyserver_plugin_kv_list";
+pub const LYSERVER_PLUGIN_ABI_KV_COMPARE_AND_SWAP_METHOD: &str = "lyserver_plugin_kv_compare_and_swap";
+
+tokio::task_local! {
+    /// Plugins with a call in progress on the current task, the outermost first. Their stores are locked
+    /// until the call returns, so invoking any of them again would wait forever.
+    static PLUGIN_INVOKE_CHAIN: Vec<String>;
+}
 
 macro_rules! add_linker_func {
     ($linker:expr, $name:expr, $handler:expr) => {
@@ -36,15 +59,13 @@ pub fn mutate_linker<'a>(
     plugin_shared_data: Arc<LYServerPluginSharedData>,
 ) -> &'a mut wasmtime::Linker<LYServerWASMLinkerState> {
     let plugin_id = Arc::new(metadata.id.clone());
+    let fuel_per_call = get_plugin_fuel_per_call(metadata, &plugin_shared_data.app_shared_data);
 
     add_linker_func!(
         linker,
         LYSERVER_PLUGIN_ABI_STDOUT_WRITE_METHOD,
         |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len): (i32, i32)| Box::new(async move {
-            let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-            let start = ptr as usize;
-            let end = start + len as usize;
-            let data = mem.data(&caller)[start..end].to_vec();
+            let data = read_guest_memory(&mut caller, ptr, len)?;
             let msg = String::from_utf8_lossy(&data);
             println!("{}", msg);
             Ok(())
@@ -59,10 +80,7 @@ pub fn mutate_linker<'a>(
             let plugin_id_clone = plugin_id_clone.clone();
 
             async move {
-                let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let start = ptr as usize;
-                let end = start + len as usize;
-                let data = mem.data(&caller)[start..end].to_vec();
+                let data = read_guest_memory(&mut caller, ptr, len)?;
                 let msg = String::from_utf8_lossy(&data);
                 log::info!("[Plugin {}]: {}", &plugin_id_clone, msg);
                 Ok(())
@@ -78,10 +96,7 @@ pub fn mutate_linker<'a>(
             let plugin_id_clone = plugin_id_clone.clone();
 
             async move {
-                let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let start = ptr as usize;
-                let end = start + len as usize;
-                let data = mem.data(&caller)[start..end].to_vec();
+                let data = read_guest_memory(&mut caller, ptr, len)?;
                 let msg = String::from_utf8_lossy(&data);
                 log::warn!("[Plugin {}]: {}", &plugin_id_clone, msg);
                 Ok(())
@@ -97,10 +112,7 @@ pub fn mutate_linker<'a>(
             let plugin_id_clone = plugin_id_clone.clone();
 
             async move {
-                let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let start = ptr as usize;
-                let end = start + len as usize;
-                let data = mem.data(&caller)[start..end].to_vec();
+                let data = read_guest_memory(&mut caller, ptr, len)?;
                 let msg = String::from_utf8_lossy(&data);
                 log::error!("[Plugin {}]: {}", &plugin_id_clone, msg);
                 Ok(())
@@ -116,10 +128,7 @@ pub fn mutate_linker<'a>(
             let plugin_id_clone = plugin_id_clone.clone();
 
             async move {
-                let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let start = ptr as usize;
-                let end = start + len as usize;
-                let data = mem.data(&caller)[start..end].to_vec();
+                let data = read_guest_memory(&mut caller, ptr, len)?;
                 let msg = String::from_utf8_lossy(&data);
                 log::debug!("[Plugin {}]: {}", &plugin_id_clone, msg);
                 Ok(())
@@ -132,44 +141,46 @@ pub fn mutate_linker<'a>(
         linker,
         LYSERVER_PLUGIN_ABI_RECEIVE_MESSAGE_METHOD,
         move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ret_ptr_ptr, ret_len_ptr): (i32, i32)| Box::new({
-            let plugin_id_clone = plugin_id.clone();
             let plugin_shared_data_clone = plugin_shared_data_clone.clone();
 
             async move {
-                let memory = caller
-                    .get_export("memory")
-                    .and_then(|e| e.into_memory())
-                    .expect("memory export not found");
-
                 let message = plugin_shared_data_clone.receive_event().await;
 
-                if let Some(response) = message {
-                    let serialized = serde_cbor::to_vec(&response)
-                        .expect("Failed to serialize message");
-
-                    let response_len = serialized.len();
-
-                    let alloc = caller
-                        .get_export(LYSERVER_PLUGIN_ABI_ALLOC_METHOD)
-                        .and_then(|e| e.into_func())
-                        .expect(format!(
-                            "Plugin {} does not export '{}'",
-                            &plugin_id_clone,
-                            LYSERVER_PLUGIN_ABI_ALLOC_METHOD
-                        ).as_str());
-
-                    let ptr = alloc
-                        .typed::<i32, i32>(&caller)?
-                        .call_async(&mut caller, response_len as i32)
-                        .await?;
-
-                    memory.write(&mut caller, ptr as usize, &serialized)?;
-                    memory.write(&mut caller, ret_ptr_ptr as usize, &(ptr as i32).to_le_bytes())?;
-                    memory.write(&mut caller, ret_len_ptr.try_into().unwrap(), &(response_len as i32).to_le_bytes())?;
-                } else {
-                    memory.write(&mut caller, ret_ptr_ptr as usize, &(0i32).to_le_bytes())?;
-                    memory.write(&mut caller, ret_len_ptr.try_into().unwrap(), &(0i32).to_le_bytes())?;
-                }
+                // Event loop guests never return from init, so every received event starts a new call budget
+                caller.set_fuel(fuel_per_call)?;
+
+                // A closed channel hands the guest a null pointer, which ends its event loop
+                let result: anyhow::Result<Vec<u8>> = match message {
+                    Some(event) => Ok(serde_cbor::to_vec(&event)?),
+                    None => Ok(Vec::new()),
+                };
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await?;
+
+                Ok(())
+            }
+        })
+    );
+
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_TRY_RECEIVE_MESSAGE_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ret_ptr_ptr, ret_len_ptr): (i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                // Never waits, guests get a null pointer back when no event is queued
+                let result: anyhow::Result<Vec<u8>> = match plugin_shared_data_clone.receive_event_sync() {
+                    Some(event) => {
+                        caller.set_fuel(fuel_per_call)?;
+
+                        Ok(serde_cbor::to_vec(&event)?)
+                    }
+                    None => Ok(Vec::new()),
+                };
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await?;
 
                 Ok(())
             }
@@ -184,14 +195,17 @@ pub fn mutate_linker<'a>(
             let plugin_shared_data_clone = plugin_shared_data_clone.clone();
     
             async move {
-                let memory = caller
-                    .get_export("memory")
-                    .and_then(|e| e.into_memory())
-                    .expect("memory export not found");
-    
-                let start = ptr as usize;
-                let end = start + len as usize;
-                let data = memory.data(&caller)[start..end].to_vec();
+                let data = read_guest_memory(&mut caller, ptr, len)?;
+
+                // Guests which announce themselves from inside init keep polling there instead of returning
+                if *caller.data().guest_state.borrow() == LYServerWASMGuestState::Initializing {
+                    let is_init_event = serde_cbor::from_slice::<LYServerMessageEvent>(&data)
+                        .is_ok_and(|event| event.event_type == "plugin_init");
+
+                    if is_init_event {
+                        caller.data().guest_state.send_replace(LYServerWASMGuestState::Polling);
+                    }
+                }
 
                 let result_code: u32 = tokio::task::spawn_blocking(move || {
                     let result_code: u32 = match plugin_shared_data_clone.dispatch_raw_event(data) {
@@ -199,24 +213,521 @@ pub fn mutate_linker<'a>(
                         Err(_) => 1,
                     };
 
-                    return result_code;
+                    result_code
                 }).await?;
 
-                let ret_ptr = ret_ptr as usize;
-                let ret_bytes = result_code.to_le_bytes();
-                memory.data_mut(&mut caller)[ret_ptr..ret_ptr + 4]
-                    .copy_from_slice(&ret_bytes);
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|e| e.into_memory())
+                    .ok_or_else(|| anyhow::anyhow!("memory export not found"))?;
+
+                memory.write(&mut caller, ret_ptr as usize, &result_code.to_le_bytes())?;
     
                 Ok(())
             }
         })
     );
     
+    // Everything below is only available to plugins which were granted the matching capability,
+    // modules importing anything else fail to instantiate
+    // Plugins may always query the databases they declare themselves
+    if metadata.has_capability(&LYServerPluginCapability::DatabaseQuery) || !metadata.databases.is_empty() {
+        let plugin_shared_data_clone = plugin_shared_data.clone();
+        let plugin_id_clone = plugin_id.clone();
+        let own_databases = Arc::new(metadata.databases.iter().map(|database| database.name.clone()).collect::<Vec<_>>());
+        let can_query_server = metadata.has_capability(&LYServerPluginCapability::DatabaseQuery);
+        add_linker_func!(
+            linker,
+            LYSERVER_PLUGIN_ABI_DATABASE_QUERY_METHOD,
+            move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+                let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+                let plugin_id_clone = plugin_id_clone.clone();
+                let own_databases = own_databases.clone();
+
+                async move {
+                    let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                    let result = async {
+                        let (database, query, args) = serde_cbor::from_slice::<(String, String, Vec<String>)>(&request)
+                            .map_err(|e| anyhow::anyhow!("Invalid database query: {}", e))?;
+
+                        let args = args.into_iter()
+                            .map(LYServerDatabaseValue::from)
+                            .collect();
+
+                        // The plugin's own databases shadow the server ones of the same name
+                        let result = if own_databases.contains(&database) {
+                            plugin_shared_data_clone.app_shared_data.query_plugin_database(&plugin_id_clone, &database, &query, args).await?
+                        } else if !can_query_server || database == "preferences" {
+                            // Preferences are only reachable through the preferences.* capabilities
+                            return Err(anyhow::anyhow!("Access to the '{}' database is not allowed", database));
+                        } else {
+                            plugin_shared_data_clone.app_shared_data.query(&database, &query, args).await?
+                        };
+
+                        Ok(serde_cbor::to_vec(&result)?)
+                    }.await;
+
+                    write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+                }
+            })
+        );
+    }
+
+    if metadata.has_capability(&LYServerPluginCapability::PreferencesRead) {
+        let plugin_shared_data_clone = plugin_shared_data.clone();
+        add_linker_func!(
+            linker,
+            LYSERVER_PLUGIN_ABI_PREFERENCES_GET_METHOD,
+            move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+                let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+                async move {
+                    let key = read_guest_memory(&mut caller, ptr, len)?;
+
+                    let result = async {
+                        let key = String::from_utf8(key)
+                            .map_err(|e| anyhow::anyhow!("Preference key is not valid UTF-8: {}", e))?;
+
+                        let result = get_preferences_service(&plugin_shared_data_clone)?.get(&key).await?;
+
+                        Ok(serde_cbor::to_vec(&result)?)
+                    }.await;
+
+                    write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+                }
+            })
+        );
+    }
+
+    if metadata.has_capability(&LYServerPluginCapability::PreferencesWrite) {
+        let plugin_shared_data_clone = plugin_shared_data.clone();
+        add_linker_func!(
+            linker,
+            LYSERVER_PLUGIN_ABI_PREFERENCES_SET_METHOD,
+            move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+                let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+                async move {
+                    let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                    let result = async {
+                        let (key, value) = serde_cbor::from_slice::<(String, serde_json::Value)>(&request)
+                            .map_err(|e| anyhow::anyhow!("Invalid preference: {}", e))?;
+
+                        let result = get_preferences_service(&plugin_shared_data_clone)?.set(&key, value).await?;
+
+                        Ok(serde_cbor::to_vec(&result)?)
+                    }.await;
+
+                    write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+                }
+            })
+        );
+    }
+
+    // Every plugin gets its own KV namespace, so these need no capability
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_KV_GET_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                let result = async {
+                    let key = serde_cbor::from_slice::<String>(&request)
+                        .map_err(|e| anyhow::anyhow!("Invalid KV key: {}", e))?;
+
+                    let result = plugin_shared_data_clone.kv_get(&key).await?;
+
+                    Ok(serde_cbor::to_vec(&result)?)
+                }.await;
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+            }
+        })
+    );
+
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_KV_SET_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                let result = async {
+                    let (key, value) = serde_cbor::from_slice::<(String, Vec<u8>)>(&request)
+                        .map_err(|e| anyhow::anyhow!("Invalid KV entry: {}", e))?;
+
+                    plugin_shared_data_clone.kv_set(&key, value).await?;
+
+                    Ok(serde_cbor::to_vec(&())?)
+                }.await;
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+            }
+        })
+    );
+
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_KV_DELETE_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                let result = async {
+                    let key = serde_cbor::from_slice::<String>(&request)
+                        .map_err(|e| anyhow::anyhow!("Invalid KV key: {}", e))?;
+
+                    let result = plugin_shared_data_clone.kv_delete(&key).await?;
+
+                    Ok(serde_cbor::to_vec(&result)?)
+                }.await;
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+            }
+        })
+    );
+
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_KV_LIST_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                let result = async {
+                    let prefix = serde_cbor::from_slice::<String>(&request)
+                        .map_err(|e| anyhow::anyhow!("Invalid KV prefix: {}", e))?;
+
+                    let result = plugin_shared_data_clone.kv_list_prefix(&prefix).await?;
+
+                    Ok(serde_cbor::to_vec(&result)?)
+                }.await;
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+            }
+        })
+    );
+
+    let plugin_shared_data_clone = plugin_shared_data.clone();
+    add_linker_func!(
+        linker,
+        LYSERVER_PLUGIN_ABI_KV_COMPARE_AND_SWAP_METHOD,
+        move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+            let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+
+            async move {
+                let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                let result = async {
+                    let (key, expected, value) = serde_cbor::from_slice::<(String, Option<Vec<u8>>, Option<Vec<u8>>)>(&request)
+                        .map_err(|e| anyhow::anyhow!("Invalid KV swap: {}", e))?;
+
+                    let result = plugin_shared_data_clone.kv_compare_and_swap(&key, expected, value).await?;
+
+                    Ok(serde_cbor::to_vec(&result)?)
+                }.await;
+
+                write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+            }
+        })
+    );
+
+    let service_capabilities = metadata.capabilities.iter()
+        .filter_map(|capability| match capability {
+            LYServerPluginCapability::ServiceCall(service) => Some(service.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if !service_capabilities.is_empty() {
+        let plugin_shared_data_clone = plugin_shared_data.clone();
+        let service_capabilities = Arc::new(service_capabilities);
+        add_linker_func!(
+            linker,
+            LYSERVER_PLUGIN_ABI_SERVICE_CALL_METHOD,
+            move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+                let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+                let service_capabilities = service_capabilities.clone();
+
+                async move {
+                    let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                    let result = async {
+                        let (service, method, data) = serde_cbor::from_slice::<(String, String, Vec<u8>)>(&request)
+                            .map_err(|e| anyhow::anyhow!("Invalid service call: {}", e))?;
+
+                        if !service_capabilities.contains(&service) {
+                            return Err(anyhow::anyhow!("Calling the '{}' service is not allowed", service));
+                        }
+
+                        let serialized_service = plugin_shared_data_clone.app_shared_data
+                            .get_serialized_service(&service)
+                            .ok_or_else(|| anyhow::anyhow!("Service '{}' is not available to plugins", service))?;
+
+                        serialized_service.call(&method, data).await
+                    }.await;
+
+                    write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+                }
+            })
+        );
+    }
+
+    let invoke_capabilities = metadata.capabilities.iter()
+        .filter_map(|capability| match capability {
+            LYServerPluginCapability::PluginsInvoke(target) => Some(target.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if !invoke_capabilities.is_empty() {
+        let plugin_shared_data_clone = plugin_shared_data.clone();
+        let invoke_capabilities = Arc::new(invoke_capabilities);
+        let plugin_id_clone = plugin_id.clone();
+        add_linker_func!(
+            linker,
+            LYSERVER_PLUGIN_ABI_INVOKE_PLUGIN_METHOD,
+            move |mut caller: Caller<'_, LYServerWASMLinkerState>, (ptr, len, ret_ptr_ptr, ret_len_ptr): (i32, i32, i32, i32)| Box::new({
+                let plugin_shared_data_clone = plugin_shared_data_clone.clone();
+                let invoke_capabilities = invoke_capabilities.clone();
+                let plugin_id_clone = plugin_id_clone.clone();
+
+                async move {
+                    let request = read_guest_memory(&mut caller, ptr, len)?;
+
+                    let result = async {
+                        let (target, method, args) = serde_cbor::from_slice::<(String, String, Vec<u8>)>(&request)
+                            .map_err(|e| anyhow::anyhow!("Invalid plugin invocation: {}", e))?;
+
+                        if !invoke_capabilities.iter().any(|allowed| allowed == "*" || *allowed == target) {
+                            return Err(anyhow::anyhow!("Invoking plugin '{}' is not allowed", target));
+                        }
+
+                        let target_metadata = plugin_shared_data_clone.app_shared_data
+                            .get_plugin_metadata_by_id(&target)
+                            .await
+                            .ok_or_else(|| anyhow::anyhow!("Plugin '{}' is not loaded", target))?;
+
+                        // Built-in plugins would run the request with the server's privileges, guests reach them
+                        // through the host functions which check their own capabilities instead
+                        if target_metadata.wasm_entry_point.is_none() {
+                            return Err(anyhow::anyhow!("Built-in plugin '{}' cannot be invoked by other plugins", target));
+                        }
+
+                        let mut chain = PLUGIN_INVOKE_CHAIN.try_with(|chain| chain.clone()).unwrap_or_default();
+                        if chain.last() != Some(&*plugin_id_clone) {
+                            chain.push(plugin_id_clone.to_string());
+                        }
+
+                        if chain.contains(&target) {
+                            return Err(anyhow::anyhow!("Invoking plugin '{}' would deadlock, it is already being called ({} -> {})", target, chain.join(" -> "), target));
+                        }
+
+                        let plugin = plugin_shared_data_clone.app_shared_data
+                            .get_plugin_by_id(&target)
+                            .await
+                            .ok_or_else(|| anyhow::anyhow!("Plugin '{}' is not loaded", target))?;
+
+                        chain.push(target);
+                        PLUGIN_INVOKE_CHAIN.scope(chain, plugin.invoke(&method, args)).await
+                    }.await;
+
+                    write_guest_response(&mut caller, result, ret_ptr_ptr, ret_len_ptr).await
+                }
+            })
+        );
+    }
+
     linker
 }
 
+/// The fuel a plugin may consume per call, unlimited when the budget is `0`.
+pub fn get_plugin_fuel_per_call(metadata: &LYServerPluginMetadata, shared_data: &LYServerSharedData) -> u64 {
+    match metadata.limits.fuel_per_call.unwrap_or(shared_data.plugin_fuel_per_call) {
+        0 => u64::MAX,
+        fuel_per_call => fuel_per_call,
+    }
+}
+
+/// Copies a buffer out of guest memory. The guest chooses `ptr` and `len`, so they are checked against the
+/// size of its memory before anything is allocated.
+fn read_guest_memory(caller: &mut Caller<'_, LYServerWASMLinkerState>, ptr: i32, len: i32) -> anyhow::Result<Vec<u8>> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow::anyhow!("memory export not found"))?;
+
+    let start = usize::try_from(ptr).map_err(|_| anyhow::anyhow!("Invalid guest pointer {}", ptr))?;
+    let len = usize::try_from(len).map_err(|_| anyhow::anyhow!("Invalid guest buffer length {}", len))?;
+
+    let end = start.checked_add(len)
+        .filter(|end| *end <= memory.data_size(&*caller))
+        .ok_or_else(|| anyhow::anyhow!("Guest buffer of {} bytes at {} is outside of its memory", len, start))?;
+
+    Ok(memory.data(&*caller)[start..end].to_vec())
+}
+
+/// Copies the result of a host call into guest memory, errors are passed to the guest as a UTF-8 message.
+///
+/// Returns `0` on success and `1` on error.
+async fn write_guest_response(
+    caller: &mut Caller<'_, LYServerWASMLinkerState>,
+    result: anyhow::Result<Vec<u8>>,
+    ret_ptr_ptr: i32,
+    ret_len_ptr: i32,
+) -> anyhow::Result<i32> {
+    let (result_code, data) = match result {
+        Ok(data) => (0, data),
+        Err(e) => (1, e.to_string().into_bytes()),
+    };
+
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow::anyhow!("memory export not found"))?;
+
+    let alloc = caller
+        .get_export(LYSERVER_PLUGIN_ABI_ALLOC_METHOD)
+        .and_then(|e| e.into_func())
+        .ok_or_else(|| anyhow::anyhow!("Plugin does not export '{}'", LYSERVER_PLUGIN_ABI_ALLOC_METHOD))?;
+
+    let ptr = alloc
+        .typed::<i32, i32>(&*caller)?
+        .call_async(&mut *caller, data.len() as i32)
+        .await?;
+
+    memory.write(&mut *caller, ptr as usize, &data)?;
+    memory.write(&mut *caller, ret_ptr_ptr as usize, &ptr.to_le_bytes())?;
+    memory.write(&mut *caller, ret_len_ptr as usize, &(data.len() as i32).to_le_bytes())?;
+
+    Ok(result_code)
+}
+
+pub use lyserver_shared_data::LYSERVER_PLUGIN_DATA_DIR;
+
+/// A host directory mounted into the WASI filesystem of a plugin.
+#[derive(Debug, Clone)]
+pub struct LYServerWASMPreopen {
+    pub host_path: PathBuf,
+    pub guest_path: String,
+    pub writable: bool,
+}
+
+/// Environment variable guests find their resolved `[config]` in, as a JSON object.
+pub const LYSERVER_PLUGIN_CONFIG_ENV: &str = "LYSERVER_PLUGIN_CONFIG";
+
+/// Everything a plugin's WASI context is built from, resolved again whenever the plugin is restarted.
+#[derive(Debug, Clone)]
+pub struct LYServerWASMSandbox {
+    pub preopens: Vec<LYServerWASMPreopen>,
+    pub config: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Resolves the `[config]` of a plugin, overriding its defaults with the `plugins.<id>.*` preferences.
+pub async fn resolve_plugin_config_values(metadata: &LYServerPluginMetadata, shared_data: &LYServerSharedData) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    if metadata.config.is_empty() {
+        return Ok(serde_json::Map::new());
+    }
+
+    let prefix = plugin_config_preference_prefix(&metadata.id);
+
+    let overrides = match shared_data.get_service::<dyn LYServerPreferencesService>() {
+        Some(preferences) => preferences.get_all().await?
+            .into_iter()
+            .filter_map(|preference| {
+                let key = preference.key().strip_prefix(&prefix)?.to_string();
+                Some((key, preference.value()))
+            })
+            .collect(),
+        None => {
+            log::debug!("Preferences service is not available, plugin '{}' is configured with its defaults", metadata.id);
+            BTreeMap::new()
+        }
+    };
+
+    resolve_plugin_config(&metadata.config, overrides)
+        .map_err(|e| anyhow::Error::msg(format!("Plugin '{}' is misconfigured: {}", metadata.id, e)))
+}
+
+/// Resolves the directories mounted into a plugin, its private data directory and the `fs.read` grants of its manifest.
+pub fn resolve_plugin_preopens(metadata: &LYServerPluginMetadata, shared_data: &LYServerSharedData) -> anyhow::Result<Vec<LYServerWASMPreopen>> {
+    let data_path = shared_data.resolve_plugin_data_path(&metadata.id);
+
+    std::fs::create_dir_all(&data_path)
+        .map_err(|e| anyhow::Error::msg(format!("Failed to create data directory '{}' for plugin '{}': {}", data_path.display(), metadata.id, e)))?;
+
+    let mut preopens = vec![LYServerWASMPreopen {
+        host_path: data_path,
+        guest_path: LYSERVER_PLUGIN_DATA_DIR.to_string(),
+        writable: true,
+    }];
+
+    for capability in &metadata.capabilities {
+        if let LYServerPluginCapability::FsRead(path) = capability {
+            let host_path = shared_data.resolve_plugin_fs_grant(Path::new(path))
+                .map_err(|e| anyhow::Error::msg(format!("Failed to grant '{}' to plugin '{}': {}", capability, metadata.id, e)))?;
+
+            preopens.push(LYServerWASMPreopen {
+                host_path,
+                guest_path: path.clone(),
+                writable: false,
+            });
+        }
+    }
+
+    Ok(preopens)
+}
+
+/// WASI context of a plugin with its resolved directories preopened and config in the environment.
+fn create_wasi_ctx_builder(metadata: &LYServerPluginMetadata, sandbox: &LYServerWASMSandbox) -> anyhow::Result<WasiCtxBuilder> {
+    let mut wasi_ctx_builder = WasiCtxBuilder::new();
+    wasi_ctx_builder.inherit_stdio();
+    wasi_ctx_builder.env(LYSERVER_PLUGIN_CONFIG_ENV, serde_json::to_string(&sandbox.config)?);
+
+    for preopen in &sandbox.preopens {
+        let (dir_perms, file_perms) = match preopen.writable {
+            true => (DirPerms::all(), FilePerms::all()),
+            false => (DirPerms::READ, FilePerms::READ),
+        };
+
+        wasi_ctx_builder
+            .preopened_dir(&preopen.host_path, &preopen.guest_path, dir_perms, file_perms)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to mount '{}' into plugin '{}': {}", preopen.host_path.display(), metadata.id, e)))?;
+    }
+
+    Ok(wasi_ctx_builder)
+}
+
+/// Whether `bytes` hold a component rather than a core module, both share the `\0asm` magic but differ in version.
+fn is_component(bytes: &[u8]) -> bool {
+    bytes.get(4..8) == Some(&[0x0d, 0x00, 0x01, 0x00])
+}
+
+fn get_preferences_service(plugin_shared_data: &LYServerPluginSharedData) -> anyhow::Result<Arc<dyn LYServerPreferencesService>> {
+    plugin_shared_data.app_shared_data
+        .get_service::<dyn LYServerPreferencesService>()
+        .ok_or_else(|| anyhow::anyhow!("Preferences service is not available"))
+}
+
 pub struct LYServerWASMLinkerState {
     wasi_ctx: WasiP1Ctx,
+    limiter: LYServerWASMResourceLimiter,
+    guest_state: Arc<tokio::sync::watch::Sender<LYServerWASMGuestState>>,
 }
 
 
@@ -229,24 +740,172 @@ impl LYServerWASMLoader {
     pub fn new(shared_data: Arc<LYServerSharedData>) -> Self {
         let mut config = wasmtime::Config::new();
         config.async_support(true);
+        config.consume_fuel(true);
         let engine = wasmtime::Engine::new(&config)
             .expect("Failed to create Wasmtime engine");
 
         Self { engine, shared_data }
     }
 
-    pub async fn create_wasm_plugin_instance(&self, metadata: &LYServerPluginMetadata, wasm_entry_point_path: &Path, plugin_shared_data: Arc<LYServerPluginSharedData>) -> anyhow::Result<plugin_impl::LYServerWASMPlugin> {
+    /// Loads a plugin from a preview 2 component or, for plugins built against the `extern "C"` ABI, a preview 1 core module.
+    pub async fn create_wasm_plugin_instance(&self, metadata: &LYServerPluginMetadata, wasm_entry_point_path: &Path, plugin_shared_data: Arc<LYServerPluginSharedData>) -> anyhow::Result<LYServerPluginInstance> {
+        plugin_shared_data.set_capabilities(metadata.capabilities.clone());
+
+        let bytes = std::fs::read(wasm_entry_point_path)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to read '{}': {}", wasm_entry_point_path.display(), e)))?;
+
+        let fuel_per_call = get_plugin_fuel_per_call(metadata, &self.shared_data);
+
+        // Checked before the resource usage is registered, so a rejected grant or config leaves nothing to clean up
+        let sandbox = LYServerWASMSandbox {
+            preopens: resolve_plugin_preopens(metadata, &self.shared_data)?,
+            config: resolve_plugin_config_values(metadata, &self.shared_data).await?,
+        };
+
+        let resource_usage = Arc::new(create_plugin_resource_usage(metadata, &self.shared_data));
+        self.shared_data.register_plugin_resource_usage(&metadata.id, Arc::clone(&resource_usage));
+
+        let wasm_plugin: anyhow::Result<LYServerPluginInstance> = if is_component(&bytes) {
+            self.create_component_plugin(metadata, &bytes, plugin_shared_data, fuel_per_call, resource_usage, sandbox).await
+        } else {
+            self.create_module_plugin(metadata, &bytes, plugin_shared_data, fuel_per_call, resource_usage, sandbox).await
+        };
+
+        wasm_plugin.map_err(|e| {
+            self.shared_data.unregister_plugin_resource_usage(&metadata.id);
+
+            anyhow::Error::msg(format!("Failed to create WASM plugin instance from '{}': {}", wasm_entry_point_path.display(), e))
+        })
+    }
+
+    async fn create_module_plugin(&self, metadata: &LYServerPluginMetadata, bytes: &[u8], plugin_shared_data: Arc<LYServerPluginSharedData>, fuel_per_call: u64, resource_usage: Arc<LYServerPluginResourceUsage>, sandbox: LYServerWASMSandbox) -> anyhow::Result<LYServerPluginInstance> {
+        let module = Module::new(&self.engine, bytes)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to load module: {}", e)))?;
+
+        let abi = check_module_abi(metadata, &module, bytes)?;
+
         let mut linker: wasmtime::Linker<LYServerWASMLinkerState> = wasmtime::Linker::new(&self.engine);
-        let linker = mutate_linker(&mut linker, &metadata, plugin_shared_data.clone());
+        let linker = mutate_linker(&mut linker, metadata, plugin_shared_data.clone());
         wasmtime_wasi::preview1::add_to_linker_async(linker, |c| &mut c.wasi_ctx)?;
 
-        let module = Module::from_file(&self.engine, wasm_entry_point_path)
-            .map_err(|e| anyhow::Error::msg(format!("Failed to load module from file '{}': {}", wasm_entry_point_path.display(), e)))?;
+        if !abi.shims.is_empty() {
+            log::info!("Plugin '{}' was built for ABI v{}, applying {} shim(s)", metadata.id, abi.version, abi.shims.len());
+
+            linker.allow_shadowing(true);
+
+            for shim in abi.shims {
+                shim(linker, metadata, plugin_shared_data.clone())?;
+            }
+        }
+
+        let wasm_plugin = LYServerWASMPlugin::new(metadata.clone(), linker, &module, plugin_shared_data, fuel_per_call, resource_usage, sandbox).await?;
+
+        Ok(Arc::new(wasm_plugin))
+    }
+
+    async fn create_component_plugin(&self, metadata: &LYServerPluginMetadata, bytes: &[u8], plugin_shared_data: Arc<LYServerPluginSharedData>, fuel_per_call: u64, resource_usage: Arc<LYServerPluginResourceUsage>, sandbox: LYServerWASMSandbox) -> anyhow::Result<LYServerPluginInstance> {
+        let component = Component::new(&self.engine, bytes)
+            .map_err(|e| anyhow::Error::msg(format!("Failed to load component: {}", e)))?;
+
+        let wasm_plugin = LYServerWASMComponentPlugin::new(metadata.clone(), &self.engine, &component, plugin_shared_data, fuel_per_call, resource_usage, sandbox).await?;
 
-        let wasm_plugin = LYServerWASMPlugin::new(metadata.clone(), &self.engine, linker, &module, plugin_shared_data)
+        Ok(Arc::new(wasm_plugin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lyserver_plugin_common::LYServerPluginMetadata;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::plugin_impl::tests::{create_test_shared_data, load_test_module, test_module, wat_bytes};
+
+    use super::*;
+
+    /// A guest whose invoke forwards to `target`, or answers with `pong` without a target.
+    fn invoke_test_module(target: Option<&str>) -> String {
+        let extra = match target {
+            Some(target) => {
+                let request = serde_cbor::to_vec(&(target, "ping", Vec::<u8>::new())).unwrap();
+
+                format!(r#"
+                    (import "env" "lyserver_plugin_invoke_plugin" (func $invoke_plugin (param i32 i32 i32 i32) (result i32)))
+                    (data (memory 0) (i32.const 0) "{}")
+                    (func (export "lyserver_plugin_invoke") (param i32 i32 i32 i32) (result i32)
+                        (call $invoke_plugin (i32.const 0) (i32.const {}) (local.get 2) (local.get 3)))
+                "#, wat_bytes(&request), request.len())
+            }
+            None => r#"
+                (data (memory 0) (i32.const 0) "pong")
+                (func (export "lyserver_plugin_invoke") (param i32 i32 i32 i32) (result i32)
+                    (i32.store (local.get 2) (i32.const 0))
+                    (i32.store (local.get 3) (i32.const 4))
+                    (i32.const 0))
+            "#.to_string(),
+        };
+
+        test_module("", "", &extra)
+    }
+
+    /// Loads guests as `(id, invoke target)` and registers them as loaded plugins.
+    async fn load_invoke_test_plugins(name: &str, plugins: &[(&str, Option<&str>)]) -> Vec<LYServerPluginInstance> {
+        let (shared_data, _bus) = create_test_shared_data(name).await;
+        let mut instances = Vec::new();
+
+        for (id, target) in plugins {
+            let mut metadata = LYServerPluginMetadata::builder()
+                .id(*id)
+                .name(*id)
+                .version("1.0.0")
+                .wasm_entry_point(format!("{}.wasm", id));
+
+            if let Some(target) = target {
+                metadata = metadata.capability(LYServerPluginCapability::PluginsInvoke(target.to_string()));
+            }
+
+            let metadata = metadata.build();
+            let plugin = load_test_module(&shared_data, &metadata, &invoke_test_module(*target)).await;
+            plugin.init().await.unwrap();
+
+            shared_data.loaded_plugins.write().await.push((Arc::clone(&plugin), metadata, CancellationToken::new()));
+            instances.push(plugin);
+        }
+
+        instances
+    }
+
+    async fn invoke(plugin: &LYServerPluginInstance) -> anyhow::Result<Vec<u8>> {
+        // Runs into the timeout instead of hanging when the chain is not detected
+        tokio::time::timeout(std::time::Duration::from_secs(5), plugin.invoke("ping", Vec::new()))
             .await
-            .map_err(|e| anyhow::Error::msg(format!("Failed to create WASM plugin instance from '{}': {}", wasm_entry_point_path.display(), e)))?;
+            .expect("invoke deadlocked")
+    }
+
+    #[tokio::test]
+    async fn plugins_invoke_each_other() {
+        let plugins = load_invoke_test_plugins("invoke", &[("a@plugin", Some("b@plugin")), ("b@plugin", None)]).await;
+
+        // The chain only lives as long as a call, so calling again works
+        assert_eq!(invoke(&plugins[0]).await.unwrap(), b"pong");
+        assert_eq!(invoke(&plugins[0]).await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn invoking_a_plugin_which_is_already_being_called_fails() {
+        let plugins = load_invoke_test_plugins("invoke-cycle", &[("a@plugin", Some("b@plugin")), ("b@plugin", Some("a@plugin"))]).await;
+
+        let error = invoke(&plugins[0]).await.unwrap_err();
+
+        assert!(error.to_string().contains("Invoking plugin 'a@plugin' would deadlock"), "{}", error);
+        assert!(error.to_string().contains("(a@plugin -> b@plugin -> a@plugin)"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn plugins_cannot_invoke_themselves() {
+        let plugins = load_invoke_test_plugins("invoke-self", &[("a@plugin", Some("a@plugin"))]).await;
+
+        let error = invoke(&plugins[0]).await.unwrap_err();
 
-        Ok(wasm_plugin)
+        assert!(error.to_string().contains("(a@plugin -> a@plugin)"), "{}", error);
     }
 }
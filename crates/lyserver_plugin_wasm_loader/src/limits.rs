@@ -0,0 +1,132 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use lyserver_plugin_common::LYServerPluginMetadata;
+use lyserver_shared_data::{LYServerPluginResourceUsage, LYServerSharedData};
+
+/// Enforces the memory, table and instance limits of a plugin store and records its usage.
+pub struct LYServerWASMResourceLimiter {
+    plugin_id: String,
+    usage: Arc<LYServerPluginResourceUsage>,
+}
+
+impl LYServerWASMResourceLimiter {
+    pub fn new(plugin_id: &str, usage: Arc<LYServerPluginResourceUsage>) -> Self {
+        Self {
+            plugin_id: plugin_id.to_string(),
+            usage,
+        }
+    }
+}
+
+impl wasmtime::ResourceLimiter for LYServerWASMResourceLimiter {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> anyhow::Result<bool> {
+        // Plugins can have more than one memory, the limit applies to all of them combined
+        let memory_bytes = self.usage.memory_bytes.load(Ordering::Relaxed).saturating_sub(current) + desired;
+
+        if memory_bytes > self.usage.max_memory_bytes {
+            log::warn!("[Plugin {}]: Denied growing memory to {} bytes, limit is {} bytes", self.plugin_id, memory_bytes, self.usage.max_memory_bytes);
+            return Ok(false);
+        }
+
+        self.usage.memory_bytes.store(memory_bytes, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> anyhow::Result<bool> {
+        let table_elements = self.usage.table_elements.load(Ordering::Relaxed).saturating_sub(current) + desired;
+
+        if table_elements > self.usage.max_table_elements {
+            log::warn!("[Plugin {}]: Denied growing tables to {} elements, limit is {} elements", self.plugin_id, table_elements, self.usage.max_table_elements);
+            return Ok(false);
+        }
+
+        self.usage.table_elements.store(table_elements, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.usage.max_instances
+    }
+}
+
+/// Resolves the limits of a plugin from its manifest, falling back to the server defaults.
+pub fn create_plugin_resource_usage(metadata: &LYServerPluginMetadata, shared_data: &LYServerSharedData) -> LYServerPluginResourceUsage {
+    LYServerPluginResourceUsage::new(
+        metadata.limits.max_memory_bytes.unwrap_or(shared_data.plugin_max_memory_bytes),
+        metadata.limits.max_table_elements.unwrap_or(shared_data.plugin_max_table_elements),
+        metadata.limits.max_instances.unwrap_or(shared_data.plugin_max_instances),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::ResourceLimiter as _;
+
+    use super::*;
+
+    const PAGE: usize = 64 * 1024;
+
+    fn create_limiter(max_memory_bytes: usize, max_table_elements: usize) -> (LYServerWASMResourceLimiter, Arc<LYServerPluginResourceUsage>) {
+        let usage = Arc::new(LYServerPluginResourceUsage::new(max_memory_bytes, max_table_elements, 1));
+
+        (LYServerWASMResourceLimiter::new("test@plugin", Arc::clone(&usage)), usage)
+    }
+
+    #[test]
+    fn memory_growth_is_recorded_until_the_limit() {
+        let (mut limiter, usage) = create_limiter(2 * PAGE, 0);
+
+        assert!(limiter.memory_growing(0, PAGE, None).unwrap());
+        assert!(limiter.memory_growing(PAGE, 2 * PAGE, None).unwrap());
+        assert_eq!(usage.memory_bytes.load(Ordering::Relaxed), 2 * PAGE);
+
+        assert!(!limiter.memory_growing(2 * PAGE, 3 * PAGE, None).unwrap());
+        assert_eq!(usage.memory_bytes.load(Ordering::Relaxed), 2 * PAGE);
+    }
+
+    #[test]
+    fn memory_limit_applies_to_all_memories_combined() {
+        let (mut limiter, usage) = create_limiter(3 * PAGE, 0);
+
+        assert!(limiter.memory_growing(0, 2 * PAGE, None).unwrap());
+        assert!(!limiter.memory_growing(0, 2 * PAGE, None).unwrap());
+        assert!(limiter.memory_growing(0, PAGE, None).unwrap());
+        assert_eq!(usage.memory_bytes.load(Ordering::Relaxed), 3 * PAGE);
+    }
+
+    #[test]
+    fn table_growth_is_recorded_until_the_limit() {
+        let (mut limiter, usage) = create_limiter(0, 10);
+
+        assert!(limiter.table_growing(0, 4, None).unwrap());
+        assert!(limiter.table_growing(0, 6, None).unwrap());
+        assert!(!limiter.table_growing(6, 7, None).unwrap());
+        assert_eq!(usage.table_elements.load(Ordering::Relaxed), 10);
+        assert_eq!(limiter.instances(), 1);
+    }
+
+    #[test]
+    fn guests_cannot_grow_memory_past_the_limit() {
+        let (limiter, usage) = create_limiter(2 * PAGE, 0);
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "grow") (param i32) (result i32)
+                    (memory.grow (local.get 0))))
+        "#).unwrap();
+
+        let mut store = wasmtime::Store::new(&engine, limiter);
+        store.limiter(|limiter| limiter);
+
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let grow = instance.get_typed_func::<i32, i32>(&mut store, "grow").unwrap();
+
+        assert_eq!(grow.call(&mut store, 1).unwrap(), 1);
+        assert_eq!(grow.call(&mut store, 1).unwrap(), -1);
+        assert_eq!(usage.memory_bytes.load(Ordering::Relaxed), 2 * PAGE);
+    }
+}
@@ -1,46 +1,116 @@
-use std::sync::{Arc};
+use std::sync::Arc;
 
 use lyserver_messaging_shared::LYServerMessageEvent;
-use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
+use lyserver_plugin_common::{LYServerPlugin, LYServerPluginCrash, LYServerPluginMetadata};
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::LYServerSharedData;
-use serde_json::Value;
-use tokio::sync::Mutex;
-use wasmtime::WasmParams;
-use wasmtime_wasi::{p2::WasiCtxBuilder, preview1::WasiP1Ctx};
-
-use crate::{LYServerWASMLinkerState, LYSERVER_PLUGIN_ABI_ALLOC_METHOD, LYSERVER_PLUGIN_ABI_DESTROY_METHOD, LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD, LYSERVER_PLUGIN_ABI_INIT_METHOD};
+use lyserver_shared_data::LYServerPluginResourceUsage;
+use tokio::sync::{watch, Mutex};
+
+use crate::{create_wasi_ctx_builder, limits::LYServerWASMResourceLimiter, LYServerWASMLinkerState, LYServerWASMSandbox, LYSERVER_PLUGIN_ABI_ALLOC_METHOD, LYSERVER_PLUGIN_ABI_DESTROY_METHOD, LYSERVER_PLUGIN_ABI_FREE_METHOD, LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD, LYSERVER_PLUGIN_ABI_INIT_METHOD, LYSERVER_PLUGIN_ABI_INVOKE_METHOD};
+
+/// Lets the executor run other tasks while a plugin burns through its fuel
+pub(crate) const PLUGIN_FUEL_ASYNC_YIELD_INTERVAL: u64 = 100_000;
+
+/// How far a guest got through `lyserver_plugin_init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LYServerWASMGuestState {
+    /// Init is running and the guest has not announced itself yet
+    Initializing,
+    /// The guest sent `plugin_init` from inside init and polls its events there instead of returning
+    Polling,
+    /// Init returned, events are pushed into `lyserver_plugin_handle_message_event`
+    Ready,
+}
 
 pub struct LYServerWASMPlugin {
     metadata: LYServerPluginMetadata,
 
-    instance: wasmtime::Instance,
-    store: Arc<Mutex<wasmtime::Store<LYServerWASMLinkerState>>>,
+    engine: wasmtime::Engine,
+    linker: wasmtime::Linker<LYServerWASMLinkerState>,
+    module: wasmtime::Module,
+    fuel_per_call: u64,
+    resource_usage: Arc<LYServerPluginResourceUsage>,
+    sandbox: LYServerWASMSandbox,
+
+    runtime: Arc<Mutex<LYServerWASMPluginRuntime>>,
+    guest_state: Arc<watch::Sender<LYServerWASMGuestState>>,
 
     plugin_shared_data: Arc<LYServerPluginSharedData>,
+}
 
-    init: Arc<wasmtime::TypedFunc<(), ()>>,
-    destroy: Arc<wasmtime::TypedFunc<(), ()>>,
-    handle_message_event: Arc<wasmtime::TypedFunc<(i32, i32), ()>>,
+/// A single instantiation of the plugin module, replaced when the plugin is restarted.
+struct LYServerWASMPluginRuntime {
+    instance: wasmtime::Instance,
+    store: wasmtime::Store<LYServerWASMLinkerState>,
+
+    init: wasmtime::TypedFunc<(), ()>,
+    destroy: wasmtime::TypedFunc<(), ()>,
+    handle_message_event: wasmtime::TypedFunc<(i32, i32), ()>,
+    /// Optional, only guests serving other plugins export it
+    invoke: Option<wasmtime::TypedFunc<(i32, i32, i32, i32), i32>>,
+    /// Optional, guests without it leak the buffers they hand back from `invoke`
+    free: Option<wasmtime::TypedFunc<(i32, i32), ()>>,
 }
 
 impl LYServerWASMPlugin {
     pub async fn new(
         metadata: LYServerPluginMetadata,
-        engine: &wasmtime::Engine,
         linker: &mut wasmtime::Linker<LYServerWASMLinkerState>,
         module: &wasmtime::Module,
         plugin_shared_data: Arc<LYServerPluginSharedData>,
+        fuel_per_call: u64,
+        resource_usage: Arc<LYServerPluginResourceUsage>,
+        sandbox: LYServerWASMSandbox,
     ) -> anyhow::Result<Self> {
-        let wasi_ctx = WasiCtxBuilder::new()
-            .inherit_stdio()
-            .build_p1();
+        let guest_state = Arc::new(watch::Sender::new(LYServerWASMGuestState::Initializing));
+        // Modules are bound to the engine which compiled them
+        let engine = module.engine();
+        let runtime = Self::instantiate(&metadata, engine, linker, module, &resource_usage, &sandbox, &guest_state).await?;
+
+        Ok(Self {
+            metadata,
+
+            engine: engine.clone(),
+            linker: linker.clone(),
+            module: module.clone(),
+            fuel_per_call,
+            resource_usage,
+            sandbox,
+
+            runtime: Arc::new(Mutex::new(runtime)),
+            guest_state,
+
+            plugin_shared_data,
+        })
+    }
+
+    async fn instantiate(
+        metadata: &LYServerPluginMetadata,
+        engine: &wasmtime::Engine,
+        linker: &wasmtime::Linker<LYServerWASMLinkerState>,
+        module: &wasmtime::Module,
+        resource_usage: &Arc<LYServerPluginResourceUsage>,
+        sandbox: &LYServerWASMSandbox,
+        guest_state: &Arc<watch::Sender<LYServerWASMGuestState>>,
+    ) -> anyhow::Result<LYServerWASMPluginRuntime> {
+        let wasi_ctx = create_wasi_ctx_builder(metadata, sandbox)?.build_p1();
+
+        // A new store starts without any memory or tables
+        resource_usage.reset();
 
         let mut store = wasmtime::Store::new(engine, LYServerWASMLinkerState {
-            wasi_ctx: wasi_ctx
+            wasi_ctx,
+            limiter: LYServerWASMResourceLimiter::new(&metadata.id, Arc::clone(resource_usage)),
+            guest_state: Arc::clone(guest_state),
         });
 
-        let instance = linker.instantiate_async(&mut store, &module)
+        store.limiter(|state| &mut state.limiter);
+
+        // Start instances with an empty tank, every call refuels with the plugin's budget
+        store.set_fuel(0)?;
+        store.fuel_async_yield_interval(Some(PLUGIN_FUEL_ASYNC_YIELD_INTERVAL))?;
+
+        let instance = linker.instantiate_async(&mut store, module)
             .await
             .map_err(|e| anyhow::Error::msg(format!("Failed to instantiate plugin '{}': {}", metadata.id, e)))?;
 
@@ -56,17 +126,134 @@ impl LYServerWASMPlugin {
             .get_typed_func::<(i32, i32), ()>(&mut store, LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD)
             .map_err(|e| anyhow::Error::msg(format!("No method named '{}' in plugin '{}': {}", LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD, metadata.id, e)))?;
 
-        Ok(Self {
-            metadata,
+        let invoke_fn = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, LYSERVER_PLUGIN_ABI_INVOKE_METHOD)
+            .ok();
+
+        let free_fn = instance
+            .get_typed_func::<(i32, i32), ()>(&mut store, LYSERVER_PLUGIN_ABI_FREE_METHOD)
+            .ok();
 
+        Ok(LYServerWASMPluginRuntime {
             instance,
-            store: Arc::new(Mutex::new(store)),
+            store,
 
-            plugin_shared_data,
+            init: init_fn,
+            destroy: destroy_fn,
+            handle_message_event: handle_message_event_fn,
+            invoke: invoke_fn,
+            free: free_fn,
+        })
+    }
+
+    /// Throws away the current instance, including all of its memory, and instantiates the module again.
+    async fn restart(&self, runtime: &mut LYServerWASMPluginRuntime) -> anyhow::Result<()> {
+        log::warn!("Restarting plugin '{}'...", self.metadata.id);
+
+        *runtime = Self::instantiate(&self.metadata, &self.engine, &self.linker, &self.module, &self.resource_usage, &self.sandbox, &self.guest_state).await?;
+
+        Ok(())
+    }
+
+    /// Calls the guest's init, returning from it marks the guest as ready to have events pushed into it.
+    async fn call_init(&self, runtime: &mut LYServerWASMPluginRuntime) -> anyhow::Result<()> {
+        self.guest_state.send_replace(LYServerWASMGuestState::Initializing);
+
+        runtime.refuel(self.fuel_per_call)?;
+        runtime.init.call_async(&mut runtime.store, ()).await?;
+
+        // Guests which announced themselves from inside init have already sent `plugin_init`
+        if self.guest_state.send_replace(LYServerWASMGuestState::Ready) != LYServerWASMGuestState::Polling {
+            self.plugin_shared_data.dispatch_init_event().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the guest to finish init, returns `false` for guests which poll their events from inside init.
+    async fn wait_until_ready(&self) -> bool {
+        let mut guest_state = self.guest_state.subscribe();
+
+        guest_state.wait_for(|state| *state != LYServerWASMGuestState::Initializing)
+            .await
+            .is_ok_and(|state| *state == LYServerWASMGuestState::Ready)
+    }
+
+    /// Logs calls which ran out of fuel, returns whether the plugin should be restarted because of it.
+    fn handle_fuel_exhaustion(&self, method: &str, e: &anyhow::Error) -> bool {
+        if e.downcast_ref::<wasmtime::Trap>() != Some(&wasmtime::Trap::OutOfFuel) {
+            return false;
+        }
+
+        log::error!("Plugin '{}' exceeded its CPU budget of {} fuel in '{}'", self.metadata.id, self.fuel_per_call, method);
+
+        self.metadata.limits.restart_on_exhaustion
+    }
+}
+
+impl LYServerWASMPluginRuntime {
+    fn refuel(&mut self, fuel_per_call: u64) -> anyhow::Result<()> {
+        self.store.set_fuel(fuel_per_call)
+    }
+
+    fn memory(&mut self) -> anyhow::Result<wasmtime::Memory> {
+        self.instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("memory export not found"))
+    }
+
+    /// Copies `data` into a buffer allocated by the guest, which takes ownership of it.
+    async fn write_guest(&mut self, data: &[u8]) -> anyhow::Result<i32> {
+        let ptr = self.instance
+            .get_typed_func::<i32, i32>(&mut self.store, LYSERVER_PLUGIN_ABI_ALLOC_METHOD)?
+            .call_async(&mut self.store, data.len() as i32)
+            .await?;
+
+        let memory = self.memory()?;
+        memory.write(&mut self.store, ptr as usize, data)?;
+
+        Ok(ptr)
+    }
+
+    async fn free_guest(&mut self, ptr: i32, len: i32) -> anyhow::Result<()> {
+        match self.free.clone() {
+            Some(free) => free.call_async(&mut self.store, (ptr, len)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Calls the guest's `lyserver_plugin_invoke` export with a CBOR encoded `(method, args)` request.
+    async fn call_invoke(&mut self, invoke: &wasmtime::TypedFunc<(i32, i32, i32, i32), i32>, request: &[u8]) -> anyhow::Result<anyhow::Result<Vec<u8>>> {
+        let request_ptr = self.write_guest(request).await?;
+
+        // Slots the guest writes the pointer and length of its result into
+        let ret_ptr = self.write_guest(&[0u8; 8]).await?;
+
+        let result_code = invoke
+            .call_async(&mut self.store, (request_ptr, request.len() as i32, ret_ptr, ret_ptr + 4))
+            .await?;
+
+        let memory = self.memory()?;
+
+        let mut slots = [0u8; 8];
+        memory.read(&self.store, ret_ptr as usize, &mut slots)?;
+
+        let data_ptr = i32::from_le_bytes(slots[0..4].try_into()?);
+        let data_len = i32::from_le_bytes(slots[4..8].try_into()?);
 
-            init: init_fn.into(),
-            destroy: destroy_fn.into(),
-            handle_message_event: handle_message_event_fn.into(),
+        // Both come from the guest, so they are checked before anything is copied
+        let data = usize::try_from(data_ptr).ok()
+            .zip(usize::try_from(data_len).ok())
+            .and_then(|(start, len)| memory.data(&self.store).get(start..start.checked_add(len)?))
+            .ok_or_else(|| anyhow::anyhow!("Invoke result of {} bytes at {} is outside of the guest memory", data_len, data_ptr))?
+            .to_vec();
+
+        self.free_guest(data_ptr, data_len).await?;
+        self.free_guest(ret_ptr, 8).await?;
+
+        Ok(match result_code {
+            0 => Ok(data),
+            _ => Err(anyhow::anyhow!(String::from_utf8_lossy(&data).to_string())),
         })
     }
 }
@@ -78,33 +265,30 @@ impl LYServerPlugin for LYServerWASMPlugin {
     }
 
     async fn init(&self) -> anyhow::Result<()> {
-        let init_fn = Arc::clone(&self.init);
-        let metadata = self.metadata.clone();
-        let store = Arc::clone(&self.store);
+        // Not spawned: polling guests stay in init for their whole lifetime, so dropping
+        // this future has to stop the guest and release the store for destroy
+        let mut runtime = self.runtime.lock().await;
 
-        let result = tokio::task::spawn(async move {
-            let mut store = store.lock().await;
+        // Not restarted in place: a guest which runs out of fuel in init would do so on every attempt, the
+        // supervisor restarts it instead, with backoff and up to its maximum number of restarts
+        self.call_init(&mut runtime).await.map_err(|e| {
+            self.handle_fuel_exhaustion(LYSERVER_PLUGIN_ABI_INIT_METHOD, &e);
 
-            init_fn.call_async(&mut *store, ())
-                .await
-                .map_err(|e| anyhow::Error::msg(format!("Failed to call init for '{}': {}", metadata.id, e)))
-        }).await;
-
-        match result {
-            Ok(res) => res,
-            Err(e) => Err(anyhow::Error::msg(format!("Failed to spawn init task for '{}': {}", self.metadata.id, e))),
-        }
+            anyhow::Error::msg(format!("Failed to call init for '{}': {}", self.metadata.id, e))
+        })
     }
 
     async fn destroy(&self) -> anyhow::Result<()> {
-        let destroy_fn = Arc::clone(&self.destroy);
         let metadata = self.metadata.clone();
-        let store = Arc::clone(&self.store);
+        let runtime = Arc::clone(&self.runtime);
+        let fuel_per_call = self.fuel_per_call;
 
         let result = tokio::task::spawn(async move {
-            let mut store = store.lock().await;
+            let mut runtime = runtime.lock().await;
+            let runtime = &mut *runtime;
+            runtime.refuel(fuel_per_call)?;
 
-            destroy_fn.call_async(&mut *store, ())
+            runtime.destroy.call_async(&mut runtime.store, ())
                 .await
                 .map_err(|e| anyhow::Error::msg(format!("Failed to call destroy for '{}': {}", metadata.id, e)))
         }).await;
@@ -116,9 +300,13 @@ impl LYServerPlugin for LYServerWASMPlugin {
     }
 
     async fn handle_message_event(&self, event: LYServerMessageEvent) -> anyhow::Result<()> {
-        let handle_message_event_fn = Arc::clone(&self.handle_message_event);
         let metadata = self.metadata.clone();
-        let store = Arc::clone(&self.store);
+
+        if !self.wait_until_ready().await {
+            log::debug!("Plugin '{}' polls its events, not pushing '{}' to it", metadata.id, event.event_type);
+
+            return Ok(());
+        }
     
         // serialize event to CBOR
         let serialized = serde_cbor::to_vec(&event)
@@ -126,11 +314,14 @@ impl LYServerPlugin for LYServerWASMPlugin {
     
         let message_len = serialized.len() as i32;
 
-        let wasm_message_ptr = {
-            let mut store = store.lock().await;
+        let mut runtime = self.runtime.lock().await;
+        let runtime = &mut *runtime;
+
+        runtime.refuel(self.fuel_per_call)?;
 
-            let alloc_func = self.instance
-                .get_export(&mut *store, LYSERVER_PLUGIN_ABI_ALLOC_METHOD)
+        let wasm_message_ptr = {
+            let alloc_func = runtime.instance
+                .get_export(&mut runtime.store, LYSERVER_PLUGIN_ABI_ALLOC_METHOD)
                 .and_then(|e| e.into_func())
                 .ok_or_else(|| anyhow::anyhow!(
                     "Plugin {} does not export '{}'",
@@ -139,8 +330,8 @@ impl LYServerPlugin for LYServerWASMPlugin {
                 ))?;
 
             // --- get memory ---
-            let memory = self.instance
-                .get_export(&mut *store, "memory")
+            let memory = runtime.instance
+                .get_export(&mut runtime.store, "memory")
                 .and_then(|e| e.into_memory())
                 .ok_or_else(|| anyhow::anyhow!("Plugin {} does not export memory", metadata.id))?;
 
@@ -148,8 +339,8 @@ impl LYServerPlugin for LYServerWASMPlugin {
             let wasm_ptr = {
                 let mut wasm_ptr: Option<i32> = None;
                 alloc_func
-                    .typed::<i32, i32>(&*store)?
-                    .call_async(&mut *store, message_len)
+                    .typed::<i32, i32>(&runtime.store)?
+                    .call_async(&mut runtime.store, message_len)
                     .await
                     .map(|ptr| wasm_ptr = Some(ptr))
                     .map_err(|e| anyhow::anyhow!(
@@ -160,7 +351,7 @@ impl LYServerPlugin for LYServerWASMPlugin {
             };
 
             // write to memory
-            memory.write(&mut *store, wasm_ptr as usize, &serialized)
+            memory.write(&mut runtime.store, wasm_ptr as usize, &serialized)
                 .map_err(|e| anyhow::anyhow!(
                     "Failed to write message to wasm memory for '{}': {}",
                     metadata.id, e
@@ -168,34 +359,306 @@ impl LYServerPlugin for LYServerWASMPlugin {
 
             wasm_ptr
         };
-    
-        let result = tokio::task::spawn(async move {
-            let mut store = store.lock().await;
-    
-            handle_message_event_fn
-                .call_async(&mut *store, (wasm_message_ptr, message_len))
-                .await
-                .map_err(|e| anyhow::anyhow!(
-                    "Failed to call handle_message_event for '{}': {}",
-                    metadata.id, e
-                ))
-        }).await;
-    
+
+        let result = runtime.handle_message_event
+            .call_async(&mut runtime.store, (wasm_message_ptr, message_len))
+            .await;
+
         match result {
-            Ok(res) => res,
-            Err(e) => Err(anyhow::anyhow!(
-                "Failed to spawn handle_message_event task for '{}': {}",
-                self.metadata.id, e
-            )),
+            Ok(()) => Ok(()),
+            Err(e) if self.handle_fuel_exhaustion(LYSERVER_PLUGIN_ABI_HANDLE_MESSAGE_EVENT_METHOD, &e) => {
+                self.restart(runtime).await?;
+
+                // The fresh instance has to be initialized again before it can handle further events
+                self.call_init(runtime)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to call init for '{}' after restarting: {}", metadata.id, e))?;
+
+                Err(anyhow::anyhow!("Plugin '{}' ran out of fuel while handling '{}' and was restarted", metadata.id, event.event_type))
+            }
+            // The guest trapped, its state cannot be trusted anymore
+            Err(e) => Err(LYServerPluginCrash(format!(
+                "Failed to call handle_message_event for '{}': {}",
+                metadata.id, e
+            )).into()),
+        }
+    }
+
+    async fn invoke(&self, method: &str, args: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let request = serde_cbor::to_vec(&(method, args))
+            .map_err(|e| anyhow::anyhow!("Failed to serialize invocation for '{}': {}", self.metadata.id, e))?;
+
+        if !self.wait_until_ready().await {
+            return Err(anyhow::anyhow!("Plugin '{}' polls its events from init and cannot be invoked", self.metadata.id));
+        }
+
+        let mut runtime = self.runtime.lock().await;
+        let runtime = &mut *runtime;
+
+        let invoke = runtime.invoke.clone()
+            .ok_or_else(|| anyhow::anyhow!("Plugin '{}' does not export '{}'", self.metadata.id, LYSERVER_PLUGIN_ABI_INVOKE_METHOD))?;
+
+        runtime.refuel(self.fuel_per_call)?;
+
+        match runtime.call_invoke(&invoke, &request).await {
+            Ok(result) => result,
+            Err(e) if self.handle_fuel_exhaustion(LYSERVER_PLUGIN_ABI_INVOKE_METHOD, &e) => {
+                self.restart(runtime).await?;
+
+                self.call_init(runtime)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to call init for '{}' after restarting: {}", self.metadata.id, e))?;
+
+                Err(anyhow::anyhow!("Plugin '{}' ran out of fuel while invoking '{}' and was restarted", self.metadata.id, method))
+            }
+            Err(e) => Err(anyhow::anyhow!("Failed to invoke '{}' on '{}': {}", method, self.metadata.id, e)),
         }
     }
 
-    async fn invoke(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
-        Err(anyhow::anyhow!("Method 'invoke' is not implemented for plugin '{}'", self.metadata.id))
+    fn message_event_concurrency(&self) -> Option<usize> {
+        // Calls into a guest are serialized on its store, pushing one event at a time keeps them in order
+        Some(1)
     }
 
-    async fn receive(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
-        // This method is not implemented in the original code, so we can leave it empty or handle it as needed.
-        Err(anyhow::anyhow!("Method 'receive' is not implemented for plugin '{}'", self.metadata.id))
+    fn handles_rpc_events(&self) -> bool {
+        // Guests answer calls themselves through `rpc::respond`
+        true
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use lyserver_messaging_shared::LYServerMessageEventTarget;
+    use lyserver_plugin_common::{LYServerPluginCapability, LYServerPluginLimits};
+    use lyserver_shared_data::{LYServerPluginInstance, LYServerSharedData};
+    use tokio::sync::broadcast;
+
+    use crate::LYServerWASMLoader;
+
+    use super::*;
+
+    pub(crate) const TEST_PLUGIN_ID: &str = "module@plugin";
+
+    /// Builds a guest module around the given bodies of init and handle_message_event, which receive the
+    /// event as `$ptr` and `$len`. Buffers handed to the host start at 1024, `$alloc` hands out memory above.
+    pub(crate) fn test_module(init: &str, handle_message_event: &str, extra: &str) -> String {
+        format!(r#"
+            (module
+                (import "env" "lyserver_plugin_receive_message" (func $receive (param i32 i32)))
+                (import "env" "lyserver_plugin_send_message" (func $send (param i32 i32 i32)))
+                {extra}
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 4096))
+                (global $handled (mut i32) (i32.const 0))
+                (func $alloc (export "lyserver_plugin_alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $heap))
+                    (global.set $heap (i32.add (global.get $heap) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "lyserver_plugin_init") {init})
+                (func (export "lyserver_plugin_destroy"))
+                (func (export "lyserver_plugin_handle_message_event") (param $ptr i32) (param $len i32)
+                    (global.set $handled (i32.add (global.get $handled) (i32.const 1)))
+                    {handle_message_event}))
+        "#)
+    }
+
+    /// Escapes `data` for a WAT string, e.g. in a data segment.
+    pub(crate) fn wat_bytes(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("\\{:02x}", byte)).collect()
+    }
+
+    pub(crate) async fn create_test_shared_data(name: &str) -> (Arc<LYServerSharedData>, broadcast::Receiver<LYServerMessageEvent>) {
+        let data_dir = std::env::temp_dir().join(format!("lyserver-module-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        let shared_data = Arc::new(LYServerSharedData::new_from_args([
+            "lyserver", "--port", "0", "--data-dir", data_dir.to_str().unwrap(),
+        ]).unwrap());
+        let bus = shared_data.messaging_global_tx.subscribe();
+
+        (shared_data, bus)
+    }
+
+    pub(crate) async fn load_test_module(shared_data: &Arc<LYServerSharedData>, metadata: &LYServerPluginMetadata, wat: &str) -> LYServerPluginInstance {
+        let mut plugin_shared_data = LYServerPluginSharedData::new(Arc::clone(shared_data));
+        plugin_shared_data.register_plugin_messaging(metadata.id.clone()).await.unwrap();
+
+        let wasm_path = shared_data.data_dir.join(format!("{}.wasm", metadata.id));
+        std::fs::write(&wasm_path, wat::parse_str(wat).unwrap()).unwrap();
+
+        LYServerWASMLoader::new(Arc::clone(shared_data))
+            .create_wasm_plugin_instance(metadata, &wasm_path, Arc::new(plugin_shared_data))
+            .await
+            .unwrap()
+    }
+
+    fn test_metadata(limits: LYServerPluginLimits) -> LYServerPluginMetadata {
+        LYServerPluginMetadata::builder()
+            .id(TEST_PLUGIN_ID)
+            .name("module")
+            .version("1.0.0")
+            .limits(limits)
+            .capability(LYServerPluginCapability::MessagingSend("*".to_string()))
+            .build()
+    }
+
+    pub(crate) fn test_event(event_type: &str) -> LYServerMessageEvent {
+        LYServerMessageEvent::new(event_type, LYServerMessageEventTarget::All, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()), ())
+    }
+
+    /// Spins forever on the first event the instance handles.
+    const SPIN_ON_FIRST_EVENT: &str = r#"
+        (if (i32.eq (global.get $handled) (i32.const 1))
+            (then (loop $spin (br $spin))))
+    "#;
+
+    fn fuel_limits(restart_on_exhaustion: bool) -> LYServerPluginLimits {
+        LYServerPluginLimits {
+            fuel_per_call: Some(100_000),
+            restart_on_exhaustion,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn guests_running_out_of_fuel_are_restarted() {
+        let (shared_data, mut bus) = create_test_shared_data("fuel-restart").await;
+        let plugin = load_test_module(&shared_data, &test_metadata(fuel_limits(true)), &test_module("", SPIN_ON_FIRST_EVENT, "")).await;
+
+        plugin.init().await.unwrap();
+        assert_eq!(bus.try_recv().unwrap().event_type, "plugin_init");
+
+        let error = plugin.handle_message_event(test_event("library_changed")).await.unwrap_err();
+
+        assert!(error.downcast_ref::<LYServerPluginCrash>().is_none(), "{}", error);
+        assert_eq!(error.to_string(), format!("Plugin '{}' ran out of fuel while handling 'library_changed' and was restarted", TEST_PLUGIN_ID));
+
+        // The fresh instance was initialized again and starts counting its events from zero
+        assert_eq!(bus.try_recv().unwrap().event_type, "plugin_init");
+        assert!(plugin.handle_message_event(test_event("library_changed")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn guests_running_out_of_fuel_crash_unless_restarted_on_exhaustion() {
+        let (shared_data, _bus) = create_test_shared_data("fuel-crash").await;
+        let plugin = load_test_module(&shared_data, &test_metadata(fuel_limits(false)), &test_module("", SPIN_ON_FIRST_EVENT, "")).await;
+
+        plugin.init().await.unwrap();
+
+        let error = plugin.handle_message_event(test_event("library_changed")).await.unwrap_err();
+
+        assert!(error.downcast_ref::<LYServerPluginCrash>().is_some(), "{}", error);
+    }
+
+    /// Sends the event back to the bus, only succeeds for events sent as the plugin itself.
+    const ECHO_EVENT: &str = "(call $send (local.get $ptr) (local.get $len) (i32.const 1024))";
+
+    /// Returns from init after receiving an event.
+    const RECEIVE_ONE_EVENT: &str = "(call $receive (i32.const 1032) (i32.const 1036))";
+
+    /// Announces the guest from init with the event at 0, then echoes the events it polls until its channel is closed.
+    fn polling_test_module() -> String {
+        let init_event = LYServerMessageEvent::new("plugin_init", LYServerMessageEventTarget::All, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()), ());
+        let init_event = serde_cbor::to_vec(&init_event).unwrap();
+
+        let init = format!(r#"
+            (call $send (i32.const 0) (i32.const {}) (i32.const 1024))
+            (loop $poll
+                (call $receive (i32.const 1032) (i32.const 1036))
+                (if (i32.eqz (i32.load (i32.const 1032))) (then (return)))
+                (call $send (i32.load (i32.const 1032)) (i32.load (i32.const 1036)) (i32.const 1024))
+                (br $poll))
+        "#, init_event.len());
+
+        test_module(&init, ECHO_EVENT, &format!(r#"(data (memory 0) (i32.const 0) "{}")"#, wat_bytes(&init_event)))
+    }
+
+    async fn deliver(shared_data: &LYServerSharedData, event: LYServerMessageEvent) {
+        let tx = shared_data.messaging_plugin_tx.read().await.get(TEST_PLUGIN_ID).cloned().unwrap();
+        tx.send(event).unwrap();
+    }
+
+    async fn next_event(bus: &mut broadcast::Receiver<LYServerMessageEvent>) -> LYServerMessageEvent {
+        tokio::time::timeout(std::time::Duration::from_secs(5), bus.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_are_pushed_once_init_returned() {
+        let (shared_data, mut bus) = create_test_shared_data("state-ready").await;
+        let plugin = load_test_module(&shared_data, &test_metadata(Default::default()), &test_module("", ECHO_EVENT, "")).await;
+
+        plugin.init().await.unwrap();
+        assert_eq!(next_event(&mut bus).await.event_type, "plugin_init");
+
+        plugin.handle_message_event(test_event("library_changed")).await.unwrap();
+        assert_eq!(next_event(&mut bus).await.event_type, "library_changed");
+    }
+
+    #[tokio::test]
+    async fn events_wait_for_init_to_return() {
+        let (shared_data, mut bus) = create_test_shared_data("state-initializing").await;
+        let plugin = load_test_module(&shared_data, &test_metadata(Default::default()), &test_module(RECEIVE_ONE_EVENT, ECHO_EVENT, "")).await;
+
+        let init = tokio::spawn({
+            let plugin = Arc::clone(&plugin);
+            async move { plugin.init().await }
+        });
+        let handle = tokio::spawn({
+            let plugin = Arc::clone(&plugin);
+            async move { plugin.handle_message_event(test_event("library_changed")).await }
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+
+        // The guest only receives events sent after it started waiting
+        while !init.is_finished() {
+            deliver(&shared_data, test_event("wake_up")).await;
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        init.await.unwrap().unwrap();
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(next_event(&mut bus).await.event_type, "plugin_init");
+        assert_eq!(next_event(&mut bus).await.event_type, "library_changed");
+    }
+
+    #[tokio::test]
+    async fn guests_announcing_themselves_from_init_poll_their_events() {
+        let (shared_data, mut bus) = create_test_shared_data("state-polling").await;
+        let plugin = load_test_module(&shared_data, &test_metadata(Default::default()), &polling_test_module()).await;
+
+        let init = tokio::spawn({
+            let plugin = Arc::clone(&plugin);
+            async move { plugin.init().await }
+        });
+
+        let init_event = next_event(&mut bus).await;
+        assert_eq!(init_event.event_type, "plugin_init");
+        assert_eq!(init_event.event_sender, LYServerMessageEventTarget::Plugin(TEST_PLUGIN_ID.to_string()));
+
+        // Pushing would wait for the store init holds forever, so these return right away
+        plugin.handle_message_event(test_event("pushed")).await.unwrap();
+        let error = plugin.invoke("method", Vec::new()).await.unwrap_err();
+        assert!(error.to_string().contains("polls its events"), "{}", error);
+
+        loop {
+            deliver(&shared_data, test_event("polled")).await;
+
+            match tokio::time::timeout(std::time::Duration::from_millis(10), bus.recv()).await {
+                Ok(event) => {
+                    assert_eq!(event.unwrap().event_type, "polled");
+                    break;
+                }
+                Err(_) => continue,
+            }
+        }
+
+        init.abort();
+        assert!(init.await.unwrap_err().is_cancelled());
+
+        plugin.destroy().await.unwrap();
     }
 }
@@ -4,4 +4,20 @@ pub extern "C" fn lyserver_plugin_alloc(size: i32) -> *mut u8 {
     let ptr = buf.as_mut_ptr();
     std::mem::forget(buf);
     ptr
-}
\ No newline at end of file
+}
+
+/// # Safety
+///
+/// `ptr` must come from [`lyserver_plugin_alloc`] or a boxed slice of exactly `size` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lyserver_plugin_free(ptr: *mut u8, size: i32) {
+    drop(unsafe { Vec::from_raw_parts(ptr, 0, size as usize) });
+}
+
+/// Version of the plugin ABI this runtime implements, read by the host from the module's custom section.
+pub const LYSERVER_PLUGIN_ABI_VERSION: u32 = 3;
+
+// Lives next to the exports above so it is linked into every plugin which re-exports this module
+#[used]
+#[unsafe(link_section = "lyserver_plugin_abi_version")]
+static LYSERVER_PLUGIN_ABI_VERSION_SECTION: [u8; 4] = LYSERVER_PLUGIN_ABI_VERSION.to_le_bytes();
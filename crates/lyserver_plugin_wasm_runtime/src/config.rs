@@ -0,0 +1,24 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Environment variable the host puts the resolved `[config]` of the plugin in.
+pub const CONFIG_ENV: &str = "LYSERVER_PLUGIN_CONFIG";
+
+/// The `[config]` of the plugin, with the defaults of its manifest overridden by the `plugins.<id>.*` preferences.
+pub fn all() -> Result<Map<String, Value>, String> {
+    let config = std::env::var(CONFIG_ENV)
+        .map_err(|e| format!("Failed to read config: {}", e))?;
+
+    serde_json::from_str(&config)
+        .map_err(|e| format!("Failed to deserialize config: {}", e))
+}
+
+/// Reads a single config option, `None` when it has neither a default nor an override.
+pub fn get<T: DeserializeOwned>(key: &str) -> Result<Option<T>, String> {
+    match all()?.remove(key) {
+        Some(value) => serde_json::from_value(value)
+            .map(Some)
+            .map_err(|e| format!("Invalid config option '{}': {}", key, e)),
+        None => Ok(None),
+    }
+}
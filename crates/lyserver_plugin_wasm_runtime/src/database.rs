@@ -0,0 +1,14 @@
+use serde_json::Value;
+
+use crate::{externs::lyserver_plugin_database_query, host};
+
+/// Runs a query against one of the server databases, requires the `database.query` capability.
+pub fn query(database: &str, query: &str, args: Vec<String>) -> Result<Value, String> {
+    let request = serde_cbor::to_vec(&(database, query, args))
+        .map_err(|e| format!("Failed to serialize query: {}", e))?;
+
+    let response = host::call(lyserver_plugin_database_query, &request)?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize query result: {}", e))
+}
@@ -6,5 +6,16 @@ unsafe extern "C" {
     pub fn lyserver_plugin_log_error(ptr: *const u8, len: usize);
     pub fn lyserver_plugin_log_debug(ptr: *const u8, len: usize);
     pub fn lyserver_plugin_receive_message(ret_ptr: *mut u8, ret_len: *mut u8);
+    pub fn lyserver_plugin_try_receive_message(ret_ptr: *mut u8, ret_len: *mut u8);
     pub fn lyserver_plugin_send_message(ptr: *const u8, len: usize, ret_ptr: *mut usize);
+    pub fn lyserver_plugin_database_query(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_preferences_get(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_preferences_set(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_service_call(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_invoke_plugin(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_kv_get(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_kv_set(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_kv_delete(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_kv_list(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
+    pub fn lyserver_plugin_kv_compare_and_swap(ptr: *const u8, len: usize, ret_ptr: *mut u8, ret_len: *mut u8) -> i32;
 }
\ No newline at end of file
@@ -0,0 +1,9 @@
+use std::path::{Path, PathBuf};
+
+/// The plugin's private, writable data directory, kept across restarts and upgrades.
+pub const DATA_DIR: &str = "/data";
+
+/// Resolves `path` inside the plugin's data directory.
+pub fn data_path(path: impl AsRef<Path>) -> PathBuf {
+    Path::new(DATA_DIR).join(path)
+}
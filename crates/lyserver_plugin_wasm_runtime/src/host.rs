@@ -0,0 +1,30 @@
+/// Signature shared by host functions which take a request buffer and hand back a result buffer.
+pub type HostCall = unsafe extern "C" fn(*const u8, usize, *mut u8, *mut u8) -> i32;
+
+/// Calls a host function, returning its result buffer or the error message the host passed back.
+pub fn call(func: HostCall, request: &[u8]) -> Result<Vec<u8>, String> {
+    let mut ptr: i32 = 0;
+    let mut len: i32 = 0;
+
+    let result_code = unsafe {
+        func(
+            request.as_ptr(),
+            request.len(),
+            &mut ptr as *mut _ as *mut u8,
+            &mut len as *mut _ as *mut u8,
+        )
+    };
+
+    let data = if ptr == 0 || len == 0 {
+        Vec::new()
+    } else {
+        // The host allocated the buffer through lyserver_plugin_alloc, so we own it now
+        unsafe { Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize) }
+    };
+
+    if result_code == 0 {
+        Ok(data)
+    } else {
+        Err(String::from_utf8_lossy(&data).to_string())
+    }
+}
@@ -1,11 +1,37 @@
+use std::{cell::{Cell, RefCell}, collections::VecDeque};
+
 pub use lyserver_messaging_shared::LYServerMessageEvent;
 
+thread_local! {
+    /// Events received while blocked in an RPC call, handed out again by `recv` before polling the host
+    static DEFERRED: RefCell<VecDeque<LYServerMessageEvent>> = const { RefCell::new(VecDeque::new()) };
+    /// Whether the host pushes events into `lyserver_plugin_handle_message_event`, which also delivers deferred events
+    static PUSHED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn defer(event: LYServerMessageEvent) {
+    if PUSHED.with(Cell::get) {
+        return;
+    }
+
+    DEFERRED.with(|deferred| deferred.borrow_mut().push_back(event));
+}
+
 pub fn recv_raw() -> Option<Vec<u8>> {
+    receive_with(crate::externs::lyserver_plugin_receive_message)
+}
+
+/// Like `recv_raw`, but returns `None` right away when no event is queued.
+pub fn try_recv_raw() -> Option<Vec<u8>> {
+    receive_with(crate::externs::lyserver_plugin_try_receive_message)
+}
+
+fn receive_with(func: unsafe extern "C" fn(*mut u8, *mut u8)) -> Option<Vec<u8>> {
     let mut ptr: i32 = 0;
     let mut len: i32 = 0;
 
     unsafe {
-        crate::externs::lyserver_plugin_receive_message(
+        func(
             &mut ptr as *mut _ as *mut u8,
             &mut len as *mut _ as *mut u8,
         );
@@ -14,8 +40,8 @@ pub fn recv_raw() -> Option<Vec<u8>> {
             return None;
         }
 
-        let slice = std::slice::from_raw_parts(ptr as *const u8, len as usize);
-        Some(slice.to_vec())
+        // The host allocated the buffer through lyserver_plugin_alloc, so we own it now
+        Some(Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize))
     }
 }
 
@@ -33,43 +59,114 @@ pub fn tx_raw(msg: Vec<u8>) -> usize {
     ret
 }
 
+/// Blocks until the next event arrives, only meant for guests which poll their events from inside init.
+///
+/// Guests returning from `lyserver_plugin_init` get their events pushed into `lyserver_plugin_handle_message_event` instead.
 pub fn recv() -> Option<LYServerMessageEvent> {
-    if let Some(raw) = recv_raw() {
-        match serde_cbor::from_slice::<LYServerMessageEvent>(&raw) {
-            Ok(event) => return Some(event),
-            Err(e) => return None
-        }
-    } else {
-        None
+    if let Some(event) = DEFERRED.with(|deferred| deferred.borrow_mut().pop_front()) {
+        return Some(event);
+    }
+
+    recv_raw().and_then(|raw| serde_cbor::from_slice::<LYServerMessageEvent>(&raw).ok())
+}
+
+/// Returns the next queued event without blocking.
+pub fn try_recv() -> Option<LYServerMessageEvent> {
+    if let Some(event) = DEFERRED.with(|deferred| deferred.borrow_mut().pop_front()) {
+        return Some(event);
     }
+
+    try_recv_from_host()
 }
 
+pub(crate) fn try_recv_from_host() -> Option<LYServerMessageEvent> {
+    try_recv_raw().and_then(|raw| serde_cbor::from_slice::<LYServerMessageEvent>(&raw).ok())
+}
+
+/// Sends an event, failing when the host refused it, e.g. because the plugin lacks the capability to send it.
 pub fn tx(msg: &LYServerMessageEvent) -> Result<(), String> {
     let serialized = serde_cbor::to_vec(msg)
         .map_err(|e| format!("Failed to serialize message: {}", e))?;
-    
-    let len = tx_raw(serialized);
-    if len > 1 {
-        Err(format!("Failed to send message"))
-    } else {
-        Ok(())
+
+    // The host answers 0 once the event is dispatched, anything else means it was rejected
+    if tx_raw(serialized) != 0 {
+        return Err(format!("The host refused to send '{}' event", msg.event_type));
     }
+
+    Ok(())
 }
 
+/// Reads the event passed to `lyserver_plugin_handle_message_event`, freeing the buffer the host allocated for it.
 pub fn deserialize_event(message_ptr: *mut u8, message_len: *mut u8) -> Result<LYServerMessageEvent, String> {
     let ptr: i32 = message_ptr as i32;
     let len: i32 = message_len as i32;
 
-    let slice = unsafe {
+    let data = unsafe {
         if ptr == 0 || len == 0 {
             return Err("Invalid pointer or length".to_string());
         }
 
-        std::slice::from_raw_parts(ptr as *const u8, len as usize)
+        Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize)
     };
 
-    match serde_cbor::from_slice::<LYServerMessageEvent>(&slice) {
-        Ok(event) => return Ok(event),
-        Err(e) => return Err(format!("Failed to deserialize event: {}", e)),
+    PUSHED.with(|pushed| pushed.set(true));
+
+    match serde_cbor::from_slice::<LYServerMessageEvent>(&data) {
+        Ok(event) => Ok(event),
+        Err(e) => Err(format!("Failed to deserialize event: {}", e)),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::rpc::{self, LYServerRPCErrorKind};
+
+    use super::*;
+
+    thread_local! {
+        /// Event types the stand-in host lets through, like a `messaging.send:<type>` capability
+        static ALLOWED_EVENT_TYPES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Stands in for the host function, answering 1 for events the plugin may not send as the host does
+    #[unsafe(no_mangle)]
+    extern "C" fn lyserver_plugin_send_message(ptr: *const u8, len: usize, ret_ptr: *mut usize) {
+        let data = unsafe { std::slice::from_raw_parts(ptr, len) };
+        let event = serde_cbor::from_slice::<LYServerMessageEvent>(data).unwrap();
+
+        let allowed = ALLOWED_EVENT_TYPES.with(|allowed| allowed.borrow().contains(&event.event_type));
+
+        unsafe { *ret_ptr = if allowed { 0 } else { 1 } };
+    }
+
+    // `rpc::call` polls for the response after sending; the host never has one queued here
+    #[unsafe(no_mangle)]
+    extern "C" fn lyserver_plugin_try_receive_message(ret_ptr: *mut u8, ret_len: *mut u8) {
+        unsafe {
+            *(ret_ptr as *mut i32) = 0;
+            *(ret_len as *mut i32) = 0;
+        }
+    }
+
+    fn allow_event_types(event_types: &[&str]) {
+        ALLOWED_EVENT_TYPES.with(|allowed| *allowed.borrow_mut() = event_types.iter().map(|event_type| event_type.to_string()).collect());
+    }
+
+    #[test]
+    fn tx_reports_events_the_host_refused() {
+        allow_event_types(&["granted"]);
+
+        assert!(tx(&LYServerMessageEvent::new("granted", "other@plugin", "test@plugin", ())).is_ok());
+        assert!(tx(&LYServerMessageEvent::new("denied", "other@plugin", "test@plugin", ())).is_err());
+    }
+
+    #[test]
+    fn rpc_call_fails_with_a_transport_error_when_the_request_is_refused() {
+        allow_event_types(&[]);
+
+        let error = rpc::call::<_, ()>("test@plugin", "other@plugin", "method", &(), std::time::Duration::from_secs(1)).unwrap_err();
+
+        assert_eq!(error.kind, LYServerRPCErrorKind::Transport);
+    }
+}
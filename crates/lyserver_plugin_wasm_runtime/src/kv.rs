@@ -0,0 +1,38 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{externs::{lyserver_plugin_kv_compare_and_swap, lyserver_plugin_kv_delete, lyserver_plugin_kv_get, lyserver_plugin_kv_list, lyserver_plugin_kv_set}, host::{self, HostCall}};
+
+fn call<Req: Serialize, Resp: DeserializeOwned>(func: HostCall, request: &Req) -> Result<Resp, String> {
+    let request = serde_cbor::to_vec(request)
+        .map_err(|e| format!("Failed to serialize KV request: {}", e))?;
+
+    let response = host::call(func, &request)?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize KV response: {}", e))
+}
+
+/// Reads a key from the plugin's key/value storage, which persists across restarts.
+pub fn get(key: &str) -> Result<Option<Vec<u8>>, String> {
+    call(lyserver_plugin_kv_get, &key)
+}
+
+/// Stores a value, failing when it would push the plugin over its KV quota.
+pub fn set(key: &str, value: &[u8]) -> Result<(), String> {
+    call(lyserver_plugin_kv_set, &(key, value))
+}
+
+/// Removes a key, returning whether it existed.
+pub fn delete(key: &str) -> Result<bool, String> {
+    call(lyserver_plugin_kv_delete, &key)
+}
+
+/// The keys starting with `prefix`, in ascending order.
+pub fn list_prefix(prefix: &str) -> Result<Vec<String>, String> {
+    call(lyserver_plugin_kv_list, &prefix)
+}
+
+/// Replaces the value of a key if it still holds `expected`, `None` standing for a missing key on both sides.
+pub fn compare_and_swap(key: &str, expected: Option<&[u8]>, value: Option<&[u8]>) -> Result<bool, String> {
+    call(lyserver_plugin_kv_compare_and_swap, &(key, expected, value))
+}
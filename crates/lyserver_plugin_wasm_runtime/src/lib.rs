@@ -2,5 +2,14 @@ pub mod log;
 pub mod ipc;
 pub mod externs;
 pub mod alloc;
+pub mod host;
+pub mod database;
+pub mod preferences;
+pub mod rpc;
+pub mod services;
+pub mod plugins;
+pub mod fs;
+pub mod config;
+pub mod kv;
 
 pub use lyserver_http_shared as http;
\ No newline at end of file
@@ -0,0 +1,77 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{externs::lyserver_plugin_invoke_plugin, host};
+
+/// Invokes `method` on another WASM plugin, requires the `plugins.invoke:<plugin-id>` capability. Calls which
+/// would reach a plugin already waiting further up the call chain fail instead of deadlocking.
+pub fn invoke<Args: Serialize, Ret: DeserializeOwned>(plugin_id: &str, method: &str, args: &Args) -> Result<Ret, String> {
+    let args = serde_cbor::to_vec(args)
+        .map_err(|e| format!("Failed to serialize invocation arguments: {}", e))?;
+
+    let request = serde_cbor::to_vec(&(plugin_id, method, args))
+        .map_err(|e| format!("Failed to serialize invocation: {}", e))?;
+
+    let response = host::call(lyserver_plugin_invoke_plugin, &request)?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize invocation result: {}", e))
+}
+
+/// Serves a call of the `lyserver_plugin_invoke` export, guests which can be invoked forward it here:
+///
+/// ```ignore
+/// #[unsafe(no_mangle)]
+/// pub unsafe extern "C" fn lyserver_plugin_invoke(ptr: *mut u8, len: i32, ret_ptr: *mut i32, ret_len: *mut i32) -> i32 {
+///     unsafe {
+///         plugins::handle_invoke(ptr, len, ret_ptr, ret_len, |method, args| match method {
+///             "greet" => plugins::respond(&format!("Hello, {}!", plugins::args::<String>(&args)?)),
+///             _ => Err(format!("Unknown method '{}'", method)),
+///         })
+///     }
+/// }
+/// ```
+///
+/// Returns `0` on success and `1` on error, the result buffer is freed by the host.
+///
+/// # Safety
+///
+/// The arguments must be the ones the host passed to `lyserver_plugin_invoke`.
+pub unsafe fn handle_invoke<F>(ptr: *mut u8, len: i32, ret_ptr: *mut i32, ret_len: *mut i32, handler: F) -> i32
+where
+    F: FnOnce(&str, Vec<u8>) -> Result<Vec<u8>, String>,
+{
+    // The host allocated the request through lyserver_plugin_alloc, so we own it now
+    let request = unsafe { Vec::from_raw_parts(ptr, len as usize, len as usize) };
+
+    let result = serde_cbor::from_slice::<(String, Vec<u8>)>(&request)
+        .map_err(|e| format!("Invalid invocation: {}", e))
+        .and_then(|(method, args)| handler(&method, args));
+
+    let (result_code, data) = match result {
+        Ok(data) => (0, data),
+        Err(e) => (1, e.into_bytes()),
+    };
+
+    // Boxing drops any spare capacity, lyserver_plugin_free expects the exact length
+    let data = data.into_boxed_slice();
+    let data_len = data.len();
+
+    unsafe {
+        *ret_ptr = Box::into_raw(data) as *mut u8 as i32;
+        *ret_len = data_len as i32;
+    }
+
+    result_code
+}
+
+/// Decodes the CBOR encoded arguments of an invocation.
+pub fn args<T: DeserializeOwned>(args: &[u8]) -> Result<T, String> {
+    serde_cbor::from_slice(args)
+        .map_err(|e| format!("Invalid invocation arguments: {}", e))
+}
+
+/// Encodes the result of an invocation as CBOR.
+pub fn respond<T: Serialize>(result: &T) -> Result<Vec<u8>, String> {
+    serde_cbor::to_vec(result)
+        .map_err(|e| format!("Failed to serialize invocation result: {}", e))
+}
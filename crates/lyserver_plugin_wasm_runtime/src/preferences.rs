@@ -0,0 +1,22 @@
+use serde_json::Value;
+
+use crate::{externs::{lyserver_plugin_preferences_get, lyserver_plugin_preferences_set}, host};
+
+/// Reads a preference, requires the `preferences.read` capability.
+pub fn get(key: &str) -> Result<Value, String> {
+    let response = host::call(lyserver_plugin_preferences_get, key.as_bytes())?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize preference: {}", e))
+}
+
+/// Creates or updates a preference, requires the `preferences.write` capability.
+pub fn set(key: &str, value: Value) -> Result<Value, String> {
+    let request = serde_cbor::to_vec(&(key, value))
+        .map_err(|e| format!("Failed to serialize preference: {}", e))?;
+
+    let response = host::call(lyserver_plugin_preferences_set, &request)?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize preference: {}", e))
+}
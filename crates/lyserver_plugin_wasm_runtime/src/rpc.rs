@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use lyserver_messaging_shared::rpc::{LYServerRPCCancel, LYServerRPCError, LYServerRPCErrorKind, LYServerRPCRequest, LYServerRPCResponse, LYSERVER_RPC_CANCEL_EVENT, LYSERVER_RPC_REQUEST_EVENT, LYSERVER_RPC_RESPONSE_EVENT};
+
+use crate::ipc::{self, LYServerMessageEvent};
+
+/// How long to sleep between polls of the host while waiting for a response, doubled after every empty poll
+/// up to `MAX_POLL_INTERVAL` so long waits do not burn through the call's fuel.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Calls `method` on the `target` plugin as `plugin_id`, blocking for up to `timeout`, requires the `rpc.call` capability.
+///
+/// The host is polled with the non-blocking receive, so the timeout holds even when no event arrives at all.
+/// Other events received while waiting are not lost, `ipc::try_recv` returns them afterwards.
+pub fn call<Req: Serialize, Resp: DeserializeOwned>(
+    plugin_id: &str,
+    target: &str,
+    method: &str,
+    request: &Req,
+    timeout: Duration,
+) -> Result<Resp, LYServerRPCError> {
+    let request = LYServerRPCRequest::new(method, request)?;
+    let correlation_id = request.correlation_id.clone();
+
+    ipc::tx(&LYServerMessageEvent::new(LYSERVER_RPC_REQUEST_EVENT, target, plugin_id, request))
+        .map_err(|e| LYServerRPCError::new(LYServerRPCErrorKind::Transport, e))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut poll_interval = MIN_POLL_INTERVAL;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero()) {
+        let Some(event) = ipc::try_recv_from_host() else {
+            std::thread::sleep(poll_interval.min(remaining));
+            poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+            continue;
+        };
+
+        poll_interval = MIN_POLL_INTERVAL;
+
+        match event.rpc_response_for(&correlation_id) {
+            Some(response) if event.event_sender.plugin_id().as_deref() == Some(target) => return response.into_result(),
+            _ => ipc::defer(event),
+        }
+    }
+
+    let cancel = LYServerRPCCancel { correlation_id };
+    let _ = ipc::tx(&LYServerMessageEvent::new(LYSERVER_RPC_CANCEL_EVENT, target, plugin_id, cancel));
+
+    Err(LYServerRPCError::new(LYServerRPCErrorKind::Timeout, format!("'{}' on '{}' did not respond within {}ms", method, target, timeout.as_millis())))
+}
+
+/// Parses the call carried by `event`, if it is an `rpc_request`.
+pub fn request(event: &LYServerMessageEvent) -> Option<LYServerRPCRequest> {
+    if event.event_type != LYSERVER_RPC_REQUEST_EVENT {
+        return None;
+    }
+
+    event.data_as::<LYServerRPCRequest>().ok()
+}
+
+/// Answers the call carried by `event` as `plugin_id`.
+pub fn respond<Resp: Serialize>(
+    plugin_id: &str,
+    event: &LYServerMessageEvent,
+    result: Result<Resp, LYServerRPCError>,
+) -> Result<(), String> {
+    let request = request(event)
+        .ok_or_else(|| format!("Event '{}' is not an RPC request", event.event_type))?;
+
+    let response = match result {
+        Ok(data) => LYServerRPCResponse::ok(request.correlation_id, &data),
+        Err(error) => LYServerRPCResponse::err(request.correlation_id, error),
+    };
+
+    let reply = event.reply(LYSERVER_RPC_RESPONSE_EVENT, plugin_id.into(), response)
+        .map_err(|e| format!("Failed to create RPC response: {}", e))?;
+
+    ipc::tx(&reply)
+}
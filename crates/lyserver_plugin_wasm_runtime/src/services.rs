@@ -0,0 +1,17 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{externs::lyserver_plugin_service_call, host};
+
+/// Calls `method` on a service published by another plugin, requires the `services.call:<service>` capability.
+pub fn call<Req: Serialize, Resp: DeserializeOwned>(service: &str, method: &str, request: &Req) -> Result<Resp, String> {
+    let data = serde_cbor::to_vec(request)
+        .map_err(|e| format!("Failed to serialize service request: {}", e))?;
+
+    let request = serde_cbor::to_vec(&(service, method, data))
+        .map_err(|e| format!("Failed to serialize service call: {}", e))?;
+
+    let response = host::call(lyserver_plugin_service_call, &request)?;
+
+    serde_cbor::from_slice(&response)
+        .map_err(|e| format!("Failed to deserialize service response: {}", e))
+}
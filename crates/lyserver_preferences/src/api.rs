@@ -1,131 +1,28 @@
 use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
+use lyserver_messaging_shared::LYServerMessageEventTarget;
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::LYServerSharedDataDatabase as _;
-use serde::{Deserialize, Serialize};
+use lyserver_shared_data::{LYServerDatabaseRow, LYServerPreferencesService, LYServerSharedDataDatabase as _, LYSERVER_PREFERENCE_CHANGED_EVENT};
 use serde_json::Value;
 
-#[repr(u8)]
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum LYServerPreferenceType {
-    Null = 0,
-    I32 = 1,
-    F32 = 2,
-    U32 = 3,
-    Boolean = 4,
-    String = 5,
-    JSON = 6,
-}
-
-impl Serialize for LYServerPreferenceType {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_u8(self.clone() as u8)
-    }
-}
-
-impl<'de> Deserialize<'de> for LYServerPreferenceType {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let value = u8::deserialize(deserializer)?;
-        match value {
-            0 => Ok(LYServerPreferenceType::Null),
-            1 => Ok(LYServerPreferenceType::I32),
-            2 => Ok(LYServerPreferenceType::F32),
-            3 => Ok(LYServerPreferenceType::U32),
-            4 => Ok(LYServerPreferenceType::Boolean),
-            5 => Ok(LYServerPreferenceType::String),
-            6 => Ok(LYServerPreferenceType::JSON),
-            _ => Err(serde::de::Error::custom("Invalid LYServerPreferenceType value")),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub enum LYServerPreference {
-    Null(LYServerPreferenceTyped<Option<()>>),
-    I32(LYServerPreferenceTyped<i32>),
-    F32(LYServerPreferenceTyped<f32>),
-    U32(LYServerPreferenceTyped<u32>),
-    Boolean(LYServerPreferenceTyped<bool>),
-    String(LYServerPreferenceTyped<String>),
-    JSON(LYServerPreferenceTyped<Value>),
-}
-
-impl LYServerPreference {
-    pub fn key(&self) -> &str {
-        match self {
-            LYServerPreference::Null(pref) => &pref.key,
-            LYServerPreference::I32(pref) => &pref.key,
-            LYServerPreference::F32(pref) => &pref.key,
-            LYServerPreference::U32(pref) => &pref.key,
-            LYServerPreference::Boolean(pref) => &pref.key,
-            LYServerPreference::String(pref) => &pref.key,
-            LYServerPreference::JSON(pref) => &pref.key,
-        }
-    }
-
-    pub fn is_locked(&self) -> bool {
-        match self {
-            LYServerPreference::Null(pref) => pref.is_locked,
-            LYServerPreference::I32(pref) => pref.is_locked,
-            LYServerPreference::F32(pref) => pref.is_locked,
-            LYServerPreference::U32(pref) => pref.is_locked,
-            LYServerPreference::Boolean(pref) => pref.is_locked,
-            LYServerPreference::String(pref) => pref.is_locked,
-            LYServerPreference::JSON(pref) => pref.is_locked,
-        }
-    }
-}
-
-impl Serialize for LYServerPreference {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        match self {
-            LYServerPreference::Null(pref) => pref.serialize(serializer),
-            LYServerPreference::I32(pref) => pref.serialize(serializer),
-            LYServerPreference::F32(pref) => pref.serialize(serializer),
-            LYServerPreference::U32(pref) => pref.serialize(serializer),
-            LYServerPreference::Boolean(pref) => pref.serialize(serializer),
-            LYServerPreference::String(pref) => pref.serialize(serializer),
-            LYServerPreference::JSON(pref) => pref.serialize(serializer),
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LYServerPreferenceTyped<T> {
-    pub key: String,
-    pub value: T,
-    pub native_type: LYServerPreferenceType,
-    pub is_locked: bool,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-}
+pub use lyserver_shared_data::{LYServerPreference, LYServerPreferenceType, LYServerPreferenceTyped};
 
 #[derive(Clone)]
 pub struct LYServerPreferencesAPI {
     plugin_shared_data: Arc<LYServerPluginSharedData>,
 }
 
-const SELECT_PREFERENCES_QUERY: &'static str = "select p.key, p.value, p.native_type_id, p.is_locked, p.created_at, p.updated_at from preferences p";
-const SELECT_PREFERENCES_QUERY_WITH_KEY: &'static str = "select p.key, p.value, p.native_type_id, p.is_locked, p.created_at, p.updated_at from preferences p where p.key = ?";
-const SET_PREFERENCE_WITH_KEY: &'static str = r#"
+const SELECT_PREFERENCES_QUERY: &str = "select p.key, p.value, p.native_type_id, p.is_locked, p.created_at, p.updated_at from preferences p";
+const SELECT_PREFERENCES_QUERY_WITH_KEY: &str = "select p.key, p.value, p.native_type_id, p.is_locked, p.created_at, p.updated_at from preferences p where p.key = ?";
+const SET_PREFERENCE_WITH_KEY: &str = r#"
 insert into preferences (key, value, native_type_id) 
 values (?, ?, ?) 
 on conflict(key) do update set 
 value = excluded.value, 
 native_type_id = excluded.native_type_id
 "#;
-const SET_PREFERENCE_LOCK_WITH_KEY: &'static str = "update preferences set is_locked = ? where key = ?";
-const DELETE_PREFERENCE_WITH_KEY: &'static str = "delete from preferences where key = ?";
+const SET_PREFERENCE_LOCK_WITH_KEY: &str = "update preferences set is_locked = ? where key = ?";
+const DELETE_PREFERENCE_WITH_KEY: &str = "delete from preferences where key = ?";
 
 impl LYServerPreferencesAPI {
     pub fn new(plugin_shared_data: Arc<LYServerPluginSharedData>) -> Self {
@@ -135,84 +32,55 @@ impl LYServerPreferencesAPI {
     }
 
     pub async fn get_all_preferences(&self) -> anyhow::Result<Vec<LYServerPreference>> {
-        self.plugin_shared_data.app_shared_data.query("preferences".to_string(), SELECT_PREFERENCES_QUERY.to_string(), vec![]).await
-            .and_then(|result| {
-                result.as_array()
-                    .ok_or_else(|| anyhow::anyhow!("Expected an array of preferences"))
-                    .and_then(|items| {
-                        items.iter()
-                            .map(Self::deserialize_preference)
-                            .collect::<Result<Vec<_>, _>>()
-                    })
-            })
+        self.plugin_shared_data.app_shared_data.query("preferences", SELECT_PREFERENCES_QUERY, vec![]).await?
+            .iter()
+            .map(Self::deserialize_preference)
+            .collect()
     }
 
     pub async fn get_preference_by_id(&self, pref_name: &str) -> anyhow::Result<LYServerPreference> {
-        self.plugin_shared_data.app_shared_data.query("preferences".to_string(), SELECT_PREFERENCES_QUERY_WITH_KEY.to_string(), vec![pref_name.to_string()]).await
-            .and_then(|result| {
-                result.as_array()
-                    .ok_or_else(|| anyhow::anyhow!("Expected an array of preferences"))
-                    .and_then(|items| {
-                        items.first()
-                            .ok_or_else(|| anyhow::anyhow!("Preference not found"))
-                            .and_then(Self::deserialize_preference)
-                    })
-            })
+        self.plugin_shared_data.app_shared_data.query("preferences", SELECT_PREFERENCES_QUERY_WITH_KEY, vec![pref_name.into()]).await?
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Preference not found"))
+            .and_then(Self::deserialize_preference)
     }
 
-    pub fn deserialize_preference(result: &Value) -> anyhow::Result<LYServerPreference> {
+    pub fn deserialize_preference(result: &LYServerDatabaseRow) -> anyhow::Result<LYServerPreference> {
         log::debug!("Deserializing preference: {:?}", result);
 
-        let native_type_id = result.get("native_type_id").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference native_type_id not found"))?;
+        let column = |name: &str| result.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Preference {} not found", name));
 
-        let native_type_id = native_type_id
-            .as_str();
+        let native_type_id = column("native_type_id")?
+            .as_i64()
+            .unwrap_or(0);
 
         log::debug!("Deserializing preference with native_type_id: {:?}", native_type_id);
 
-        let native_type_id = native_type_id.and_then(|s| s.parse::<u32>().ok())
-            .unwrap_or(0)
-            as u32;
-
-        let key = result.get("key").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference key not found"))?
+        let key = column("key")?
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Preference key is not a valid string"))?
             .to_string();
 
-        let value = result.get("value").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference value not found"))?
+        let value = column("value")?
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Preference value is not a valid string"))?
             .to_string();
 
-        let is_locked = result.get("is_locked").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference is_locked not found"))?
-            .as_str()
-            .and_then(|s| s.parse::<u8>().ok())
-            .and_then(|v| match v {
-                1 => Some(true),
-                _ => Some(false),
-            })
-            .unwrap_or(false);
-
-        let created_at = result.get("created_at").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference created_at not found"))?
+        let is_locked = column("is_locked")?.as_i64() == Some(1);
+
+        let created_at = column("created_at")?
             .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Preference created_at is not a valid string"))?
-            .to_string();
+            .ok_or_else(|| anyhow::anyhow!("Preference created_at is not a valid string"))?;
 
-        let created_at = chrono::NaiveDateTime::parse_from_str(&created_at, "%Y-%m-%d %H:%M:%S")?
+        let created_at = chrono::NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S")?
             .and_utc();
 
-        let updated_at = result.get("updated_at").cloned()
-            .ok_or_else(|| anyhow::anyhow!("Preference updated_at not found"))?
+        let updated_at = column("updated_at")?
             .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Preference created_at is not a valid string"))?
-            .to_string();
+            .ok_or_else(|| anyhow::anyhow!("Preference updated_at is not a valid string"))?;
 
-        let updated_at = chrono::NaiveDateTime::parse_from_str(&updated_at, "%Y-%m-%d %H:%M:%S")?
+        let updated_at = chrono::NaiveDateTime::parse_from_str(updated_at, "%Y-%m-%d %H:%M:%S")?
             .and_utc();
 
         let native_preference_type = match native_type_id {
@@ -280,10 +148,7 @@ impl LYServerPreferencesAPI {
     pub async fn does_preference_exist<T: Into<String>>(&self, key: T) -> bool {
         let key_str: String = key.into();
 
-        match self.get_preference_by_id(&key_str).await {
-            Ok(_) => true,
-            Err(_) => false,
-        }
+        self.get_preference_by_id(&key_str).await.is_ok()
     }
 
     pub async fn is_preference_locked<T: Into<String>>(&self, key: T) -> bool {
@@ -313,15 +178,17 @@ impl LYServerPreferencesAPI {
         };
 
         self.plugin_shared_data.app_shared_data.query(
-            "preferences".to_string(), 
-            SET_PREFERENCE_WITH_KEY.to_string(), 
+            "preferences", 
+            SET_PREFERENCE_WITH_KEY, 
             vec![
-                key_str, 
-                value_str, 
-                (native_type as u32).to_string()
+                key_str.clone().into(), 
+                value_str.into(), 
+                (native_type as u32).into()
             ]
         ).await?;
 
+        self.dispatch_changed_event(&key_str).await;
+
         Ok(())
     }
 
@@ -334,17 +201,11 @@ impl LYServerPreferencesAPI {
             log::warn!("Unlocking preference '{}'", key_str);
         }
 
-        let is_locked = if is_locked {
-            1
-        } else {
-            0
-        };
-
         if self.does_preference_exist(&key_str).await {
             self.plugin_shared_data.app_shared_data.query(
-                "preferences".to_string(), 
-                SET_PREFERENCE_LOCK_WITH_KEY.to_string(), 
-                vec![is_locked.to_string(), key_str.clone()]
+                "preferences", 
+                SET_PREFERENCE_LOCK_WITH_KEY, 
+                vec![is_locked.into(), key_str.into()]
             ).await?;
         }
 
@@ -363,6 +224,18 @@ impl LYServerPreferencesAPI {
         Ok(())
     }
 
+    /// Tells every plugin about a changed preference, the change itself is already stored.
+    async fn dispatch_changed_event(&self, key: &str) {
+        let result = match self.plugin_shared_data.create_event(LYSERVER_PREFERENCE_CHANGED_EVENT, LYServerMessageEventTarget::All, key).await {
+            Ok(event) => self.plugin_shared_data.dispatch_event(event),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            log::warn!("Failed to announce the change of preference '{}': {}", key, e);
+        }
+    }
+
     pub async fn delete_preference_by_id<T: Into<String>>(&self, key: T) -> anyhow::Result<()> {
         let key_str: String = key.into();
 
@@ -372,14 +245,39 @@ impl LYServerPreferencesAPI {
             }
 
             self.plugin_shared_data.app_shared_data.query(
-                "preferences".to_string(), 
-                DELETE_PREFERENCE_WITH_KEY.to_string(), 
-                vec![key_str.to_string()]
+                "preferences", 
+                DELETE_PREFERENCE_WITH_KEY, 
+                vec![key_str.clone().into()]
             ).await?;
 
+            self.dispatch_changed_event(&key_str).await;
+
             Ok(())
         } else {
             anyhow::bail!("Preference '{}' does not exist", key_str);
         }
     }
-}
\ No newline at end of file
+}
+
+#[async_trait::async_trait]
+impl LYServerPreferencesService for LYServerPreferencesAPI {
+    async fn get(&self, key: &str) -> anyhow::Result<LYServerPreference> {
+        self.get_preference_by_id(key).await
+    }
+
+    async fn get_all(&self) -> anyhow::Result<Vec<LYServerPreference>> {
+        self.get_all_preferences().await
+    }
+
+    async fn set(&self, key: &str, value: Value) -> anyhow::Result<LYServerPreference> {
+        let native_type = LYServerPreferenceType::from_value(&value)?;
+
+        self.set_preference(key, value, native_type).await?;
+
+        self.get_preference_by_id(key).await
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.delete_preference_by_id(key).await
+    }
+}
@@ -1,18 +1,20 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
-use lyserver_http_shared::{router::LYServerHTTPRouter, LYServerHTTPRequest};
+use lyserver_http_shared::{error::LYServerHTTPError, router::LYServerHTTPRouter, LYServerHTTPHandleIntent, LYServerHTTPRequest};
 use lyserver_messaging_shared::LYServerMessageEvent;
 use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
 use lyserver_plugin_shared_data::LYServerPluginSharedData;
-use lyserver_shared_data::{LYServerSharedData, LYServerSharedDataDatabase, LYServerSharedDataPlugins};
-use serde::{Deserialize, Serialize};
+use lyserver_shared_data::{LYServerPreferencesService, LYServerSharedDataServices as _};
+use serde::Deserialize;
 use serde_json::{json, Value};
 
-use crate::api::{LYServerPreferenceType, LYServerPreferenceTyped, LYServerPreferencesAPI};
+use crate::api::{LYServerPreferenceType, LYServerPreferencesAPI};
 
 mod api;
 
+/// Method the HTTP server calls with the requests this plugin claimed.
+const PREFERENCES_HTTP_RPC_METHOD: &str = "http_request";
+
 pub struct LYServerPreferencesPlugin {
     plugin_shared_data: Arc<LYServerPluginSharedData>,
 
@@ -28,157 +30,164 @@ impl LYServerPreferencesPlugin {
             api: Arc::new(LYServerPreferencesAPI::new(plugin_shared_data_clone)),
         })
     }
-}
 
-#[async_trait::async_trait]
-impl LYServerPlugin for LYServerPreferencesPlugin {
-    fn metadata(&self) -> LYServerPluginMetadata {
+    pub fn plugin_metadata() -> LYServerPluginMetadata {
         LYServerPluginMetadata::builder()
             .id("preferences@lyserver.local")
             .name("LYServerPreferencesPlugin")
             .description("Preferences plugin for LYServer")
             .version(env!("CARGO_PKG_VERSION").to_string())
             .author("LYServer")
+            .dependency("database@lyserver.local", env!("CARGO_PKG_VERSION"))
             .build()
     }
 
-    async fn init(&self) -> anyhow::Result<()> {
-        self.api.set_server_version_preference().await?;
+    /// Routes of the preferences HTTP API, served over RPC to the HTTP server.
+    fn router(api: Arc<LYServerPreferencesAPI>) -> LYServerHTTPRouter {
+        let mut router = LYServerHTTPRouter::new();
 
-        self.plugin_shared_data.dispatch_init_event().await?;
+        let api_clone = Arc::clone(&api);
+        router.add_matcher("GET", "/preferences", move |route| {
+            let api_clone = Arc::clone(&api_clone);
 
-        Ok(())
-    }
+            async move {
+                let all_preferences = api_clone.get_all_preferences().await?;
 
-    async fn handle_message_event(
-        &self,
-        event: LYServerMessageEvent,
-    ) -> anyhow::Result<()> {
-        if event.event_type == "http_request" {
-            let request = event.data_as::<LYServerHTTPRequest>().expect("Failed to deserialize LYServerHTTPRequest");
+                let response = route.request.build_response()
+                    .json(json!({
+                        "ok": true,
+                        "data": all_preferences
+                    }))
+                    .build();
+
+                Ok(response)
+            }
+        });
 
-            let mut router = LYServerHTTPRouter::new();
+        let api_clone = Arc::clone(&api);
+        router.add_matcher("GET", "/preferences/:key", move |route| {
+            let api_clone = Arc::clone(&api_clone);
 
-            let api_clone = Arc::clone(&self.api);
-            router.add_matcher("GET", "/preferences", move |route| {
-                let api_clone = Arc::clone(&api_clone);
+            async move {
+                let key = route.params.get("key")
+                    .ok_or_else(|| anyhow::anyhow!("Missing 'key' parameter in request"))?
+                    .to_string();
 
-                async move {
-                    let all_preferences = api_clone.get_all_preferences().await?;
-                
+                if let Ok(preference) = api_clone.get_preference_by_id(&key).await {
                     let response = route.request.build_response()
                         .json(json!({
                             "ok": true,
-                            "data": all_preferences
+                            "data": preference
                         }))
                         .build();
-            
+
                     Ok(response)
+                } else {
+                    let error_response = route.request.not_found_response();
+                    Ok(error_response)
                 }
-            });
-
-            let api_clone = Arc::clone(&self.api);
-            router.add_matcher("GET", "/preferences/:key", move |route| {
-                let api_clone = Arc::clone(&api_clone);
-
-                async move {
-                    let key = route.params.get("key")
-                        .ok_or_else(|| anyhow::anyhow!("Missing 'key' parameter in request"))?
-                        .to_string();
-
-                    if let Ok(preference) = api_clone.get_preference_by_id(&key).await {
-                        let response = route.request.build_response()
-                            .json(json!({
-                                "ok": true,
-                                "data": preference
-                            }))
-                            .build();
-
-                        Ok(response)
-                    } else {
-                        let error_response = route.request.not_found_response();
-                        Ok(error_response)
-                    }
-                }
-            });
-
-            let api_clone = Arc::clone(&self.api);
-            router.add_matcher("PUT", "/preferences", move |route| {
-                let api_clone = Arc::clone(&api_clone);
-
-                async move {
-                    #[derive(Deserialize)]
-                    struct PutPreferenceRequest {
-                        key: String,
-                        value: Value,
-                    }
-
-                    let body = route.request.body_json::<PutPreferenceRequest>()?;
-
-                    let new_native_type = match &body.value {
-                        Value::Null => LYServerPreferenceType::Null,
-                        Value::Bool(_) => LYServerPreferenceType::Boolean,
-                        Value::Number(num) => {
-                            if num.is_f64() {
-                                LYServerPreferenceType::F32
-                            } else if num.is_u64() {
-                                LYServerPreferenceType::U32
-                            } else if num.is_i64() {
-                                LYServerPreferenceType::I32
-                            } else {
-                                return Err(anyhow::anyhow!("Invalid number type in preference value"));
-                            }
-                        },
-                        Value::String(_) => LYServerPreferenceType::String,
-                        Value::Array(_) => LYServerPreferenceType::JSON,
-                        Value::Object(_) => LYServerPreferenceType::JSON,
-                    };
-
-                    let pref_exists = api_clone.does_preference_exist(&body.key).await;
-
-                    api_clone.set_preference(
-                        body.key.clone(), 
-                        body.value, 
-                        new_native_type
-                    ).await?;
-
-                    let new_preference = api_clone.get_preference_by_id(&body.key).await?;
+            }
+        });
 
-                    let response = route.request.build_response()
-                        .status_code(if pref_exists { 200 } else { 201 })
-                        .json(json!({
-                            "ok": true,
-                            "data": new_preference
-                        }))
-                        .build();
+        let api_clone = Arc::clone(&api);
+        router.add_matcher("PUT", "/preferences", move |route| {
+            let api_clone = Arc::clone(&api_clone);
 
-                    Ok(response)
+            async move {
+                #[derive(Deserialize)]
+                struct PutPreferenceRequest {
+                    key: String,
+                    value: Value,
                 }
-            });
 
-            let api_clone = Arc::clone(&self.api);
-            router.add_matcher("DELETE", "/preferences", move |route| {
-                let api_clone = Arc::clone(&api_clone);
+                let body = route.request.body_json::<PutPreferenceRequest>()?;
 
-                async move {
-                    #[derive(Deserialize)]
-                    struct DeletePreferenceRequest {
-                        key: String,
-                    }
+                let new_native_type = LYServerPreferenceType::from_value(&body.value)?;
 
-                    let body = route.request.body_json::<DeletePreferenceRequest>()?;
+                let pref_exists = api_clone.does_preference_exist(&body.key).await;
 
-                    api_clone.delete_preference_by_id(&body.key).await?;
+                api_clone.set_preference(
+                    body.key.clone(), 
+                    body.value, 
+                    new_native_type
+                ).await?;
 
-                    let response = route.request.build_response()
-                        .status_code(204)
-                        .build();
-                    Ok(response)
+                let new_preference = api_clone.get_preference_by_id(&body.key).await?;
+
+                let response = route.request.build_response()
+                    .status_code(if pref_exists { 200 } else { 201 })
+                    .json(json!({
+                        "ok": true,
+                        "data": new_preference
+                    }))
+                    .build();
+
+                Ok(response)
+            }
+        });
+
+        let api_clone = Arc::clone(&api);
+        router.add_matcher("DELETE", "/preferences", move |route| {
+            let api_clone = Arc::clone(&api_clone);
+
+            async move {
+                #[derive(Deserialize)]
+                struct DeletePreferenceRequest {
+                    key: String,
                 }
-            });
 
-            if let Some(response) = router.respond(request).await {
-                self.plugin_shared_data.reply_event("http_response", event, response).await?;
+                let body = route.request.body_json::<DeletePreferenceRequest>()?;
+
+                api_clone.delete_preference_by_id(&body.key).await?;
+
+                let response = route.request.build_response()
+                    .status_code(204)
+                    .build();
+                Ok(response)
+            }
+        });
+
+        router
+    }
+}
+
+#[async_trait::async_trait]
+impl LYServerPlugin for LYServerPreferencesPlugin {
+    fn metadata(&self) -> LYServerPluginMetadata {
+        Self::plugin_metadata()
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        self.api.set_server_version_preference().await?;
+
+        self.plugin_shared_data.app_shared_data.register_service::<dyn LYServerPreferencesService>(&Self::plugin_metadata().id, self.api.clone());
+
+        let api = Arc::clone(&self.api);
+        self.plugin_shared_data.register_rpc_handler(PREFERENCES_HTTP_RPC_METHOD, move |request: LYServerHTTPRequest| {
+            let router = Self::router(Arc::clone(&api));
+
+            async move {
+                router.respond(request).await
+                    .ok_or_else(|| LYServerHTTPError::new(404, "No preferences route matches the request").into())
+            }
+        });
+
+        self.plugin_shared_data.dispatch_init_event().await?;
+
+        Ok(())
+    }
+
+    async fn handle_message_event(
+        &self,
+        event: LYServerMessageEvent,
+    ) -> anyhow::Result<()> {
+        if event.event_type == "http_request" {
+            let request = event.data_as::<LYServerHTTPRequest>().expect("Failed to deserialize LYServerHTTPRequest");
+
+            if Self::router(Arc::clone(&self.api)).has_match(&request) {
+                let intent = LYServerHTTPHandleIntent { rpc_method: Some(PREFERENCES_HTTP_RPC_METHOD.to_string()) };
+
+                self.plugin_shared_data.reply_event("http_request_handle_intent", event, intent).await?;
             }
         }
 
@@ -189,35 +198,26 @@ impl LYServerPlugin for LYServerPreferencesPlugin {
         Ok(())
     }
 
-    async fn invoke(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
+    async fn invoke(&self, method: &str, args: Vec<u8>) -> anyhow::Result<Vec<u8>> {
         match method {
             "get" => {
-                if args.len() != 1 {
-                    return Err(anyhow::anyhow!("Invalid arguments for 'get' method"));
-                }
+                let key = serde_cbor::from_slice::<String>(&args)?;
+
+                Ok(serde_cbor::to_vec(&self.api.get(&key).await?)?)
+            }
+            "get_all" => Ok(serde_cbor::to_vec(&self.api.get_all().await?)?),
+            "set" => {
+                let (key, value) = serde_cbor::from_slice::<(String, Value)>(&args)?;
 
-                let pref_name = args.get(0)
-                    .cloned()
-                    .ok_or_else(|| anyhow::anyhow!("Missing preference name argument for get method."))?;
-
-                self.api.get_preference_by_id(&pref_name).await
-                    .and_then(|pref| {
-                        serde_json::to_value(pref)
-                            .map_err(|e| anyhow::anyhow!("Failed to serialize preference: {}", e))
-                    })
-            },
-            "get_all" => {
-                self.api.get_all_preferences().await
-                    .and_then(|prefs| {
-                        serde_json::to_value(prefs)
-                            .map_err(|e| anyhow::anyhow!("Failed to serialize preferences: {}", e))
-                    })
+                Ok(serde_cbor::to_vec(&self.api.set(&key, value).await?)?)
+            }
+            "delete" => {
+                let key = serde_cbor::from_slice::<String>(&args)?;
+                self.api.delete(&key).await?;
+
+                Ok(serde_cbor::to_vec(&())?)
             }
             _ => Err(anyhow::anyhow!("Unknown method: {}", method)),
         }
     }
-
-    async fn receive(&self, method: &str, args: Vec<String>) -> anyhow::Result<Value> {
-        Ok("Received method not implemented".to_string().into())
-    }
 }
\ No newline at end of file
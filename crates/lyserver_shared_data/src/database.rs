@@ -1,27 +1,136 @@
-use std::{any, path::{Path, PathBuf}};
+use std::collections::HashMap;
 
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
 
-use crate::{LYServerSharedData, LYServerSharedDataPlugins};
+use crate::{LYServerService, LYServerSharedData, LYServerSharedDataServices as _};
+
+/// A value bound to or read from an SQLite column.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum LYServerDatabaseValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+pub type LYServerDatabaseRow = HashMap<String, LYServerDatabaseValue>;
+
+impl LYServerDatabaseValue {
+    pub fn is_null(&self) -> bool {
+        matches!(self, LYServerDatabaseValue::Null)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            LYServerDatabaseValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            LYServerDatabaseValue::Real(value) => Some(*value),
+            LYServerDatabaseValue::Integer(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            LYServerDatabaseValue::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for LYServerDatabaseValue {
+    fn from(value: String) -> Self {
+        LYServerDatabaseValue::Text(value)
+    }
+}
+
+impl From<&str> for LYServerDatabaseValue {
+    fn from(value: &str) -> Self {
+        LYServerDatabaseValue::Text(value.to_string())
+    }
+}
+
+impl From<i64> for LYServerDatabaseValue {
+    fn from(value: i64) -> Self {
+        LYServerDatabaseValue::Integer(value)
+    }
+}
+
+impl From<u32> for LYServerDatabaseValue {
+    fn from(value: u32) -> Self {
+        LYServerDatabaseValue::Integer(value as i64)
+    }
+}
+
+impl From<bool> for LYServerDatabaseValue {
+    fn from(value: bool) -> Self {
+        LYServerDatabaseValue::Integer(value as i64)
+    }
+}
+
+impl From<f64> for LYServerDatabaseValue {
+    fn from(value: f64) -> Self {
+        LYServerDatabaseValue::Real(value)
+    }
+}
+
+impl From<Vec<u8>> for LYServerDatabaseValue {
+    fn from(value: Vec<u8>) -> Self {
+        LYServerDatabaseValue::Blob(value)
+    }
+}
+
+impl<T: Into<LYServerDatabaseValue>> From<Option<T>> for LYServerDatabaseValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(LYServerDatabaseValue::Null, Into::into)
+    }
+}
+
+/// Runs queries against the server databases, provided by `database@lyserver.local`.
+#[async_trait::async_trait]
+pub trait LYServerDatabaseService: Send + Sync {
+    async fn query(&self, database: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>>;
+    /// Opens the database `name` of a plugin at `<data_dir>/databases/<plugin-id>/<name>.db`, applying the
+    /// migrations it has not seen yet, given as SQL in order.
+    async fn open_plugin_database(&self, plugin_id: &str, name: &str, migrations: Vec<String>) -> anyhow::Result<()>;
+    /// Closes every database opened for a plugin.
+    async fn close_plugin_databases(&self, plugin_id: &str) -> anyhow::Result<()>;
+    /// Runs a query against one of the databases of `plugin_id`, other plugins cannot reach them.
+    async fn query_plugin_database(&self, plugin_id: &str, name: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>>;
+}
+
+impl LYServerService for dyn LYServerDatabaseService {
+    const NAME: &'static str = "database";
+}
 
 #[async_trait::async_trait]
 pub trait LYServerSharedDataDatabase {
-    async fn query(&self, database: String, query: String, args: Vec<String>) -> anyhow::Result<Value>;
+    async fn query(&self, database: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>>;
+    async fn query_plugin_database(&self, plugin_id: &str, name: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>>;
 }
 
 #[async_trait::async_trait]
 impl LYServerSharedDataDatabase for LYServerSharedData {
-    async fn query(&self, database: String, query: String, args: Vec<String>) -> anyhow::Result<Value> {
-        if let Some(db) = self.get_plugin_by_id("database@lyserver.local").await {
-            let mut query_params = vec![database.to_string(), query.to_string()];
-            query_params.extend(args.into_iter().map(|arg| arg.into()));
+    async fn query(&self, database: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>> {
+        let db = self.get_service::<dyn LYServerDatabaseService>()
+            .ok_or_else(|| anyhow::anyhow!("Database service is not available"))?;
+
+        db.query(database, query, args).await
+            .map_err(|e| anyhow::anyhow!("Failed to execute query '{}': {}", query, e))
+    }
 
-            let result = db.invoke("query", query_params).await
-                .map_err(|e| anyhow::anyhow!("Failed to execute query '{}': {}", query, e))?;
+    async fn query_plugin_database(&self, plugin_id: &str, name: &str, query: &str, args: Vec<LYServerDatabaseValue>) -> anyhow::Result<Vec<LYServerDatabaseRow>> {
+        let db = self.get_service::<dyn LYServerDatabaseService>()
+            .ok_or_else(|| anyhow::anyhow!("Database service is not available"))?;
 
-            Ok(result)
-        } else {
-            Err(anyhow::anyhow!("Database plugin not found"))
-        }
+        db.query_plugin_database(plugin_id, name, query, args).await
+            .map_err(|e| anyhow::anyhow!("Failed to execute query '{}' on '{}': {}", query, name, e))
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,32 @@
 use std::{path::{Path, PathBuf}};
 
+use lyserver_plugin_common::{LYServerPluginCapability, LYServerPluginMetadata};
+
 use crate::LYServerSharedData;
 
+/// Guest path of the private, writable data directory every WASM plugin gets.
+pub const LYSERVER_PLUGIN_DATA_DIR: &str = "/data";
+
 pub trait LYServerSharedDataDirectories {
     fn resolve_data_path(&self, path: &Path) -> PathBuf;
     fn resolve_data_path_str(&self, path: &'static str) -> PathBuf;
+    /// The private directory of a plugin, `<data_dir>/plugin_data/<plugin-id>`.
+    fn resolve_plugin_data_path(&self, plugin_id: &str) -> PathBuf;
+    /// Resolves a directory a plugin asked for access to, which has to be inside one of the allowed plugin roots.
+    fn resolve_plugin_fs_grant(&self, path: &Path) -> anyhow::Result<PathBuf>;
+    /// Maps a path as a WASM plugin sees it to the host, it has to be inside the plugin's data directory or
+    /// one of its `fs.read` grants.
+    fn resolve_plugin_guest_path(&self, metadata: &LYServerPluginMetadata, path: &Path) -> anyhow::Result<PathBuf>;
+}
+
+/// Directory name for a plugin id, which comes from an untrusted manifest.
+pub fn plugin_dir_name(plugin_id: &str) -> String {
+    plugin_id
+        .chars()
+        .enumerate()
+        // A leading dot would allow ids like ".." to escape the parent directory
+        .map(|(i, c)| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '@') || (c == '.' && i > 0) { c } else { '_' })
+        .collect()
 }
 
 impl LYServerSharedDataDirectories for LYServerSharedData {
@@ -12,21 +34,171 @@ impl LYServerSharedDataDirectories for LYServerSharedData {
         let mut data_path = self.data_dir.clone();
         data_path.push(path);
 
-        return if let Err(_) = data_path.canonicalize() {
+        if data_path.canonicalize().is_err() {
             data_path
         } else {
             data_path.canonicalize().unwrap()
-        };
+        }
     }
     
     fn resolve_data_path_str(&self, path: &'static str) -> PathBuf {
         let mut data_path = self.data_dir.clone();
         data_path.push(Path::new(path));
 
-        return if let Err(_) = data_path.canonicalize() {
+        if data_path.canonicalize().is_err() {
             data_path
         } else {
             data_path.canonicalize().unwrap()
-        };
+        }
+    }
+
+    fn resolve_plugin_data_path(&self, plugin_id: &str) -> PathBuf {
+        self.resolve_data_path(&Path::new("plugin_data").join(plugin_dir_name(plugin_id)))
+    }
+
+    fn resolve_plugin_fs_grant(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        // Canonicalizing resolves `..` and symlinks, so a grant cannot point outside of the root it was checked against
+        let path = path.canonicalize()
+            .map_err(|e| anyhow::anyhow!("Cannot access '{}': {}", path.display(), e))?;
+
+        let is_allowed = self.plugin_fs_allowed_roots.iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root));
+
+        if !is_allowed {
+            return Err(anyhow::anyhow!("'{}' is not inside any of the allowed plugin roots, see --plugin-fs-allowed-root", path.display()));
+        }
+
+        Ok(path)
+    }
+
+    fn resolve_plugin_guest_path(&self, metadata: &LYServerPluginMetadata, path: &Path) -> anyhow::Result<PathBuf> {
+        let mut mounts = vec![(PathBuf::from(LYSERVER_PLUGIN_DATA_DIR), self.resolve_plugin_data_path(&metadata.id))];
+
+        for capability in &metadata.capabilities {
+            if let LYServerPluginCapability::FsRead(grant) = capability
+                && let Ok(host_path) = self.resolve_plugin_fs_grant(Path::new(grant)) {
+                mounts.push((PathBuf::from(grant), host_path));
+            }
+        }
+
+        for (guest_root, host_root) in mounts {
+            let Ok(relative_path) = path.strip_prefix(&guest_root) else {
+                continue;
+            };
+
+            // Canonicalizing both sides keeps `..` and symlinks from leaving the mount
+            let (Ok(host_root), Ok(host_path)) = (host_root.canonicalize(), host_root.join(relative_path).canonicalize()) else {
+                continue;
+            };
+
+            if host_path.starts_with(&host_root) {
+                return Ok(host_path);
+            }
+        }
+
+        Err(anyhow::anyhow!("'{}' is not inside the data directory or an fs.read grant of plugin '{}'", path.display(), metadata.id))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PLUGIN_ID: &str = "files@plugin";
+
+    /// Lays out `<base>/data` as the data directory, `<base>/allowed` as the only allowed root and a secret
+    /// next to them, which symlinks inside the data directory and the allowed root point at.
+    struct TestDirectories {
+        base: PathBuf,
+        shared_data: LYServerSharedData,
+    }
+
+    impl TestDirectories {
+        async fn create(name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("lyserver-directories-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&base);
+
+            let plugin_data = base.join("data/plugin_data").join(plugin_dir_name(TEST_PLUGIN_ID));
+            std::fs::create_dir_all(&plugin_data).unwrap();
+            std::fs::write(plugin_data.join("state.json"), "{}").unwrap();
+
+            std::fs::create_dir_all(base.join("allowed/music")).unwrap();
+            std::fs::write(base.join("allowed/music/song.flac"), "").unwrap();
+            std::fs::write(base.join("secret.txt"), "").unwrap();
+
+            std::os::unix::fs::symlink(base.join("secret.txt"), plugin_data.join("secret.txt")).unwrap();
+            std::os::unix::fs::symlink(&base, base.join("allowed/music/parent")).unwrap();
+
+            let shared_data = LYServerSharedData::new_from_args([
+                "lyserver", "--port", "0",
+                "--data-dir", base.join("data").to_str().unwrap(),
+                "--plugin-fs-allowed-root", base.join("allowed").to_str().unwrap(),
+            ]).unwrap();
+
+            Self { base, shared_data }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.base.join(path)
+        }
+
+        fn metadata(&self, grants: &[&str]) -> LYServerPluginMetadata {
+            grants.iter()
+                .fold(LYServerPluginMetadata::builder().id(TEST_PLUGIN_ID).version("1.0.0"), |metadata, grant| {
+                    metadata.capability(LYServerPluginCapability::FsRead(self.path(grant).to_str().unwrap().to_string()))
+                })
+                .build()
+        }
+    }
+
+    #[test]
+    fn plugin_dir_names_stay_inside_their_parent() {
+        assert_eq!(plugin_dir_name("files@plugin"), "files@plugin");
+        assert_eq!(plugin_dir_name("com.example.files"), "com.example.files");
+        assert_eq!(plugin_dir_name(".."), "_.");
+        assert_eq!(plugin_dir_name("../../etc"), "_._.._etc");
+        assert_eq!(plugin_dir_name("/etc"), "_etc");
+    }
+
+    #[tokio::test]
+    async fn fs_grants_have_to_be_inside_an_allowed_root() {
+        let test = TestDirectories::create("grants").await;
+
+        assert_eq!(test.shared_data.resolve_plugin_fs_grant(&test.path("allowed/music")).unwrap(), test.path("allowed/music").canonicalize().unwrap());
+
+        assert!(test.shared_data.resolve_plugin_fs_grant(&test.path("data")).is_err());
+        assert!(test.shared_data.resolve_plugin_fs_grant(&test.path("allowed/../secret.txt")).is_err());
+        assert!(test.shared_data.resolve_plugin_fs_grant(&test.path("allowed/music/parent")).is_err());
+        assert!(test.shared_data.resolve_plugin_fs_grant(&test.path("allowed/missing")).is_err());
+    }
+
+    #[tokio::test]
+    async fn guest_paths_resolve_inside_the_data_directory() {
+        let test = TestDirectories::create("data").await;
+        let metadata = test.metadata(&[]);
+
+        let state = test.shared_data.resolve_plugin_guest_path(&metadata, Path::new("/data/state.json")).unwrap();
+        assert_eq!(state, test.shared_data.resolve_plugin_data_path(TEST_PLUGIN_ID).join("state.json"));
+
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, Path::new("/data/../../secret.txt")).is_err());
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, Path::new("/data/secret.txt")).is_err());
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, Path::new("/secret.txt")).is_err());
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, &test.path("secret.txt")).is_err());
+    }
+
+    #[tokio::test]
+    async fn guest_paths_resolve_inside_fs_grants() {
+        let test = TestDirectories::create("grant-paths").await;
+        let metadata = test.metadata(&["allowed/music"]);
+
+        let song = test.shared_data.resolve_plugin_guest_path(&metadata, &test.path("allowed/music/song.flac")).unwrap();
+        assert_eq!(song, test.path("allowed/music/song.flac").canonicalize().unwrap());
+
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, &test.path("allowed/music/../../secret.txt")).is_err());
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, &test.path("allowed/music/parent/secret.txt")).is_err());
+
+        // Grants outside of the allowed roots are not mounted at all
+        let metadata = test.metadata(&["."]);
+        assert!(test.shared_data.resolve_plugin_guest_path(&metadata, &test.path("secret.txt")).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::{LYServerService, LYServerSharedData};
+
+/// Key/value storage namespaced per plugin, provided by `database@lyserver.local`.
+///
+/// Writes fail once the keys and values of a namespace would take up more than `max_bytes`, where `0` disables the quota.
+#[async_trait::async_trait]
+pub trait LYServerKVService: Send + Sync {
+    async fn get(&self, namespace: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn set(&self, namespace: &str, key: &str, value: Vec<u8>, max_bytes: u64) -> anyhow::Result<()>;
+    /// Removes a key, returning whether it existed.
+    async fn delete(&self, namespace: &str, key: &str) -> anyhow::Result<bool>;
+    /// The keys starting with `prefix`, in ascending order.
+    async fn list_prefix(&self, namespace: &str, prefix: &str) -> anyhow::Result<Vec<String>>;
+    /// Atomically replaces the value of a key if it still holds `expected`, where `None` stands for a missing key
+    /// on both sides. Returns whether the value was swapped.
+    async fn compare_and_swap(&self, namespace: &str, key: &str, expected: Option<Vec<u8>>, value: Option<Vec<u8>>, max_bytes: u64) -> anyhow::Result<bool>;
+    /// Bytes taken up by the keys and values of a namespace.
+    async fn usage(&self, namespace: &str) -> anyhow::Result<u64>;
+}
+
+impl LYServerService for dyn LYServerKVService {
+    const NAME: &'static str = "kv";
+}
+
+#[async_trait::async_trait]
+pub trait LYServerSharedDataKV {
+    /// KV quota of a loaded plugin, from its `[limits]` or the server default.
+    async fn get_plugin_kv_max_bytes(&self, plugin_id: &str) -> u64;
+}
+
+#[async_trait::async_trait]
+impl LYServerSharedDataKV for LYServerSharedData {
+    async fn get_plugin_kv_max_bytes(&self, plugin_id: &str) -> u64 {
+        self.loaded_plugins.read().await
+            .iter()
+            .find(|(_, metadata, _)| metadata.id == plugin_id)
+            .and_then(|(_, metadata, _)| metadata.limits.kv_max_bytes)
+            .unwrap_or(self.plugin_kv_max_bytes)
+    }
+}
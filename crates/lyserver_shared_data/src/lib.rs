@@ -3,20 +3,28 @@ mod status;
 mod plugins;
 mod messaging;
 mod database;
+mod kv;
+mod preferences;
+mod resources;
+mod services;
 
-pub use directories::LYServerSharedDataDirectories;
+pub use directories::{plugin_dir_name, LYServerSharedDataDirectories, LYSERVER_PLUGIN_DATA_DIR};
 use lyserver_messaging_shared::LYServerMessageEvent;
 pub use status::LYServerSharedDataStatus;
-pub use plugins::{LYServerPluginInstance, LYServerSharedDataPlugins};
+pub use plugins::{LYServerInstalledPlugin, LYServerPluginController, LYServerPluginControllerError, LYServerPluginControllerErrorKind, LYServerPluginInstance, LYServerPluginSupervisorState, LYServerPluginSupervisorStatus, LYServerSharedDataPlugins};
 pub use messaging::{LYServerSharedDataMessaging};
-pub use database::{LYServerSharedDataDatabase};
+pub use database::{LYServerDatabaseRow, LYServerDatabaseService, LYServerDatabaseValue, LYServerSharedDataDatabase};
+pub use kv::{LYServerKVService, LYServerSharedDataKV};
+pub use preferences::{LYSERVER_PREFERENCE_CHANGED_EVENT, LYServerPreference, LYServerPreferenceType, LYServerPreferenceTyped, LYServerPreferencesService};
+pub use resources::{LYServerPluginResourceUsage, LYServerPluginResourceUsageData, LYServerSharedDataResources};
+pub use services::{LYServerSerializedService, LYServerService, LYServerServiceRegistry, LYServerSharedDataServices};
 
-use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
+use lyserver_plugin_common::LYServerPluginMetadata;
 use sysinfo::System;
-use tokio::sync::{broadcast::{Receiver, Sender}, mpsc::{UnboundedReceiver, UnboundedSender}, Mutex, RwLock};
+use tokio::sync::{broadcast::Sender, Mutex, RwLock};
 use tokio_util::sync::CancellationToken;
 
-use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener}, path::{Path, PathBuf}, sync::{atomic::AtomicBool, Arc}, time::SystemTime};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener}, path::PathBuf, sync::{atomic::AtomicBool, Arc}, time::SystemTime};
 
 use clap::Parser;
 
@@ -28,6 +36,15 @@ const SERVER_DEFAULT_DATA_DIR: &str = "/var/opt/lyserver";
 const SERVER_DEFAULT_DATA_DIR: &str = "/Library/Application Support/lyserver";
 #[cfg(target_os = "windows")]
 const SERVER_DEFAULT_DATA_DIR: &str = r"C:\ProgramData\lyserver";
+const SERVER_DEFAULT_PLUGIN_FUEL_PER_CALL: u64 = 1_000_000_000;
+const SERVER_DEFAULT_PLUGIN_MAX_MEMORY_BYTES: usize = 256 * 1024 * 1024;
+const SERVER_DEFAULT_PLUGIN_MAX_TABLE_ELEMENTS: usize = 100_000;
+const SERVER_DEFAULT_PLUGIN_MAX_INSTANCES: usize = 10;
+const SERVER_DEFAULT_PLUGIN_EVENT_CONCURRENCY: usize = 16;
+const SERVER_DEFAULT_PLUGIN_KV_MAX_BYTES: u64 = 16 * 1024 * 1024;
+const SERVER_DEFAULT_FFMPEG_PATH: &str = "ffmpeg";
+const SERVER_DEFAULT_TRANSCODE_WORKERS: usize = 2;
+const SERVER_DEFAULT_TRANSCODE_CACHE_MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -43,12 +60,73 @@ struct Args {
     /// Directory to store server data
     #[arg(short, long, default_value = SERVER_DEFAULT_DATA_DIR)]
     data_dir: String,
+
+    /// Default CPU budget of a single WASM plugin call in fuel units, 0 disables the limit
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_FUEL_PER_CALL)]
+    plugin_fuel_per_call: u64,
+
+    /// Default cap on the linear memory of a WASM plugin, in bytes
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_MAX_MEMORY_BYTES)]
+    plugin_max_memory_bytes: usize,
+
+    /// Default cap on the table elements of a WASM plugin
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_MAX_TABLE_ELEMENTS)]
+    plugin_max_table_elements: usize,
+
+    /// Default cap on the instances a WASM plugin may create
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_MAX_INSTANCES)]
+    plugin_max_instances: usize,
+
+    /// How many bus events a native plugin may handle at the same time
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_EVENT_CONCURRENCY)]
+    plugin_event_concurrency: usize,
+
+    /// Directory WASM plugins may be granted read access to with `fs.read:<path>`, can be given more than once
+    #[arg(long = "plugin-fs-allowed-root")]
+    plugin_fs_allowed_roots: Vec<PathBuf>,
+
+    /// Default cap on the key/value storage of a plugin, in bytes, 0 disables the limit
+    #[arg(long, default_value_t = SERVER_DEFAULT_PLUGIN_KV_MAX_BYTES)]
+    plugin_kv_max_bytes: u64,
+
+    /// ffmpeg binary used to transcode tracks
+    #[arg(long, default_value = SERVER_DEFAULT_FFMPEG_PATH)]
+    ffmpeg_path: PathBuf,
+
+    /// How many tracks may be transcoded at the same time
+    #[arg(long, default_value_t = SERVER_DEFAULT_TRANSCODE_WORKERS)]
+    transcode_workers: usize,
+
+    /// Cap on the cache of transcoded tracks, in bytes, the least recently used ones are evicted first
+    #[arg(long, default_value_t = SERVER_DEFAULT_TRANSCODE_CACHE_MAX_BYTES)]
+    transcode_cache_max_bytes: u64,
+
+    /// Token required as `Authorization: Bearer <token>` to install, remove, enable or disable plugins
+    /// over HTTP, without one these endpoints only accept clients connecting from localhost without a reverse proxy
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Only log the pending migrations of the server's databases and exit, without applying them
+    #[arg(long)]
+    migrations_dry_run: bool,
 }
 
 #[derive(Clone)]
 pub struct LYServerSharedData {
     pub bind_address: SocketAddr,
     pub data_dir: PathBuf,
+    pub plugin_fuel_per_call: u64,
+    pub plugin_max_memory_bytes: usize,
+    pub plugin_max_table_elements: usize,
+    pub plugin_max_instances: usize,
+    pub plugin_event_concurrency: usize,
+    pub plugin_fs_allowed_roots: Vec<PathBuf>,
+    pub plugin_kv_max_bytes: u64,
+    pub ffmpeg_path: PathBuf,
+    pub transcode_workers: usize,
+    pub transcode_cache_max_bytes: u64,
+    pub admin_token: Option<String>,
+    pub migrations_dry_run: bool,
     pub version: &'static str,
     pub start_ts: SystemTime,
 
@@ -56,6 +134,12 @@ pub struct LYServerSharedData {
 
     pub loaded_plugins: Arc<RwLock<Vec<(LYServerPluginInstance, LYServerPluginMetadata, CancellationToken)>>>,
     pub plugin_message: Arc<Mutex<Option<String>>>,
+    pub plugin_controller: Arc<RwLock<Option<Arc<dyn LYServerPluginController>>>>,
+    /// Updated from inside wasmtime resource limiter callbacks, so this can't be an async lock
+    pub plugin_resource_usage: Arc<std::sync::RwLock<HashMap<String, Arc<LYServerPluginResourceUsage>>>>,
+    pub plugin_supervisor_status: Arc<std::sync::RwLock<HashMap<String, LYServerPluginSupervisorStatus>>>,
+    /// Typed services published by plugins, keyed by the service type
+    pub services: Arc<std::sync::RwLock<LYServerServiceRegistry>>,
 
     pub messaging_global_tx: Arc<Sender<LYServerMessageEvent>>,
     pub messaging_plugin_tx: Arc<RwLock<HashMap<String, Arc<Sender<LYServerMessageEvent>>>>>,
@@ -74,7 +158,7 @@ pub struct LYServerSharedData {
 }
 
 impl LYServerSharedData {
-    fn new(bind_address: SocketAddr, data_dir: PathBuf) -> Self {
+    fn new(bind_address: SocketAddr, data_dir: PathBuf, args: &Args) -> Self {
         let (tx, _) = tokio::sync::broadcast::channel::<LYServerMessageEvent>(512);
 
         let pid = std::process::id();
@@ -90,11 +174,23 @@ impl LYServerSharedData {
         let os_kernel_version = System::kernel_version().unwrap_or_else(|| "Unknown".to_string());
         let os_hostname = System::host_name().unwrap_or_else(|| "Unknown".to_string());
 
-        let rx = tx.subscribe();
+        let _rx = tx.subscribe();
 
         let data = Self {
             bind_address,
             data_dir,
+            plugin_fuel_per_call: args.plugin_fuel_per_call,
+            plugin_max_memory_bytes: args.plugin_max_memory_bytes,
+            plugin_max_table_elements: args.plugin_max_table_elements,
+            plugin_max_instances: args.plugin_max_instances,
+            plugin_event_concurrency: args.plugin_event_concurrency.max(1),
+            plugin_fs_allowed_roots: args.plugin_fs_allowed_roots.clone(),
+            plugin_kv_max_bytes: args.plugin_kv_max_bytes,
+            ffmpeg_path: args.ffmpeg_path.clone(),
+            transcode_workers: args.transcode_workers.max(1),
+            transcode_cache_max_bytes: args.transcode_cache_max_bytes,
+            admin_token: args.admin_token.clone().filter(|token| !token.is_empty()),
+            migrations_dry_run: args.migrations_dry_run,
 
             version: env!("CARGO_PKG_VERSION"),
             start_ts: SystemTime::now(),
@@ -103,6 +199,10 @@ impl LYServerSharedData {
 
             loaded_plugins: Arc::new(RwLock::new(Vec::new())),
             plugin_message: Arc::new(Mutex::new(None)),
+            plugin_controller: Arc::new(RwLock::new(None)),
+            plugin_resource_usage: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            plugin_supervisor_status: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            services: Arc::new(std::sync::RwLock::new(LYServerServiceRegistry::default())),
 
             messaging_global_tx: Arc::new(tx),
             messaging_plugin_tx: Arc::new(HashMap::new().into()),
@@ -136,12 +236,36 @@ impl LYServerSharedData {
         log::info!("Server Options:");
         log::info!("    Bind Address: {}", data.bind_address);
         log::info!("    Data Directory: {}", data.data_dir.display());
+        log::info!("    Plugin Fuel Per Call: {}", data.plugin_fuel_per_call);
+        log::info!("    Plugin Memory Limit: {} bytes", data.plugin_max_memory_bytes);
+        log::info!("    Plugin KV Limit: {} bytes", data.plugin_kv_max_bytes);
+        log::info!("    Transcode Workers: {}", data.transcode_workers);
+        log::info!("    Plugin Management: {}", match data.admin_token {
+            Some(_) => "admin token",
+            None => "localhost only",
+        });
+
+        for root in &data.plugin_fs_allowed_roots {
+            log::info!("    Plugin Filesystem Root: {}", root.display());
+        }
 
         data
     }
 
     pub fn new_from_argv() -> anyhow::Result<Self> {
-        let args = Args::parse();
+        Self::new_from_parsed_args(Args::parse())
+    }
+
+    /// Like `new_from_argv`, but parses the given command line, e.g. to start a server in tests.
+    pub fn new_from_args<I, T>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Self::new_from_parsed_args(Args::try_parse_from(args)?)
+    }
+
+    fn new_from_parsed_args(args: Args) -> anyhow::Result<Self> {
 
         let bind_address = SocketAddr::new(args.address, args.port);
         if let Err(e) = TcpListener::bind(bind_address) {
@@ -168,7 +292,7 @@ impl LYServerSharedData {
         std::fs::read_dir(&data_dir)
             .map_err(|e| anyhow::anyhow!("Cannot read data directory '{}': {}", data_dir.display(), e))?;
  
-        Ok(Self::new(bind_address, data_dir))
+        Ok(Self::new(bind_address, data_dir, &args))
     }
 }
 
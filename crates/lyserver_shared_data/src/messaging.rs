@@ -1,9 +1,7 @@
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
 use lyserver_messaging_shared::LYServerMessageEvent;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::sync::{broadcast::Sender, mpsc::{UnboundedReceiver, UnboundedSender}, RwLock};
+use tokio::sync::broadcast::Sender;
 
 use crate::LYServerSharedData;
 
@@ -15,6 +13,7 @@ pub trait LYServerSharedDataMessaging {
         plugin_id: String,
         tx: Arc<Sender<LYServerMessageEvent>>,
     ) -> anyhow::Result<()>;
+    async fn unregister_plugin_messaging(&self, plugin_id: &str) -> anyhow::Result<()>;
     async fn receive_event(&self) -> Option<LYServerMessageEvent>;
 }
 
@@ -52,6 +51,21 @@ impl LYServerSharedDataMessaging for LYServerSharedData {
         Ok(())
     }
 
+    async fn unregister_plugin_messaging(&self, plugin_id: &str) -> anyhow::Result<()> {
+        log::info!("Unregistering plugin messaging for '{}'", plugin_id);
+
+        self.messaging_plugin_tx
+            .write()
+            .await
+            .remove(plugin_id)
+            .ok_or_else(|| anyhow::anyhow!(
+                "Plugin '{}' is not registered for messaging",
+                plugin_id
+            ))?;
+
+        Ok(())
+    }
+
     async fn receive_event(&self) -> Option<LYServerMessageEvent> {
         let mut rx = self.messaging_global_tx.subscribe();
 
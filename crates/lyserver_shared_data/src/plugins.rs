@@ -1,16 +1,91 @@
-use std::{path::{Path, PathBuf}, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc};
 
 use lyserver_plugin_common::{LYServerPlugin, LYServerPluginMetadata};
-use tokio::sync::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 
 use crate::LYServerSharedData;
 
 pub type LYServerPluginInstance = Arc<dyn LYServerPlugin + Send + Sync>;
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LYServerInstalledPlugin {
+    pub metadata: LYServerPluginMetadata,
+    pub path: Option<String>,
+    pub builtin: bool,
+    pub enabled: bool,
+    pub loaded: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LYServerPluginSupervisorStatus {
+    pub state: LYServerPluginSupervisorState,
+    pub restart_count: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LYServerPluginSupervisorState {
+    #[default]
+    Running,
+    Restarting,
+    /// The plugin crashed and will not be restarted anymore
+    Failed,
+}
+
+/// Why a plugin operation was refused, failures without one went wrong on the server's side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LYServerPluginControllerErrorKind {
+    NotInstalled,
+    /// The plugin is built in, or its id is taken by a built-in plugin
+    Conflict,
+    /// The archive is not a plugin or its manifest is invalid
+    InvalidArchive,
+}
+
+#[derive(Debug)]
+pub struct LYServerPluginControllerError {
+    pub kind: LYServerPluginControllerErrorKind,
+    pub message: String,
+}
+
+impl LYServerPluginControllerError {
+    pub fn new(kind: LYServerPluginControllerErrorKind, message: impl std::fmt::Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for LYServerPluginControllerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LYServerPluginControllerError {}
+
+/// Runtime management of plugins, implemented by the server's plugin manager.
+#[async_trait::async_trait]
+pub trait LYServerPluginController: Send + Sync {
+    async fn list_plugins(&self) -> anyhow::Result<Vec<LYServerInstalledPlugin>>;
+    /// Installs (or upgrades) a plugin from a zip or tar archive containing its manifest.toml and wasm module.
+    async fn install_plugin(&self, archive: Vec<u8>) -> anyhow::Result<LYServerPluginMetadata>;
+    async fn uninstall_plugin(&self, plugin_id: &str) -> anyhow::Result<()>;
+    async fn enable_plugin(&self, plugin_id: &str) -> anyhow::Result<()>;
+    async fn disable_plugin(&self, plugin_id: &str) -> anyhow::Result<()>;
+}
+
 #[async_trait::async_trait]
 pub trait LYServerSharedDataPlugins {
     async fn get_plugin_by_id(&self, id: &str) -> Option<LYServerPluginInstance>;
     async fn get_plugin_metadata_by_id(&self, id: &str) -> Option<LYServerPluginMetadata>;
+    async fn get_plugin_controller(&self) -> Option<Arc<dyn LYServerPluginController>>;
+    async fn set_plugin_controller(&self, controller: Arc<dyn LYServerPluginController>);
+    fn get_plugin_supervisor_status(&self) -> BTreeMap<String, LYServerPluginSupervisorStatus>;
+    fn set_plugin_supervisor_status(&self, id: &str, status: LYServerPluginSupervisorStatus);
+    fn remove_plugin_supervisor_status(&self, id: &str);
 }
 
 #[async_trait::async_trait]
@@ -40,4 +115,27 @@ impl LYServerSharedDataPlugins for LYServerSharedData {
             .into_iter()
             .find(|metadata| metadata.id == id)
     }
+
+    async fn get_plugin_controller(&self) -> Option<Arc<dyn LYServerPluginController>> {
+        self.plugin_controller.read().await.clone()
+    }
+
+    async fn set_plugin_controller(&self, controller: Arc<dyn LYServerPluginController>) {
+        *self.plugin_controller.write().await = Some(controller);
+    }
+
+    fn get_plugin_supervisor_status(&self) -> BTreeMap<String, LYServerPluginSupervisorStatus> {
+        self.plugin_supervisor_status.read().unwrap()
+            .iter()
+            .map(|(id, status)| (id.clone(), status.clone()))
+            .collect()
+    }
+
+    fn set_plugin_supervisor_status(&self, id: &str, status: LYServerPluginSupervisorStatus) {
+        self.plugin_supervisor_status.write().unwrap().insert(id.to_string(), status);
+    }
+
+    fn remove_plugin_supervisor_status(&self, id: &str) {
+        self.plugin_supervisor_status.write().unwrap().remove(id);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::LYServerService;
+
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LYServerPreferenceType {
+    Null = 0,
+    I32 = 1,
+    F32 = 2,
+    U32 = 3,
+    Boolean = 4,
+    String = 5,
+    JSON = 6,
+}
+
+impl LYServerPreferenceType {
+    /// The native type a JSON value is stored as.
+    pub fn from_value(value: &Value) -> anyhow::Result<Self> {
+        match value {
+            Value::Null => Ok(LYServerPreferenceType::Null),
+            Value::Bool(_) => Ok(LYServerPreferenceType::Boolean),
+            Value::Number(num) => {
+                if num.is_f64() {
+                    Ok(LYServerPreferenceType::F32)
+                } else if num.is_u64() {
+                    Ok(LYServerPreferenceType::U32)
+                } else if num.is_i64() {
+                    Ok(LYServerPreferenceType::I32)
+                } else {
+                    Err(anyhow::anyhow!("Invalid number type in preference value"))
+                }
+            },
+            Value::String(_) => Ok(LYServerPreferenceType::String),
+            Value::Array(_) => Ok(LYServerPreferenceType::JSON),
+            Value::Object(_) => Ok(LYServerPreferenceType::JSON),
+        }
+    }
+}
+
+impl Serialize for LYServerPreferenceType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u8(self.clone() as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for LYServerPreferenceType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = u8::deserialize(deserializer)?;
+        match value {
+            0 => Ok(LYServerPreferenceType::Null),
+            1 => Ok(LYServerPreferenceType::I32),
+            2 => Ok(LYServerPreferenceType::F32),
+            3 => Ok(LYServerPreferenceType::U32),
+            4 => Ok(LYServerPreferenceType::Boolean),
+            5 => Ok(LYServerPreferenceType::String),
+            6 => Ok(LYServerPreferenceType::JSON),
+            _ => Err(serde::de::Error::custom("Invalid LYServerPreferenceType value")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LYServerPreference {
+    Null(LYServerPreferenceTyped<Option<()>>),
+    I32(LYServerPreferenceTyped<i32>),
+    F32(LYServerPreferenceTyped<f32>),
+    U32(LYServerPreferenceTyped<u32>),
+    Boolean(LYServerPreferenceTyped<bool>),
+    String(LYServerPreferenceTyped<String>),
+    JSON(LYServerPreferenceTyped<Value>),
+}
+
+impl LYServerPreference {
+    pub fn key(&self) -> &str {
+        match self {
+            LYServerPreference::Null(pref) => &pref.key,
+            LYServerPreference::I32(pref) => &pref.key,
+            LYServerPreference::F32(pref) => &pref.key,
+            LYServerPreference::U32(pref) => &pref.key,
+            LYServerPreference::Boolean(pref) => &pref.key,
+            LYServerPreference::String(pref) => &pref.key,
+            LYServerPreference::JSON(pref) => &pref.key,
+        }
+    }
+
+    pub fn value(&self) -> Value {
+        match self {
+            LYServerPreference::Null(_) => Value::Null,
+            LYServerPreference::I32(pref) => Value::from(pref.value),
+            LYServerPreference::F32(pref) => Value::from(pref.value),
+            LYServerPreference::U32(pref) => Value::from(pref.value),
+            LYServerPreference::Boolean(pref) => Value::from(pref.value),
+            LYServerPreference::String(pref) => Value::from(pref.value.clone()),
+            LYServerPreference::JSON(pref) => pref.value.clone(),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        match self {
+            LYServerPreference::Null(pref) => pref.is_locked,
+            LYServerPreference::I32(pref) => pref.is_locked,
+            LYServerPreference::F32(pref) => pref.is_locked,
+            LYServerPreference::U32(pref) => pref.is_locked,
+            LYServerPreference::Boolean(pref) => pref.is_locked,
+            LYServerPreference::String(pref) => pref.is_locked,
+            LYServerPreference::JSON(pref) => pref.is_locked,
+        }
+    }
+}
+
+impl Serialize for LYServerPreference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            LYServerPreference::Null(pref) => pref.serialize(serializer),
+            LYServerPreference::I32(pref) => pref.serialize(serializer),
+            LYServerPreference::F32(pref) => pref.serialize(serializer),
+            LYServerPreference::U32(pref) => pref.serialize(serializer),
+            LYServerPreference::Boolean(pref) => pref.serialize(serializer),
+            LYServerPreference::String(pref) => pref.serialize(serializer),
+            LYServerPreference::JSON(pref) => pref.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LYServerPreferenceTyped<T> {
+    pub key: String,
+    pub value: T,
+    pub native_type: LYServerPreferenceType,
+    pub is_locked: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Event sent to every plugin when a preference was set or deleted, its data is the preference key.
+pub const LYSERVER_PREFERENCE_CHANGED_EVENT: &str = "preference_changed";
+
+/// Reads and writes server preferences, provided by `preferences@lyserver.local`.
+#[async_trait::async_trait]
+pub trait LYServerPreferencesService: Send + Sync {
+    async fn get(&self, key: &str) -> anyhow::Result<LYServerPreference>;
+    async fn get_all(&self) -> anyhow::Result<Vec<LYServerPreference>>;
+    /// Creates or updates a preference, returning it as stored.
+    async fn set(&self, key: &str, value: Value) -> anyhow::Result<LYServerPreference>;
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+impl LYServerService for dyn LYServerPreferencesService {
+    const NAME: &'static str = "preferences";
+}
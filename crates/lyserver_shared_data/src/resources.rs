@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::LYServerSharedData;
+
+/// Live resource usage of a WASM plugin, updated by the resource limiter of its store.
+#[derive(Debug, Default)]
+pub struct LYServerPluginResourceUsage {
+    pub memory_bytes: AtomicUsize,
+    pub table_elements: AtomicUsize,
+
+    pub max_memory_bytes: usize,
+    pub max_table_elements: usize,
+    pub max_instances: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LYServerPluginResourceUsageData {
+    pub memory_bytes: usize,
+    pub max_memory_bytes: usize,
+    pub table_elements: usize,
+    pub max_table_elements: usize,
+    pub max_instances: usize,
+}
+
+impl LYServerPluginResourceUsage {
+    pub fn new(max_memory_bytes: usize, max_table_elements: usize, max_instances: usize) -> Self {
+        Self {
+            max_memory_bytes,
+            max_table_elements,
+            max_instances,
+            ..Default::default()
+        }
+    }
+
+    /// Clears the usage counters, used when the plugin store is re-created.
+    pub fn reset(&self) {
+        self.memory_bytes.store(0, Ordering::Relaxed);
+        self.table_elements.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LYServerPluginResourceUsageData {
+        LYServerPluginResourceUsageData {
+            memory_bytes: self.memory_bytes.load(Ordering::Relaxed),
+            max_memory_bytes: self.max_memory_bytes,
+            table_elements: self.table_elements.load(Ordering::Relaxed),
+            max_table_elements: self.max_table_elements,
+            max_instances: self.max_instances,
+        }
+    }
+}
+
+pub trait LYServerSharedDataResources {
+    fn register_plugin_resource_usage(&self, plugin_id: &str, usage: Arc<LYServerPluginResourceUsage>);
+    fn unregister_plugin_resource_usage(&self, plugin_id: &str);
+    fn get_plugin_resource_usage(&self) -> BTreeMap<String, LYServerPluginResourceUsageData>;
+}
+
+impl LYServerSharedDataResources for LYServerSharedData {
+    fn register_plugin_resource_usage(&self, plugin_id: &str, usage: Arc<LYServerPluginResourceUsage>) {
+        self.plugin_resource_usage.write().unwrap().insert(plugin_id.to_string(), usage);
+    }
+
+    fn unregister_plugin_resource_usage(&self, plugin_id: &str) {
+        self.plugin_resource_usage.write().unwrap().remove(plugin_id);
+    }
+
+    fn get_plugin_resource_usage(&self) -> BTreeMap<String, LYServerPluginResourceUsageData> {
+        self.plugin_resource_usage.read().unwrap()
+            .iter()
+            .map(|(plugin_id, usage)| (plugin_id.clone(), usage.snapshot()))
+            .collect()
+    }
+}
@@ -0,0 +1,89 @@
+use std::{any::{Any, TypeId}, collections::{BTreeMap, HashMap}, sync::Arc};
+
+use crate::LYServerSharedData;
+
+/// A service interface plugins can publish, implemented on the `dyn` trait object type of the service,
+/// e.g. `impl LYServerService for dyn LYServerDatabaseService`.
+pub trait LYServerService: Send + Sync + 'static {
+    /// Name the service is known by to WASM guests and in `services.call:<name>` capabilities
+    const NAME: &'static str;
+
+    /// Adapter exposing the service to WASM guests, which can only pass serialized arguments.
+    fn serialized(_service: Arc<Self>) -> Option<Arc<dyn LYServerSerializedService>> {
+        None
+    }
+}
+
+/// Serialized fallback of a service, taking and returning CBOR encoded values.
+#[async_trait::async_trait]
+pub trait LYServerSerializedService: Send + Sync {
+    async fn call(&self, method: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+}
+
+struct LYServerRegisteredService {
+    provider: String,
+    name: &'static str,
+    /// Holds an `Arc<S>` for the service type `S` it is keyed by
+    service: Box<dyn Any + Send + Sync>,
+    serialized: Option<Arc<dyn LYServerSerializedService>>,
+}
+
+#[derive(Default)]
+pub struct LYServerServiceRegistry {
+    services: HashMap<TypeId, LYServerRegisteredService>,
+}
+
+pub trait LYServerSharedDataServices {
+    /// Publishes `service` on behalf of the plugin `provider`, replacing any previous provider of the same service.
+    fn register_service<S: LYServerService + ?Sized>(&self, provider: &str, service: Arc<S>);
+    /// Removes every service published by the plugin `provider`, called when it is unloaded.
+    fn unregister_plugin_services(&self, provider: &str);
+    fn get_service<S: LYServerService + ?Sized>(&self) -> Option<Arc<S>>;
+    fn get_serialized_service(&self, name: &str) -> Option<Arc<dyn LYServerSerializedService>>;
+    /// The registered service names and the plugins providing them.
+    fn get_service_providers(&self) -> BTreeMap<String, String>;
+}
+
+impl LYServerSharedDataServices for LYServerSharedData {
+    fn register_service<S: LYServerService + ?Sized>(&self, provider: &str, service: Arc<S>) {
+        log::info!("Plugin '{}' provides the '{}' service", provider, S::NAME);
+
+        let registered_service = LYServerRegisteredService {
+            provider: provider.to_string(),
+            name: S::NAME,
+            serialized: S::serialized(Arc::clone(&service)),
+            service: Box::new(service),
+        };
+
+        if let Some(previous) = self.services.write().unwrap().services.insert(TypeId::of::<S>(), registered_service)
+            && previous.provider != provider {
+                log::warn!("Plugin '{}' replaced '{}' as the provider of the '{}' service", provider, previous.provider, S::NAME);
+            }
+    }
+
+    fn unregister_plugin_services(&self, provider: &str) {
+        self.services.write().unwrap().services
+            .retain(|_, registered_service| registered_service.provider != provider);
+    }
+
+    fn get_service<S: LYServerService + ?Sized>(&self) -> Option<Arc<S>> {
+        self.services.read().unwrap().services
+            .get(&TypeId::of::<S>())
+            .and_then(|registered_service| registered_service.service.downcast_ref::<Arc<S>>())
+            .cloned()
+    }
+
+    fn get_serialized_service(&self, name: &str) -> Option<Arc<dyn LYServerSerializedService>> {
+        self.services.read().unwrap().services
+            .values()
+            .find(|registered_service| registered_service.name == name)
+            .and_then(|registered_service| registered_service.serialized.clone())
+    }
+
+    fn get_service_providers(&self) -> BTreeMap<String, String> {
+        self.services.read().unwrap().services
+            .values()
+            .map(|registered_service| (registered_service.name.to_string(), registered_service.provider.clone()))
+            .collect()
+    }
+}
@@ -1,12 +1,12 @@
 use std::{
-    path::{Path, PathBuf}, sync::Arc, time::Duration
+    collections::BTreeMap, sync::Arc, time::Duration
 };
 
 use lyserver_plugin_common::LYServerPluginMetadata;
 use serde::{Deserialize, Serialize};
-use sysinfo::{Pid, System};
+use sysinfo::Pid;
 
-use crate::LYServerSharedData;
+use crate::{LYServerPluginResourceUsageData, LYServerPluginSupervisorStatus, LYServerSharedData, LYServerSharedDataPlugins as _, LYServerSharedDataResources as _};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LYServerSharedDataStatusData {
@@ -15,6 +15,8 @@ pub struct LYServerSharedDataStatusData {
     pub uptime: u128,
     pub start_time: u128,
     pub loaded_plugins: Vec<LYServerPluginMetadata>,
+    pub plugin_resources: BTreeMap<String, LYServerPluginResourceUsageData>,
+    pub plugin_supervisors: BTreeMap<String, LYServerPluginSupervisorStatus>,
     pub pid: u32,
     pub used_memory: u64,
     pub cpu_count: usize,
@@ -37,12 +39,14 @@ impl LYServerSharedDataStatus for LYServerSharedData {
             .unwrap_or(self.data_dir.display().to_string());
 
         let version = self.version;
-        let start_ts = self.start_ts.clone();
+        let start_ts = self.start_ts;
         let loaded_plugins = self.loaded_plugins.clone();
+        let plugin_resources = self.get_plugin_resource_usage();
+        let plugin_supervisors = self.get_plugin_supervisor_status();
 
         let system_clone = Arc::clone(&self.system);
-        let system_cpu_count_clone = self.system_cpu_count.clone();
-        let pid_clone = self.pid.clone();
+        let system_cpu_count_clone = self.system_cpu_count;
+        let pid_clone = self.pid;
         let platform_clone = self.platform.clone();
         let os_clone = self.os.clone();
         let os_version_clone = self.os_version.clone();
@@ -67,6 +71,8 @@ impl LYServerSharedDataStatus for LYServerSharedData {
                     .unwrap_or(Duration::from_secs(0))
                     .as_secs() as u128,
                 loaded_plugins,
+                plugin_resources,
+                plugin_supervisors,
                 pid: pid_clone,
                 used_memory,
                 cpu_count: system_cpu_count_clone,